@@ -0,0 +1,348 @@
+//! Minimal systemd-style calendar expressions (`OnCalendar`)
+//!
+//! Supported shape: `[Weekdays] [Year-Month-Day] [Hour:Minute[:Second]]`, where each
+//! numeric component may be `*`, a value, a `a..b` range, a `/step` repetition, or a
+//! comma-separated list of those. Examples: `03:00`, `*-*-* 04:30:00`, `Mon,Fri 09:00`,
+//! `Mon..Fri *-*-* 08:00`, `*:0/15`. The shortcuts `minutely`, `hourly`, `daily`,
+//! `weekly` and `monthly` stand for the start of each such period.
+
+use anyhow::{Context, Result, bail};
+use chrono::{
+    DateTime, Datelike, Duration as ChronoDuration, Local, NaiveDate, TimeZone, Timelike,
+};
+
+/// How many days ahead we search before giving up on a spec that never matches
+const SEARCH_DAYS: i64 = 366 * 5;
+
+/// A parsed calendar expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CalendarSpec {
+    /// Allowed weekdays, Monday = 0; `None` means every day
+    weekdays: Option<Vec<u32>>,
+    years: Option<Vec<u32>>,
+    months: Option<Vec<u32>>,
+    days: Option<Vec<u32>>,
+    hours: Vec<u32>,
+    minutes: Vec<u32>,
+    seconds: Vec<u32>,
+}
+
+const WEEKDAYS: [&str; 7] = ["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+/// Shortcuts and the expressions they stand for, as in systemd
+const SHORTCUTS: [(&str, &str); 5] = [
+    ("minutely", "*-*-* *:*:00"),
+    ("hourly", "*-*-* *:00:00"),
+    ("daily", "*-*-* 00:00:00"),
+    ("weekly", "Mon *-*-* 00:00:00"),
+    ("monthly", "*-*-01 00:00:00"),
+];
+
+impl CalendarSpec {
+    pub fn parse(expr: &str) -> Result<Self> {
+        let shortcut = SHORTCUTS
+            .iter()
+            .find(|(name, _)| expr.trim().eq_ignore_ascii_case(name));
+        if let Some((_, canonical)) = shortcut {
+            return Self::parse(canonical);
+        }
+
+        let mut weekdays = None;
+        let mut date = None;
+        let mut time = None;
+
+        for token in expr.split_whitespace() {
+            if token.starts_with(|c: char| c.is_ascii_alphabetic()) && weekdays.is_none() {
+                weekdays = Some(parse_weekdays(token)?);
+            } else if token.contains(':') && time.is_none() {
+                time = Some(token);
+            } else if token.contains('-') && date.is_none() && time.is_none() {
+                date = Some(token);
+            } else {
+                bail!(
+                    "Unexpected token {:?} in calendar expression {:?}",
+                    token,
+                    expr
+                );
+            }
+        }
+
+        if weekdays.is_none() && date.is_none() && time.is_none() {
+            bail!("Empty calendar expression");
+        }
+
+        let (years, months, days) = match date {
+            Some(date) => {
+                let parts: Vec<&str> = date.split('-').collect();
+                let [y, m, d] = parts[..] else {
+                    bail!("Invalid date {:?}, expected Year-Month-Day", date);
+                };
+                (
+                    parse_field(y, 1970, 2199).context("Invalid year")?,
+                    parse_field(m, 1, 12).context("Invalid month")?,
+                    parse_field(d, 1, 31).context("Invalid day")?,
+                )
+            }
+            None => (None, None, None),
+        };
+
+        let (hours, minutes, seconds) = match time {
+            Some(time) => {
+                let parts: Vec<&str> = time.split(':').collect();
+                let (h, m, s) = match parts[..] {
+                    [h, m] => (h, m, "0"),
+                    [h, m, s] => (h, m, s),
+                    _ => bail!("Invalid time {:?}, expected Hour:Minute[:Second]", time),
+                };
+                (
+                    expand(parse_field(h, 0, 23).context("Invalid hour")?, 0, 23),
+                    expand(parse_field(m, 0, 59).context("Invalid minute")?, 0, 59),
+                    expand(parse_field(s, 0, 59).context("Invalid second")?, 0, 59),
+                )
+            }
+            None => (vec![0], vec![0], vec![0]),
+        };
+
+        Ok(Self {
+            weekdays,
+            years,
+            months,
+            days,
+            hours,
+            minutes,
+            seconds,
+        })
+    }
+
+    fn matches_date(&self, date: NaiveDate) -> bool {
+        let allowed = |field: &Option<Vec<u32>>, value: u32| {
+            field.as_ref().is_none_or(|values| values.contains(&value))
+        };
+
+        allowed(&self.weekdays, date.weekday().num_days_from_monday())
+            && allowed(&self.years, date.year() as u32)
+            && allowed(&self.months, date.month())
+            && allowed(&self.days, date.day())
+    }
+
+    /// First (hour, minute, second) on a matching day that is not earlier than `min`
+    fn first_time_from(&self, min: (u32, u32, u32)) -> Option<(u32, u32, u32)> {
+        for &h in &self.hours {
+            for &m in &self.minutes {
+                for &s in &self.seconds {
+                    if (h, m, s) >= min {
+                        return Some((h, m, s));
+                    }
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Returns the first instant strictly after `now` that matches `spec`
+///
+/// Returns `None` if nothing matches within the search horizon (e.g. a date in the past).
+pub fn next_occurrence(spec: &CalendarSpec, now: DateTime<Local>) -> Option<DateTime<Local>> {
+    let start = now + ChronoDuration::seconds(1);
+    let start_time = (start.hour(), start.minute(), start.second());
+    let start_date = start.date_naive();
+
+    for offset in 0..SEARCH_DAYS {
+        let date = start_date + ChronoDuration::days(offset);
+        if !spec.matches_date(date) {
+            continue;
+        }
+
+        let min = if offset == 0 { start_time } else { (0, 0, 0) };
+        let mut from = min;
+        // Skip local times that fall into a DST gap
+        while let Some((h, m, s)) = spec.first_time_from(from) {
+            let naive = date.and_hms_opt(h, m, s)?;
+            if let Some(instant) = Local.from_local_datetime(&naive).earliest() {
+                return Some(instant);
+            }
+            from = (h, m, s + 1);
+        }
+    }
+    None
+}
+
+/// The earliest [`next_occurrence`] of any of `specs`, e.g. of a unit's `OnCalendar` list
+pub fn next_occurrence_of(specs: &[CalendarSpec], now: DateTime<Local>) -> Option<DateTime<Local>> {
+    specs
+        .iter()
+        .filter_map(|spec| next_occurrence(spec, now))
+        .min()
+}
+
+fn parse_weekdays(token: &str) -> Result<Vec<u32>> {
+    let lookup = |name: &str| {
+        let lower = name.to_ascii_lowercase();
+        WEEKDAYS
+            .iter()
+            .position(|day| lower.starts_with(day))
+            .map(|i| i as u32)
+            .with_context(|| format!("Unknown weekday {:?}", name))
+    };
+
+    let mut days = Vec::new();
+    for part in token.split(',') {
+        match part.split_once("..") {
+            Some((from, to)) => {
+                let (from, to) = (lookup(from)?, lookup(to)?);
+                if from > to {
+                    bail!("Invalid weekday range {:?}", part);
+                }
+                days.extend(from..=to);
+            }
+            None => days.push(lookup(part)?),
+        }
+    }
+    days.sort_unstable();
+    days.dedup();
+    Ok(days)
+}
+
+/// Parses one numeric component. `None` means "any value".
+fn parse_field(field: &str, min: u32, max: u32) -> Result<Option<Vec<u32>>> {
+    if field == "*" {
+        return Ok(None);
+    }
+
+    let number = |s: &str| -> Result<u32> {
+        let value: u32 = s
+            .parse()
+            .with_context(|| format!("Invalid number {:?}", s))?;
+        if !(min..=max).contains(&value) {
+            bail!("{} is out of range {}..{}", value, min, max);
+        }
+        Ok(value)
+    };
+
+    let mut values = Vec::new();
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => {
+                let step: u32 = step
+                    .parse()
+                    .with_context(|| format!("Invalid step {:?}", step))?;
+                if step == 0 {
+                    bail!("Step must be positive");
+                }
+                (range, Some(step))
+            }
+            None => (part, None),
+        };
+
+        let (from, to) = match range.split_once("..") {
+            Some((from, to)) => (number(from)?, number(to)?),
+            None if range == "*" => (min, max),
+            None => {
+                let from = number(range)?;
+                // "a/step" repeats until the end of the range
+                (from, if step.is_some() { max } else { from })
+            }
+        };
+        if from > to {
+            bail!("Invalid range {:?}", part);
+        }
+        values.extend((from..=to).step_by(step.unwrap_or(1) as usize));
+    }
+    values.sort_unstable();
+    values.dedup();
+    Ok(Some(values))
+}
+
+fn expand(field: Option<Vec<u32>>, min: u32, max: u32) -> Vec<u32> {
+    field.unwrap_or_else(|| (min..=max).collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(y: i32, mo: u32, d: u32, h: u32, mi: u32, s: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, s).unwrap()
+    }
+
+    fn next(expr: &str, now: DateTime<Local>) -> DateTime<Local> {
+        next_occurrence(&CalendarSpec::parse(expr).unwrap(), now).unwrap()
+    }
+
+    #[test]
+    fn parses_fields() {
+        let spec = CalendarSpec::parse("Mon..Wed,Fri *-1,7-1/10 08:0/20").unwrap();
+        assert_eq!(spec.weekdays, Some(vec![0, 1, 2, 4]));
+        assert_eq!(spec.years, None);
+        assert_eq!(spec.months, Some(vec![1, 7]));
+        assert_eq!(spec.days, Some(vec![1, 11, 21, 31]));
+        assert_eq!(spec.hours, vec![8]);
+        assert_eq!(spec.minutes, vec![0, 20, 40]);
+        assert_eq!(spec.seconds, vec![0]);
+
+        let spec = CalendarSpec::parse("*:*:30").unwrap();
+        assert_eq!(spec.hours.len(), 24);
+        assert_eq!(spec.minutes.len(), 60);
+        assert_eq!(spec.seconds, vec![30]);
+    }
+
+    #[test]
+    fn rejects_malformed_expressions() {
+        for expr in [
+            "",
+            "25:00",
+            "12:60",
+            "*-13-01",
+            "*-*",
+            "Funday 10:00",
+            "Fri..Mon",
+            "*:0/0",
+            "10:00 11:00",
+            "1:2:3:4",
+        ] {
+            assert!(CalendarSpec::parse(expr).is_err(), "{:?} parsed", expr);
+        }
+    }
+
+    #[test]
+    fn next_occurrence_is_strictly_later() {
+        // 2026-03-02 is a Monday
+        let now = at(2026, 3, 2, 3, 0, 0);
+        assert_eq!(next("03:00", now), at(2026, 3, 3, 3, 0, 0));
+        assert_eq!(next("04:30", now), at(2026, 3, 2, 4, 30, 0));
+        assert_eq!(next("*:0/15", now), at(2026, 3, 2, 3, 15, 0));
+        assert_eq!(next("Fri 09:00", now), at(2026, 3, 6, 9, 0, 0));
+        assert_eq!(next("*-*-01 00:00", now), at(2026, 4, 1, 0, 0, 0));
+        assert_eq!(next("2027-01-01", now), at(2027, 1, 1, 0, 0, 0));
+        let past = CalendarSpec::parse("2020-01-01 00:00").unwrap();
+        assert_eq!(next_occurrence(&past, now), None);
+    }
+
+    #[test]
+    fn shortcuts_stand_for_their_expressions() {
+        for (name, canonical) in SHORTCUTS {
+            assert_eq!(
+                CalendarSpec::parse(name).unwrap(),
+                CalendarSpec::parse(canonical).unwrap()
+            );
+        }
+        assert!(CalendarSpec::parse(" Daily ").is_ok());
+
+        let now = at(2026, 3, 4, 10, 20, 30);
+        assert_eq!(next("minutely", now), at(2026, 3, 4, 10, 21, 0));
+        assert_eq!(next("hourly", now), at(2026, 3, 4, 11, 0, 0));
+        assert_eq!(next("weekly", now), at(2026, 3, 9, 0, 0, 0));
+    }
+
+    #[test]
+    fn a_list_takes_its_earliest_entry() {
+        let specs = ["Sat 10:00", "Thu 09:00"].map(|expr| CalendarSpec::parse(expr).unwrap());
+        let now = at(2026, 3, 4, 10, 0, 0);
+        assert_eq!(
+            next_occurrence_of(&specs, now),
+            Some(at(2026, 3, 5, 9, 0, 0))
+        );
+        assert_eq!(next_occurrence_of(&[], now), None);
+    }
+}
@@ -0,0 +1,292 @@
+//! `Condition*` checks evaluated when a timer fires
+
+use crate::unit::{NetworkProbe, TimerUnit};
+use log::debug;
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Sysfs directory holding the device's power supplies
+const POWER_SUPPLY_DIR: &str = "/sys/class/power_supply";
+
+/// Sysfs directory holding the network interfaces
+const NET_DIR: &str = "/sys/class/net";
+
+/// How long a `Connect` probe waits for each address before giving up on it
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// `ARPHRD_LOOPBACK`, the `type` of loopback interfaces
+const LOOPBACK_TYPE: &str = "772";
+
+/// Evaluates a path condition; a leading '!' negates it (systemd convention)
+fn path_condition(spec: &Path, test: impl Fn(&Path) -> bool) -> bool {
+    match spec.to_str().and_then(|s| s.strip_prefix('!')) {
+        Some(path) => !test(Path::new(path)),
+        None => test(spec),
+    }
+}
+
+/// Whether external power is connected, judged from `<root>/*/online` of non-battery supplies
+///
+/// Falls back to the battery's `status` when no such supply reports anything.
+fn on_ac_power(root: &Path) -> bool {
+    let read = |path: PathBuf| fs::read_to_string(path).map(|s| s.trim().to_string());
+
+    if let Ok(entries) = fs::read_dir(root) {
+        for entry in entries.flatten() {
+            let supply = entry.path();
+            if read(supply.join("type")).is_ok_and(|kind| kind == "Battery") {
+                continue;
+            }
+            if read(supply.join("online")).is_ok_and(|online| online == "1") {
+                return true;
+            }
+        }
+    }
+
+    read(root.join("battery").join("status"))
+        .is_ok_and(|status| status == "Charging" || status == "Full")
+}
+
+/// Battery charge in percent, or `None` on devices that don't report it
+fn battery_capacity(root: &Path) -> Option<u8> {
+    fs::read_to_string(root.join("battery").join("capacity"))
+        .ok()?
+        .trim()
+        .parse()
+        .ok()
+}
+
+/// Evaluates `ConditionACPower`/`ConditionBatteryAbove` against the supplies under `root`
+fn power_conditions_met(unit: &TimerUnit, root: &Path) -> bool {
+    let ac = unit
+        .condition_ac_power
+        .is_none_or(|required| on_ac_power(root) == required);
+
+    // Without a readable capacity we can't judge, so don't block the unit
+    let battery = unit
+        .condition_battery_above
+        .is_none_or(|min| battery_capacity(root).is_none_or(|capacity| capacity >= min));
+
+    ac && battery
+}
+
+/// Whether some non-loopback interface under `root` is up
+///
+/// Cellular interfaces often report `unknown`, so those count when they have a carrier.
+fn interface_up(root: &Path) -> bool {
+    let read = |path: PathBuf| fs::read_to_string(path).map(|s| s.trim().to_string());
+
+    let Ok(entries) = fs::read_dir(root) else {
+        return false;
+    };
+    entries.flatten().any(|entry| {
+        let iface = entry.path();
+        if read(iface.join("type")).is_ok_and(|kind| kind == LOOPBACK_TYPE) {
+            return false;
+        }
+        match read(iface.join("operstate")).as_deref() {
+            Ok("up") => true,
+            Ok("unknown") => read(iface.join("carrier")).is_ok_and(|carrier| carrier == "1"),
+            _ => false,
+        }
+    })
+}
+
+/// Whether a TCP connection to `target` (`host:port`) succeeds on any of its addresses
+fn can_connect(target: &str) -> bool {
+    let addrs = match target.to_socket_addrs() {
+        Ok(addrs) => addrs,
+        Err(e) => {
+            debug!("Failed to resolve {}: {}", target, e);
+            return false;
+        }
+    };
+    addrs
+        .into_iter()
+        .any(|addr| TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT).is_ok())
+}
+
+/// Whether the network is reachable according to `probe`, with interfaces under `net_root`
+fn network_online(probe: &NetworkProbe, net_root: &Path) -> bool {
+    match probe {
+        NetworkProbe::Operstate => interface_up(net_root),
+        NetworkProbe::Connect(target) => can_connect(target),
+    }
+}
+
+/// Checks evaluated at firing time; all must hold for the unit to run
+pub(crate) fn conditions_met(unit: &TimerUnit) -> bool {
+    let exists = unit
+        .condition_path_exists
+        .as_deref()
+        .is_none_or(|spec| path_condition(spec, Path::exists));
+
+    let not_empty = unit.condition_file_not_empty.as_deref().is_none_or(|spec| {
+        path_condition(spec, |path| {
+            fs::metadata(path).is_ok_and(|meta| meta.is_file() && meta.len() > 0)
+        })
+    });
+
+    // Probed last, as a connect attempt is the slowest check
+    exists
+        && not_empty
+        && power_conditions_met(unit, Path::new(POWER_SUPPLY_DIR))
+        && unit.condition_network.is_none_or(|required| {
+            network_online(&unit.network_probe, Path::new(NET_DIR)) == required
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn unit(source: &str) -> TimerUnit {
+        format!("Exec = \"true\"\nOnBootSec = \"1h\"\n{}", source)
+            .parse()
+            .unwrap()
+    }
+
+    /// A fresh directory of this test's own in the temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("micetimer-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn path_conditions_hold_for_present_absent_and_non_empty_files() {
+        let dir = scratch("conditions");
+        let empty = dir.join("empty");
+        let full = dir.join("full");
+        let missing = dir.join("missing");
+        fs::write(&empty, "").unwrap();
+        fs::write(&full, "enabled\n").unwrap();
+        let met = |key: &str, path: &Path, negated: bool| {
+            let bang = if negated { "!" } else { "" };
+            conditions_met(&unit(&format!("{} = \"{}{}\"", key, bang, path.display())))
+        };
+
+        assert!(met("ConditionPathExists", &empty, false));
+        assert!(!met("ConditionPathExists", &missing, false));
+        assert!(met("ConditionPathExists", &missing, true));
+        assert!(!met("ConditionPathExists", &empty, true));
+
+        assert!(met("ConditionFileNotEmpty", &full, false));
+        assert!(!met("ConditionFileNotEmpty", &empty, false));
+        assert!(!met("ConditionFileNotEmpty", &missing, false));
+        assert!(!met("ConditionFileNotEmpty", &dir, false));
+        assert!(met("ConditionFileNotEmpty", &empty, true));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A stub sysfs class directory (`power_supply`, `net`) with the given
+    /// `(device, file, content)` entries
+    fn sysfs_class(name: &str, files: &[(&str, &str, &str)]) -> PathBuf {
+        let root = scratch(name);
+        for (device, file, content) in files {
+            fs::create_dir_all(root.join(device)).unwrap();
+            fs::write(root.join(device).join(file), content).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn power_conditions_read_the_stubbed_supplies() {
+        let charging = sysfs_class(
+            "power-charging",
+            &[
+                ("ac", "type", "Mains\n"),
+                ("ac", "online", "1\n"),
+                ("battery", "type", "Battery\n"),
+                ("battery", "capacity", "80\n"),
+            ],
+        );
+        let draining = sysfs_class(
+            "power-draining",
+            &[
+                ("ac", "online", "0\n"),
+                ("battery", "type", "Battery\n"),
+                ("battery", "status", "Discharging\n"),
+                ("battery", "capacity", "15\n"),
+            ],
+        );
+        let on_ac = unit("ConditionACPower = true");
+        let on_battery = unit("ConditionACPower = false");
+        let charged = unit("ConditionBatteryAbove = 50");
+
+        assert!(power_conditions_met(&on_ac, &charging));
+        assert!(!power_conditions_met(&on_battery, &charging));
+        assert!(power_conditions_met(&charged, &charging));
+        assert!(!power_conditions_met(&on_ac, &draining));
+        assert!(power_conditions_met(&on_battery, &draining));
+        assert!(!power_conditions_met(&charged, &draining));
+
+        // The battery's own status counts when no other supply reports
+        let full = sysfs_class("power-full", &[("battery", "status", "Full\n")]);
+        assert!(power_conditions_met(&on_ac, &full));
+        // An unknown capacity doesn't block the unit
+        assert!(power_conditions_met(&charged, &full));
+
+        for root in [charging, draining, full] {
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn network_condition_reads_the_stubbed_operstate() {
+        let online = sysfs_class(
+            "net-online",
+            &[
+                ("lo", "type", "772\n"),
+                ("lo", "operstate", "unknown\n"),
+                ("lo", "carrier", "1\n"),
+                ("wlan0", "type", "1\n"),
+                ("wlan0", "operstate", "up\n"),
+            ],
+        );
+        let offline = sysfs_class(
+            "net-offline",
+            &[
+                ("lo", "type", "772\n"),
+                ("lo", "operstate", "unknown\n"),
+                ("lo", "carrier", "1\n"),
+                ("wlan0", "operstate", "down\n"),
+                ("rmnet0", "operstate", "unknown\n"),
+                ("rmnet0", "carrier", "0\n"),
+            ],
+        );
+        // Cellular links report unknown, and count once they have a carrier
+        let cellular = sysfs_class(
+            "net-cellular",
+            &[
+                ("rmnet0", "operstate", "unknown\n"),
+                ("rmnet0", "carrier", "1\n"),
+            ],
+        );
+
+        let probe = NetworkProbe::Operstate;
+        assert!(network_online(&probe, &online));
+        assert!(!network_online(&probe, &offline));
+        assert!(network_online(&probe, &cellular));
+        assert!(!network_online(&probe, &online.join("missing")));
+
+        for root in [online, offline, cellular] {
+            fs::remove_dir_all(root).unwrap();
+        }
+    }
+
+    #[test]
+    fn connect_probe_needs_a_listener() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let target = listener.local_addr().unwrap().to_string();
+        assert!(can_connect(&target));
+        drop(listener);
+        assert!(!can_connect(&target));
+        assert!(!can_connect("not a target"));
+    }
+}
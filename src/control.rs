@@ -0,0 +1,490 @@
+//! Control socket: line commands for inspecting and triggering a running daemon
+//!
+//! Each connection carries one request line and receives one response, then is closed:
+//!
+//! - `LIST`: every armed unit with its next elapse
+//! - `STATUS <name>`: the unit's last run and result, run counters, and the next run
+//! - `TRIGGER <name>`: fire the unit now, honouring its `Concurrency`
+//! - `PAUSE` / `RESUME`: stop every timer, keeping the time each had left, and re-arm
+//!   them for that time
+//! - `SET <name> OnUnitActiveSec=<duration>` (or `OnUnitInactiveSec`): change the
+//!   unit's interval in memory and re-arm it for that long from now, until a reload
+//!
+//! Prefixing a request with `JSON` (e.g. `JSON LIST`) switches the response from
+//! text to a single line of JSON.
+
+use crate::metrics::Metrics;
+use crate::scheduler::FireDecision;
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Local};
+use serde::Serialize;
+use std::fmt::Write as _;
+use std::fs;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::io::{AsFd, BorrowedFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+/// How long a client may take to send its request or read the response
+const CLIENT_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// How long `send_request` waits for the daemon's response
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest accepted request line
+const MAX_REQUEST: u64 = 4096;
+
+/// Listening socket; removed from the filesystem when dropped
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Binds the socket with mode 0600: `TRIGGER` runs commands as root
+    pub(crate) fn bind(path: &Path) -> Result<Self> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        // A socket left behind by a daemon that didn't exit cleanly would make bind fail
+        if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+            fs::remove_file(path)?;
+        }
+
+        // Bind in a private directory and only move the socket into place once it's
+        // 0600, so there's no window where others can connect. Changing the umask
+        // instead would also affect commands forked meanwhile.
+        let listener = bind_private(path)
+            .with_context(|| format!("Failed to bind control socket {:?}", path))?;
+        listener.set_nonblocking(true)?;
+
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// The next pending connection, if any
+    pub(crate) fn accept(&self) -> io::Result<Option<UnixStream>> {
+        match self.listener.accept() {
+            Ok((stream, _)) => Ok(Some(stream)),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+impl AsFd for ControlSocket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Binds a socket with mode 0600 inside a fresh 0700 directory, then renames it to `path`
+fn bind_private(path: &Path) -> io::Result<UnixListener> {
+    let mut staging = path.as_os_str().to_owned();
+    staging.push(format!(".{}.d", std::process::id()));
+    let staging = PathBuf::from(staging);
+    let _ = fs::remove_dir_all(&staging);
+    fs::DirBuilder::new().mode(0o700).create(&staging)?;
+
+    let socket = staging.join("sock");
+    let bound = UnixListener::bind(&socket).and_then(|listener| {
+        fs::set_permissions(&socket, fs::Permissions::from_mode(0o600))?;
+        fs::rename(&socket, path)?;
+        Ok(listener)
+    });
+    let _ = fs::remove_dir_all(&staging);
+    bound
+}
+
+/// Reads the request line of a freshly accepted client
+///
+/// The timeouts bound how long a stuck client can hold up the event loop.
+pub(crate) fn read_request(stream: &UnixStream) -> io::Result<String> {
+    stream.set_nonblocking(false)?;
+    stream.set_read_timeout(Some(CLIENT_TIMEOUT))?;
+    stream.set_write_timeout(Some(CLIENT_TIMEOUT))?;
+
+    let mut line = String::new();
+    BufReader::new(stream.take(MAX_REQUEST)).read_line(&mut line)?;
+    Ok(line)
+}
+
+/// Sends one request line to a running daemon and returns its full response
+pub fn send_request(path: impl AsRef<Path>, request: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    stream.set_read_timeout(Some(RESPONSE_TIMEOUT))?;
+    writeln!(stream, "{}", request)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(response)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum Request {
+    List,
+    Status(String),
+    Trigger(String),
+    Pause,
+    Resume,
+    /// Changes a unit's repeat interval until the next reload
+    Set {
+        name: String,
+        key: IntervalKey,
+        interval: Duration,
+    },
+}
+
+/// The repeat intervals `SET` can change
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum IntervalKey {
+    Active,
+    Inactive,
+}
+
+impl IntervalKey {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            IntervalKey::Active => "OnUnitActiveSec",
+            IntervalKey::Inactive => "OnUnitInactiveSec",
+        }
+    }
+}
+
+/// Parses a request line; the flag tells whether a JSON response was asked for
+///
+/// The flag is returned even for invalid requests, so the error is rendered as asked.
+pub(crate) fn parse_request(line: &str) -> (bool, Result<Request>) {
+    let mut words = line.split_whitespace().peekable();
+    let json = words
+        .next_if(|word| word.eq_ignore_ascii_case("JSON"))
+        .is_some();
+
+    let request = match (words.next(), words.next(), words.next(), words.next()) {
+        (Some(command), None, None, None) if command.eq_ignore_ascii_case("LIST") => {
+            Ok(Request::List)
+        }
+        (Some(command), Some(name), None, None) if command.eq_ignore_ascii_case("STATUS") => {
+            Ok(Request::Status(name.to_string()))
+        }
+        (Some(command), Some(name), None, None) if command.eq_ignore_ascii_case("TRIGGER") => {
+            Ok(Request::Trigger(name.to_string()))
+        }
+        (Some(command), None, None, None) if command.eq_ignore_ascii_case("PAUSE") => {
+            Ok(Request::Pause)
+        }
+        (Some(command), None, None, None) if command.eq_ignore_ascii_case("RESUME") => {
+            Ok(Request::Resume)
+        }
+        (Some(command), Some(name), Some(assignment), None)
+            if command.eq_ignore_ascii_case("SET") =>
+        {
+            parse_assignment(assignment).map(|(key, interval)| Request::Set {
+                name: name.to_string(),
+                key,
+                interval,
+            })
+        }
+        _ => Err(anyhow!(
+            "Unknown request {:?}, expected LIST, STATUS <name>, TRIGGER <name>, PAUSE, RESUME \
+             or SET <name> <key>=<duration>",
+            line.trim()
+        )),
+    };
+    (json, request)
+}
+
+/// Parses the `OnUnitActiveSec=5m` of a `SET` request
+fn parse_assignment(assignment: &str) -> Result<(IntervalKey, Duration)> {
+    let (key, value) = assignment
+        .split_once('=')
+        .ok_or_else(|| anyhow!("Expected <key>=<duration>, got {:?}", assignment))?;
+    let key = [IntervalKey::Active, IntervalKey::Inactive]
+        .into_iter()
+        .find(|known| known.as_str().eq_ignore_ascii_case(key))
+        .ok_or_else(|| {
+            anyhow!(
+                "Unknown field {:?}, SET supports OnUnitActiveSec and OnUnitInactiveSec",
+                key
+            )
+        })?;
+    let interval = humantime::parse_duration(value)
+        .with_context(|| format!("Invalid {} {:?}", key.as_str(), value))?;
+    if interval.is_zero() {
+        bail!("{} must be more than 0", key.as_str());
+    }
+    Ok((key, interval))
+}
+
+/// What the daemon knows about one armed unit
+#[derive(Debug, Serialize)]
+pub(crate) struct UnitStatus {
+    pub name: String,
+    /// Seconds until the next expiration; `None` if the timer is disarmed
+    pub next_elapse_sec: Option<u64>,
+    /// Number of firings currently executing
+    pub running: usize,
+    /// Unix time at which the last run finished
+    pub last_run: Option<u64>,
+    /// `"success"` or `"failure"`
+    pub last_result: Option<&'static str>,
+    pub runs: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_exit_code: Option<i32>,
+    /// Wall time of the last run, hooks and retries included
+    pub last_duration_sec: Option<f64>,
+    /// Unix time of the last skipped firing
+    pub last_skip: Option<u64>,
+    /// `"condition"`, `"still-running"`, `"rate-limited"`, `"requirement"` or `"dry-run"`
+    pub last_skip_reason: Option<&'static str>,
+}
+
+impl UnitStatus {
+    pub(crate) fn new(
+        name: &str,
+        next_elapse: Option<Duration>,
+        running: usize,
+        metrics: &Metrics,
+    ) -> Self {
+        let last_run = metrics.last_run;
+        Self {
+            name: name.to_string(),
+            next_elapse_sec: next_elapse.map(|delay| delay.as_secs()),
+            running,
+            last_run: last_run.and_then(|(finished, _)| {
+                finished
+                    .duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|since| since.as_secs())
+            }),
+            last_result: last_run.map(|(_, success)| if success { "success" } else { "failure" }),
+            runs: metrics.runs,
+            successes: metrics.successes,
+            failures: metrics.failures,
+            last_exit_code: metrics.last_exit_code,
+            last_duration_sec: metrics.last_duration.map(|duration| duration.as_secs_f64()),
+            last_skip: metrics.last_skip.and_then(|(at, _)| {
+                at.duration_since(UNIX_EPOCH)
+                    .ok()
+                    .map(|since| since.as_secs())
+            }),
+            last_skip_reason: metrics.last_skip.map(|(_, reason)| reason.as_str()),
+        }
+    }
+
+    fn next_text(&self) -> String {
+        match self.next_elapse_sec {
+            Some(secs) => {
+                let at = Local::now() + chrono::Duration::seconds(secs as i64);
+                format!(
+                    "in {} (at {})",
+                    humantime::format_duration(Duration::from_secs(secs)),
+                    at.format("%Y-%m-%d %H:%M:%S")
+                )
+            }
+            None => "-".to_string(),
+        }
+    }
+
+    fn last_run_text(&self) -> String {
+        match (self.last_run, self.last_result) {
+            (Some(secs), Some(result)) => {
+                let at = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(secs));
+                format!("{} ({})", at.format("%Y-%m-%d %H:%M:%S"), result)
+            }
+            _ => "never".to_string(),
+        }
+    }
+
+    /// One log line for the SIGUSR1 schedule dump
+    pub(crate) fn summary(&self) -> String {
+        format!(
+            "[{}] next: {}, last run: {}, running: {}",
+            self.name,
+            self.next_text(),
+            self.last_run_text(),
+            self.running
+        )
+    }
+}
+
+pub(crate) enum Response {
+    List(Vec<UnitStatus>),
+    Status(UnitStatus),
+    Triggered(FireDecision),
+    /// Outcome of `PAUSE` or `RESUME`, e.g. `"paused"` or `"already paused"`
+    Done(&'static str),
+    Error(String),
+}
+
+impl Response {
+    /// Renders the response as newline-terminated text or JSON
+    pub(crate) fn render(&self, json: bool) -> String {
+        if json {
+            let value = match self {
+                Response::List(units) => serde_json::to_value(units),
+                Response::Status(unit) => serde_json::to_value(unit),
+                Response::Triggered(decision) => {
+                    Ok(serde_json::json!({ "result": decision.as_str() }))
+                }
+                Response::Done(result) => Ok(serde_json::json!({ "result": result })),
+                Response::Error(message) => Ok(serde_json::json!({ "error": message })),
+            };
+            return match value {
+                Ok(value) => format!("{}\n", value),
+                Err(e) => format!("{}\n", serde_json::json!({ "error": e.to_string() })),
+            };
+        }
+
+        let mut out = String::new();
+        match self {
+            Response::List(units) => {
+                for unit in units {
+                    let _ = writeln!(out, "{}\t{}", unit.name, unit.next_text());
+                }
+            }
+            Response::Status(unit) => {
+                let _ = writeln!(out, "name: {}", unit.name);
+                let _ = writeln!(out, "running: {}", unit.running);
+                let _ = writeln!(out, "last run: {}", unit.last_run_text());
+                let _ = writeln!(
+                    out,
+                    "runs: {} ({} succeeded, {} failed)",
+                    unit.runs, unit.successes, unit.failures
+                );
+                if let Some(code) = unit.last_exit_code {
+                    let _ = writeln!(out, "last exit code: {}", code);
+                }
+                if let Some(secs) = unit.last_duration_sec {
+                    let _ = writeln!(out, "last duration: {:.3}s", secs);
+                }
+                if let (Some(secs), Some(reason)) = (unit.last_skip, unit.last_skip_reason) {
+                    let at = DateTime::<Local>::from(UNIX_EPOCH + Duration::from_secs(secs));
+                    let _ = writeln!(
+                        out,
+                        "last skip: {} ({})",
+                        at.format("%Y-%m-%d %H:%M:%S"),
+                        reason
+                    );
+                }
+                let _ = writeln!(out, "next run: {}", unit.next_text());
+            }
+            Response::Triggered(decision @ (FireDecision::Skip | FireDecision::Queue)) => {
+                let _ = writeln!(out, "{} (still running)", decision.as_str());
+            }
+            Response::Triggered(decision) => {
+                let _ = writeln!(out, "{}", decision.as_str());
+            }
+            Response::Done(result) => {
+                let _ = writeln!(out, "{}", result);
+            }
+            Response::Error(message) => {
+                let _ = writeln!(out, "ERR {}", message);
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn requests_parse_case_insensitively_with_an_optional_json_prefix() {
+        let parse = |line: &str| {
+            let (json, request) = parse_request(line);
+            (json, request.ok())
+        };
+        assert_eq!(parse("LIST\n"), (false, Some(Request::List)));
+        assert_eq!(parse("json list"), (true, Some(Request::List)));
+        assert_eq!(
+            parse("STATUS backup"),
+            (false, Some(Request::Status("backup".to_string())))
+        );
+        assert_eq!(
+            parse("JSON trigger backup"),
+            (true, Some(Request::Trigger("backup".to_string())))
+        );
+
+        // Invalid requests still report the format their error is wanted in
+        assert_eq!(parse("JSON STATUS"), (true, None));
+        assert_eq!(parse("LIST extra"), (false, None));
+        assert_eq!(parse("TRIGGER a b"), (false, None));
+        assert_eq!(parse(""), (false, None));
+    }
+
+    #[test]
+    fn schedule_dump_line_shows_next_elapse_last_run_and_running() {
+        let idle = UnitStatus::new("idle", None, 0, &Metrics::default());
+        assert_eq!(
+            idle.summary(),
+            "[idle] next: -, last run: never, running: 0"
+        );
+
+        let finished = UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let metrics = Metrics {
+            runs: 3,
+            failures: 1,
+            last_run: Some((finished, false)),
+            ..Metrics::default()
+        };
+        let busy = UnitStatus::new("busy", Some(Duration::from_secs(90)), 1, &metrics);
+        let summary = busy.summary();
+        let at = DateTime::<Local>::from(finished).format("%Y-%m-%d %H:%M:%S");
+        assert!(
+            summary.starts_with("[busy] next: in 1m 30s (at "),
+            "{}",
+            summary
+        );
+        assert!(
+            summary.ends_with(&format!("), last run: {} (failure), running: 1", at)),
+            "{}",
+            summary
+        );
+    }
+
+    #[test]
+    fn set_requests_parse_one_interval_assignment() {
+        let (_, request) = parse_request("set backup onunitactivesec=5m");
+        assert_eq!(
+            request.unwrap(),
+            Request::Set {
+                name: "backup".to_string(),
+                key: IntervalKey::Active,
+                interval: Duration::from_secs(300),
+            }
+        );
+        // The value can't hold a space, which would split the request
+        assert!(
+            parse_request("SET backup OnUnitActiveSec=1h 30min")
+                .1
+                .is_err()
+        );
+        assert_eq!(
+            parse_assignment("OnUnitInactiveSec=90min").unwrap(),
+            (IntervalKey::Inactive, Duration::from_secs(5400))
+        );
+
+        let error = |assignment: &str| parse_assignment(assignment).unwrap_err().to_string();
+        assert!(error("OnBootSec=5m").starts_with("Unknown field \"OnBootSec\""));
+        assert!(error("OnUnitActiveSec").starts_with("Expected <key>=<duration>"));
+        assert!(error("OnUnitActiveSec=soon").starts_with("Invalid OnUnitActiveSec"));
+        assert_eq!(
+            error("OnUnitActiveSec=0s"),
+            "OnUnitActiveSec must be more than 0"
+        );
+    }
+}
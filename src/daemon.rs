@@ -0,0 +1,144 @@
+//! Classic double-fork daemonization
+//!
+//! The sequence is:
+//! 1. Create a pipe, then fork. The original process only waits on the pipe and
+//!    exits with 0 once the daemon reports readiness, or 1 if the daemon dies first.
+//! 2. The child calls `setsid()` to become a session leader without a controlling terminal.
+//! 3. It forks again and the session leader exits, so the daemon (not a session leader)
+//!    can never reacquire a terminal.
+//! 4. Once the event loop is set up, [`Detached::ready`] does `chdir("/")`, detaches
+//!    stdio and writes the readiness byte, releasing the original process.
+
+use anyhow::{Context, Result};
+use nix::errno::Errno;
+use nix::fcntl::OFlag;
+use nix::sys::signal::kill;
+use nix::sys::wait::waitpid;
+use nix::unistd::{ForkResult, Pid, chdir, close, dup2, fork, isatty, pipe2, setsid};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::io::{AsRawFd, FromRawFd};
+use std::path::Path;
+
+/// Handle held by the daemon process until startup has finished
+pub struct Detached {
+    ready: File,
+}
+
+/// Forks into the background. Only returns in the daemon process.
+pub fn daemonize() -> Result<Detached> {
+    // Close-on-exec, so commands started before readiness (RunOnStart) don't hold the
+    // write end and keep the parent waiting until they exit
+    let (read_fd, write_fd) = pipe2(OFlag::O_CLOEXEC).context("Failed to create readiness pipe")?;
+
+    // SAFETY: called before any threads are spawned
+    if let ForkResult::Parent { child } = unsafe { fork() }.context("First fork failed")? {
+        let _ = close(write_fd);
+        // Reap the intermediate session leader, which exits right after the second fork
+        let _ = waitpid(child, None);
+        let mut pipe = unsafe { File::from_raw_fd(read_fd) };
+        let mut buf = [0u8; 1];
+        // EOF without the byte means the daemon exited during startup
+        let ready = matches!(pipe.read(&mut buf), Ok(1));
+        std::process::exit(if ready { 0 } else { 1 });
+    }
+
+    let _ = close(read_fd);
+    setsid().context("setsid failed")?;
+
+    if let ForkResult::Parent { .. } = unsafe { fork() }.context("Second fork failed")? {
+        // The intermediate session leader exits right away
+        unsafe { libc::_exit(0) };
+    }
+
+    Ok(Detached {
+        ready: unsafe { File::from_raw_fd(write_fd) },
+    })
+}
+
+impl Detached {
+    /// Finishes detaching and tells the waiting parent that startup succeeded
+    pub fn ready(mut self) -> Result<()> {
+        chdir("/").context("Failed to chdir to /")?;
+
+        let null = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open("/dev/null")
+            .context("Failed to open /dev/null")?;
+        let null_fd = null.as_raw_fd();
+
+        dup2(null_fd, libc::STDIN_FILENO)?;
+        // Keep stdout/stderr if the caller already redirected them (e.g. to a log file)
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            if isatty(fd).unwrap_or(false) {
+                dup2(null_fd, fd)?;
+            }
+        }
+
+        self.ready
+            .write_all(b"1")
+            .context("Failed to notify parent process")?;
+        Ok(())
+    }
+}
+
+/// Returns the PID recorded in `path` if that process is still alive
+///
+/// A missing, unparsable or stale PID file (process gone) yields `None`.
+pub fn running_pid(path: &str) -> Option<Pid> {
+    let content = fs::read_to_string(path).ok()?;
+    let pid = Pid::from_raw(content.trim().parse().ok()?);
+    (pid != Pid::this() && is_alive(pid)).then_some(pid)
+}
+
+/// Signal 0 probes for existence; EPERM still means the process exists
+fn is_alive(pid: Pid) -> bool {
+    matches!(kill(pid, None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Records our PID, replacing any stale file
+pub fn write_pid_file(path: &str) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(path, format!("{}\n", Pid::this()))
+        .with_context(|| format!("Failed to write PID file {}", path))
+}
+
+pub fn remove_pid_file(path: &str) {
+    if let Err(e) = fs::remove_file(path)
+        && e.kind() != std::io::ErrorKind::NotFound
+    {
+        log::error!("Failed to remove PID file {}: {}", path, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    #[test]
+    fn pid_file_counts_only_while_its_process_lives() {
+        let path = std::env::temp_dir().join(format!("micetimer-pid-{}", std::process::id()));
+        let path = path.to_str().unwrap();
+        assert_eq!(running_pid(path), None);
+
+        fs::write(path, "not a pid\n").unwrap();
+        assert_eq!(running_pid(path), None);
+        // Our own PID is what we'd write ourselves, not another instance
+        write_pid_file(path).unwrap();
+        assert_eq!(running_pid(path), None);
+
+        let mut child = Command::new("sleep").arg("10").spawn().unwrap();
+        fs::write(path, format!("{}\n", child.id())).unwrap();
+        assert_eq!(running_pid(path), Some(Pid::from_raw(child.id() as i32)));
+        child.kill().unwrap();
+        child.wait().unwrap();
+        assert_eq!(running_pid(path), None);
+
+        remove_pid_file(path);
+        assert!(!Path::new(path).exists());
+    }
+}
@@ -0,0 +1,2210 @@
+//! Running a unit's command: identity, environment, output, timeouts, retries and hooks
+
+use crate::condition::conditions_met;
+use crate::expand::{expand, expand_specifiers};
+use crate::metrics::SkipReason;
+use crate::report::report_result;
+use crate::stamp::{DEFAULT_STAMP_DIR, write_stamp};
+use crate::unit::{Exec, IoClass, KillMode, OutputTarget, ServiceType, TimerUnit};
+use crate::wakelock::WakeLocks;
+use anyhow::{Context, Result};
+use crossbeam_channel::{Receiver, Sender};
+use log::{Level, debug, error, info, log, warn};
+use nix::sys::signal::{SigSet, Signal, kill, killpg};
+use nix::unistd::{Gid, Group, Pid, Uid, User, setgid, setgroups, setuid};
+use std::collections::VecDeque;
+use std::ffi::CString;
+use std::fs::{self, OpenOptions};
+use std::io::{self, BufRead, BufReader, Read};
+use std::num::NonZeroUsize;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::{CommandExt, ExitStatusExt};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long a timed-out command gets between its `KillSignal` and SIGKILL
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// Upper bound for the exponential retry backoff
+const MAX_RETRY_BACKOFF: Duration = Duration::from_secs(600);
+
+/// How long to wait for relayed output to flush after a command exits
+const OUTPUT_DRAIN: Duration = Duration::from_secs(1);
+
+/// `PATH` of a command with `CleanEnvironment`, unless its `Environment` sets one
+const CLEAN_PATH: &str = "/system/bin:/system/xbin:/vendor/bin:/usr/bin:/bin";
+
+/// Polling granularity while waiting on a child with a deadline
+const WAIT_POLL: Duration = Duration::from_millis(50);
+
+/// How firings run, beyond what their units say: the daemon's own options
+///
+/// A [`crate::Scheduler`] carries one and hands it to every firing; cloning it shares
+/// the wakelocks, so locks taken by any clone are released together.
+#[derive(Clone)]
+pub struct RunConfig {
+    dry_run: bool,
+    /// 0 relays output as it comes
+    max_capture: usize,
+    /// The `oom_score_adj` commands get unless they set `OOMScoreAdjust`
+    command_oom_score_adj: Option<i32>,
+    wakelocks: Arc<WakeLocks>,
+    /// Where `Persistent` units keep their last-run stamps
+    stamp_dir: PathBuf,
+}
+
+impl Default for RunConfig {
+    fn default() -> Self {
+        Self {
+            dry_run: false,
+            max_capture: 0,
+            command_oom_score_adj: None,
+            wakelocks: Arc::default(),
+            stamp_dir: PathBuf::from(DEFAULT_STAMP_DIR),
+        }
+    }
+}
+
+impl RunConfig {
+    /// Makes every firing log what it would run instead of running it
+    ///
+    /// Conditions are still evaluated and timers re-armed as usual, but no wakelock is
+    /// taken, no command (or hook) is spawned, and no `Persistent` stamp is written.
+    pub fn set_dry_run(&mut self, enabled: bool) {
+        self.dry_run = enabled;
+    }
+
+    pub(crate) fn dry_run(&self) -> bool {
+        self.dry_run
+    }
+
+    /// Caps the output `StandardOutput = "Log"` captures per stream and firing to its
+    /// last `bytes`, logged once the stream ends; `None` logs every line as it comes
+    pub fn set_max_capture_bytes(&mut self, bytes: Option<NonZeroUsize>) {
+        self.max_capture = bytes.map_or(0, NonZeroUsize::get);
+    }
+
+    /// Takes wakelocks through `wakelocks` instead of the detected backend
+    pub fn set_wakelocks(&mut self, wakelocks: WakeLocks) {
+        self.wakelocks = Arc::new(wakelocks);
+    }
+
+    pub fn wakelocks(&self) -> &Arc<WakeLocks> {
+        &self.wakelocks
+    }
+
+    /// Keeps the last-run stamps of `Persistent` units in `dir` instead of
+    /// `/data/adb/micetimer/state`
+    pub fn set_stamp_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.stamp_dir = dir.into();
+    }
+
+    pub(crate) fn stamp_dir(&self) -> &Path {
+        &self.stamp_dir
+    }
+
+    /// Sets the daemon's own `oom_score_adj`, e.g. low so it survives memory pressure
+    ///
+    /// Commands are reset to 0 unless they set `OOMScoreAdjust` themselves, so they
+    /// don't inherit the daemon's protection.
+    pub fn adjust_daemon_oom_score(&mut self, adjust: i32) -> Result<()> {
+        fs::write("/proc/self/oom_score_adj", adjust.to_string())
+            .context("Failed to write /proc/self/oom_score_adj")?;
+        self.command_oom_score_adj = Some(0);
+        Ok(())
+    }
+}
+
+/// The last bytes of a stream, and how many came before them
+struct OutputTail {
+    buf: VecDeque<u8>,
+    cap: usize,
+    dropped: u64,
+}
+
+impl OutputTail {
+    fn new(cap: usize) -> Self {
+        Self {
+            buf: VecDeque::with_capacity(cap.min(64 * 1024)),
+            cap,
+            dropped: 0,
+        }
+    }
+
+    fn push(&mut self, data: &[u8]) {
+        // Only the last `cap` bytes of `data` can survive anyway
+        let skip = data.len().saturating_sub(self.cap);
+        self.dropped += skip as u64;
+        let data = &data[skip..];
+
+        let overflow = (self.buf.len() + data.len()).saturating_sub(self.cap);
+        self.buf.drain(..overflow);
+        self.dropped += overflow as u64;
+        self.buf.extend(data);
+    }
+
+    /// The retained lines, led by a marker if anything was dropped
+    ///
+    /// The partial line the cap cut into is dropped too.
+    fn lines(&self) -> Vec<String> {
+        let (front, back) = self.buf.as_slices();
+        let text = String::from_utf8_lossy(&[front, back].concat()).into_owned();
+        let mut lines: Vec<String> = text.lines().map(str::to_string).collect();
+        if self.dropped > 0 {
+            let cut = usize::from(lines.len() > 1);
+            lines.splice(
+                ..cut,
+                [format!("... {} bytes of output truncated", self.dropped)],
+            );
+        }
+        lines
+    }
+}
+
+/// Writes `value` (ASCII digits) to `/proc/self/oom_score_adj`; `false` on failure
+///
+/// Called in the child between fork and exec, so it only makes raw syscalls.
+fn write_oom_score_adj(value: &[u8]) -> bool {
+    unsafe {
+        let fd = libc::open(
+            c"/proc/self/oom_score_adj".as_ptr(),
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return false;
+        }
+        let written = libc::write(fd, value.as_ptr().cast(), value.len());
+        libc::close(fd);
+        written == value.len() as isize
+    }
+}
+
+/// Makes the calling process's next exec transition to SELinux `context`, as
+/// libselinux's `setexeccon` does; returns `false` if the kernel refused
+///
+/// Called in the child between fork and exec, so it only makes raw syscalls. The
+/// child has a single thread, so its `attr/exec` is the thread's.
+fn set_exec_context(context: &CString) -> bool {
+    let value = context.as_bytes();
+    unsafe {
+        let fd = libc::open(
+            c"/proc/self/attr/exec".as_ptr(),
+            libc::O_WRONLY | libc::O_CLOEXEC,
+        );
+        if fd < 0 {
+            return false;
+        }
+        let written = libc::write(fd, value.as_ptr().cast(), value.len());
+        libc::close(fd);
+        written == value.len() as isize
+    }
+}
+
+/// Logs if the command with `pid` didn't end up in SELinux context `expected`, e.g.
+/// on kernels without SELinux or when policy forbids the transition
+fn check_selinux_context(name: &str, pid: u32, expected: &str) {
+    let actual = match fs::read(format!("/proc/{}/attr/current", pid)) {
+        Ok(actual) => actual,
+        // Already exited
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return,
+        Err(e) => {
+            warn!(
+                "[{}] failed to set SELinuxContext={}: {}",
+                name, expected, e
+            );
+            return;
+        }
+    };
+    let actual = String::from_utf8_lossy(&actual);
+    let actual = actual.trim_end_matches(['\0', '\n']);
+    if actual != expected {
+        warn!(
+            "[{}] failed to set SELinuxContext={} (is {:?})",
+            name, expected, actual
+        );
+    }
+}
+
+/// Moves the calling process into the cgroup whose `cgroup.procs` is `procs`
+///
+/// Called in the child between fork and exec, so it only makes raw syscalls.
+fn join_cgroup(procs: &CString) -> io::Result<()> {
+    unsafe {
+        let fd = libc::open(procs.as_ptr(), libc::O_WRONLY | libc::O_CLOEXEC);
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // "0" stands for the writing process itself
+        let written = libc::write(fd, c"0".as_ptr().cast(), 1);
+        let error = io::Error::last_os_error();
+        libc::close(fd);
+        match written {
+            1 => Ok(()),
+            _ => Err(error),
+        }
+    }
+}
+
+/// Sends `signal` to every process in the cgroup at `path`
+pub(crate) fn kill_cgroup(path: &Path, signal: Signal) {
+    let Ok(procs) = fs::read_to_string(path.join("cgroup.procs")) else {
+        return;
+    };
+    for pid in procs.lines().filter_map(|line| line.trim().parse().ok()) {
+        let _ = kill(Pid::from_raw(pid), signal);
+    }
+}
+
+/// Removes the unit's cgroup directory once a command in it was reaped
+///
+/// Fails with `EBUSY` while processes (e.g. a concurrent run, or what a forking
+/// command left behind) are still in it, which is fine: the last one out removes it.
+fn remove_cgroup(path: &Path) {
+    match fs::remove_dir(path) {
+        Ok(()) => debug!("Removed cgroup {:?}", path),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EBUSY) | Some(libc::ENOENT)) => {}
+        Err(e) => debug!("Failed to remove cgroup {:?}: {}", path, e),
+    }
+}
+
+/// Logs if the command with `pid` didn't end up with `oom_score_adj` `expected`
+///
+/// The child can't log the failed write itself, and failing it wouldn't be worth
+/// not running the command.
+fn check_oom_score_adj(name: &str, pid: u32, expected: i32) {
+    let Ok(actual) = fs::read_to_string(format!("/proc/{}/oom_score_adj", pid)) else {
+        // Already exited
+        return;
+    };
+    if actual.trim().parse() != Ok(expected) {
+        warn!(
+            "[{}] failed to set OOMScoreAdjust={} (is {})",
+            name,
+            expected,
+            actual.trim()
+        );
+    }
+}
+
+/// Identity a command switches to before exec; the daemon itself stays root
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Credentials {
+    uid: Option<Uid>,
+    gid: Option<Gid>,
+}
+
+/// Resolves a user name or numeric UID, along with its primary group if known
+fn resolve_user(spec: &str) -> Result<(Uid, Option<Gid>)> {
+    if let Ok(raw) = spec.parse::<u32>() {
+        let uid = Uid::from_raw(raw);
+        let gid = User::from_uid(uid).ok().flatten().map(|user| user.gid);
+        return Ok((uid, gid));
+    }
+
+    let user = User::from_name(spec)?.with_context(|| format!("Unknown user {:?}", spec))?;
+    Ok((user.uid, Some(user.gid)))
+}
+
+/// Resolves a group name or numeric GID
+fn resolve_group(spec: &str) -> Result<Gid> {
+    if let Ok(raw) = spec.parse::<u32>() {
+        return Ok(Gid::from_raw(raw));
+    }
+
+    let group = Group::from_name(spec)?.with_context(|| format!("Unknown group {:?}", spec))?;
+    Ok(group.gid)
+}
+
+/// Resolves `User`/`Group`, or `None` if the command keeps the daemon's identity
+pub(crate) fn resolve_credentials(unit: &TimerUnit) -> Result<Option<Credentials>> {
+    if unit.user.is_none() && unit.group.is_none() {
+        return Ok(None);
+    }
+
+    let (uid, user_gid) = match &unit.user {
+        Some(spec) => {
+            let (uid, gid) = resolve_user(spec)?;
+            (Some(uid), gid)
+        }
+        None => (None, None),
+    };
+    let gid = match &unit.group {
+        Some(spec) => Some(resolve_group(spec)?),
+        None => user_gid,
+    };
+
+    Ok(Some(Credentials { uid, gid }))
+}
+
+/// Parses KEY=VALUE lines; blank lines and `#` comments are ignored
+fn parse_environment_file(name: &str, content: &str) -> Vec<(String, String)> {
+    let mut vars = Vec::new();
+    for (lineno, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        match line.split_once('=') {
+            Some((key, value)) if !key.trim().is_empty() => {
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                vars.push((key.trim().to_string(), value.to_string()));
+            }
+            _ => error!(
+                "[{}] ignoring malformed environment line {}: {:?}",
+                name,
+                lineno + 1,
+                line
+            ),
+        }
+    }
+    vars
+}
+
+/// Collects the command's environment: `Environment` first, then `EnvironmentFile`
+///
+/// Later entries win, so the file overrides inline values (as in systemd).
+fn unit_environment(name: &str, unit: &TimerUnit) -> Vec<(String, String)> {
+    let mut vars: Vec<(String, String)> = unit
+        .environment
+        .iter()
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect();
+
+    if let Some(path) = &unit.environment_file {
+        match fs::read_to_string(path) {
+            Ok(content) => vars.extend(parse_environment_file(name, &content)),
+            Err(e) => error!(
+                "[{}] failed to read EnvironmentFile {:?}: {}",
+                name, path, e
+            ),
+        }
+    }
+    vars
+}
+
+/// Points the command's stdout/stderr at the unit's output target
+fn configure_output(unit: &TimerUnit, command: &mut Command) -> io::Result<()> {
+    match &unit.standard_output {
+        OutputTarget::Null => {
+            command.stdout(Stdio::null()).stderr(Stdio::null());
+        }
+        OutputTarget::Log => {
+            command.stdout(Stdio::piped()).stderr(Stdio::piped());
+        }
+        OutputTarget::File(path) => {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            command.stdout(file.try_clone()?).stderr(file);
+        }
+    }
+    Ok(())
+}
+
+/// Relays a child's output stream into the log line by line, without buffering it all
+///
+/// With a nonzero `cap` ([`RunConfig::set_max_capture_bytes`]), only the stream's last
+/// `cap` bytes are logged, once it ends.
+fn relay_output<R: Read + Send + 'static>(
+    name: &str,
+    stream: R,
+    level: Level,
+    cap: usize,
+    done: Sender<()>,
+) -> io::Result<()> {
+    let name = name.to_string();
+    thread::Builder::new()
+        .name(format!("out-{}", name))
+        .spawn(move || {
+            if cap > 0 {
+                let mut stream = stream;
+                let mut tail = OutputTail::new(cap);
+                let mut chunk = [0u8; 4096];
+                loop {
+                    match stream.read(&mut chunk) {
+                        Ok(0) => break,
+                        Ok(n) => tail.push(&chunk[..n]),
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                        Err(_) => break,
+                    }
+                }
+                for line in tail.lines() {
+                    log!(level, unit = name.as_str(); "[{}] {}", name, line);
+                }
+                let _ = done.send(());
+                return;
+            }
+
+            for line in BufReader::new(stream).lines() {
+                match line {
+                    Ok(line) => log!(level, unit = name.as_str(); "[{}] {}", name, line),
+                    Err(_) => break,
+                }
+            }
+            let _ = done.send(());
+        })?;
+    Ok(())
+}
+
+/// Starts log relays for a piped child; returns a receiver signalled as each relay ends
+fn start_relays(name: &str, child: &mut Child, cap: usize) -> Option<Receiver<()>> {
+    let (stdout, stderr) = (child.stdout.take()?, child.stderr.take()?);
+    let (done, finished) = crossbeam_channel::bounded(2);
+
+    for result in [
+        relay_output(name, stdout, Level::Info, cap, done.clone()),
+        relay_output(name, stderr, Level::Warn, cap, done),
+    ] {
+        if let Err(e) = result {
+            error!("[{}] failed to relay output: {}", name, e);
+        }
+    }
+    Some(finished)
+}
+
+/// Gives relays a moment to flush the tail of the output after the child exits
+///
+/// They aren't joined outright: a backgrounded grandchild may keep the pipe open forever.
+fn drain_relays(relays: Option<Receiver<()>>) {
+    let Some(finished) = relays else {
+        return;
+    };
+    let deadline = Instant::now() + OUTPUT_DRAIN;
+    for _ in 0..2 {
+        if finished.recv_deadline(deadline).is_err() {
+            break;
+        }
+    }
+}
+
+/// Polls a child until it exits or `timeout` elapses
+fn wait_timeout(child: &mut Child, timeout: Duration) -> io::Result<Option<ExitStatus>> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        if Instant::now() >= deadline {
+            return Ok(None);
+        }
+        thread::sleep(WAIT_POLL);
+    }
+}
+
+/// Terminates a timed-out child: its `KillSignal`, then SIGKILL after a grace period
+///
+/// The child leads its own process group, so `ControlGroup` reaches everything it
+/// started; `Process` signals the child alone. Whatever is in the unit's `CGroup`
+/// is signalled either way, since nothing in it should outlive the run.
+fn kill_child(unit: &TimerUnit, child: &mut Child) -> io::Result<ExitStatus> {
+    let pid = Pid::from_raw(child.id() as i32);
+    let send = |signal| {
+        let result = match unit.kill_mode {
+            KillMode::ControlGroup => killpg(pid, signal),
+            KillMode::Process => kill(pid, signal),
+        };
+        if let Some(cgroup) = &unit.cgroup {
+            kill_cgroup(cgroup, signal);
+        }
+        result
+    };
+
+    let _ = send(unit.kill_signal);
+    if let Some(status) = wait_timeout(child, KILL_GRACE)? {
+        return Ok(status);
+    }
+
+    let _ = send(Signal::SIGKILL);
+    child.wait()
+}
+
+/// `ioprio_set(2)` target type for a single process
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+
+/// Bit position of the class within an IO priority value
+const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+/// Priority within the class when only `IOSchedulingClass` is set, as the kernel defaults to
+const DEFAULT_IO_PRIORITY: u8 = 4;
+
+/// The `ioprio_set(2)` value for the unit's IO scheduling settings, if it has any
+fn io_priority(unit: &TimerUnit) -> Option<libc::c_int> {
+    let class = match (unit.io_scheduling_class, unit.io_scheduling_priority) {
+        (Some(class), _) => class,
+        (None, Some(_)) => IoClass::BestEffort,
+        (None, None) => return None,
+    };
+    let class = match class {
+        IoClass::Realtime => 1,
+        IoClass::BestEffort => 2,
+        IoClass::Idle => 3,
+    };
+    let priority = unit.io_scheduling_priority.unwrap_or(DEFAULT_IO_PRIORITY);
+    Some((class << IOPRIO_CLASS_SHIFT) | libc::c_int::from(priority))
+}
+
+/// Sets the calling process's niceness and IO priority; children inherit both
+///
+/// Called in the child between fork and exec, so it only makes raw syscalls.
+fn set_priorities(nice: Option<i32>, io_priority: Option<libc::c_int>) -> io::Result<()> {
+    if let Some(nice) = nice
+        && unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, nice) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    if let Some(io_priority) = io_priority
+        && unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, io_priority) } == -1
+    {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Applies `MemoryMax` and `CPUQuota` as rlimits of the calling process
+///
+/// The hard CPU limit is a second above the soft one, so the command first gets a
+/// SIGXCPU that tells a quota kill apart, and SIGKILL only if it ignores that.
+fn set_limits(memory_max: Option<u64>, cpu_quota: Option<Duration>) -> io::Result<()> {
+    if let Some(bytes) = memory_max {
+        let limit = libc::rlimit {
+            rlim_cur: bytes as libc::rlim_t,
+            rlim_max: bytes as libc::rlim_t,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_AS, &limit) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(quota) = cpu_quota {
+        let secs = quota.as_secs().max(1) as libc::rlim_t;
+        let limit = libc::rlimit {
+            rlim_cur: secs,
+            rlim_max: secs + 1,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_CPU, &limit) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// A byte count with the largest suffix that divides it, e.g. `128M`
+fn format_size(bytes: u64) -> String {
+    [(40, "T"), (30, "G"), (20, "M"), (10, "K")]
+        .into_iter()
+        .find(|&(shift, _)| bytes >= 1 << shift && bytes.is_multiple_of(1 << shift))
+        .map_or(format!("{}B", bytes), |(shift, suffix)| {
+            format!("{}{}", bytes >> shift, suffix)
+        })
+}
+
+/// Which limit a failed command with `status` most likely ran into, for the log
+///
+/// SIGXCPU only comes from `CPUQuota`. Failed allocations under `MemoryMax` usually
+/// end in an abort or a crash, which can't be told apart from other ones for sure.
+/// A shell reports a child killed by a signal as exit code 128 + signal.
+fn limit_hit(unit: &TimerUnit, status: ExitStatus) -> Option<String> {
+    let signal = status.signal().or_else(|| {
+        status
+            .code()
+            .filter(|&code| code > 128)
+            .map(|code| code - 128)
+    })?;
+    if signal == libc::SIGXCPU
+        && let Some(quota) = unit.cpu_quota
+    {
+        return Some(format!(
+            "killed for exceeding CPUQuota ({})",
+            humantime::format_duration(quota)
+        ));
+    }
+    if [libc::SIGABRT, libc::SIGSEGV, libc::SIGBUS].contains(&signal)
+        && let Some(bytes) = unit.memory_max
+    {
+        return Some(format!(
+            "killed by signal {}, likely out of memory under MemoryMax ({})",
+            signal,
+            format_size(bytes)
+        ));
+    }
+    None
+}
+
+/// Whether the `RuntimeMaxSec` deadline of the current firing has passed
+fn overran(deadline: Option<Instant>) -> bool {
+    deadline.is_some_and(|deadline| Instant::now() >= deadline)
+}
+
+/// Spawns the unit's command in its own process group, relaying its output
+///
+/// `extra_env` is set on top of the unit's environment.
+fn spawn_command(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    exec: &Exec,
+    extra_env: Vec<(String, String)>,
+) -> io::Result<(Child, Option<Receiver<()>>)> {
+    let tag = unit.tag(name);
+    let mut vars = unit_environment(tag, unit);
+    vars.extend(extra_env);
+    // A shell expands variables itself, with its own quoting rules
+    let expanded = match exec {
+        Exec::Argv(_) => exec.map(|arg| expand(arg, name, &vars)),
+        Exec::Shell(_) | Exec::File(_) => exec.map(|line| expand_specifiers(line, name)),
+    };
+    let mut command = expanded.command(unit.shell.as_deref());
+    if unit.clean_environment {
+        command.env_clear().env("PATH", CLEAN_PATH);
+    }
+    command.envs(vars);
+
+    if let Some(dir) = &unit.working_directory {
+        if dir.is_dir() {
+            command.current_dir(dir);
+        } else {
+            error!(
+                "[{}] WorkingDirectory {:?} does not exist, running from daemon's CWD",
+                tag, dir
+            );
+        }
+    }
+
+    // Own process group, so a timeout can kill everything the command started
+    command.process_group(0);
+
+    // Resolve names here: lookups aren't async-signal-safe inside pre_exec
+    let credentials = resolve_credentials(unit).map_err(io::Error::other)?;
+    let nice = unit.nice;
+    let io_priority = io_priority(unit);
+    let oom_score_adj = unit.oom_score_adjust.or(config.command_oom_score_adj);
+    let oom_value = oom_score_adj.map(|adjust| adjust.to_string().into_bytes());
+    let umask = unit.umask;
+    let exec_context = match &unit.selinux_context {
+        Some(context) => Some(CString::new(context.as_str()).map_err(io::Error::other)?),
+        None => None,
+    };
+    let (memory_max, cpu_quota) = (unit.memory_max, unit.cpu_quota);
+    let cgroup_procs = match &unit.cgroup {
+        Some(cgroup) => {
+            fs::create_dir_all(cgroup)?;
+            let procs = cgroup.join("cgroup.procs");
+            Some(CString::new(procs.as_os_str().as_bytes()).map_err(io::Error::other)?)
+        }
+        None => None,
+    };
+
+    unsafe {
+        command.pre_exec(move || {
+            // The daemon blocks the signals it handles via signalfd; don't pass that mask on
+            SigSet::empty().thread_set_mask()?;
+
+            // Before anything else runs, so all of it is accounted to the cgroup
+            if let Some(procs) = &cgroup_procs {
+                join_cgroup(procs)?;
+            }
+
+            // While still root, so a negative Nice or realtime IO class is permitted
+            set_priorities(nice, io_priority)?;
+            set_limits(memory_max, cpu_quota)?;
+            // Checked by the parent, which can log
+            if let Some(value) = &oom_value {
+                write_oom_score_adj(value);
+            }
+            if let Some(mask) = umask {
+                libc::umask(mask as libc::mode_t);
+            }
+
+            // Before dropping privileges; checked by the parent, which can log
+            if let Some(context) = &exec_context {
+                set_exec_context(context);
+            }
+
+            // Drop privileges in the child only: groups first, while we can still change them
+            if let Some(credentials) = credentials {
+                if let Some(gid) = credentials.gid {
+                    setgroups(&[gid])?;
+                    setgid(gid)?;
+                }
+                if let Some(uid) = credentials.uid {
+                    setuid(uid)?;
+                }
+            }
+            Ok(())
+        });
+    }
+
+    configure_output(unit, &mut command)?;
+
+    let mut child = command.spawn()?;
+    let relays = start_relays(tag, &mut child, config.max_capture);
+    if let Some(adjust) = oom_score_adj {
+        check_oom_score_adj(tag, child.id(), adjust);
+    }
+    if let Some(context) = &unit.selinux_context {
+        check_selinux_context(tag, child.id(), context);
+    }
+    Ok((child, relays))
+}
+
+/// Spawns the unit's command with [`spawn_command`] and waits for it
+///
+/// The wait is bounded by `TimeoutSec` and by the firing's `RuntimeMaxSec` deadline,
+/// whichever comes first.
+fn run_command(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    exec: &Exec,
+    deadline: Option<Instant>,
+    extra_env: Vec<(String, String)>,
+) -> io::Result<ExitStatus> {
+    let tag = unit.tag(name);
+    let (mut child, relays) = spawn_command(config, name, unit, exec, extra_env)?;
+
+    let remaining = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+    let (limit, budget) = match (unit.timeout_sec, remaining) {
+        (Some(timeout), Some(remaining)) if remaining < timeout => (Some(remaining), true),
+        (Some(timeout), _) => (Some(timeout), false),
+        (None, remaining) => (remaining, true),
+    };
+
+    let status = match limit {
+        None => child.wait(),
+        Some(limit) => match wait_timeout(&mut child, limit)? {
+            Some(status) => Ok(status),
+            None => {
+                let status = kill_child(unit, &mut child);
+                if budget {
+                    error!(
+                        unit = tag;
+                        "[{}] exceeded RuntimeMaxSec ({:?}), killed",
+                        tag,
+                        unit.runtime_max_sec.unwrap_or_default()
+                    );
+                } else {
+                    error!(unit = tag; "[{}] timed out after {:?}, killed", tag, limit);
+                }
+                status
+            }
+        },
+    };
+
+    // What a forking command left behind may hold the pipes open for good
+    if unit.service_type != ServiceType::Forking {
+        drain_relays(relays);
+    }
+    if let Some(cgroup) = &unit.cgroup {
+        remove_cgroup(cgroup);
+    }
+    status
+}
+
+/// Starts the main command of a `Type = "Simple"` unit without waiting for it;
+/// returns whether it could be started
+///
+/// A thread still reaps it, so it doesn't linger as a zombie once it exits.
+fn start_detached(config: &RunConfig, name: &str, unit: &TimerUnit) -> bool {
+    let tag = unit.tag(name);
+    let mut child = match spawn_command(config, name, unit, &unit.exec, Vec::new()) {
+        Ok((child, _)) => child,
+        Err(e) => {
+            error!(unit = tag; "Failed to start [{}]: {}", tag, e);
+            return false;
+        }
+    };
+    info!(unit = tag; "Started [{}] (pid {}), not waiting for it", tag, child.id());
+
+    let reaper_tag = tag.to_string();
+    let cgroup = unit.cgroup.clone();
+    let reaped = thread::Builder::new()
+        .name(format!("reap-{}", tag))
+        .spawn(move || {
+            match child.wait() {
+                Ok(status) => debug!("[{}] detached command exited ({})", reaper_tag, status),
+                Err(e) => error!(
+                    "[{}] failed to wait for detached command: {}",
+                    reaper_tag, e
+                ),
+            }
+            if let Some(cgroup) = &cgroup {
+                remove_cgroup(cgroup);
+            }
+        });
+    if let Err(e) = reaped {
+        error!("[{}] failed to start reaper thread: {}", tag, e);
+    }
+    true
+}
+
+/// Backoff before retry number `attempt` (1-based): base * 2^(attempt-1), capped
+fn retry_delay(base: Duration, attempt: u32) -> Duration {
+    let factor = 1u32
+        .checked_shl(attempt.saturating_sub(1))
+        .unwrap_or(u32::MAX);
+    base.saturating_mul(factor).min(MAX_RETRY_BACKOFF)
+}
+
+/// Whether the main command's exit status counts as success under `SuccessExitStatus`
+fn succeeded(unit: &TimerUnit, status: ExitStatus) -> bool {
+    status.success()
+        || unit
+            .success_exit_status
+            .iter()
+            .any(|success| success.matches(status))
+}
+
+/// Runs the main command once and logs the result; `None` if it couldn't be run
+fn run_main(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    deadline: Option<Instant>,
+) -> Option<ExitStatus> {
+    let tag = unit.tag(name);
+    match run_command(config, name, unit, &unit.exec, deadline, Vec::new()) {
+        Ok(s) if s.success() => {
+            info!(unit = tag; "Finished [{}]: Success", tag);
+            Some(s)
+        }
+        Ok(s) if succeeded(unit, s) => {
+            info!(unit = tag; "Finished [{}]: Success ({})", tag, s);
+            Some(s)
+        }
+        Ok(s) => {
+            match limit_hit(unit, s) {
+                Some(limit) => error!(unit = tag; "Finished [{}]: Failed, {}", tag, limit),
+                None => {
+                    error!(unit = tag; "Finished [{}]: Failed with exit code {:?}", tag, s.code())
+                }
+            }
+            Some(s)
+        }
+        Err(e) => {
+            error!(unit = tag; "Finished [{}]: Error executing command: {}", tag, e);
+            None
+        }
+    }
+}
+
+/// Runs the main command, retrying failures with exponential backoff
+///
+/// No retry is started that would begin after the `RuntimeMaxSec` deadline.
+/// Returns the status of the last attempt.
+fn run_with_retries(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    deadline: Option<Instant>,
+) -> Option<ExitStatus> {
+    let tag = unit.tag(name);
+    let mut attempt = 0;
+    loop {
+        let status = run_main(config, name, unit, deadline);
+        if status.is_some_and(|status| succeeded(unit, status))
+            || attempt >= unit.retries
+            || overran(deadline)
+        {
+            return status;
+        }
+        let code = status.and_then(|status| status.code());
+        if !unit.retry_on_exit.is_empty()
+            && !code.is_some_and(|code| unit.retry_on_exit.contains(&code))
+        {
+            match code {
+                Some(code) => info!(
+                    unit = tag;
+                    "[{}] exit code {} isn't in RetryOnExit, not retrying", tag, code
+                ),
+                None => info!(
+                    unit = tag;
+                    "[{}] no exit code to match RetryOnExit, not retrying", tag
+                ),
+            }
+            return status;
+        }
+
+        attempt += 1;
+        let delay = retry_delay(unit.retry_backoff_sec, attempt);
+        if deadline.is_some_and(|deadline| Instant::now() + delay >= deadline) {
+            error!(
+                unit = tag;
+                "[{}] retry {}/{} would exceed RuntimeMaxSec, giving up",
+                tag, attempt, unit.retries
+            );
+            return status;
+        }
+        info!(
+            "[{}] retry {}/{} in {:?}",
+            tag, attempt, unit.retries, delay
+        );
+        thread::sleep(delay);
+    }
+}
+
+/// Runs the unit's `ExecCondition` commands in order; returns `false` as soon as one
+/// exits nonzero or can't be run
+fn exec_conditions_met(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    deadline: Option<Instant>,
+) -> bool {
+    let tag = unit.tag(name);
+    for command in &unit.exec_condition {
+        debug!("[{}] running ExecCondition: {}", tag, command);
+        let condition = Exec::Shell(command.clone());
+        match run_command(config, name, unit, &condition, deadline, Vec::new()) {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                info!(unit = tag; "[{}] ExecCondition {}, skipping: {}", tag, status, command);
+                return false;
+            }
+            Err(e) => {
+                error!(unit = tag; "[{}] ExecCondition failed ({}), skipping: {}", tag, e, command);
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Runs hook commands in order; fails if one failed without a `-` prefix, with its
+/// exit status if it ran to completion
+///
+/// A failing hook stops the remaining hooks in the list.
+fn run_hooks(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    kind: &str,
+    hooks: &[String],
+    deadline: Option<Instant>,
+) -> Result<(), Option<ExitStatus>> {
+    let tag = unit.tag(name);
+    for hook in hooks {
+        if overran(deadline) {
+            error!(
+                "[{}] RuntimeMaxSec exceeded, skipping remaining {} hooks",
+                tag, kind
+            );
+            return Err(None);
+        }
+
+        // systemd convention: a leading '-' means the hook's failure is ignored
+        let (ignore_failure, line) = match hook.strip_prefix('-') {
+            Some(line) => (true, line),
+            None => (false, hook.as_str()),
+        };
+
+        debug!("[{}] running {}: {}", tag, kind, line);
+        let hook = Exec::Shell(line.to_string());
+        let (failure, status) = match run_command(config, name, unit, &hook, deadline, Vec::new()) {
+            Ok(status) if status.success() => continue,
+            Ok(status) => (format!("exit code {:?}", status.code()), Some(status)),
+            Err(e) => (e.to_string(), None),
+        };
+
+        if ignore_failure {
+            debug!(
+                "[{}] {} failed ({}), ignoring: {}",
+                tag, kind, failure, line
+            );
+        } else {
+            error!("[{}] {} failed ({}): {}", tag, kind, failure, line);
+            return Err(status);
+        }
+    }
+    Ok(())
+}
+
+/// Runs the unit's `OnSuccess` or `OnFailure` command once `Exec` finished with `status`,
+/// or `OnFailure` once an `ExecStartPre` hook failed with it
+///
+/// `MICETIMER_EXIT_CODE` (failures only) is empty if the command couldn't be started,
+/// and 128 + the signal number if a signal killed it, as in the shell. The
+/// `RuntimeMaxSec` budget doesn't apply, so a run that overran is still reported.
+/// The hook's own failure is logged and leaves the run's result alone.
+fn run_result_hook(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+    success: bool,
+    command: &str,
+    status: Option<ExitStatus>,
+) {
+    let tag = unit.tag(name);
+    let kind = if success { "OnSuccess" } else { "OnFailure" };
+    let mut context = vec![
+        ("MICETIMER_UNIT".to_string(), name.to_string()),
+        ("MICETIMER_EXEC".to_string(), unit.exec.to_string()),
+    ];
+    if !success {
+        let exit_code = status
+            .and_then(|status| status.code().or(status.signal().map(|signal| 128 + signal)))
+            .map(|code| code.to_string())
+            .unwrap_or_default();
+        context.push(("MICETIMER_EXIT_CODE".to_string(), exit_code));
+    }
+
+    debug!("[{}] running {}: {}", tag, kind, command);
+    let hook = Exec::Shell(command.to_string());
+    match run_command(config, name, unit, &hook, None, context) {
+        Ok(status) if status.success() => {}
+        Ok(status) => error!(unit = tag; "[{}] {} failed ({}): {}", tag, kind, status, command),
+        Err(e) => error!(unit = tag; "[{}] {} failed ({}): {}", tag, kind, e, command),
+    }
+}
+
+/// How one completed firing went
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RunOutcome {
+    pub success: bool,
+    /// Exit code of the last main command attempt; `None` if it was killed by a
+    /// signal, couldn't be spawned or never ran
+    pub exit_code: Option<i32>,
+    /// Wall time of the whole firing, hooks and retries included
+    pub duration: Duration,
+}
+
+/// Runs a unit's command to completion, holding its wakelock for the duration
+///
+/// Returns whether the run succeeded, or `None` if a condition or `ExecCondition`
+/// skipped it or it was a dry run. This is what a firing does, minus the concurrency bookkeeping of the
+/// scheduler.
+pub fn execute_timer(config: &RunConfig, name: &str, unit: &TimerUnit) -> Option<bool> {
+    run_unit(config, name, unit)
+        .ok()
+        .map(|outcome| outcome.success)
+}
+
+/// [`execute_timer`], reporting the details the scheduler keeps metrics of, or why
+/// the firing was skipped
+///
+/// Either way the result is also written to the unit's `ResultDir`.
+pub(crate) fn run_unit(
+    config: &RunConfig,
+    name: &str,
+    unit: &TimerUnit,
+) -> Result<RunOutcome, SkipReason> {
+    let started = SystemTime::now();
+    let result = fire(config, name, unit);
+    report_result(name, unit, started, &result);
+    result
+}
+
+fn fire(config: &RunConfig, name: &str, unit: &TimerUnit) -> Result<RunOutcome, SkipReason> {
+    let tag = unit.tag(name);
+    if !conditions_met(unit) {
+        debug!(unit = tag; "[{}] condition not met, skipping", tag);
+        return Err(SkipReason::Condition);
+    }
+
+    if config.dry_run {
+        info!(unit = tag; "[DRY] would run [{}]: {}", tag, unit.exec);
+        return Err(SkipReason::DryRun);
+    }
+
+    let wakelocks = &config.wakelocks;
+    let lock_name = wakelocks.lock_name(name);
+
+    // Acquire Android WakeLock, or have it acquired if the run outlasts WakeLockDelay
+    let delayed_wakelock = unit
+        .wake_lock_delay
+        .filter(|_| unit.holds_wakelock())
+        .map(|delay| wakelocks.acquire_after(&lock_name, delay, unit.wake_lock_timeout_sec));
+    let use_wakelock = unit.holds_wakelock()
+        && delayed_wakelock.is_none()
+        && wakelocks.acquire(&lock_name, unit.wake_lock_timeout_sec);
+
+    let started = Instant::now();
+    let deadline = unit.runtime_max_sec.map(|max| started + max);
+
+    if !exec_conditions_met(config, name, unit, deadline) {
+        if use_wakelock {
+            wakelocks.release(&lock_name);
+        }
+        if let Some(wakelock) = delayed_wakelock {
+            wakelock.release();
+        }
+        return Err(SkipReason::Condition);
+    }
+
+    if let Some(desc) = &unit.description {
+        info!("Timer [{}]: {}", tag, desc);
+    }
+    info!(unit = tag; "Executing [{}]: {}", tag, unit.exec);
+
+    let mut success = false;
+    let mut exit_code = None;
+    let pre = run_hooks(
+        config,
+        name,
+        unit,
+        "ExecStartPre",
+        &unit.exec_start_pre,
+        deadline,
+    );
+    if let Err(status) = pre {
+        // The firing was aborted, which is as much a failure as Exec failing
+        if let Some(command) = &unit.on_failure {
+            run_result_hook(config, name, unit, false, command, status);
+        }
+    } else {
+        let status = match unit.service_type {
+            ServiceType::Simple => {
+                success = start_detached(config, name, unit);
+                None
+            }
+            _ => {
+                let status = run_with_retries(config, name, unit, deadline);
+                success = status.is_some_and(|status| succeeded(unit, status));
+                status
+            }
+        };
+        exit_code = status.and_then(|status| status.code());
+        if success
+            && unit.persistent
+            && let Err(e) = write_stamp(&config.stamp_dir, name, SystemTime::now())
+        {
+            error!("Failed to write stamp for [{}]: {}", tag, e);
+        }
+
+        let _ = run_hooks(
+            config,
+            name,
+            unit,
+            "ExecStartPost",
+            &unit.exec_start_post,
+            deadline,
+        );
+
+        let hook = if success {
+            &unit.on_success
+        } else {
+            &unit.on_failure
+        };
+        if let Some(command) = hook {
+            run_result_hook(config, name, unit, success, command, status);
+        }
+    }
+
+    // Release Android WakeLock
+    if use_wakelock {
+        wakelocks.release(&lock_name);
+    }
+    if let Some(wakelock) = delayed_wakelock {
+        wakelock.release();
+    }
+    Ok(RunOutcome {
+        success,
+        exit_code,
+        duration: started.elapsed(),
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use std::collections::HashMap;
+    use std::env;
+    use std::os::unix::fs::PermissionsExt;
+    use std::path::PathBuf;
+    use std::sync::{Mutex, OnceLock};
+
+    /// A fresh cgroup to run commands in, or `None` (skipping the test) without root
+    /// or a writable cgroup hierarchy
+    fn test_cgroup(name: &str) -> Option<PathBuf> {
+        [
+            "/sys/fs/cgroup",
+            "/sys/fs/cgroup/pids",
+            "/sys/fs/cgroup/unified",
+        ]
+        .into_iter()
+        .map(Path::new)
+        .filter(|base| base.join("cgroup.procs").exists())
+        .map(|base| base.join(format!("{}-{}", name, std::process::id())))
+        .find(|path| fs::create_dir(path).is_ok())
+    }
+
+    fn unit(source: &str) -> TimerUnit {
+        source.parse().unwrap()
+    }
+
+    /// Log records emitted by this test binary, as `LEVEL message`; other modules' tests
+    /// share it, since a logger can only be set once
+    pub(crate) fn captured_logs() -> &'static Mutex<Vec<String>> {
+        struct Capture(Mutex<Vec<String>>);
+
+        impl log::Log for Capture {
+            fn enabled(&self, _: &log::Metadata) -> bool {
+                true
+            }
+
+            fn log(&self, record: &log::Record) {
+                let line = format!("{} {}", record.level(), record.args());
+                self.0.lock().unwrap().push(line);
+            }
+
+            fn flush(&self) {}
+        }
+
+        static CAPTURE: OnceLock<&'static Capture> = OnceLock::new();
+        let capture = CAPTURE.get_or_init(|| {
+            let capture = Box::leak(Box::new(Capture(Mutex::default())));
+            log::set_logger(capture).unwrap();
+            log::set_max_level(log::LevelFilter::Info);
+            capture
+        });
+        &capture.0
+    }
+
+    /// A path of this test's own in the temporary directory
+    fn scratch(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("micetimer-{}-{}", name, std::process::id()))
+    }
+
+    fn cgroup_pids(path: &Path) -> Vec<String> {
+        fs::read_to_string(path.join("cgroup.procs"))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn timeout_kills_cgroup_with_kill_mode_process() {
+        let Some(cgroup) = test_cgroup("micetimer-test-kill") else {
+            eprintln!("no writable cgroup hierarchy, skipping");
+            return;
+        };
+        let unit: TimerUnit = format!(
+            r#"
+            Exec = "sleep 30 & sleep 30"
+            OnBootSec = "1h"
+            TimeoutSec = "300ms"
+            KillMode = "Process"
+            WakeLock = false
+            CGroup = {:?}
+            "#,
+            cgroup
+        )
+        .parse()
+        .unwrap();
+
+        let status = run_command(
+            &RunConfig::default(),
+            "test",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(!status.success());
+
+        // The background sleep was in the cgroup, not in the signalled process
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while cgroup.exists() && !cgroup_pids(&cgroup).is_empty() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(cgroup_pids(&cgroup), Vec::<String>::new());
+        remove_cgroup(&cgroup);
+        assert!(!cgroup.exists());
+    }
+
+    #[test]
+    fn timeout_terminates_a_runaway_command() {
+        let unit = unit(
+            r#"
+            Exec = "sleep 10"
+            OnBootSec = "1h"
+            TimeoutSec = "300ms"
+            WakeLock = false
+            "#,
+        );
+        let started = Instant::now();
+        let status = run_command(
+            &RunConfig::default(),
+            "runaway",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGTERM));
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn environment_file_overrides_inline_environment() {
+        let path = scratch("env");
+        fs::write(
+            &path,
+            "# comment\n\nB = \"from file\"\nC=plain\nbroken line\n",
+        )
+        .unwrap();
+        let unit = unit(&format!(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            Environment = {{ A = "inline", B = "inline" }}
+            EnvironmentFile = {:?}
+            "#,
+            path
+        ));
+
+        // Later entries win when the command's environment is set
+        let vars: HashMap<String, String> = unit_environment("env", &unit).into_iter().collect();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(vars.len(), 3);
+        assert_eq!(vars["A"], "inline");
+        assert_eq!(vars["B"], "from file");
+        assert_eq!(vars["C"], "plain");
+    }
+
+    #[test]
+    fn command_runs_in_working_directory() {
+        let output = scratch("pwd.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = "pwd"
+            OnBootSec = "1h"
+            WorkingDirectory = "/proc"
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            output
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "pwd",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(printed, "/proc\n");
+    }
+
+    #[test]
+    fn user_and_group_resolve_by_name_or_number() {
+        let root = (Uid::from_raw(0), Some(Gid::from_raw(0)));
+        assert_eq!(resolve_user("root").unwrap(), root);
+        assert_eq!(resolve_user("0").unwrap(), root);
+        // Numbers need no account, which leaves the group unknown
+        assert_eq!(resolve_user("54321").unwrap(), (Uid::from_raw(54321), None));
+        assert!(resolve_user("no-such-user-micetimer").is_err());
+
+        assert_eq!(resolve_group("root").unwrap(), Gid::from_raw(0));
+        assert_eq!(resolve_group("3003").unwrap(), Gid::from_raw(3003));
+        assert!(resolve_group("no-such-group-micetimer").is_err());
+
+        let credentials = |source: &str| {
+            let unit = unit(&format!("Exec = \"true\"\nOnBootSec = \"1h\"\n{}", source));
+            resolve_credentials(&unit)
+                .unwrap()
+                .map(|credentials| (credentials.uid, credentials.gid))
+        };
+        assert_eq!(credentials(""), None);
+        assert_eq!(credentials("User = \"root\""), Some((Some(root.0), root.1)));
+        assert_eq!(
+            credentials("User = \"root\"\nGroup = \"3003\""),
+            Some((Some(root.0), Some(Gid::from_raw(3003))))
+        );
+    }
+
+    #[test]
+    fn log_output_relays_each_stream_line() {
+        let logs = captured_logs();
+        let unit = unit(
+            r#"
+            Exec = "echo relayed stdout line; echo relayed stderr line >&2"
+            OnBootSec = "1h"
+            StandardOutput = "Log"
+            "#,
+        );
+        let status = run_command(
+            &RunConfig::default(),
+            "relay",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+
+        let logs = logs.lock().unwrap();
+        assert!(logs.contains(&"INFO [relay] relayed stdout line".to_string()));
+        assert!(logs.contains(&"WARN [relay] relayed stderr line".to_string()));
+    }
+
+    #[test]
+    fn failing_pre_hook_aborts_unless_prefixed() {
+        let marker = scratch("pre-marker");
+        let pre = |hook: &str| {
+            let _ = fs::remove_file(&marker);
+            let unit = unit(&format!(
+                r#"
+                Exec = "touch {}"
+                OnBootSec = "1h"
+                WakeLock = false
+                ExecStartPre = ["true", {:?}]
+                "#,
+                marker.display(),
+                hook
+            ));
+            let outcome = fire(&RunConfig::default(), "pre", &unit).unwrap();
+            (outcome.success, marker.exists())
+        };
+
+        assert_eq!(pre("false"), (false, false));
+        assert_eq!(pre("-false"), (true, true));
+        fs::remove_file(&marker).unwrap();
+    }
+
+    #[test]
+    fn retry_backoff_doubles_up_to_the_cap() {
+        let base = Duration::from_secs(5);
+        assert_eq!(retry_delay(base, 1), Duration::from_secs(5));
+        assert_eq!(retry_delay(base, 2), Duration::from_secs(10));
+        assert_eq!(retry_delay(base, 4), Duration::from_secs(40));
+        assert_eq!(retry_delay(base, 10), MAX_RETRY_BACKOFF);
+        assert_eq!(retry_delay(base, 100), MAX_RETRY_BACKOFF);
+    }
+
+    #[test]
+    fn failing_command_is_retried_until_it_succeeds() {
+        let count = scratch("retry-count");
+        let _ = fs::remove_file(&count);
+        // Fails on the first two invocations, succeeds on the third
+        let unit = unit(&format!(
+            r#"
+            Exec = "echo run >> {0}; [ $(wc -l < {0}) -ge 3 ]"
+            OnBootSec = "1h"
+            WakeLock = false
+            Retries = 5
+            RetryBackoffSec = "10ms"
+            "#,
+            count.display()
+        ));
+        let status = run_with_retries(&RunConfig::default(), "retry", &unit, None).unwrap();
+        let runs = fs::read_to_string(&count).unwrap().lines().count();
+        fs::remove_file(&count).unwrap();
+        assert!(status.success());
+        assert_eq!(runs, 3);
+    }
+
+    #[test]
+    fn runtime_budget_cuts_retries_and_hooks_short() {
+        let count = scratch("budget-count");
+        let _ = fs::remove_file(&count);
+        let retrying = unit(&format!(
+            r#"
+            Exec = "echo run >> {}; false"
+            OnBootSec = "1h"
+            WakeLock = false
+            Retries = 10
+            RetryBackoffSec = "100ms"
+            RuntimeMaxSec = "250ms"
+            "#,
+            count.display()
+        ));
+        let outcome = fire(&RunConfig::default(), "budget", &retrying).unwrap();
+        let runs = fs::read_to_string(&count).unwrap().lines().count();
+        fs::remove_file(&count).unwrap();
+        // The second retry would start 300ms in, past the budget
+        assert!(!outcome.success);
+        assert_eq!(runs, 2);
+        assert!(outcome.duration < Duration::from_millis(250));
+
+        let post = scratch("budget-post");
+        let overrunning = unit(&format!(
+            r#"
+            Exec = "sleep 10"
+            OnBootSec = "1h"
+            WakeLock = false
+            RuntimeMaxSec = "200ms"
+            ExecStartPost = ["touch {}"]
+            "#,
+            post.display()
+        ));
+        let outcome = fire(&RunConfig::default(), "overrun", &overrunning).unwrap();
+        assert!(!outcome.success);
+        assert!(outcome.duration < Duration::from_secs(2));
+        assert!(!post.exists());
+    }
+
+    #[test]
+    fn success_exit_status_is_neither_retried_nor_logged_as_failure() {
+        let logs = captured_logs();
+        let count = scratch("nothing-to-do");
+        let _ = fs::remove_file(&count);
+        let unit = unit(&format!(
+            r#"
+            Exec = "echo run >> {}; exit 75"
+            OnBootSec = "1h"
+            WakeLock = false
+            Retries = 3
+            RetryBackoffSec = "10ms"
+            SuccessExitStatus = [0, 75, "SIGUSR1"]
+            "#,
+            count.display()
+        ));
+        let status = run_with_retries(&RunConfig::default(), "nothing-to-do", &unit, None).unwrap();
+        let runs = fs::read_to_string(&count).unwrap().lines().count();
+        fs::remove_file(&count).unwrap();
+        assert_eq!(status.code(), Some(75));
+        assert!(succeeded(&unit, status));
+        assert_eq!(runs, 1);
+
+        let logs = logs.lock().unwrap();
+        let mentions: Vec<&String> = logs
+            .iter()
+            .filter(|line| line.contains("[nothing-to-do]"))
+            .collect();
+        assert!(
+            mentions.iter().all(|line| !line.starts_with("ERROR")),
+            "{:?}",
+            mentions
+        );
+        assert!(
+            mentions
+                .contains(&&"INFO Finished [nothing-to-do]: Success (exit status: 75)".to_string())
+        );
+
+        // Signal names match a command killed by that signal
+        let killed = Command::new("sh")
+            .args(["-c", "kill -USR1 $$"])
+            .status()
+            .unwrap();
+        assert!(succeeded(&unit, killed));
+        let failed = Command::new("sh").args(["-c", "exit 1"]).status().unwrap();
+        assert!(!succeeded(&unit, failed));
+    }
+
+    #[test]
+    fn on_failure_receives_the_exit_code() {
+        let report = scratch("on-failure");
+        let _ = fs::remove_file(&report);
+        let unit = unit(&format!(
+            r#"
+            Exec = "exit 7"
+            OnBootSec = "1h"
+            WakeLock = false
+            Retries = 1
+            RetryBackoffSec = "10ms"
+            OnFailure = "echo $MICETIMER_UNIT $MICETIMER_EXIT_CODE $MICETIMER_EXEC >> {}"
+            "#,
+            report.display()
+        ));
+        let outcome = fire(&RunConfig::default(), "failing", &unit).unwrap();
+        assert!(!outcome.success);
+        assert_eq!(outcome.exit_code, Some(7));
+
+        // Once, after the retries
+        let reported = fs::read_to_string(&report).unwrap();
+        fs::remove_file(&report).unwrap();
+        assert_eq!(reported, "failing 7 exit 7\n");
+    }
+
+    #[test]
+    fn on_success_runs_only_after_success() {
+        let report = scratch("on-success");
+        let run = |exec: &str| {
+            let _ = fs::remove_file(&report);
+            let unit = unit(&format!(
+                r#"
+                Exec = "{}"
+                OnBootSec = "1h"
+                WakeLock = false
+                OnSuccess = "echo $MICETIMER_UNIT [$MICETIMER_EXEC] [$MICETIMER_EXIT_CODE] >> {}; false"
+                "#,
+                exec,
+                report.display()
+            ));
+            let success = fire(&RunConfig::default(), "staged", &unit)
+                .unwrap()
+                .success;
+            (success, fs::read_to_string(&report).ok())
+        };
+
+        // The hook's own failure leaves the run successful
+        assert_eq!(run("true"), (true, Some("staged [true] []\n".to_string())));
+        assert_eq!(run("false"), (false, None));
+    }
+
+    #[test]
+    fn command_runs_with_the_configured_niceness() {
+        let output = scratch("nice.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = "cut -d' ' -f19 /proc/self/stat; ionice || true"
+            OnBootSec = "1h"
+            Nice = 7
+            IOSchedulingClass = "best-effort"
+            IOSchedulingPriority = 6
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            output
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "nice",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+
+        let mut lines = printed.lines();
+        assert_eq!(lines.next(), Some("7"));
+        // Only checked where ionice(1) is installed
+        if let Some(io) = lines.next() {
+            assert_eq!(io, "best-effort: prio 6");
+        }
+
+        let invalid = |source: &str| {
+            format!("Exec = \"true\"\nOnBootSec = \"1h\"\n{}", source)
+                .parse::<TimerUnit>()
+                .is_err()
+        };
+        assert!(invalid("Nice = 20"));
+        assert!(invalid("Nice = -21"));
+        assert!(invalid("IOSchedulingPriority = 8"));
+    }
+
+    #[test]
+    fn command_gets_the_configured_oom_score_adj() {
+        let output = scratch("oom.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = "cat /proc/self/oom_score_adj"
+            OnBootSec = "1h"
+            OOMScoreAdjust = 500
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            output
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "oom",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        assert_eq!(printed, "500\n");
+
+        let invalid = |adjust: i32| {
+            format!(
+                "Exec = \"true\"\nOnBootSec = \"1h\"\nOOMScoreAdjust = {}",
+                adjust
+            )
+            .parse::<TimerUnit>()
+            .is_err()
+        };
+        assert!(invalid(1001));
+        assert!(invalid(-1001));
+        assert!(!invalid(-1000));
+    }
+
+    #[test]
+    fn failing_exec_condition_skips_without_a_failure() {
+        let marker = scratch("condition-marker");
+        let failure = scratch("condition-failure");
+        let unit = unit(&format!(
+            r#"
+            Exec = "touch {}"
+            OnBootSec = "1h"
+            WakeLock = false
+            ExecCondition = ["true", "exit 1", "touch {}"]
+            OnFailure = "touch {}"
+            "#,
+            marker.display(),
+            marker.display(),
+            failure.display()
+        ));
+        let result = fire(&RunConfig::default(), "gated", &unit);
+        assert!(matches!(result, Err(SkipReason::Condition)));
+        // Neither the main command, the later conditions nor OnFailure ran
+        assert!(!marker.exists());
+        assert!(!failure.exists());
+    }
+
+    #[test]
+    fn kill_mode_signals_the_group_or_the_main_process() {
+        // Whether the background child of a timed-out command survived it
+        let survivor = |kill_mode: &str| {
+            let pid_file = scratch(&format!("kill-{}", kill_mode));
+            let unit = unit(&format!(
+                r#"
+                Exec = "sleep 30 & echo $! > {}; wait"
+                OnBootSec = "1h"
+                TimeoutSec = "300ms"
+                KillMode = "{}"
+                "#,
+                pid_file.display(),
+                kill_mode
+            ));
+            let status = run_command(
+                &RunConfig::default(),
+                "kill",
+                &unit,
+                &unit.exec,
+                None,
+                Vec::new(),
+            )
+            .unwrap();
+            assert_eq!(status.signal(), Some(libc::SIGTERM));
+
+            let pid: i32 = fs::read_to_string(&pid_file)
+                .unwrap()
+                .trim()
+                .parse()
+                .unwrap();
+            fs::remove_file(&pid_file).unwrap();
+            thread::sleep(Duration::from_millis(50));
+            let alive = fs::read_to_string(format!("/proc/{}/stat", pid)).is_ok_and(|stat| {
+                !stat
+                    .rsplit(')')
+                    .next()
+                    .unwrap()
+                    .trim_start()
+                    .starts_with('Z')
+            });
+            let _ = kill(Pid::from_raw(pid), Signal::SIGKILL);
+            alive
+        };
+        assert!(!survivor("ControlGroup"));
+        assert!(survivor("Process"));
+    }
+
+    #[test]
+    fn clean_environment_keeps_only_the_configured_variables() {
+        let output = scratch("clean-env.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = ["env"]
+            OnBootSec = "1h"
+            CleanEnvironment = true
+            Environment = {{ ONLY = "this" }}
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            output
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "clean-env",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        let mut lines: Vec<&str> = printed.lines().collect();
+        lines.sort_unstable();
+        assert_eq!(
+            lines,
+            ["ONLY=this", format!("PATH={}", CLEAN_PATH).as_str()]
+        );
+    }
+
+    #[test]
+    fn shell_line_runs_through_the_configured_shell() {
+        let stub = scratch("shell-stub");
+        let calls = scratch("shell-stub.log");
+        fs::write(
+            &stub,
+            format!(
+                "#!/bin/sh\nprintf '%s\\n' \"$*\" >> {}\nexec sh \"$@\"\n",
+                calls.display()
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&stub, fs::Permissions::from_mode(0o755)).unwrap();
+        let unit = unit(&format!(
+            r#"
+            Exec = "exit 7"
+            OnBootSec = "1h"
+            Shell = [{:?}, "-e", "-c"]
+            "#,
+            stub
+        ));
+
+        let status = run_command(
+            &RunConfig::default(),
+            "shell",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        let logged = fs::read_to_string(&calls).unwrap();
+        fs::remove_file(&stub).unwrap();
+        fs::remove_file(&calls).unwrap();
+        // The stub got the prefix args with the line last, and ran it
+        assert_eq!(logged, "-e -c exit 7\n");
+        assert_eq!(status.code(), Some(7));
+    }
+
+    #[test]
+    fn umask_applies_to_files_the_command_creates() {
+        let created = scratch("umask-created");
+        let unit = unit(&format!(
+            r#"
+            Exec = "touch {}"
+            OnBootSec = "1h"
+            UMask = "0077"
+            "#,
+            created.display()
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "umask",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let mode = fs::metadata(&created).unwrap().permissions().mode();
+        fs::remove_file(&created).unwrap();
+        assert_eq!(mode & 0o777, 0o600);
+
+        assert!(
+            "Exec = \"true\"\nOnBootSec = \"1h\"\nUMask = \"0089\""
+                .parse::<TimerUnit>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn log_tag_replaces_the_unit_name_in_log_records() {
+        let logs = captured_logs();
+        let unit = unit(
+            r#"
+            Exec = "echo tagged"
+            OnBootSec = "1h"
+            WakeLock = false
+            LogTag = "Friendly Sync"
+            StandardOutput = "Log"
+            "#,
+        );
+        assert!(
+            fire(&RunConfig::default(), "sync-stem", &unit)
+                .unwrap()
+                .success
+        );
+
+        let logs = logs.lock().unwrap();
+        let tagged: Vec<&String> = logs
+            .iter()
+            .filter(|line| line.contains("[Friendly Sync]"))
+            .collect();
+        assert!(
+            tagged.iter().any(|line| line.contains("Executing")),
+            "{:?}",
+            tagged
+        );
+        assert!(
+            tagged.iter().any(|line| line.contains("tagged")),
+            "{:?}",
+            tagged
+        );
+        assert!(
+            !logs.iter().any(|line| line.contains("sync-stem")),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn command_lands_in_the_configured_cgroup() {
+        let Some(cgroup) = test_cgroup("micetimer-test-join") else {
+            eprintln!("no writable cgroup hierarchy, skipping");
+            return;
+        };
+        let output = scratch("cgroup.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = "cat /proc/self/cgroup"
+            OnBootSec = "1h"
+            WakeLock = false
+            CGroup = {:?}
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            cgroup, output
+        ));
+
+        let status = run_command(
+            &RunConfig::default(),
+            "cgroup",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        remove_cgroup(&cgroup);
+        assert!(status.success());
+        let name = cgroup.file_name().unwrap().to_string_lossy();
+        assert!(
+            printed
+                .lines()
+                .any(|line| line.ends_with(&format!("/{}", name))),
+            "{}",
+            printed
+        );
+    }
+
+    #[test]
+    fn allocating_past_memory_max_is_logged_as_the_limit() {
+        if Command::new("python3").arg("-V").output().is_err() {
+            eprintln!("no python3 to allocate with, skipping");
+            return;
+        }
+        let logs = captured_logs();
+        // Allocation failures tend to end in an abort; this one always does
+        let unit = unit(
+            r#"
+            Exec = ["python3", "-c", "import os\ntry: bytearray(256 << 20)\nexcept MemoryError: os.abort()"]
+            OnBootSec = "1h"
+            WakeLock = false
+            MemoryMax = "64M"
+            "#,
+        );
+        let status = run_main(&RunConfig::default(), "hungry", &unit, None).unwrap();
+        assert_eq!(status.signal(), Some(libc::SIGABRT));
+
+        let logs = logs.lock().unwrap();
+        let finished = logs
+            .iter()
+            .find(|line| line.contains("Finished [hungry]"))
+            .unwrap();
+        assert!(
+            finished.ends_with("likely out of memory under MemoryMax (64M)"),
+            "{}",
+            finished
+        );
+    }
+
+    #[test]
+    fn only_exit_codes_in_retry_on_exit_are_retried() {
+        let count = scratch("retry-on-exit");
+        let runs = |code: i32| {
+            let _ = fs::remove_file(&count);
+            let unit = unit(&format!(
+                r#"
+                Exec = "echo run >> {}; exit {}"
+                OnBootSec = "1h"
+                WakeLock = false
+                Retries = 2
+                RetryBackoffSec = "10ms"
+                RetryOnExit = [2]
+                "#,
+                count.display(),
+                code
+            ));
+            let status =
+                run_with_retries(&RunConfig::default(), "retry-on-exit", &unit, None).unwrap();
+            assert_eq!(status.code(), Some(code));
+            fs::read_to_string(&count).unwrap().lines().count()
+        };
+
+        assert_eq!(runs(1), 1);
+        assert_eq!(runs(2), 3);
+        fs::remove_file(&count).unwrap();
+
+        let error = "Exec = \"true\"\nOnBootSec = \"1h\"\nRetryOnExit = [2]"
+            .parse::<TimerUnit>()
+            .unwrap_err();
+        assert!(error.to_string().contains("Retries"), "{}", error);
+    }
+
+    #[test]
+    fn output_tail_keeps_the_last_bytes_behind_a_marker() {
+        let mut tail = OutputTail::new(10);
+        tail.push(b"line1\n");
+        assert_eq!(tail.lines(), ["line1"]);
+
+        // Past the cap, in one push and across several
+        tail.push(b"line2\nline3\n");
+        assert_eq!(tail.lines(), ["... 8 bytes of output truncated", "line3"]);
+        // One long line can't lose its cut-off start to the marker as well
+        let mut flood = OutputTail::new(10);
+        flood.push(&[b'a'; 20]);
+        flood.push(&[b'z'; 10]);
+        assert_eq!(
+            flood.lines(),
+            ["... 20 bytes of output truncated", "zzzzzzzzzz"]
+        );
+    }
+
+    #[test]
+    fn service_type_decides_what_a_firing_waits_for() {
+        let fire_timed = |source: &str| {
+            let unit = unit(&format!("OnBootSec = \"1h\"\nWakeLock = false\n{}", source));
+            let started = Instant::now();
+            let outcome = fire(&RunConfig::default(), "typed", &unit).unwrap();
+            (outcome, started.elapsed())
+        };
+        let slow = "Exec = \"sleep 0.5; exit 3\"";
+
+        // OneShot (the default) waits for the command and tracks its exit
+        let (outcome, took) = fire_timed(slow);
+        assert!(took >= Duration::from_millis(500));
+        assert_eq!((outcome.success, outcome.exit_code), (false, Some(3)));
+
+        // Simple only starts it
+        let (outcome, took) = fire_timed(&format!("{}\nType = \"Simple\"", slow));
+        assert!(took < Duration::from_millis(400), "{:?}", took);
+        assert_eq!((outcome.success, outcome.exit_code), (true, None));
+
+        // Forking waits for the parent, not for what it left running
+        let marker = scratch("forked");
+        let _ = fs::remove_file(&marker);
+        let (outcome, took) = fire_timed(&format!(
+            "Exec = \"(sleep 0.5; touch {}) & exit 0\"\nType = \"Forking\"",
+            marker.display()
+        ));
+        assert!(took < Duration::from_millis(400), "{:?}", took);
+        assert_eq!((outcome.success, outcome.exit_code), (true, Some(0)));
+        assert!(!marker.exists());
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !marker.exists() {
+            assert!(Instant::now() < deadline, "the forked child never finished");
+            thread::sleep(Duration::from_millis(20));
+        }
+        fs::remove_file(&marker).unwrap();
+
+        let simple_retries = "Exec = \"true\"\nOnBootSec = \"1h\"\nType = \"Simple\"\nRetries = 2";
+        assert!(simple_retries.parse::<TimerUnit>().is_err());
+    }
+
+    #[test]
+    fn selinux_context_applies_or_logs_and_runs_anyway() {
+        let output = scratch("selinux.log");
+        let run = |context: &str| {
+            let unit = unit(&format!(
+                r#"
+                Exec = "cat /proc/self/attr/current"
+                OnBootSec = "1h"
+                WakeLock = false
+                SELinuxContext = {:?}
+                StandardOutput = {{ File = {:?} }}
+                "#,
+                context, output
+            ));
+            let status = run_command(
+                &RunConfig::default(),
+                "selinux",
+                &unit,
+                &unit.exec,
+                None,
+                Vec::new(),
+            )
+            .unwrap();
+            let printed = fs::read_to_string(&output).unwrap();
+            fs::remove_file(&output).unwrap();
+            (status, printed.trim_end_matches(['\0', '\n']).to_string())
+        };
+
+        if Path::new("/sys/fs/selinux/enforce").exists() {
+            // Our own context is always a permitted transition
+            let current = fs::read_to_string("/proc/self/attr/current").unwrap();
+            let current = current.trim_end_matches(['\0', '\n']);
+            let (status, printed) = run(current);
+            assert!(status.success());
+            assert_eq!(printed, current);
+            return;
+        }
+
+        let logs = captured_logs();
+        let (status, printed) = run("u:r:magisk:s0");
+        assert!(status.success());
+        assert_ne!(printed, "u:r:magisk:s0");
+        let logs = logs.lock().unwrap();
+        assert!(
+            logs.iter().any(|line| line
+                .starts_with("WARN [selinux] failed to set SELinuxContext=u:r:magisk:s0")),
+            "{:?}",
+            logs
+        );
+    }
+
+    #[test]
+    fn shell_lines_leave_variables_and_quoting_to_the_shell() {
+        let output = scratch("shell-vars.log");
+        let unit = unit(&format!(
+            r#"
+            Exec = "echo [${{GREETING:-hello}}] ['$HOME'] [$VALUE] %n"
+            OnBootSec = "1h"
+            Environment = {{ VALUE = "$(echo injected)" }}
+            StandardOutput = {{ File = {:?} }}
+            "#,
+            output
+        ));
+        let status = run_command(
+            &RunConfig::default(),
+            "vars",
+            &unit,
+            &unit.exec,
+            None,
+            Vec::new(),
+        )
+        .unwrap();
+        assert!(status.success());
+        let printed = fs::read_to_string(&output).unwrap();
+        fs::remove_file(&output).unwrap();
+        // A value is data to the shell, not syntax
+        assert_eq!(printed, "[hello] [$HOME] [$(echo injected)] vars\n");
+    }
+
+    #[test]
+    fn configs_apply_only_to_their_own_firings() {
+        let marker = scratch("dry-config");
+        let _ = fs::remove_file(&marker);
+        let unit = unit(&format!(
+            "Exec = \"touch {}\"\nOnBootSec = \"1h\"\nWakeLock = false",
+            marker.display()
+        ));
+
+        let mut dry = RunConfig::default();
+        dry.set_dry_run(true);
+        assert!(matches!(fire(&dry, "dry", &unit), Err(SkipReason::DryRun)));
+        assert!(!marker.exists());
+
+        assert!(fire(&RunConfig::default(), "wet", &unit).unwrap().success);
+        assert!(marker.exists());
+        fs::remove_file(&marker).unwrap();
+    }
+}
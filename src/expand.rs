@@ -0,0 +1,181 @@
+//! Expansion of `%` specifiers and `$VAR` references in command lines
+//!
+//! - `%i`: the instance of a template unit, replaced when the unit is loaded
+//! - `%n`: the unit name; `%%`: a literal `%`
+//!
+//! Shell lines (and `ExecFile` paths) only get the specifiers, via
+//! [`expand_specifiers`]: the shell expands variables itself, from the environment
+//! the command receives, and knows about quoting and `${VAR:-default}`.
+//!
+//! `Exec` arrays don't go through a shell, so [`expand`] also replaces in each argument:
+//!
+//! - `$VAR`, `${VAR}`: the unit's environment, falling back to the daemon's;
+//!   unset variables expand to nothing
+//! - `$$`: a literal `$`
+//!
+//! Anything else (e.g. `${VAR:-default}` or `$1`) is left as is.
+
+use log::debug;
+use std::env;
+use std::iter::Peekable;
+use std::str::Chars;
+
+/// Expands the `%` specifiers of `template` for unit `name`, leaving `$` alone
+pub(crate) fn expand_specifiers(template: &str, name: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        push_specifier(&mut out, chars.next(), name);
+    }
+    out
+}
+
+/// Expands one argument of an `Exec` array for unit `name`, whose environment is
+/// `vars` (later entries win)
+pub(crate) fn expand(template: &str, name: &str, vars: &[(String, String)]) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '%' => push_specifier(&mut out, chars.next(), name),
+            '$' => match chars.peek() {
+                Some('$') => {
+                    chars.next();
+                    out.push('$');
+                }
+                Some('{') => {
+                    let rest: String = chars.clone().skip(1).collect();
+                    match rest.split_once('}') {
+                        Some((var, _)) if is_identifier(var) => {
+                            // Past the `{`, the name and the `}`
+                            chars.nth(var.len() + 1);
+                            out.push_str(&lookup(var, name, vars));
+                        }
+                        // Not a plain name, or unterminated: keep it verbatim
+                        _ => out.push('$'),
+                    }
+                }
+                Some(&c) if c.is_ascii_alphabetic() || c == '_' => {
+                    let var = take_identifier(&mut chars);
+                    out.push_str(&lookup(&var, name, vars));
+                }
+                _ => out.push('$'),
+            },
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+/// Appends what `%` followed by `next` stands for
+fn push_specifier(out: &mut String, next: Option<char>, name: &str) {
+    match next {
+        Some('n') => out.push_str(name),
+        Some('%') => out.push('%'),
+        Some(other) => {
+            out.push('%');
+            out.push(other);
+        }
+        None => out.push('%'),
+    }
+}
+
+fn is_identifier(var: &str) -> bool {
+    var.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+        && var.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Replaces `%i` with `instance`, leaving every other specifier (and `%%`) for run time
+pub(crate) fn expand_instance(template: &str, instance: &str) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '%' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('i') => out.push_str(instance),
+            Some(other) => {
+                out.push('%');
+                out.push(other);
+            }
+            None => out.push('%'),
+        }
+    }
+    out
+}
+
+fn take_identifier(chars: &mut Peekable<Chars<'_>>) -> String {
+    let mut var = String::new();
+    while let Some(&c) = chars.peek() {
+        if !(c.is_ascii_alphanumeric() || c == '_') {
+            break;
+        }
+        var.push(c);
+        chars.next();
+    }
+    var
+}
+
+fn lookup(var: &str, name: &str, vars: &[(String, String)]) -> String {
+    if let Some((_, value)) = vars.iter().rev().find(|(key, _)| key == var) {
+        return value.clone();
+    }
+    env::var(var).unwrap_or_else(|_| {
+        debug!("[{}] ${} is not set, expanding to nothing", name, var);
+        String::new()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vars(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn arguments_expand_name_variables_and_escapes() {
+        let vars = vars(&[("DEST", "/sdcard"), ("TAG", "old"), ("TAG", "new")]);
+        let expand = |template: &str| expand(template, "backup", &vars);
+
+        assert_eq!(expand("run %n"), "run backup");
+        assert_eq!(expand("data $DEST/${TAG}.tar"), "data /sdcard/new.tar");
+        assert_eq!(expand("[$MICETIMER_TEST_UNSET_VAR]"), "[]");
+        assert_eq!(expand("price: $$5, 100%%"), "price: $5, 100%");
+        assert_eq!(expand("$PATH"), env::var("PATH").unwrap());
+        // Only plain names are variables; the rest is kept
+        assert_eq!(expand("${X:-d} ${1} ${open"), "${X:-d} ${1} ${open");
+        assert_eq!(expand("$1 $(date) %x"), "$1 $(date) %x");
+    }
+
+    #[test]
+    fn shell_lines_only_expand_specifiers() {
+        assert_eq!(
+            expand_specifiers("echo [${GREETING:-hello}] ['$HOME'] $$ %n 100%%", "greet"),
+            "echo [${GREETING:-hello}] ['$HOME'] $$ greet 100%"
+        );
+        assert_eq!(expand_specifiers("%x%", "greet"), "%x%");
+    }
+
+    #[test]
+    fn instance_expansion_leaves_other_specifiers() {
+        assert_eq!(
+            expand_instance("sync %i as %n", "acct1"),
+            "sync acct1 as %n"
+        );
+        assert_eq!(expand_instance("100%% of %i%", "acct2"), "100%% of acct2%");
+    }
+}
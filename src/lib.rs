@@ -0,0 +1,29 @@
+//! A systemd-style timer daemon for Android with WakeLock support
+//!
+//! Units are TOML files (see [`TimerUnit`]) loaded with [`load_timers`] and armed
+//! on a [`Scheduler`], whose event loop [`run`] drives until a shutdown signal.
+
+pub mod calendar;
+mod condition;
+mod control;
+mod exec;
+mod expand;
+mod metrics;
+mod report;
+mod scheduler;
+mod stamp;
+mod state;
+mod unit;
+mod wakelock;
+mod watch;
+mod watchdog;
+
+pub use control::send_request;
+pub use exec::{RunConfig, execute_timer};
+pub use scheduler::{FireDecision, Scheduler, Tick, next_elapse, run};
+pub use unit::{
+    BackoffConfig, Concurrency, Exec, IoClass, KillMode, NetworkProbe, OnMissed, OutputTarget,
+    SeedSource, ServiceType, SuccessStatus, TimerUnit, UnitFile, example_unit, load_timers,
+    scan_units,
+};
+pub use wakelock::{NoopWakeLock, SysfsWakeLock, WakeLock, WakeLocks};
@@ -0,0 +1,152 @@
+//! The `list` subcommand: every configured unit as a table or JSON, without a daemon
+
+use anyhow::Result;
+use chrono::Local;
+use clap::ValueEnum;
+use micetimer::{TimerUnit, next_elapse, scan_units};
+use serde::Serialize;
+use std::path::Path;
+use std::time::Duration;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Output {
+    /// Aligned columns for people
+    Text,
+    /// A JSON array of rows for scripts
+    Json,
+}
+
+/// One unit, or one file that failed to load
+#[derive(Debug, Serialize)]
+struct Row {
+    name: String,
+    /// `None` for invalid files
+    enabled: Option<bool>,
+    /// The unit's triggers, e.g. `OnBootSec=5m OnUnitActiveSec=6h`
+    schedule: String,
+    wake_lock: Option<bool>,
+    /// Seconds until the first elapse if the daemon started now
+    next_elapse_sec: Option<u64>,
+    /// Why the file couldn't be loaded
+    error: Option<String>,
+}
+
+fn schedule(unit: &TimerUnit) -> String {
+    let duration = |key: &str, value: Option<Duration>| {
+        value.map(|value| format!("{}={}", key, humantime::format_duration(value)))
+    };
+    [
+        duration("OnBootSec", unit.on_boot_sec),
+        duration("OnStartupSec", unit.on_startup_sec),
+        duration("OnUnitActiveSec", unit.on_unit_active_sec),
+        duration("OnUnitInactiveSec", unit.on_unit_inactive_sec),
+    ]
+    .into_iter()
+    .flatten()
+    .chain(
+        unit.on_calendar
+            .iter()
+            .map(|expr| format!("OnCalendar={}", expr)),
+    )
+    .collect::<Vec<_>>()
+    .join(" ")
+}
+
+fn rows(config_dir: &str, defaults: Option<&Path>) -> Result<Vec<Row>> {
+    let now = Local::now();
+    let mut rows = Vec::new();
+
+    for file in scan_units(config_dir, defaults)? {
+        let units = match file.units {
+            Ok(units) => units,
+            Err(e) => {
+                rows.push(Row {
+                    name: file
+                        .path
+                        .file_stem()
+                        .unwrap_or_default()
+                        .to_string_lossy()
+                        .into_owned(),
+                    enabled: None,
+                    schedule: String::new(),
+                    wake_lock: None,
+                    next_elapse_sec: None,
+                    error: Some(format!("{:#}", e)),
+                });
+                continue;
+            }
+        };
+
+        for (name, unit) in units {
+            rows.push(Row {
+                next_elapse_sec: unit
+                    .enabled
+                    .then(|| next_elapse(&unit, now))
+                    .flatten()
+                    .map(|delay| delay.as_secs()),
+                schedule: schedule(&unit),
+                enabled: Some(unit.enabled),
+                wake_lock: Some(unit.wake_lock),
+                error: None,
+                name,
+            });
+        }
+    }
+    rows.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(rows)
+}
+
+/// Prints every unit in `config_dir`; returns `false` if any file failed to load
+pub fn list(config_dir: &str, defaults: Option<&Path>, output: Output) -> Result<bool> {
+    let rows = rows(config_dir, defaults)?;
+    let valid = rows.iter().all(|row| row.error.is_none());
+
+    if output == Output::Json {
+        println!("{}", serde_json::to_string(&rows)?);
+        return Ok(valid);
+    }
+
+    let flag = |value: Option<bool>| match value {
+        Some(true) => "yes",
+        Some(false) => "no",
+        None => "-",
+    };
+    let table: Vec<[String; 5]> = rows
+        .iter()
+        .map(|row| {
+            [
+                row.name.clone(),
+                match row.enabled {
+                    None => "invalid".to_string(),
+                    enabled => flag(enabled).to_string(),
+                },
+                flag(row.wake_lock).to_string(),
+                row.next_elapse_sec.map_or("-".to_string(), |secs| {
+                    humantime::format_duration(Duration::from_secs(secs)).to_string()
+                }),
+                match &row.error {
+                    Some(error) => format!("error: {}", error),
+                    None => row.schedule.clone(),
+                },
+            ]
+        })
+        .collect();
+
+    let header = ["NAME", "ENABLED", "WAKELOCK", "NEXT", "SCHEDULE"].map(String::from);
+    let mut widths = header.clone().map(|column| column.len());
+    for row in &table {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    for row in std::iter::once(&header).chain(&table) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{:<width$}", cell, width = width))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+    Ok(valid)
+}
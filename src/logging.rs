@@ -0,0 +1,281 @@
+//! Logger setup: colored terminal text, one JSON object per line for log collectors,
+//! or Android's logcat
+
+use anyhow::{Context, Result, anyhow};
+use chrono::Local;
+use clap::ValueEnum;
+use log::kv::{self, VisitSource};
+use log::{Level, LevelFilter, Log, Metadata, Record, warn};
+use serde_json::{Map, Value};
+use std::env;
+use std::ffi::{CString, c_char, c_int};
+use std::io::{self, Write};
+
+/// Environment variables consulted for the level when `--log-level` isn't given, in order
+const LEVEL_VARS: [&str; 2] = ["MICETIMER_LOG", "RUST_LOG"];
+
+/// Tag of every logcat line, for `adb logcat -s micetimer`
+const LOGCAT_TAG: &std::ffi::CStr = c"micetimer";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogFormat {
+    /// Human-readable lines, colored on a terminal
+    Text,
+    /// `{timestamp, level, target, message, unit?}` objects, one per line
+    Json,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum LogTarget {
+    /// Standard error, in `--log-format`
+    Terminal,
+    /// Android's logcat under the `micetimer` tag; falls back to the terminal without liblog
+    Logcat,
+}
+
+/// Parses a standard level name (`off`, `error`, `warn`, `info`, `debug`, `trace`)
+pub fn parse_level(value: &str) -> Result<LevelFilter> {
+    value.trim().parse().map_err(|_| {
+        anyhow!(
+            "Unknown log level {:?}, expected off, error, warn, info, debug or trace",
+            value
+        )
+    })
+}
+
+/// The level named by the first set variable of `LEVEL_VARS`, if any
+pub fn level_from_env() -> Result<Option<LevelFilter>> {
+    for var in LEVEL_VARS {
+        if let Ok(value) = env::var(var) {
+            return parse_level(&value)
+                .with_context(|| format!("Invalid {}", var))
+                .map(Some);
+        }
+    }
+    Ok(None)
+}
+
+pub fn init(target: LogTarget, format: LogFormat, level: LevelFilter) -> Result<()> {
+    if target == LogTarget::Logcat {
+        match LogcatLogger::open(level) {
+            Some(logger) => {
+                log::set_boxed_logger(Box::new(logger))?;
+                log::set_max_level(level);
+                return Ok(());
+            }
+            None => {
+                init_terminal(format, level)?;
+                warn!("liblog is unavailable, logging to the terminal instead of logcat");
+                return Ok(());
+            }
+        }
+    }
+    init_terminal(format, level)
+}
+
+fn init_terminal(format: LogFormat, level: LevelFilter) -> Result<()> {
+    match format {
+        LogFormat::Text => simplelog::TermLogger::init(
+            level,
+            simplelog::Config::default(),
+            simplelog::TerminalMode::Mixed,
+            simplelog::ColorChoice::Auto,
+        )?,
+        LogFormat::Json => {
+            log::set_boxed_logger(Box::new(JsonLogger { level }))?;
+            log::set_max_level(level);
+        }
+    }
+    Ok(())
+}
+
+/// Writes each record to stderr as a single JSON object
+///
+/// Key-values attached to the record (e.g. `unit`) become fields of the object.
+struct JsonLogger {
+    level: LevelFilter,
+}
+
+impl Log for JsonLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = json_line(record);
+        // One write per record, so lines from concurrent workers don't interleave
+        let _ = io::stderr().lock().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = io::stderr().flush();
+    }
+}
+
+/// A record as the newline-terminated JSON object [`JsonLogger`] writes
+fn json_line(record: &Record) -> String {
+    let mut fields = Map::new();
+    fields.insert(
+        "timestamp".to_string(),
+        Local::now()
+            .to_rfc3339_opts(chrono::SecondsFormat::Millis, false)
+            .into(),
+    );
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut FieldCollector(&mut fields));
+
+    let mut line = Value::Object(fields).to_string();
+    line.push('\n');
+    line
+}
+
+struct FieldCollector<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> VisitSource<'kvs> for FieldCollector<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        self.0.insert(key.to_string(), value.to_string().into());
+        Ok(())
+    }
+}
+
+/// `__android_log_write(priority, tag, text)`
+type AndroidLogWrite = unsafe extern "C" fn(c_int, *const c_char, *const c_char) -> c_int;
+
+/// Writes each record to logcat through liblog, loaded at runtime so the binary
+/// starts (and falls back) where there is none
+struct LogcatLogger {
+    level: LevelFilter,
+    write: AndroidLogWrite,
+}
+
+impl LogcatLogger {
+    /// `None` if liblog or its `__android_log_write` can't be found
+    fn open(level: LevelFilter) -> Option<Self> {
+        // SAFETY: both names are NUL-terminated; the library is never unloaded, so the
+        // symbol stays valid for the life of the process
+        unsafe {
+            let handle = libc::dlopen(c"liblog.so".as_ptr(), libc::RTLD_NOW);
+            if handle.is_null() {
+                return None;
+            }
+            let symbol = libc::dlsym(handle, c"__android_log_write".as_ptr());
+            if symbol.is_null() {
+                return None;
+            }
+            Some(Self {
+                level,
+                write: std::mem::transmute::<*mut libc::c_void, AndroidLogWrite>(symbol),
+            })
+        }
+    }
+}
+
+/// The `android_LogPriority` logcat shows a record of `level` at
+fn android_priority(level: Level) -> c_int {
+    match level {
+        Level::Error => 6, // ANDROID_LOG_ERROR
+        Level::Warn => 5,  // ANDROID_LOG_WARN
+        Level::Info => 4,  // ANDROID_LOG_INFO
+        Level::Debug => 3, // ANDROID_LOG_DEBUG
+        Level::Trace => 2, // ANDROID_LOG_VERBOSE
+    }
+}
+
+impl Log for LogcatLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut text = record.args().to_string();
+        // All lines share the tag, so name the unit unless the message already does
+        if let Some(unit) = record.key_values().get(kv::Key::from("unit")) {
+            let unit = format!("[{}]", unit);
+            if !text.contains(&unit) {
+                text = format!("{} {}", unit, text);
+            }
+        }
+        let Ok(text) = CString::new(text.replace('\0', "")) else {
+            return;
+        };
+        // SAFETY: `write` is liblog's __android_log_write; both strings outlive the call
+        unsafe {
+            (self.write)(
+                android_priority(record.level()),
+                LOGCAT_TAG.as_ptr(),
+                text.as_ptr(),
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_line_holds_the_record_and_its_unit() {
+        let unit = [("unit", "backup")];
+        let line = json_line(
+            &Record::builder()
+                .level(Level::Warn)
+                .target("micetimer::exec")
+                .args(format_args!("Finished [{}]: Failed", "backup"))
+                .key_values(&unit)
+                .build(),
+        );
+        assert!(line.ends_with('\n') && !line.trim_end().contains('\n'));
+
+        let object: Map<String, Value> = serde_json::from_str(&line).unwrap();
+        let mut keys: Vec<&str> = object.keys().map(String::as_str).collect();
+        keys.sort_unstable();
+        assert_eq!(keys, ["level", "message", "target", "timestamp", "unit"]);
+        assert_eq!(object["level"], "WARN");
+        assert_eq!(object["target"], "micetimer::exec");
+        assert_eq!(object["message"], "Finished [backup]: Failed");
+        assert_eq!(object["unit"], "backup");
+        assert!(
+            chrono::DateTime::parse_from_rfc3339(object["timestamp"].as_str().unwrap()).is_ok()
+        );
+    }
+
+    #[test]
+    fn level_names_parse_into_filters() {
+        assert_eq!(parse_level("off").unwrap(), LevelFilter::Off);
+        assert_eq!(parse_level("error").unwrap(), LevelFilter::Error);
+        assert_eq!(parse_level("WARN").unwrap(), LevelFilter::Warn);
+        assert_eq!(parse_level(" info\n").unwrap(), LevelFilter::Info);
+        assert_eq!(parse_level("Debug").unwrap(), LevelFilter::Debug);
+        assert_eq!(parse_level("trace").unwrap(), LevelFilter::Trace);
+
+        let error = parse_level("loud").unwrap_err().to_string();
+        assert!(error.starts_with("Unknown log level \"loud\""), "{}", error);
+        assert!(parse_level("").is_err());
+    }
+
+    #[test]
+    fn levels_map_to_android_priorities() {
+        // ANDROID_LOG_VERBOSE (2) through ANDROID_LOG_ERROR (6)
+        let priorities = [
+            Level::Trace,
+            Level::Debug,
+            Level::Info,
+            Level::Warn,
+            Level::Error,
+        ]
+        .map(android_priority);
+        assert_eq!(priorities, [2, 3, 4, 5, 6]);
+    }
+}
@@ -1,164 +1,387 @@
-use anyhow::{Context, Result};
-use clap::Parser;
-use log::{debug, error, info};
-use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
-use nix::sys::time::TimeSpec;
-use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
-use serde::Deserialize;
-use std::fs;
-use std::os::unix::io::{AsFd, AsRawFd};
-use std::path::Path;
-use std::process::Command;
+mod daemon;
+mod list;
+mod logging;
+
+use anyhow::{Context, Result, bail};
+use chrono::Local;
+use clap::{Parser, Subcommand, ValueEnum};
+use log::{LevelFilter, error, info};
+use logging::{LogFormat, LogTarget};
+use micetimer::{
+    NoopWakeLock, RunConfig, Scheduler, SysfsWakeLock, TimerUnit, WakeLocks, example_unit,
+    execute_timer, load_timers, next_elapse, scan_units, send_request,
+};
+use std::io::Write;
+use std::num::NonZeroUsize;
+use std::path::{Path, PathBuf};
 use std::time::Duration;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
 struct Args {
-    /// Directory containing timer configurations
-    #[arg(short, long, default_value = "/data/adb/timers.d")]
+    /// Directory containing timer configurations, or a single unit file
+    #[arg(short, long, global = true, default_value = "/data/adb/timers.d")]
     config_dir: String,
 
+    /// Keys merged beneath every unit [default: defaults.toml beside the config directory]
+    #[arg(long, global = true)]
+    defaults: Option<String>,
+
     /// Run in foreground (don't daemonize) - useful for debugging
     #[arg(short, long)]
     foreground: bool,
-}
 
-/// Represents a single timer unit configuration (one file = one unit)
-#[derive(Debug, Deserialize, Clone)]
-#[serde(rename_all = "PascalCase")] // Match Systemd naming convention (e.g., Exec, OnBootSec)
-struct TimerUnit {
-    description: Option<String>,
+    /// Run every enabled unit once, in order, then exit instead of scheduling
+    #[arg(long, conflicts_with = "watch")]
+    once: bool,
 
-    /// Command to execute
-    exec: String,
+    /// Log each firing instead of running it; schedules and re-arming are unchanged
+    #[arg(long)]
+    dry_run: bool,
 
-    /// Active wait after boot
-    #[serde(default, with = "humantime_serde")]
-    on_boot_sec: Option<Duration>,
+    /// oom_score_adj for the daemon itself, e.g. -900 so it outlives its commands under
+    /// memory pressure; commands get 0 unless they set OOMScoreAdjust
+    #[arg(long, value_parser = clap::value_parser!(i32).range(-1000..=1000), allow_negative_numbers = true)]
+    oom_score_adjust: Option<i32>,
 
-    /// Repeat interval relative to the last activation
-    #[serde(default, with = "humantime_serde")]
-    on_unit_active_sec: Option<Duration>,
+    /// Most firings executing at once across all units; the rest wait for a free slot
+    #[arg(long)]
+    max_concurrent: Option<NonZeroUsize>,
 
-    /// Whether to hold a partial wakelock during execution
-    #[serde(default = "default_wakelock")]
-    wake_lock: bool,
+    /// Reload automatically when files in the configuration directory change
+    #[arg(short, long)]
+    watch: bool,
+
+    /// Hold back first elapses until this long after boot (e.g. 2min), so startup isn't
+    /// slowed down; units with a later OnBootSec are unaffected
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "0s")]
+    boot_grace_sec: Duration,
+
+    /// Shortest OnUnitActiveSec/OnUnitInactiveSec armed; shorter ones are raised to it
+    /// with a warning. Lower it (e.g. 100ms) only if you really want sub-second timers
+    #[arg(long, value_parser = humantime::parse_duration, default_value = "1s")]
+    min_interval_sec: Duration,
+
+    /// Exit once no timer is scheduled, e.g. after a reload removed the last one
+    #[arg(long)]
+    exit_when_empty: bool,
+
+    /// Where to record the daemon's PID for init scripts
+    #[arg(
+        long,
+        global = true,
+        default_value = "/data/adb/micetimer/micetimer.pid"
+    )]
+    pid_file: String,
+
+    /// Unix socket accepting LIST/STATUS/TRIGGER requests
+    #[arg(
+        long,
+        global = true,
+        default_value = "/data/adb/micetimer/control.sock"
+    )]
+    control_socket: String,
+
+    /// Log level: off, error, warn, info, debug or trace [default: $MICETIMER_LOG, $RUST_LOG or info]
+    #[arg(long, global = true, value_parser = logging::parse_level)]
+    log_level: Option<LevelFilter>,
+
+    /// Log at least at debug level, whatever the configured level
+    #[arg(short, long, global = true)]
+    verbose: bool,
+
+    /// Log output: colored text, or one JSON object per line
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    log_format: LogFormat,
+
+    /// Where logs go: the terminal, or logcat (`adb logcat -s micetimer`)
+    #[arg(long, global = true, value_enum, default_value_t = LogTarget::Terminal)]
+    log_target: LogTarget,
+
+    /// Log only the last this many bytes of each output stream of a firing with
+    /// StandardOutput=Log, once it ends, instead of every line as it comes
+    #[arg(long, global = true)]
+    max_capture_bytes: Option<NonZeroUsize>,
+
+    /// How wakelocks are held; auto uses sysfs if the kernel has it, and none otherwise
+    #[arg(long, global = true, value_enum, default_value_t = WakeLockBackend::Auto)]
+    wakelock_backend: WakeLockBackend,
+
+    /// Prefix of the daemon's wakelock names; locks with it that are still held at
+    /// startup were leaked by a previous instance and get released
+    #[arg(long, global = true, value_parser = parse_wakelock_prefix, default_value = "micetimer:")]
+    wakelock_prefix: String,
+
+    /// Abort if the event loop stalls this long (e.g. 60s), for a supervisor to restart
+    #[arg(long, value_parser = parse_watchdog)]
+    watchdog_sec: Option<Duration>,
+
+    /// Where SIGUSR1 writes a JSON snapshot of every unit's status
+    #[arg(long, default_value = "/data/adb/micetimer/metrics.json")]
+    metrics_file: String,
+
+    /// Where the daemon saves each unit's next elapse, to resume schedules after a restart;
+    /// `Persistent` units keep their last-run stamps in `state/` beside it
+    #[arg(long, default_value = "/data/adb/micetimer/scheduler-state.json")]
+    state_file: String,
+
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-/// Active timer runtime state
-struct RuntimeTimer {
-    name: String,
-    unit: TimerUnit,
-    tfd: TimerFd,
+impl Args {
+    /// How firings run, inline or in the daemon
+    fn run_config(&self) -> RunConfig {
+        let mut wakelocks = match self.wakelock_backend {
+            WakeLockBackend::Auto => WakeLocks::default(),
+            WakeLockBackend::Sysfs => WakeLocks::new(SysfsWakeLock::default()),
+            WakeLockBackend::None => WakeLocks::new(NoopWakeLock),
+        };
+        wakelocks.set_prefix(&self.wakelock_prefix);
+
+        let mut config = RunConfig::default();
+        config.set_dry_run(self.dry_run);
+        config.set_max_capture_bytes(self.max_capture_bytes);
+        config.set_wakelocks(wakelocks);
+        let state_dir = Path::new(&self.state_file)
+            .parent()
+            .unwrap_or(Path::new(""));
+        config.set_stamp_dir(state_dir.join("state"));
+        config
+    }
+
+    fn defaults_path(&self) -> Option<&Path> {
+        self.defaults.as_deref().map(Path::new)
+    }
 }
 
-fn default_wakelock() -> bool {
-    true
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum WakeLockBackend {
+    Auto,
+    /// /sys/power/wake_lock and wake_unlock
+    Sysfs,
+    /// Don't hold wakelocks
+    None,
 }
 
-/// Scans the configuration directory for .toml files
-fn load_timers<P: AsRef<Path>>(dir: P) -> Result<Vec<(String, TimerUnit)>> {
-    let mut timers = Vec::new();
-    let path_ref = dir.as_ref();
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Validate all configurations and print when each timer would first fire
+    Check,
+    /// Print every configured unit, including disabled and invalid ones
+    List {
+        /// Output as an aligned table or as a JSON array
+        #[arg(long, value_enum, default_value_t = list::Output::Text)]
+        output: list::Output,
+    },
+    /// Run one unit now: through the running daemon, or inline if none is running
+    Trigger {
+        /// Name of the unit to run
+        name: String,
+    },
+    /// Stop every timer of the running daemon, keeping the time each has left
+    Pause,
+    /// Re-arm the timers of a paused daemon; elapses missed while paused are skipped
+    Resume,
+    /// Change a unit's interval in the running daemon until its next reload
+    Set {
+        /// Name of the unit to change
+        name: String,
+        /// `OnUnitActiveSec=<duration>` or `OnUnitInactiveSec=<duration>`
+        assignment: String,
+    },
+    /// Print an example unit file documenting every key
+    Generate {
+        /// Write it to this new file instead of stdout
+        path: Option<PathBuf>,
+    },
+}
 
-    if !path_ref.exists() {
-        // Just return empty if dir doesn't exist yet
-        return Ok(timers);
+/// Parses `--watchdog-sec`: a duration such as `60s` or `2min`, of at least a second
+fn parse_watchdog(value: &str) -> Result<Duration> {
+    let timeout = humantime::parse_duration(value)?;
+    if timeout < Duration::from_secs(1) {
+        anyhow::bail!("The watchdog timeout must be at least 1s");
     }
+    Ok(timeout)
+}
 
-    for entry in fs::read_dir(path_ref)? {
-        let entry = entry?;
-        let path = entry.path();
+fn parse_wakelock_prefix(value: &str) -> Result<String> {
+    if value.is_empty() || value.contains(char::is_whitespace) {
+        bail!("The wakelock prefix must be non-empty and without whitespace");
+    }
+    Ok(value.to_string())
+}
 
-        // Only process .toml files
-        if path.extension().and_then(|s| s.to_str()) == Some("toml") {
-            let name = path.file_stem().unwrap().to_string_lossy().into_owned();
-            let content = fs::read_to_string(&path)?;
+/// Validates every unit in `config_dir` and prints its next elapse
+///
+/// Every broken file is reported, followed by how many there were. Returns `false`
+/// if any file failed to parse.
+fn check(config_dir: &str, defaults: Option<&Path>) -> Result<bool> {
+    let now = Local::now();
+    let files = scan_units(config_dir, defaults)?;
+    let total = files.len();
+    let mut invalid = 0;
+
+    for file in files {
+        let units = match file.units {
+            Ok(units) => units,
+            Err(e) => {
+                invalid += 1;
+                eprintln!("{}: {:#}", file.path.display(), e);
+                continue;
+            }
+        };
 
-            // Parse with context for better error messages
-            let unit: TimerUnit = toml::from_str(&content)
-                .with_context(|| format!("Failed to parse configuration: {:?}", path))?;
+        for (name, unit) in units {
+            if !unit.enabled {
+                println!("{}: disabled", name);
+                continue;
+            }
 
-            timers.push((name, unit));
+            match next_elapse(&unit, now) {
+                Some(delay) => {
+                    let at = now + chrono::Duration::from_std(delay)?;
+                    println!(
+                        "{}: next elapse in {} (at {})",
+                        name,
+                        humantime::format_duration(Duration::from_secs(delay.as_secs())),
+                        at.format("%Y-%m-%d %H:%M:%S")
+                    );
+                }
+                None => println!("{}: no upcoming occurrence", name),
+            }
         }
     }
-    Ok(timers)
-}
 
-fn execute_timer(timer: &RuntimeTimer) {
-    if let Some(desc) = &timer.unit.description {
-        info!("Timer [{}]: {}", timer.name, desc);
+    if invalid > 0 {
+        eprintln!("{} of {} configuration file(s) are invalid", invalid, total);
     }
-    info!("Executing [{}]: {}", timer.name, timer.unit.exec);
+    Ok(invalid == 0)
+}
 
-    let lock_name = format!("micetimer:{}", timer.name);
+/// Runs unit `name` now, via the daemon's control socket if it's running, inline otherwise
+///
+/// Returns whether the daemon accepted the trigger, or whether the inline run succeeded.
+fn trigger(args: &Args, name: &str) -> Result<bool> {
+    if daemon::running_pid(&args.pid_file).is_some() {
+        let response = send_request(&args.control_socket, &format!("TRIGGER {}", name))
+            .with_context(|| format!("Failed to reach the daemon at {}", args.control_socket))?;
+        print!("{}", response);
+        return Ok(!response.starts_with("ERR"));
+    }
 
-    let mut use_wakelock = false;
+    let Some((name, unit)) = load_timers(&args.config_dir, args.defaults_path())?
+        .into_iter()
+        .find(|(unit_name, _)| unit_name == name)
+    else {
+        bail!("No valid unit named {:?} in {}", name, args.config_dir);
+    };
+    if !unit.enabled {
+        bail!("[{}] is disabled", name);
+    }
 
-    // Acquire Android WakeLock
+    // A run skipped by a condition didn't fail
+    Ok(execute_timer(&args.run_config(), &name, &unit).unwrap_or(true))
+}
 
-    if timer.unit.wake_lock {
-        match fs::write("/sys/power/wake_lock", &lock_name) {
-            Ok(_) => {
-                debug!("Acquired WakeLock: {}", lock_name);
+/// Sends `request` (e.g. `PAUSE`) to the running daemon and prints its answer
+fn send_to_daemon(args: &Args, request: &str) -> Result<()> {
+    let response = send_request(&args.control_socket, request)
+        .with_context(|| format!("Failed to reach the daemon at {}", args.control_socket))?;
+    print!("{}", response);
+    Ok(())
+}
 
-                use_wakelock = true;
-            }
+/// Writes [`example_unit`] to `path`, refusing to overwrite an existing file, or to stdout
+fn generate(path: Option<&Path>) -> Result<()> {
+    let example = example_unit();
+    let Some(path) = path else {
+        print!("{}", example);
+        return Ok(());
+    };
+    let mut file = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .with_context(|| format!("Failed to create {:?}", path))?;
+    file.write_all(example.as_bytes())?;
+    Ok(())
+}
 
-            Err(e) => error!("Failed to acquire WakeLock {}: {}", lock_name, e),
+/// Runs every enabled unit once, one after the other
+///
+/// Returns `false` if any run failed; runs skipped by a condition don't count as failures.
+fn run_once(config: &RunConfig, units: Vec<(String, TimerUnit)>) -> bool {
+    let mut ok = true;
+    for (name, unit) in units {
+        if !unit.enabled {
+            info!("[{}] disabled, not running", unit.tag(&name));
+            continue;
+        }
+        if execute_timer(config, &name, &unit) == Some(false) {
+            ok = false;
         }
     }
+    ok
+}
 
-    let status = Command::new("sh").arg("-c").arg(&timer.unit.exec).status();
-
-    match status {
-        Ok(s) => {
-            if s.success() {
-                info!("Finished [{}]: Success", timer.name);
-            } else {
-                error!(
-                    "Finished [{}]: Failed with exit code {:?}",
-                    timer.name,
-                    s.code()
-                );
-            }
-        }
+fn main() -> Result<()> {
+    let mut args = Args::parse();
 
-        Err(e) => {
-            error!("Finished [{}]: Error executing command: {}", timer.name, e);
-        }
+    // Initialize logger
+    let mut level = match args.log_level {
+        Some(level) => level,
+        None => logging::level_from_env()?.unwrap_or(LevelFilter::Info),
+    };
+    if args.verbose {
+        level = level.max(LevelFilter::Debug);
     }
+    logging::init(args.log_target, args.log_format, level)?;
 
-    // Release Android WakeLock
-
-    if use_wakelock {
-        if let Err(e) = fs::write("/sys/power/wake_unlock", &lock_name) {
-            error!("Failed to release WakeLock {}: {}", lock_name, e);
-        } else {
-            debug!("Released WakeLock: {}", lock_name);
+    match &args.command {
+        Some(Command::Check) => {
+            let valid = check(&args.config_dir, args.defaults_path())?;
+            std::process::exit(if valid { 0 } else { 1 });
+        }
+        Some(Command::List { output }) => {
+            let valid = list::list(&args.config_dir, args.defaults_path(), *output)?;
+            std::process::exit(if valid { 0 } else { 1 });
         }
+        Some(Command::Trigger { name }) => {
+            let ok = trigger(&args, name)?;
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::Pause) => return send_to_daemon(&args, "PAUSE"),
+        Some(Command::Resume) => return send_to_daemon(&args, "RESUME"),
+        Some(Command::Set { name, assignment }) => {
+            return send_to_daemon(&args, &format!("SET {} {}", name, assignment));
+        }
+        Some(Command::Generate { path }) => return generate(path.as_deref()),
+        None => {}
     }
-}
 
-fn main() -> Result<()> {
-    // Initialize logger
-    simplelog::TermLogger::init(
-        simplelog::LevelFilter::Debug,
-        simplelog::Config::default(),
-        simplelog::TerminalMode::Mixed,
-        simplelog::ColorChoice::Auto,
-    )
-    .unwrap();
-
-    let args = Args::parse();
+    if args.once {
+        let ok = run_once(
+            &args.run_config(),
+            load_timers(&args.config_dir, args.defaults_path())?,
+        );
+        std::process::exit(if ok { 0 } else { 1 });
+    }
 
     info!("MiceTimer Daemon starting...");
     info!("Configuration directory: {}", args.config_dir);
 
+    if let Some(pid) = daemon::running_pid(&args.pid_file) {
+        error!("Already running as PID {}", pid);
+        std::process::exit(1);
+    }
+
     // Load timer definitions
-    let timer_units = load_timers(&args.config_dir)?;
+    let timer_units = load_timers(&args.config_dir, args.defaults_path())?;
 
-    if timer_units.is_empty() {
+    // When watching, units added later are picked up, so an empty directory is fine
+    if timer_units.is_empty() && !args.watch {
         info!("No timer configurations found in {}", args.config_dir);
         return Ok(());
     }
@@ -168,63 +391,87 @@ fn main() -> Result<()> {
         timer_units.len()
     );
 
-    let epoll = Epoll::new(EpollCreateFlags::empty())?;
-    let mut active_timers = std::collections::HashMap::new();
+    // Fork into the background unless asked to stay attached; reloads happen after
+    // chdir("/"), so the config dir must not stay relative
+    let detached = if args.foreground {
+        None
+    } else {
+        args.config_dir = std::path::absolute(&args.config_dir)?
+            .to_string_lossy()
+            .into_owned();
+        args.pid_file = std::path::absolute(&args.pid_file)?
+            .to_string_lossy()
+            .into_owned();
+        args.control_socket = std::path::absolute(&args.control_socket)?
+            .to_string_lossy()
+            .into_owned();
+        args.metrics_file = std::path::absolute(&args.metrics_file)?
+            .to_string_lossy()
+            .into_owned();
+        args.state_file = std::path::absolute(&args.state_file)?
+            .to_string_lossy()
+            .into_owned();
+        if let Some(defaults) = &args.defaults {
+            args.defaults = Some(
+                std::path::absolute(defaults)?
+                    .to_string_lossy()
+                    .into_owned(),
+            );
+        }
+        Some(daemon::daemonize()?)
+    };
 
+    if let Err(e) = daemon::write_pid_file(&args.pid_file) {
+        error!("{:#}", e);
+    }
+    let mut run_config = args.run_config();
+    if let Some(adjust) = args.oom_score_adjust
+        && let Err(e) = run_config.adjust_daemon_oom_score(adjust)
+    {
+        error!("{:#}", e);
+    }
+    // We're the only instance now, so no live daemon holds these
+    run_config.wakelocks().reclaim_stale();
+
+    let mut scheduler = Scheduler::new()?;
+    scheduler.set_run_config(run_config);
+    scheduler.set_config_dir(&args.config_dir);
+    if let Some(defaults) = args.defaults_path() {
+        scheduler.set_defaults_file(defaults);
+    }
+    scheduler.set_metrics_file(&args.metrics_file);
+    scheduler.set_max_concurrent(args.max_concurrent);
+    scheduler.set_exit_when_empty(args.exit_when_empty);
+    scheduler.set_boot_grace(args.boot_grace_sec);
+    scheduler.set_min_interval(args.min_interval_sec);
+    // Signals are blocked before any worker thread exists, so every thread inherits the
+    // mask; that includes the watchdog's
+    scheduler.handle_signals()?;
+    if let Some(timeout) = args.watchdog_sec {
+        scheduler.set_watchdog(timeout)?;
+    }
+    if let Err(e) = scheduler.listen_control(&args.control_socket) {
+        error!("{:#}", e);
+    }
+    if args.watch
+        && let Err(e) = scheduler.watch_config_dir()
+    {
+        error!("{:#}", e);
+    }
+    if let Err(e) = scheduler.set_state_file(&args.state_file) {
+        error!("{:#}", e);
+    }
     for (name, unit) in timer_units {
-        // Create TimerFd with CLOCK_BOOTTIME (crucial for Android/deep sleep)
-        let tfd = TimerFd::new(
-            ClockId::CLOCK_BOOTTIME,
-            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
-        )?;
-
-        let initial_delay = unit.on_boot_sec.unwrap_or(Duration::from_secs(1));
-
-        // We use OneShot and re-arm manually to have full control and potentially different interval logic
-        tfd.set(
-            Expiration::OneShot(TimeSpec::from(initial_delay)),
-            TimerSetTimeFlags::empty(),
-        )?;
-
-        let fd = tfd.as_fd().as_raw_fd();
-        let event = EpollEvent::new(EpollFlags::EPOLLIN, fd as u64);
-        epoll.add(&tfd, event)?;
-
-        active_timers.insert(fd, RuntimeTimer { name, unit, tfd });
-    }
-
-    info!("Event loop started. Waiting for triggers...");
-
-    let mut events = [EpollEvent::empty(); 16];
-    loop {
-        match epoll.wait(&mut events, -1) {
-            Ok(num_events) => {
-                for i in 0..num_events {
-                    let fd = events[i].data() as i32;
-                    if let Some(timer) = active_timers.get_mut(&fd) {
-                        // Read from timerfd to clear the trigger
-                        let _ = timer.tfd.wait();
-
-                        // Execute the command
-                        execute_timer(timer);
-
-                        // Re-arm if it's a repeating timer
-                        if let Some(interval) = timer.unit.on_unit_active_sec {
-                            if interval > Duration::ZERO {
-                                debug!("Re-arming [{}] for {:?}", timer.name, interval);
-                                if let Err(e) = timer.tfd.set(
-                                    Expiration::OneShot(TimeSpec::from(interval)),
-                                    TimerSetTimeFlags::empty(),
-                                ) {
-                                    error!("Failed to re-arm [{}]: {}", timer.name, e);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-            Err(e) if e == nix::Error::EINTR => continue,
-            Err(e) => return Err(e.into()),
-        }
+        scheduler.add_timer(name, unit)?;
     }
+
+    if let Some(detached) = detached {
+        detached.ready()?;
+    }
+
+    let result = micetimer::run(scheduler);
+
+    daemon::remove_pid_file(&args.pid_file);
+    info!("MiceTimer Daemon stopped");
+    result
 }
@@ -0,0 +1,63 @@
+//! Per-unit execution counters, reported by `STATUS` and the SIGUSR1 snapshot
+
+use crate::exec::RunOutcome;
+use std::time::{Duration, SystemTime};
+
+/// Why a firing didn't run
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum SkipReason {
+    /// A `Condition*` check or `ExecCondition` command didn't pass
+    Condition,
+    /// The previous run was still active (`Concurrency = "Skip"`)
+    StillRunning,
+    /// The start limit refused it
+    RateLimited,
+    /// A `Requires` unit isn't scheduled or failed its last run
+    Requirement,
+    /// The daemon runs with `--dry-run`
+    DryRun,
+}
+
+impl SkipReason {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            SkipReason::Condition => "condition",
+            SkipReason::StillRunning => "still-running",
+            SkipReason::RateLimited => "rate-limited",
+            SkipReason::Requirement => "requirement",
+            SkipReason::DryRun => "dry-run",
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub(crate) struct Metrics {
+    /// Completed runs; runs skipped by a condition aren't counted
+    pub runs: u64,
+    pub successes: u64,
+    pub failures: u64,
+    pub last_exit_code: Option<i32>,
+    pub last_duration: Option<Duration>,
+    /// When the last completed run finished, and whether it succeeded
+    pub last_run: Option<(SystemTime, bool)>,
+    /// When a firing was last skipped, and why
+    pub last_skip: Option<(SystemTime, SkipReason)>,
+}
+
+impl Metrics {
+    pub(crate) fn record(&mut self, outcome: RunOutcome, finished: SystemTime) {
+        self.runs += 1;
+        if outcome.success {
+            self.successes += 1;
+        } else {
+            self.failures += 1;
+        }
+        self.last_exit_code = outcome.exit_code;
+        self.last_duration = Some(outcome.duration);
+        self.last_run = Some((finished, outcome.success));
+    }
+
+    pub(crate) fn record_skip(&mut self, reason: SkipReason, at: SystemTime) {
+        self.last_skip = Some((at, reason));
+    }
+}
@@ -0,0 +1,124 @@
+//! Per-firing result files (`ResultDir`), a stable place for other tooling to poll
+
+use crate::exec::RunOutcome;
+use crate::metrics::SkipReason;
+use crate::state::to_millis;
+use crate::unit::TimerUnit;
+use anyhow::Result;
+use log::error;
+use serde::Serialize;
+use std::fs;
+use std::path::Path;
+use std::time::SystemTime;
+
+/// Contents of `<ResultDir>/<unit>.json`, replaced by every firing
+#[derive(Debug, Serialize)]
+struct FiringResult<'a> {
+    unit: &'a str,
+    /// Wall-clock times of the firing, in milliseconds since the Unix epoch
+    start_ms: u64,
+    end_ms: u64,
+    /// `None` if the firing was skipped
+    success: Option<bool>,
+    /// Exit code of the last main command attempt, as in [`RunOutcome`]
+    exit_code: Option<i32>,
+    /// Why the firing didn't run, e.g. `"condition"`
+    skipped: Option<&'static str>,
+}
+
+/// Writes the result file of one firing, replacing it atomically
+fn write_result(
+    dir: &Path,
+    name: &str,
+    started: SystemTime,
+    result: &Result<RunOutcome, SkipReason>,
+) -> Result<()> {
+    let (success, exit_code, skipped) = match result {
+        Ok(outcome) => (Some(outcome.success), outcome.exit_code, None),
+        Err(reason) => (None, None, Some(reason.as_str())),
+    };
+    let content = serde_json::to_vec_pretty(&FiringResult {
+        unit: name,
+        start_ms: to_millis(started),
+        end_ms: to_millis(SystemTime::now()),
+        success,
+        exit_code,
+        skipped,
+    })?;
+
+    fs::create_dir_all(dir)?;
+    let path = dir.join(format!("{}.json", name));
+    let tmp = dir.join(format!(".{}.json.tmp", name));
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
+
+/// Records a firing that started at `started` in the unit's `ResultDir`, if it has one
+///
+/// A failed write is logged and leaves the firing alone.
+pub(crate) fn report_result(
+    name: &str,
+    unit: &TimerUnit,
+    started: SystemTime,
+    result: &Result<RunOutcome, SkipReason>,
+) {
+    if let Some(dir) = &unit.result_dir
+        && let Err(e) = write_result(dir, name, started, result)
+    {
+        error!(unit = unit.tag(name); "Failed to write result file of [{}] to {:?}: {}", unit.tag(name), dir, e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::env;
+    use std::time::Duration;
+
+    #[test]
+    fn result_file_matches_the_firing_outcome() {
+        let dir = env::temp_dir().join(format!("micetimer-results-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let unit: TimerUnit = format!(
+            "Exec = \"true\"\nOnBootSec = \"1h\"\nResultDir = {:?}",
+            dir.join("nested")
+        )
+        .parse()
+        .unwrap();
+        let read = || -> Value {
+            let content = fs::read(dir.join("nested/backup.json")).unwrap();
+            serde_json::from_slice(&content).unwrap()
+        };
+
+        let started = SystemTime::now() - Duration::from_secs(2);
+        let failed = RunOutcome {
+            success: false,
+            exit_code: Some(3),
+            duration: Duration::from_secs(2),
+        };
+        report_result("backup", &unit, started, &Ok(failed));
+        let result = read();
+        assert_eq!(result["unit"], "backup");
+        assert_eq!(result["success"], false);
+        assert_eq!(result["exit_code"], 3);
+        assert_eq!(result["skipped"], Value::Null);
+        assert_eq!(result["start_ms"], to_millis(started));
+        assert!(result["end_ms"].as_u64().unwrap() >= to_millis(started) + 2000);
+
+        // The next firing replaces it, leaving no temporary file behind
+        report_result(
+            "backup",
+            &unit,
+            SystemTime::now(),
+            &Err(SkipReason::Condition),
+        );
+        let result = read();
+        assert_eq!(result["success"], Value::Null);
+        assert_eq!(result["exit_code"], Value::Null);
+        assert_eq!(result["skipped"], "condition");
+        assert_eq!(fs::read_dir(dir.join("nested")).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
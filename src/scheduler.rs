@@ -0,0 +1,3751 @@
+//! The timer engine: timerfds armed on each unit's clock, multiplexed through epoll
+
+use crate::calendar::{self, CalendarSpec};
+use crate::control::{self, ControlSocket, IntervalKey, Request, Response, UnitStatus};
+use crate::exec::{RunConfig, kill_cgroup, run_unit};
+use crate::metrics::{Metrics, SkipReason};
+use crate::report::report_result;
+use crate::stamp::read_stamp;
+use crate::state::{
+    self, STATE_VERSION, SavedRun, SavedState, SavedUnit, fingerprint, from_millis, to_millis,
+};
+use crate::unit::{ClockSource, Concurrency, OnMissed, SeedSource, TimerUnit, load_timers};
+use crate::watch::ConfigWatch;
+use crate::watchdog::Watchdog;
+use anyhow::{Context, Result, anyhow, bail};
+use chrono::{DateTime, Local};
+use log::{debug, error, info, warn};
+use nix::errno::Errno;
+use nix::sys::epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags};
+use nix::sys::eventfd::{EfdFlags, eventfd};
+use nix::sys::signal::{SigSet, Signal};
+use nix::sys::signalfd::{SfdFlags, SignalFd};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::io::{self, Write};
+use std::mem;
+use std::num::NonZeroUsize;
+use std::os::unix::io::{AsFd, AsRawFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, LockResult, Mutex, MutexGuard, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How long shutdown waits for running commands before releasing their wakelocks
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// How often the scheduler state is saved, besides on shutdown
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(300);
+
+/// Epoll tokens of the signalfd, control socket, config watch, state saving and worker
+/// re-arms; timers and shared wakeups get increasing tokens starting at `FIRST_TIMER_TOKEN`
+const SIGNAL_TOKEN: u64 = 0;
+const CONTROL_TOKEN: u64 = 1;
+const WATCH_TOKEN: u64 = 2;
+const RELOAD_TOKEN: u64 = 3;
+const STATE_TOKEN: u64 = 4;
+const REARM_TOKEN: u64 = 5;
+const FIRST_TIMER_TOKEN: u64 = 6;
+
+/// Active timer runtime state
+struct RuntimeTimer {
+    name: String,
+    unit: TimerUnit,
+    /// Shared with workers, which re-arm `OnUnitInactiveSec` units once a run finishes
+    tfd: Arc<TimerFd>,
+    /// Clock the timerfd runs on; absolute instants below are on this clock
+    clock: ClockId,
+    /// Parsed `OnCalendar` entries; empty for other units
+    calendar: Vec<CalendarSpec>,
+    /// Absolute grid point for manually re-armed interval timers
+    next_base: Option<TimeSpec>,
+    /// Number of times this timer has been armed, mixed into the jitter seed
+    armings: u64,
+    /// Run state shared with the unit's worker threads
+    status: Arc<SharedRunState>,
+    /// Token and instant of the shared wakeup the timer waits on while its own
+    /// timerfd is disarmed, see [`Scheduler::group_wakeups`]
+    wakeup: Option<(u64, TimeSpec)>,
+}
+
+impl RuntimeTimer {
+    /// Time left until the next expiration, on the timer's own timerfd or its wakeup
+    fn time_left(&self) -> Option<Duration> {
+        let Some((_, at)) = self.wakeup else {
+            return time_left(&self.tfd);
+        };
+        let now = clock_now(self.clock).ok()?;
+        Some(match at > now {
+            true => Duration::from(at - now),
+            false => Duration::ZERO,
+        })
+    }
+
+    /// The name log lines call the unit by, see [`TimerUnit::tag`]
+    fn tag(&self) -> &str {
+        self.unit.tag(&self.name)
+    }
+}
+
+/// Per-unit execution state, updated by the loop and by workers
+#[derive(Debug, Default)]
+struct RunState {
+    /// Number of firings currently executing
+    running: usize,
+    /// A firing arrived during a run and should execute once it finishes
+    queued: bool,
+    /// Counters and results of completed runs
+    metrics: Metrics,
+    /// Start times within the current `StartLimitIntervalSec` window
+    starts: VecDeque<Instant>,
+    /// Firings are being refused by the start limit
+    rate_limited: bool,
+    /// Repeat interval grown by `FailureBackoff` after consecutive failures
+    backoff: Option<Duration>,
+    /// The armed elapse was pushed out by `FailureBackoff`, off the unit's grid
+    backed_off: bool,
+}
+
+/// [`RunState`] behind a lock, with a condition variable signalled whenever a run of
+/// the unit finishes, for workers waiting on it as an `After` dependency
+#[derive(Debug, Default)]
+struct SharedRunState {
+    state: Mutex<RunState>,
+    finished: Condvar,
+}
+
+impl SharedRunState {
+    fn lock(&self) -> LockResult<MutexGuard<'_, RunState>> {
+        self.state.lock()
+    }
+
+    /// Wakes the workers waiting for the unit to finish; call after lowering `running`
+    fn notify_finished(&self) {
+        self.finished.notify_all();
+    }
+
+    /// Blocks until no run of the unit is executing
+    fn wait_idle(&self, tag: &str, name: &str) {
+        let mut state = self.lock().unwrap();
+        if state.running > 0 {
+            debug!(unit = tag; "[{}] waiting for [{}] to finish", tag, name);
+            while state.running > 0 {
+                state = self.finished.wait(state).unwrap();
+            }
+        }
+    }
+}
+
+/// Caps how many firings execute at once across all units (`--max-concurrent`)
+#[derive(Default)]
+struct FiringSlots {
+    /// `None` for no cap
+    limit: Option<NonZeroUsize>,
+    taken: Mutex<usize>,
+    freed: Condvar,
+}
+
+impl FiringSlots {
+    fn new(limit: Option<NonZeroUsize>) -> Self {
+        Self {
+            limit,
+            ..Self::default()
+        }
+    }
+
+    /// Blocks until a slot is free; it is given back when the guard drops
+    fn acquire(&self, tag: &str) -> SlotGuard<'_> {
+        let Some(limit) = self.limit else {
+            return SlotGuard(None);
+        };
+
+        let mut taken = self.taken.lock().unwrap();
+        if *taken >= limit.get() {
+            info!(
+                unit = tag;
+                "[{}] queued: {} firing(s) already running (--max-concurrent)",
+                tag, taken
+            );
+            while *taken >= limit.get() {
+                taken = self.freed.wait(taken).unwrap();
+            }
+        }
+        *taken += 1;
+        SlotGuard(Some(self))
+    }
+}
+
+struct SlotGuard<'a>(Option<&'a FiringSlots>);
+
+impl Drop for SlotGuard<'_> {
+    fn drop(&mut self) {
+        if let Some(slots) = self.0 {
+            *slots.taken.lock().unwrap() -= 1;
+            slots.freed.notify_one();
+        }
+    }
+}
+
+/// Run states of the units a firing's `After` and `Requires` name, resolved at dispatch
+#[derive(Default)]
+struct Dependencies {
+    after: Vec<(String, Arc<SharedRunState>)>,
+    /// `None` for units that aren't scheduled
+    requires: Vec<(String, Option<Arc<SharedRunState>>)>,
+}
+
+impl Dependencies {
+    /// Waits until no `After` unit is running, then checks `Requires`
+    ///
+    /// Returns whether the run may start; a skip is logged with its reason, under `tag`.
+    fn wait(&self, tag: &str) -> bool {
+        for (after, status) in &self.after {
+            status.wait_idle(tag, after);
+        }
+
+        for (required, status) in &self.requires {
+            let reason = match status {
+                None => "isn't scheduled",
+                Some(status) => match status.lock().unwrap().metrics.last_run {
+                    Some((_, false)) => "failed its last run",
+                    _ => continue,
+                },
+            };
+            info!(unit = tag; "[{}] required unit [{}] {}, skipping", tag, required, reason);
+            return false;
+        }
+        true
+    }
+}
+
+/// Reads the number of expirations since the last read, clearing the trigger
+fn read_expirations(tfd: &TimerFd) -> nix::Result<u64> {
+    let mut buf = [0u8; 8];
+    loop {
+        match nix::unistd::read(tfd.as_fd().as_raw_fd(), &mut buf) {
+            Ok(_) => return Ok(u64::from_ne_bytes(buf)),
+            Err(nix::Error::EINTR) => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+/// Decides how many times to execute a unit for `expirations` accumulated triggers
+fn runs_for_expirations(timer: &RuntimeTimer, expirations: u64) -> u64 {
+    match timer.unit.on_missed {
+        OnMissed::Coalesce => {
+            if expirations > 1 {
+                debug!(
+                    "[{}] coalesced {} missed expirations",
+                    timer.tag(),
+                    expirations - 1
+                );
+            }
+            1
+        }
+        OnMissed::RunEach => expirations,
+    }
+}
+
+/// Smallest arming delay; a zero delay would disarm the timer instead of firing it
+const ASAP: Duration = Duration::from_nanos(1);
+
+/// Rounds an absolute instant up to the next multiple of `accuracy`
+///
+/// All timers share the same boundaries, so units due within one window wake the
+/// CPU once instead of one after another. The price is firing up to `accuracy`
+/// late; `AccuracySec=0` turns the rounding off for units that need precision.
+fn align(target: TimeSpec, accuracy: Duration) -> TimeSpec {
+    let window = accuracy.as_nanos();
+    if window == 0 {
+        return target;
+    }
+    let aligned = Duration::from(target).as_nanos().div_ceil(window) * window;
+    TimeSpec::from(Duration::from_nanos(aligned as u64))
+}
+
+/// Units with accuracy windows shorter than this keep firing on their own timerfd
+/// instead of joining a shared wakeup, see [`Scheduler::group_wakeups`]
+const MIN_COALESCE_ACCURACY: Duration = Duration::from_secs(1);
+
+/// A timerfd shared by the timers on one clock due within each other's accuracy window
+///
+/// It fires once for all of them, so they cost the CPU a single wakeup. The members'
+/// own timerfds stay disarmed until they are re-armed after the firing.
+struct Wakeup {
+    tfd: TimerFd,
+    clock: ClockId,
+    at: TimeSpec,
+    /// Tokens of the member timers
+    members: HashSet<u64>,
+}
+
+/// Creates and arms the timerfd of a [`Wakeup`] at the absolute instant `at`
+fn arm_wakeup(clock: ClockId, at: TimeSpec) -> nix::Result<TimerFd> {
+    let tfd = TimerFd::new(clock, TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC)?;
+    arm_tfd_at(&tfd, clock, at, Duration::ZERO)?;
+    Ok(tfd)
+}
+
+/// Disarms a timerfd and returns the time its one-shot arming had left
+///
+/// Both happen in one `timerfd_settime`, so an arming a worker makes meanwhile is
+/// either returned here or stays armed, never lost. An arming that expired just
+/// before has nothing left, and its unread expiration is cleared along with it.
+fn take_arming(tfd: &TimerFd) -> nix::Result<Duration> {
+    let disarmed: libc::itimerspec = unsafe { mem::zeroed() };
+    let mut old: libc::itimerspec = unsafe { mem::zeroed() };
+    let fd = tfd.as_fd().as_raw_fd();
+    Errno::result(unsafe { libc::timerfd_settime(fd, 0, &disarmed, &mut old) })?;
+    Ok(Duration::from(TimeSpec::from(old.it_value)))
+}
+
+/// Drops `member` from a wakeup, closing the wakeup once nobody is left in it
+fn leave_wakeup(wakeups: &mut HashMap<u64, Wakeup>, epoll: &Epoll, token: u64, member: u64) {
+    let Some(wakeup) = wakeups.get_mut(&token) else {
+        return;
+    };
+    wakeup.members.remove(&member);
+    if wakeup.members.is_empty()
+        && let Some(wakeup) = wakeups.remove(&token)
+        && let Err(e) = epoll.delete(&wakeup.tfd)
+    {
+        error!("Failed to unregister a shared wakeup: {}", e);
+    }
+}
+
+/// Arms a one-shot expiration at the absolute instant `target`, aligned to `AccuracySec`
+fn arm_at(timer: &RuntimeTimer, target: TimeSpec) -> nix::Result<()> {
+    arm_tfd_at(&timer.tfd, timer.clock, target, timer.unit.accuracy_sec)
+}
+
+fn arm_tfd_at(
+    tfd: &TimerFd,
+    clock: ClockId,
+    target: TimeSpec,
+    accuracy: Duration,
+) -> nix::Result<()> {
+    tfd.set(
+        Expiration::OneShot(align(target, accuracy)),
+        absolute_flags(clock),
+    )
+}
+
+/// Flags arming an absolute instant on `clock`
+///
+/// On wall clocks the arming is cancelled by a clock jump (e.g. the first NTP sync),
+/// which the next read reports as `ECANCELED`.
+fn absolute_flags(clock: ClockId) -> TimerSetTimeFlags {
+    let mut flags = TimerSetTimeFlags::TFD_TIMER_ABSTIME;
+    if is_realtime(clock) {
+        flags |= TimerSetTimeFlags::TFD_TIMER_CANCEL_ON_SET;
+    }
+    flags
+}
+
+/// Re-arms a wall-clock timer whose arming a clock jump cancelled
+///
+/// Calendar units recompute their next occurrence from the new time. Other units keep
+/// the time they had left, since they count a duration rather than a wall time.
+fn rearm_after_clock_change(timer: &mut RuntimeTimer, stamps: &Path) -> nix::Result<()> {
+    if !timer.calendar.is_empty() {
+        rearm(timer);
+        return Ok(());
+    }
+
+    let left = match timer.tfd.get()? {
+        Some(Expiration::OneShot(left)) => left,
+        _ => {
+            arm_initial(timer, stamps)?;
+            return Ok(());
+        }
+    };
+    let target = clock_now(timer.clock)? + left;
+    if timer.next_base.is_some() {
+        timer.next_base = Some(target);
+    }
+    arm_at(timer, target)
+}
+
+/// Whether a persistent unit missed a run (e.g. while powered off) and should fire now
+fn catch_up_due(timer: &RuntimeTimer, last_run: SystemTime, now: SystemTime) -> bool {
+    if !timer.calendar.is_empty() {
+        let last_run = DateTime::<Local>::from(last_run);
+        return calendar::next_occurrence_of(&timer.calendar, last_run)
+            .is_some_and(|next| next <= DateTime::<Local>::from(now));
+    }
+
+    match timer
+        .unit
+        .on_unit_active_sec
+        .or(timer.unit.on_unit_inactive_sec)
+    {
+        Some(interval) if interval > Duration::ZERO => now
+            .duration_since(last_run)
+            .is_ok_and(|elapsed| elapsed >= interval),
+        _ => false,
+    }
+}
+
+/// Delay until the next occurrence of any of `specs`, or `None` if none matches again
+fn calendar_delay(specs: &[CalendarSpec], now: DateTime<Local>) -> Option<Duration> {
+    let next = calendar::next_occurrence_of(specs, now)?;
+    // The clock may have jumped between computing and arming; a negative delta fires now
+    Some((next - now).to_std().unwrap_or(Duration::ZERO))
+}
+
+/// Bounds the `RandomizedDelaySec` offset of a calendar elapse `delay` after `now`
+/// to the gap before the occurrence following it
+///
+/// Otherwise a window wider than the gap (e.g. 2h on an hourly schedule) would
+/// push the firing past that occurrence, which the re-arm after it then skips. An
+/// offset beyond the gap wraps around within it rather than piling up at its end.
+fn bound_calendar_jitter(
+    specs: &[CalendarSpec],
+    now: DateTime<Local>,
+    delay: Duration,
+    jitter: Duration,
+) -> Duration {
+    let Ok(delay) = chrono::Duration::from_std(delay) else {
+        return jitter;
+    };
+    let target = now + delay;
+    let gap = calendar::next_occurrence_of(specs, target)
+        .and_then(|following| (following - target).to_std().ok())
+        .filter(|gap| !gap.is_zero());
+    match gap {
+        Some(gap) if jitter >= gap => {
+            Duration::from_nanos((jitter.as_nanos() % gap.as_nanos()) as u64)
+        }
+        _ => jitter,
+    }
+}
+
+/// Delay from `now` until a unit's first firing, or `None` if it never fires
+///
+/// This is the schedule the daemon arms, before jitter and persistent catch-up: the
+/// soonest of `OnCalendar`, `OnBootSec` and `OnStartupSec`, or one second after
+/// startup for a repeating unit with none of them. A boot offset that already passed fires right away.
+pub fn next_elapse(unit: &TimerUnit, now: DateTime<Local>) -> Option<Duration> {
+    let boot = unit
+        .on_boot_sec
+        .map(|offset| offset.saturating_sub(uptime()));
+    let specs: Vec<CalendarSpec> = unit
+        .on_calendar
+        .iter()
+        .filter_map(|expr| CalendarSpec::parse(expr).ok())
+        .collect();
+    let calendar = calendar_delay(&specs, now);
+
+    let soonest = [calendar, boot, unit.on_startup_sec]
+        .into_iter()
+        .flatten()
+        .min();
+    match soonest {
+        None if unit.on_calendar.is_empty() => Some(Duration::from_secs(1)),
+        soonest => soonest,
+    }
+}
+
+/// Time since the system booted, including deep sleep
+fn uptime() -> Duration {
+    nix::time::clock_gettime(nix::time::ClockId::CLOCK_BOOTTIME)
+        .map(Duration::from)
+        .unwrap_or_default()
+}
+
+/// Per-boot identifier, so jitter is reproducible within a boot but differs across boots
+fn boot_nonce() -> &'static str {
+    static NONCE: OnceLock<String> = OnceLock::new();
+    NONCE.get_or_init(|| {
+        fs::read_to_string("/proc/sys/kernel/random/boot_id")
+            .map(|id| id.trim().to_string())
+            .unwrap_or_default()
+    })
+}
+
+/// Stable per-device identifier for `RandomizedDelaySeed = "Device"`: the serial
+/// number, or the machine id off Android
+fn device_id() -> &'static str {
+    static ID: OnceLock<String> = OnceLock::new();
+    ID.get_or_init(|| {
+        let serial = Command::new("getprop")
+            .arg("ro.serialno")
+            .output()
+            .ok()
+            .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+            .filter(|serial| !serial.is_empty() && serial != "unknown");
+        serial
+            .or_else(|| {
+                fs::read_to_string("/etc/machine-id")
+                    .ok()
+                    .map(|id| id.trim().to_string())
+            })
+            .unwrap_or_else(|| {
+                warn!("No device id found, RandomizedDelaySeed = \"Device\" acts like \"Unit\"");
+                String::new()
+            })
+    })
+}
+
+/// Picks a fresh `RandomizedDelaySec` offset for the next arming of `timer`
+fn next_jitter(timer: &mut RuntimeTimer) -> Duration {
+    let jitter = jitter(&timer.name, &timer.unit, timer.armings);
+    timer.armings += 1;
+    jitter
+}
+
+/// Offset in [0, RandomizedDelaySec] seeded from unit name + boot nonce + `seed`, so
+/// each cycle re-rolls, or from the unit name + its `RandomizedDelaySeed` alone
+fn jitter(name: &str, unit: &TimerUnit, seed: u64) -> Duration {
+    let max = match unit.randomized_delay_sec {
+        Some(max) if max > Duration::ZERO => max,
+        _ => return Duration::ZERO,
+    };
+
+    let source = match unit.randomized_delay_seed {
+        None | Some(SeedSource::Boot) => boot_nonce(),
+        Some(SeedSource::Device) => device_id(),
+        Some(SeedSource::Unit) => "",
+    };
+    let seed = match unit.randomized_delay_seed {
+        None => seed.to_le_bytes(),
+        Some(_) => [0; 8],
+    };
+    seeded_offset(max, source, name, seed)
+}
+
+/// Offset in [0, `max`] derived from a seed `source` (device id, boot nonce or none),
+/// the unit name and a per-arming `seed`
+fn seeded_offset(max: Duration, source: &str, name: &str, seed: [u8; 8]) -> Duration {
+    let hash = stable_hash(&[source.as_bytes(), name.as_bytes(), &seed]);
+    let nanos = u128::from(hash) % (max.as_nanos() + 1);
+    Duration::from_nanos(nanos as u64)
+}
+
+/// Hash of `parts` that, unlike `DefaultHasher`, doesn't change between Rust releases,
+/// so seeded delays survive a toolchain upgrade
+///
+/// FNV-1a over each part's length and bytes, then the splitmix64 finalizer to spread
+/// the last bytes, which FNV mixes weakly, over every bit.
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash = 0xcbf29ce484222325u64;
+    for part in parts {
+        let len = (part.len() as u64).to_le_bytes();
+        for &byte in len.iter().chain(part.iter()) {
+            hash = (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3);
+        }
+    }
+    hash = (hash ^ (hash >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    hash = (hash ^ (hash >> 27)).wrapping_mul(0x94d049bb133111eb);
+    hash ^ (hash >> 31)
+}
+
+/// Current time on the clock behind a timerfd clock
+fn clock_now(clock: ClockId) -> nix::Result<TimeSpec> {
+    let base = match clock {
+        ClockId::CLOCK_REALTIME | ClockId::CLOCK_REALTIME_ALARM => {
+            nix::time::ClockId::CLOCK_REALTIME
+        }
+        ClockId::CLOCK_MONOTONIC => nix::time::ClockId::CLOCK_MONOTONIC,
+        _ => nix::time::ClockId::CLOCK_BOOTTIME,
+    };
+    nix::time::clock_gettime(base)
+}
+
+/// [`clock_now`], taking `wall` as the current time on wall clocks
+///
+/// A calendar delay computed from `wall` then lands exactly on the occurrence when
+/// armed with `TFD_TIMER_ABSTIME`, however long passed since `wall` was read.
+fn clock_now_at(clock: ClockId, wall: DateTime<Local>) -> nix::Result<TimeSpec> {
+    if !is_realtime(clock) {
+        return clock_now(clock);
+    }
+    Ok(TimeSpec::new(
+        wall.timestamp() as _,
+        wall.timestamp_subsec_nanos() as _,
+    ))
+}
+
+fn is_realtime(clock: ClockId) -> bool {
+    matches!(
+        clock,
+        ClockId::CLOCK_REALTIME | ClockId::CLOCK_REALTIME_ALARM
+    )
+}
+
+fn is_alarm(clock: ClockId) -> bool {
+    matches!(
+        clock,
+        ClockId::CLOCK_BOOTTIME_ALARM | ClockId::CLOCK_REALTIME_ALARM
+    )
+}
+
+/// Maps a unit's `Clock` and `WakeSystem` to the clock of its timerfd
+///
+/// Only the alarm variants wake the device when they expire; the others fire once
+/// something else has woken it.
+fn timer_clock(unit: &TimerUnit) -> ClockId {
+    match (unit.clock_source(), unit.wake_system) {
+        (ClockSource::BootTime, false) => ClockId::CLOCK_BOOTTIME,
+        (ClockSource::BootTime, true) => ClockId::CLOCK_BOOTTIME_ALARM,
+        (ClockSource::Realtime, false) => ClockId::CLOCK_REALTIME,
+        (ClockSource::Realtime, true) => ClockId::CLOCK_REALTIME_ALARM,
+        // There is no monotonic alarm clock; validation rejects WakeSystem here
+        (ClockSource::Monotonic, _) => ClockId::CLOCK_MONOTONIC,
+    }
+}
+
+/// Drops the wake-up ability of an alarm clock
+fn without_alarm(clock: ClockId) -> ClockId {
+    match clock {
+        ClockId::CLOCK_BOOTTIME_ALARM => ClockId::CLOCK_BOOTTIME,
+        ClockId::CLOCK_REALTIME_ALARM => ClockId::CLOCK_REALTIME,
+        clock => clock,
+    }
+}
+
+fn create_timerfd(name: &str, unit: &TimerUnit) -> nix::Result<(ClockId, TimerFd)> {
+    let flags = TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC;
+    let clock = timer_clock(unit);
+
+    match TimerFd::new(clock, flags) {
+        Ok(tfd) => Ok((clock, tfd)),
+        Err(e) if is_alarm(clock) => {
+            let fallback = without_alarm(clock);
+            warn!(
+                "[{}] can't use {:?} ({}), falling back to {:?}; it won't wake the device",
+                unit.tag(name),
+                clock,
+                e,
+                fallback
+            );
+            Ok((fallback, TimerFd::new(fallback, flags)?))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Arms a unit for its first expiration. Returns the delay until it, before jitter and
+/// alignment, or `None` if nothing is ever scheduled. A `Persistent` unit whose stamp
+/// in `stamps` is stale is due right away.
+///
+/// Expirations are armed as absolute instants on the timer's clock, so they can be aligned
+/// to `AccuracySec`. Calendar units are re-armed after every firing; repeating units
+/// follow a fixed grid, re-armed with a fresh jitter each cycle without drift. Only
+/// precise (`AccuracySec=0`), unrandomized repeating units leave the cadence to the
+/// kernel via `IntervalDelayed`; the default `AccuracySec` keeps a unit on the grid on
+/// purpose, since a kernel interval can't align each expiration or share a wakeup.
+fn arm_initial(timer: &mut RuntimeTimer, stamps: &Path) -> nix::Result<Option<Duration>> {
+    let jitter = next_jitter(timer);
+
+    let overdue = timer.unit.persistent
+        && read_stamp(stamps, &timer.name)
+            .is_some_and(|last_run| catch_up_due(timer, last_run, SystemTime::now()));
+    if overdue {
+        info!("[{}] missed a run while offline, catching up", timer.tag());
+    }
+
+    let unit = &timer.unit;
+    let wall = Local::now();
+    let delay = match next_elapse(unit, wall) {
+        _ if overdue => Duration::ZERO,
+        Some(delay) => delay,
+        None => return Ok(None),
+    };
+    let now = clock_now_at(timer.clock, wall)?;
+
+    if !timer.calendar.is_empty() {
+        let jitter = bound_calendar_jitter(&timer.calendar, wall, delay, jitter);
+        arm_at(timer, now + TimeSpec::from(delay.max(ASAP) + jitter))?;
+        return Ok(Some(delay));
+    }
+
+    let initial_delay = delay.max(ASAP);
+    let on_grid = unit.randomized_delay_sec.is_some() || !unit.accuracy_sec.is_zero();
+
+    match unit.on_unit_active_sec {
+        Some(interval) if interval > Duration::ZERO && on_grid => {
+            let base = now + TimeSpec::from(initial_delay);
+            timer.next_base = Some(base);
+            arm_at(timer, base + TimeSpec::from(jitter))?;
+        }
+        Some(interval) if interval > Duration::ZERO => timer.tfd.set(
+            Expiration::IntervalDelayed(TimeSpec::from(initial_delay), TimeSpec::from(interval)),
+            TimerSetTimeFlags::empty(),
+        )?,
+        _ => arm_at(timer, now + TimeSpec::from(initial_delay + jitter))?,
+    }
+    Ok(Some(delay))
+}
+
+/// Raises `unit`'s repeat intervals below `floor` to it, warning about each
+fn clamp_intervals(name: &str, unit: &mut TimerUnit, floor: Duration) {
+    let tag = unit.tag(name).to_string();
+    for (key, interval) in [
+        ("OnUnitActiveSec", &mut unit.on_unit_active_sec),
+        ("OnUnitInactiveSec", &mut unit.on_unit_inactive_sec),
+    ] {
+        if let Some(value) = interval
+            && *value < floor
+        {
+            warn!(
+                "[{}] {} {:?} is below the minimum interval, using {:?}",
+                tag, key, value, floor
+            );
+            *value = floor;
+        }
+    }
+}
+
+/// Arms a repeating unit one interval from now, after `SET` changed its interval
+///
+/// Mirrors [`arm_initial`] with the interval in place of the first delay.
+fn rearm_interval(timer: &mut RuntimeTimer) -> nix::Result<()> {
+    let jitter = next_jitter(timer);
+    let now = clock_now(timer.clock)?;
+    let unit = &timer.unit;
+    let on_grid = unit.randomized_delay_sec.is_some() || !unit.accuracy_sec.is_zero();
+    timer.next_base = None;
+
+    match (unit.on_unit_active_sec, unit.on_unit_inactive_sec) {
+        (Some(interval), _) if on_grid => {
+            let base = now + TimeSpec::from(interval);
+            timer.next_base = Some(base);
+            arm_at(timer, base + TimeSpec::from(jitter))
+        }
+        (Some(interval), _) => timer.tfd.set(
+            Expiration::IntervalDelayed(TimeSpec::from(interval), TimeSpec::from(interval)),
+            TimerSetTimeFlags::empty(),
+        ),
+        (None, Some(interval)) => arm_at(timer, now + TimeSpec::from(interval + jitter)),
+        (None, None) => Ok(()),
+    }
+}
+
+/// Re-arms a timer after a firing, for the cases the kernel doesn't handle itself
+///
+/// Returns how many grid points of a repeating unit passed unnoticed (e.g. while the
+/// loop was blocked), so they count as missed expirations like kernel overruns do.
+fn rearm(timer: &mut RuntimeTimer) -> u64 {
+    let jitter = next_jitter(timer);
+
+    let result = if !timer.calendar.is_empty() {
+        let wall = Local::now();
+        let Some(delay) = calendar_delay(&timer.calendar, wall) else {
+            info!("[{}] has no further calendar occurrences", timer.tag());
+            return 0;
+        };
+        let jitter = bound_calendar_jitter(&timer.calendar, wall, delay, jitter);
+        debug!("Re-arming [{}] for {:?}", timer.tag(), delay + jitter);
+        clock_now_at(timer.clock, wall)
+            .and_then(|now| arm_at(timer, now + TimeSpec::from(delay + jitter)))
+            .map(|()| 0)
+    } else if let (Some(base), Some(interval)) = (timer.next_base, timer.unit.on_unit_active_sec) {
+        // After a backoff delay, the grid restarts from this firing instead of counting
+        // the skipped points as missed
+        let base = match mem::take(&mut timer.status.lock().unwrap().backed_off) {
+            true => clock_now(timer.clock).unwrap_or(base),
+            false => base,
+        };
+        debug!(
+            "Re-arming [{}] for {:?} (+{:?} jitter)",
+            timer.tag(),
+            interval,
+            jitter
+        );
+        rearm_grid(timer, base, interval, jitter)
+    } else {
+        return 0;
+    };
+
+    result.unwrap_or_else(|e| {
+        error!("Failed to re-arm [{}]: {}", timer.tag(), e);
+        0
+    })
+}
+
+/// Arms an `OnUnitInactiveSec` unit relative to the end of the run that just finished
+///
+/// Calendar units follow their calendar instead. Runs on the worker thread; if the
+/// unit was removed meanwhile, this only arms a timerfd nobody polls anymore.
+///
+/// `backoff` replaces the interval while `FailureBackoff` is stretching it.
+fn rearm_inactive(
+    name: &str,
+    unit: &TimerUnit,
+    tfd: &TimerFd,
+    clock: ClockId,
+    backoff: Option<Duration>,
+) {
+    let Some(interval) = unit
+        .on_unit_inactive_sec
+        .filter(|_| unit.on_calendar.is_empty())
+        .map(|interval| backoff.unwrap_or(interval))
+    else {
+        return;
+    };
+
+    let result = clock_now(clock).and_then(|now| {
+        let seed = Duration::from(now).as_nanos() as u64;
+        let jitter = jitter(name, unit, seed);
+        debug!(
+            "Re-arming [{}] for {:?} (+{:?} jitter) after its run",
+            unit.tag(name),
+            interval,
+            jitter
+        );
+        arm_tfd_at(
+            tfd,
+            clock,
+            now + TimeSpec::from(interval.max(ASAP) + jitter),
+            unit.accuracy_sec,
+        )
+    });
+    if let Err(e) = result {
+        error!("Failed to re-arm [{}]: {}", unit.tag(name), e);
+    }
+}
+
+/// Time left until a timerfd's next expiration, or `None` if it's disarmed
+///
+/// timerfd_gettime reports the time left even for absolutely armed timers.
+fn time_left(tfd: &TimerFd) -> Option<Duration> {
+    match tfd.get() {
+        Ok(Some(
+            Expiration::OneShot(left)
+            | Expiration::IntervalDelayed(left, _)
+            | Expiration::Interval(left),
+        )) => Some(Duration::from(left)),
+        _ => None,
+    }
+}
+
+/// Whether a timerfd expired and the expiration wasn't read yet
+fn expiration_pending(tfd: &TimerFd) -> bool {
+    let mut poll = libc::pollfd {
+        fd: tfd.as_fd().as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    unsafe { libc::poll(&mut poll, 1, 0) == 1 }
+}
+
+/// Disarms a timer for [`Scheduler::pause`]; returns its arming, to resume it from
+fn pause_timer(timer: &RuntimeTimer) -> nix::Result<Option<Expiration>> {
+    let armed = timer.tfd.get()?;
+    timer.tfd.unset()?;
+    Ok(armed)
+}
+
+/// Re-arms a timer paused with `armed` for the time it had left then
+fn resume_timer(timer: &mut RuntimeTimer, armed: Expiration) -> nix::Result<()> {
+    match armed {
+        Expiration::IntervalDelayed(left, interval) => timer.tfd.set(
+            Expiration::IntervalDelayed(left.max(TimeSpec::from(ASAP)), interval),
+            TimerSetTimeFlags::empty(),
+        ),
+        Expiration::OneShot(left) | Expiration::Interval(left) => {
+            let target = clock_now(timer.clock)? + left;
+            if timer.next_base.is_some() {
+                timer.next_base = Some(target);
+            }
+            // The paused arming was aligned to AccuracySec already
+            arm_tfd_at(&timer.tfd, timer.clock, target, Duration::ZERO)
+        }
+    }
+}
+
+/// Moves a timer freshly armed by [`arm_initial`] to the elapse saved before a restart
+///
+/// Does nothing unless the unit's configuration is unchanged; calendar units always
+/// follow their calendar. A saved elapse that passed while the daemon was down fires
+/// right away. Returns the restored delay until the next elapse, if any.
+fn restore_saved(timer: &mut RuntimeTimer, saved: SavedUnit) -> nix::Result<Option<Duration>> {
+    if saved.fingerprint != fingerprint(&timer.unit) {
+        debug!(
+            "[{}] configuration changed since the state was saved, re-planning",
+            timer.tag()
+        );
+        return Ok(None);
+    }
+
+    if let Some(last_run) = saved.last_run {
+        timer.status.lock().unwrap().metrics.last_run = Some(last_run.last_run());
+    }
+
+    let Some(next_elapse) = saved.next_elapse_ms.map(from_millis) else {
+        return Ok(None);
+    };
+    if !timer.calendar.is_empty() {
+        return Ok(None);
+    }
+    let left = next_elapse
+        .duration_since(SystemTime::now())
+        .unwrap_or_default()
+        .max(ASAP);
+    info!(
+        "[{}] resuming saved schedule, next elapse in {:?}",
+        timer.tag(),
+        left
+    );
+
+    // The saved elapse was aligned to AccuracySec already
+    arm_in(timer, left, Duration::ZERO)?;
+    Ok(Some(left))
+}
+
+/// Moves a timer's armed first elapse to `left` from now, keeping a kernel interval
+/// or the `AccuracySec` grid of repeating units, which then count from there
+fn arm_in(timer: &mut RuntimeTimer, left: Duration, accuracy: Duration) -> nix::Result<()> {
+    match (timer.tfd.get()?, timer.next_base) {
+        (Some(Expiration::IntervalDelayed(_, interval)), _) => timer.tfd.set(
+            Expiration::IntervalDelayed(TimeSpec::from(left), interval),
+            TimerSetTimeFlags::empty(),
+        ),
+        (_, base) => {
+            let target = clock_now(timer.clock)? + TimeSpec::from(left);
+            if base.is_some() {
+                timer.next_base = Some(target);
+            }
+            arm_tfd_at(&timer.tfd, timer.clock, target, accuracy)
+        }
+    }
+}
+
+/// How much of `grace` is left since the device booted, suspend included
+fn boot_grace_left(grace: Duration) -> nix::Result<Duration> {
+    let uptime = nix::time::clock_gettime(nix::time::ClockId::CLOCK_BOOTTIME)?;
+    Ok(grace.saturating_sub(Duration::from(uptime)))
+}
+
+/// Grows or resets a unit's `FailureBackoff` interval after a run that did or didn't
+/// succeed; returns the interval to wait before the next run while backing off
+fn failure_backoff(
+    name: &str,
+    unit: &TimerUnit,
+    status: &mut RunState,
+    success: bool,
+) -> Option<Duration> {
+    let backoff = unit.failure_backoff.as_ref()?;
+    let base = unit
+        .on_unit_active_sec
+        .or(unit.on_unit_inactive_sec)
+        .filter(|interval| !interval.is_zero())?;
+
+    if success {
+        if status.backoff.take().is_some() {
+            info!(
+                "[{}] succeeded, back to its {:?} interval",
+                unit.tag(name),
+                base
+            );
+        }
+        return None;
+    }
+
+    let current = status.backoff.unwrap_or(base);
+    let interval = Duration::try_from_secs_f64(current.as_secs_f64() * backoff.multiplier)
+        .unwrap_or(backoff.max_interval)
+        .clamp(base, backoff.max_interval);
+    status.backoff = Some(interval);
+    info!(
+        unit = unit.tag(name);
+        "[{}] failed, backing off: next run in {:?}",
+        unit.tag(name), interval
+    );
+    Some(interval)
+}
+
+/// Arms an `OnUnitActiveSec` unit `delay` from now, for `FailureBackoff`
+///
+/// A kernel-driven interval keeps its period afterwards; grid units rejoin a grid
+/// starting from this firing, see [`rearm`].
+fn rearm_backoff(name: &str, unit: &TimerUnit, tfd: &TimerFd, clock: ClockId, delay: Duration) {
+    let result = match tfd.get() {
+        Ok(Some(Expiration::IntervalDelayed(_, interval) | Expiration::Interval(interval))) => tfd
+            .set(
+                Expiration::IntervalDelayed(TimeSpec::from(delay), interval),
+                TimerSetTimeFlags::empty(),
+            ),
+        _ => clock_now(clock)
+            .and_then(|now| arm_tfd_at(tfd, clock, now + TimeSpec::from(delay), unit.accuracy_sec)),
+    };
+    if let Err(e) = result {
+        error!("Failed to re-arm [{}]: {}", unit.tag(name), e);
+    }
+}
+
+/// Moves a repeating unit to its next grid point after `base` and arms it
+///
+/// Grid points already in the past are skipped rather than fired one by one;
+/// returns how many were skipped.
+fn rearm_grid(
+    timer: &mut RuntimeTimer,
+    base: TimeSpec,
+    interval: Duration,
+    jitter: Duration,
+) -> nix::Result<u64> {
+    let now = clock_now(timer.clock)?;
+    let mut base = base + TimeSpec::from(interval);
+    let mut missed = 0;
+    if base <= now {
+        missed = (Duration::from(now - base).as_nanos() / interval.as_nanos()) as u64 + 1;
+        let skipped = interval.as_nanos() * u128::from(missed);
+        base = base + TimeSpec::from(Duration::from_nanos(skipped as u64));
+    }
+    timer.next_base = Some(base);
+
+    arm_at(timer, base + TimeSpec::from(jitter))?;
+    Ok(missed)
+}
+
+/// Difference between the running units and a freshly loaded configuration
+#[derive(Debug, Default)]
+struct ReloadDiff {
+    added: Vec<(String, TimerUnit)>,
+    removed: Vec<String>,
+    changed: Vec<(String, TimerUnit)>,
+}
+
+/// Compares running units against loaded ones; unchanged units are left out entirely
+fn diff_units(current: &HashMap<&str, &TimerUnit>, loaded: Vec<(String, TimerUnit)>) -> ReloadDiff {
+    let mut diff = ReloadDiff::default();
+
+    let loaded_names: HashSet<&str> = loaded.iter().map(|(name, _)| name.as_str()).collect();
+    diff.removed = current
+        .keys()
+        .filter(|name| !loaded_names.contains(*name))
+        .map(|name| name.to_string())
+        .collect();
+
+    for (name, unit) in loaded {
+        match current.get(name.as_str()) {
+            None => diff.added.push((name, unit)),
+            Some(old) if **old != unit => diff.changed.push((name, unit)),
+            Some(_) => {}
+        }
+    }
+    diff
+}
+
+/// What the event loop does with a firing, given the unit's concurrency policy
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum FireDecision {
+    /// A worker was started
+    Start,
+    /// The previous run is still active and the firing was dropped
+    Skip,
+    /// The previous run is still active; it runs once more when done
+    Queue,
+    /// `StartLimitBurst` firings already happened within `StartLimitIntervalSec`
+    RateLimited,
+}
+
+impl FireDecision {
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            FireDecision::Start => "started",
+            FireDecision::Skip => "skipped",
+            FireDecision::Queue => "queued",
+            FireDecision::RateLimited => "rate-limited",
+        }
+    }
+}
+
+/// Decides how to handle a firing and updates the run state accordingly
+fn decide_firing(status: &mut RunState, unit: &TimerUnit, now: Instant) -> FireDecision {
+    if status.running > 0 {
+        match unit.concurrency {
+            Concurrency::Skip => return FireDecision::Skip,
+            // Any number of firings during one run collapse into a single deferred run
+            Concurrency::Queue => {
+                status.queued = true;
+                return FireDecision::Queue;
+            }
+            Concurrency::Parallel => {}
+        }
+    }
+    if start_limit_hit(status, unit, now) {
+        return FireDecision::RateLimited;
+    }
+    status.running += 1;
+    FireDecision::Start
+}
+
+/// Records a start in the unit's window, unless the window already holds
+/// `StartLimitBurst` starts
+fn start_limit_hit(status: &mut RunState, unit: &TimerUnit, now: Instant) -> bool {
+    let window = unit.start_limit_interval_sec;
+    if unit.start_limit_burst == 0 || window.is_zero() {
+        return false;
+    }
+
+    while status
+        .starts
+        .front()
+        .is_some_and(|&start| now.duration_since(start) >= window)
+    {
+        status.starts.pop_front();
+    }
+    if status.starts.len() >= unit.start_limit_burst as usize {
+        return true;
+    }
+    status.starts.push_back(now);
+    false
+}
+
+/// Runs a firing on its own worker thread so a slow command can't block the event loop
+///
+/// The worker waits for `deps` before each run; see [`Dependencies::wait`]. Once it
+/// re-armed the unit, it signals `rearmed` so the loop groups the new arming. It
+/// counts in `workers` until then.
+fn dispatch(
+    config: &RunConfig,
+    timer: &RuntimeTimer,
+    runs: u64,
+    deps: Dependencies,
+    slots: &Arc<FiringSlots>,
+    rearmed: &Arc<OwnedFd>,
+    workers: &Arc<AtomicUsize>,
+) -> FireDecision {
+    let mut state = timer.status.lock().unwrap();
+    let decision = decide_firing(&mut state, &timer.unit, Instant::now());
+    match decision {
+        FireDecision::Start => {
+            if mem::take(&mut state.rate_limited) {
+                info!(
+                    "[{}] start-limit window cleared, scheduling again",
+                    timer.tag()
+                );
+            }
+        }
+        FireDecision::Skip => {
+            debug!(unit = timer.tag(); "[{}] still running, skipping", timer.tag());
+            let now = SystemTime::now();
+            state.metrics.record_skip(SkipReason::StillRunning, now);
+            report_result(
+                &timer.name,
+                &timer.unit,
+                now,
+                &Err(SkipReason::StillRunning),
+            );
+            return decision;
+        }
+        FireDecision::Queue => {
+            debug!(unit = timer.tag(); "[{}] still running, queued", timer.tag());
+            return decision;
+        }
+        FireDecision::RateLimited => {
+            if !mem::replace(&mut state.rate_limited, true) {
+                error!(unit = timer.tag(); "[{}] start-limit hit, not scheduling", timer.tag());
+            }
+            let now = SystemTime::now();
+            state.metrics.record_skip(SkipReason::RateLimited, now);
+            report_result(&timer.name, &timer.unit, now, &Err(SkipReason::RateLimited));
+            return decision;
+        }
+    }
+    drop(state);
+
+    let name = timer.name.clone();
+    let unit = timer.unit.clone();
+    let status = Arc::clone(&timer.status);
+    let tfd = Arc::clone(&timer.tfd);
+    let clock = timer.clock;
+    let slots = Arc::clone(slots);
+    let rearmed = Arc::clone(rearmed);
+    let worker_count = Arc::clone(workers);
+    let wakelocks = config.wakelocks();
+    let config = config.clone();
+
+    // After an alarm woke the device, take the wakelock before the worker even starts,
+    // so the device can't suspend again in between; WakeLockDelay accepts that risk
+    let early_lock = (is_alarm(timer.clock)
+        && unit.holds_wakelock()
+        && unit.wake_lock_delay.is_none()
+        && !config.dry_run())
+    .then(|| wakelocks.lock_name(&name))
+    .filter(|lock| wakelocks.acquire(lock, unit.wake_lock_timeout_sec));
+    let spawn_lock = early_lock.clone();
+    let mut early_lock = early_lock;
+
+    workers.fetch_add(1, Ordering::SeqCst);
+    let spawned = thread::Builder::new()
+        .name(format!("exec-{}", name))
+        .spawn(move || {
+            let mut runs = runs;
+            let mut last_success = None;
+            let backoff = loop {
+                for _ in 0..runs {
+                    let result = match deps.wait(unit.tag(&name)) {
+                        true => {
+                            let _slot = slots.acquire(unit.tag(&name));
+                            run_unit(&config, &name, &unit)
+                        }
+                        false => {
+                            let result = Err(SkipReason::Requirement);
+                            report_result(&name, &unit, SystemTime::now(), &result);
+                            result
+                        }
+                    };
+                    // The first run took its own lock, or was skipped and needs none
+                    if let Some(lock) = early_lock.take() {
+                        config.wakelocks().release(&lock);
+                    }
+                    let mut status = status.lock().unwrap();
+                    match result {
+                        Ok(outcome) => {
+                            last_success = Some(outcome.success);
+                            status.metrics.record(outcome, SystemTime::now());
+                        }
+                        Err(reason) => status.metrics.record_skip(reason, SystemTime::now()),
+                    }
+                }
+
+                let mut status = status.lock().unwrap();
+                if status.queued {
+                    status.queued = false;
+                    runs = 1;
+                    continue;
+                }
+                status.running -= 1;
+                let backoff = last_success
+                    .and_then(|success| failure_backoff(&name, &unit, &mut status, success));
+                if backoff.is_some() && unit.on_unit_inactive_sec.is_none() {
+                    status.backed_off = true;
+                }
+                break backoff;
+            };
+            status.notify_finished();
+            match backoff {
+                Some(delay) if unit.on_unit_inactive_sec.is_none() => {
+                    rearm_backoff(&name, &unit, &tfd, clock, delay)
+                }
+                _ => rearm_inactive(&name, &unit, &tfd, clock, backoff),
+            }
+            notify(&rearmed);
+            worker_count.fetch_sub(1, Ordering::SeqCst);
+        });
+
+    if let Err(e) = spawned {
+        workers.fetch_sub(1, Ordering::SeqCst);
+        timer.status.lock().unwrap().running -= 1;
+        timer.status.notify_finished();
+        if let Some(lock) = &spawn_lock {
+            wakelocks.release(lock);
+        }
+        error!("Failed to spawn worker for [{}]: {}", timer.tag(), e);
+    }
+    decision
+}
+
+/// Wakes the event loop through an eventfd; a wakeup already pending is enough
+fn notify(eventfd: &OwnedFd) {
+    let _ = nix::unistd::write(eventfd.as_raw_fd(), &1u64.to_ne_bytes());
+}
+
+/// Fewest events collected per `epoll_wait`; the buffer grows to one slot per
+/// registered fd, so a burst of expirations is collected in one wait
+const MIN_EVENT_BATCH: usize = 16;
+
+/// How often exit-when-empty checks whether the last running command finished
+const EXIT_POLL: Duration = Duration::from_millis(250);
+
+/// Pause before retrying after a transient `epoll_wait` failure
+const EPOLL_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// How the event loop reacts to a failed `epoll_wait`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EpollAction {
+    /// Interrupted; wait again right away
+    Retry,
+    /// Transient (e.g. out of memory); wait again after a short pause
+    Backoff,
+    /// The epoll state itself is broken; retrying would only spin
+    Fatal,
+}
+
+fn classify_epoll_error(err: Errno) -> EpollAction {
+    match err {
+        Errno::EINTR => EpollAction::Retry,
+        Errno::EBADF | Errno::EINVAL | Errno::EFAULT => EpollAction::Fatal,
+        _ => EpollAction::Backoff,
+    }
+}
+
+/// Outcome of one [`Scheduler::tick`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Tick {
+    /// Events (if any) were handled; keep ticking
+    Continue,
+    /// SIGTERM or SIGINT was received, or no timer will fire again (see
+    /// [`Scheduler::set_exit_when_empty`])
+    Shutdown,
+}
+
+/// A set of armed timers and the epoll instance that watches them
+///
+/// ```
+/// use micetimer::{Scheduler, TimerUnit};
+///
+/// let unit: TimerUnit = r#"
+///     Exec = "echo hello"
+///     OnBootSec = "10min"
+///     WakeLock = false
+/// "#
+/// .parse()?;
+///
+/// let scheduler = Scheduler::from_units(vec![("hello".to_string(), unit)])?;
+/// assert_eq!(scheduler.len(), 1);
+/// # Ok::<(), anyhow::Error>(())
+/// ```
+pub struct Scheduler {
+    epoll: Epoll,
+    /// Running timers, keyed by their epoll token
+    active_timers: HashMap<u64, RuntimeTimer>,
+    /// Token for the next timer; tokens are never reused, unlike fd numbers, so a
+    /// stale event for a removed timer can't be routed to its replacement
+    next_token: u64,
+    /// Shared timerfds of timers due together, keyed by their epoll token
+    wakeups: HashMap<u64, Wakeup>,
+    /// Signalled by workers after they re-armed their unit
+    rearmed: Arc<OwnedFd>,
+    signal_fd: Option<SignalFd>,
+    control: Option<ControlSocket>,
+    watch: Option<ConfigWatch>,
+    /// Saved state is written here on shutdown and every `STATE_SAVE_INTERVAL`
+    state_file: Option<PathBuf>,
+    state_timer: Option<TimerFd>,
+    /// State saved by the previous daemon, for units not armed since startup
+    saved_units: HashMap<String, SavedUnit>,
+    /// Directory re-read on SIGHUP
+    config_dir: Option<PathBuf>,
+    /// Defaults file applied beneath each unit on reload, instead of the default location
+    defaults_file: Option<PathBuf>,
+    /// Where SIGUSR1 writes a JSON snapshot of every unit's status
+    metrics_file: Option<PathBuf>,
+    /// Shared by every worker, to cap concurrent firings
+    firing_slots: Arc<FiringSlots>,
+    /// Worker threads of this scheduler still executing a firing
+    running_workers: Arc<AtomicUsize>,
+    /// Handed to every firing, with the wakelocks they hold
+    run_config: RunConfig,
+    /// Filled by each `epoll_wait`; grows with the registered fds, never shrinks
+    events: Vec<EpollEvent>,
+    /// Aborts the process if ticks stop coming
+    watchdog: Option<Watchdog>,
+    /// Shut down once no timer is left, e.g. after a reload removed the last one
+    exit_when_empty: bool,
+    /// No first elapse comes earlier than this after boot
+    boot_grace: Duration,
+    /// Shortest repeat interval armed; shorter ones are raised to it
+    min_interval: Duration,
+    /// While paused, the arming each timer had when it was paused, by token
+    paused: Option<HashMap<u64, Expiration>>,
+}
+
+impl Scheduler {
+    /// Creates an empty scheduler
+    pub fn new() -> Result<Self> {
+        let epoll = Epoll::new(EpollCreateFlags::empty())?;
+        let rearmed = eventfd(0, EfdFlags::EFD_NONBLOCK | EfdFlags::EFD_CLOEXEC)?;
+        epoll.add(&rearmed, EpollEvent::new(EpollFlags::EPOLLIN, REARM_TOKEN))?;
+        Ok(Self {
+            epoll,
+            active_timers: HashMap::new(),
+            next_token: FIRST_TIMER_TOKEN,
+            wakeups: HashMap::new(),
+            rearmed: Arc::new(rearmed),
+            signal_fd: None,
+            control: None,
+            watch: None,
+            state_file: None,
+            state_timer: None,
+            saved_units: HashMap::new(),
+            config_dir: None,
+            defaults_file: None,
+            metrics_file: None,
+            firing_slots: Arc::default(),
+            running_workers: Arc::default(),
+            run_config: RunConfig::default(),
+            events: vec![EpollEvent::empty(); MIN_EVENT_BATCH],
+            watchdog: None,
+            exit_when_empty: false,
+            boot_grace: Duration::ZERO,
+            min_interval: Duration::ZERO,
+            paused: None,
+        })
+    }
+
+    /// Creates a scheduler with every unit in `units` armed
+    pub fn from_units(units: impl IntoIterator<Item = (String, TimerUnit)>) -> Result<Self> {
+        let mut scheduler = Self::new()?;
+        for (name, unit) in units {
+            scheduler.add_timer(name, unit)?;
+        }
+        Ok(scheduler)
+    }
+
+    /// Sets the directory that SIGHUP (and [`Scheduler::watch_config_dir`]) reloads units from
+    pub fn set_config_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.config_dir = Some(dir.into());
+    }
+
+    /// Sets the defaults file reloads merge beneath each unit; see [`crate::scan_units`]
+    pub fn set_defaults_file(&mut self, path: impl Into<PathBuf>) {
+        self.defaults_file = Some(path.into());
+    }
+
+    /// Runs every later firing with `config`: dry runs, output capture, wakelocks
+    pub fn set_run_config(&mut self, config: RunConfig) {
+        self.run_config = config;
+    }
+
+    /// Caps how many firings execute at once across all units; the rest wait for a
+    /// free slot in their worker threads
+    pub fn set_max_concurrent(&mut self, limit: Option<NonZeroUsize>) {
+        self.firing_slots = Arc::new(FiringSlots::new(limit));
+    }
+
+    /// Aborts the process if the event loop goes `timeout` without returning to
+    /// [`Scheduler::tick`], so a supervisor can restart it; ticks then wait at most
+    /// half of `timeout`
+    ///
+    /// Call this after [`Scheduler::handle_signals`], so the monitor thread inherits
+    /// the blocked signal mask and a signal can't be delivered to it.
+    pub fn set_watchdog(&mut self, timeout: Duration) -> Result<()> {
+        let watchdog = Watchdog::start(timeout).context("Failed to start the watchdog")?;
+        watchdog.beat();
+        self.watchdog = Some(watchdog);
+        Ok(())
+    }
+
+    /// Makes [`Scheduler::tick`] report [`Tick::Shutdown`] once no timer will fire
+    /// again and no run is in flight, instead of waiting for units to be added
+    ///
+    /// One-shot units that already fired, and calendars without further occurrences,
+    /// count as done.
+    pub fn set_exit_when_empty(&mut self, exit: bool) {
+        self.exit_when_empty = exit;
+    }
+
+    /// Holds every unit's first elapse back until `grace` has passed since boot
+    /// (`CLOCK_BOOTTIME`, so suspend counts); later elapses are left alone
+    pub fn set_boot_grace(&mut self, grace: Duration) {
+        self.boot_grace = grace;
+    }
+
+    /// Raises every `OnUnitActiveSec` and `OnUnitInactiveSec` below `floor` to it, so a
+    /// typo like `0.001s` can't fork a command every millisecond
+    pub fn set_min_interval(&mut self, floor: Duration) {
+        self.min_interval = floor;
+    }
+
+    /// Sets the file that SIGUSR1 writes a JSON snapshot of every unit's status to
+    pub fn set_metrics_file(&mut self, path: impl Into<PathBuf>) {
+        self.metrics_file = Some(path.into());
+    }
+
+    /// Takes over SIGHUP, SIGUSR1, SIGTERM and SIGINT, delivering them through [`Scheduler::tick`]
+    ///
+    /// The signals are blocked on the calling thread, so call this before spawning threads.
+    pub fn handle_signals(&mut self) -> Result<()> {
+        let mut mask = SigSet::empty();
+        mask.add(Signal::SIGHUP);
+        mask.add(Signal::SIGUSR1);
+        mask.add(Signal::SIGTERM);
+        mask.add(Signal::SIGINT);
+        mask.thread_block()?;
+
+        let signal_fd =
+            SignalFd::with_flags(&mask, SfdFlags::SFD_NONBLOCK | SfdFlags::SFD_CLOEXEC)?;
+        self.epoll.add(
+            &signal_fd,
+            EpollEvent::new(EpollFlags::EPOLLIN, SIGNAL_TOKEN),
+        )?;
+        self.signal_fd = Some(signal_fd);
+        Ok(())
+    }
+
+    /// Reloads the configuration directory whenever a configuration file in it changes
+    ///
+    /// Changes are debounced, so a burst of writes causes a single reload.
+    pub fn watch_config_dir(&mut self) -> Result<()> {
+        let dir = self
+            .config_dir
+            .as_deref()
+            .context("No configuration directory to watch")?;
+        // A single unit file is replaced rather than written by many editors, which
+        // only its directory sees
+        let dir = match dir.is_file() {
+            true => dir.parent().unwrap_or(Path::new(".")),
+            false => dir,
+        };
+        let watch = ConfigWatch::new(dir)?;
+        self.epoll.add(
+            watch.inotify(),
+            EpollEvent::new(EpollFlags::EPOLLIN, WATCH_TOKEN),
+        )?;
+        self.epoll.add(
+            watch.debounce(),
+            EpollEvent::new(EpollFlags::EPOLLIN, RELOAD_TOKEN),
+        )?;
+        self.watch = Some(watch);
+        Ok(())
+    }
+
+    /// Saves each unit's next elapse and last run to `path` on shutdown and periodically
+    ///
+    /// State saved there earlier in the same boot is restored for units added from now
+    /// on until the first reload, if their configuration is unchanged; call this before
+    /// adding the units.
+    pub fn set_state_file(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let path = path.into();
+        match state::load_state(&path) {
+            Ok(saved) if saved.boot_id == boot_nonce() => {
+                self.saved_units = saved.units.into_iter().collect();
+            }
+            Ok(saved) if !saved.units.is_empty() => {
+                info!("Saved state is from a previous boot, planning schedules afresh");
+            }
+            Ok(_) => {}
+            Err(e) => warn!("Ignoring state file {:?}: {:#}", path, e),
+        }
+
+        let timer = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        let interval = TimeSpec::from(STATE_SAVE_INTERVAL);
+        timer.set(
+            Expiration::IntervalDelayed(interval, interval),
+            TimerSetTimeFlags::empty(),
+        )?;
+        self.epoll
+            .add(&timer, EpollEvent::new(EpollFlags::EPOLLIN, STATE_TOKEN))?;
+        self.state_timer = Some(timer);
+        self.state_file = Some(path);
+        Ok(())
+    }
+
+    /// Accepts `LIST`/`STATUS`/`TRIGGER` requests on a Unix socket at `path`
+    ///
+    /// See the `control` module docs for the protocol. The socket is only
+    /// accessible to the daemon's user, and removed when the scheduler is dropped.
+    pub fn listen_control(&mut self, path: impl AsRef<Path>) -> Result<()> {
+        let control = ControlSocket::bind(path.as_ref())?;
+        self.epoll.add(
+            &control,
+            EpollEvent::new(EpollFlags::EPOLLIN, CONTROL_TOKEN),
+        )?;
+        self.control = Some(control);
+        Ok(())
+    }
+
+    /// Number of armed timers
+    pub fn len(&self) -> usize {
+        self.active_timers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.active_timers.is_empty()
+    }
+
+    /// Creates, arms and registers the timer for `unit`; disabled units are skipped
+    pub fn add_timer(&mut self, name: impl Into<String>, mut unit: TimerUnit) -> Result<()> {
+        let name = name.into();
+        if !unit.enabled {
+            info!("[{}] disabled, not scheduling", unit.tag(&name));
+            return Ok(());
+        }
+        clamp_intervals(&name, &mut unit, self.min_interval);
+
+        let (clock, tfd) = create_timerfd(&name, &unit)?;
+
+        let calendar = unit
+            .on_calendar
+            .iter()
+            .map(|expr| CalendarSpec::parse(expr))
+            .collect::<Result<_>>()?;
+        let mut timer = RuntimeTimer {
+            name,
+            unit,
+            tfd: Arc::new(tfd),
+            clock,
+            calendar,
+            next_base: None,
+            armings: 0,
+            status: Arc::default(),
+            wakeup: None,
+        };
+
+        let Some(mut delay) = arm_initial(&mut timer, self.run_config.stamp_dir())? else {
+            info!(
+                "[{}] has no upcoming occurrence, not scheduling",
+                timer.tag()
+            );
+            return Ok(());
+        };
+        if let Some(saved) = self.saved_units.remove(&timer.name)
+            && let Some(left) = restore_saved(&mut timer, saved)?
+        {
+            delay = left;
+        }
+        let grace_left = boot_grace_left(self.boot_grace)?;
+        let held = delay < grace_left;
+        if held {
+            info!(
+                "[{}] within the boot grace period, first elapse in {:?}",
+                timer.tag(),
+                grace_left
+            );
+            let accuracy = timer.unit.accuracy_sec;
+            arm_in(&mut timer, grace_left, accuracy)?;
+            delay = grace_left;
+        }
+
+        let token = self.next_token;
+        self.next_token += 1;
+        // For alarms, EPOLLWAKEUP keeps the device awake until the next epoll_wait,
+        // by which point dispatch holds the unit's wakelock
+        let mut flags = EpollFlags::EPOLLIN;
+        if is_alarm(clock) {
+            flags |= EpollFlags::EPOLLWAKEUP;
+        }
+        self.epoll.add(&timer.tfd, EpollEvent::new(flags, token))?;
+
+        if let Some(paused) = &mut self.paused
+            && let Some(armed) = pause_timer(&timer)?
+        {
+            paused.insert(token, armed);
+        }
+
+        // A first elapse that is due right away (e.g. a persistent catch-up) is the
+        // start run already, and so is the one at the end of the boot grace
+        if timer.unit.run_on_start && delay > ASAP && !held && self.paused.is_none() {
+            info!("[{}] RunOnStart, firing now", timer.tag());
+            dispatch(
+                &self.run_config,
+                &timer,
+                1,
+                self.dependencies(&timer.unit),
+                &self.firing_slots,
+                &self.rearmed,
+                &self.running_workers,
+            );
+        }
+
+        self.active_timers.insert(token, timer);
+        Ok(())
+    }
+
+    /// Unregisters a timer; dropping it closes the timerfd. Returns whether it existed.
+    pub fn remove_timer(&mut self, name: &str) -> bool {
+        let Some(token) = self
+            .active_timers
+            .iter()
+            .find_map(|(token, timer)| (timer.name == name).then_some(*token))
+        else {
+            return false;
+        };
+
+        let Some(timer) = self.active_timers.remove(&token) else {
+            return false;
+        };
+        if let Err(e) = self.epoll.delete(&timer.tfd) {
+            error!("Failed to unregister [{}]: {}", timer.tag(), e);
+        }
+        if let Some((wakeup, _)) = timer.wakeup {
+            leave_wakeup(&mut self.wakeups, &self.epoll, wakeup, token);
+        }
+        true
+    }
+
+    /// Applies a freshly loaded set of units in place
+    ///
+    /// Unchanged units keep their armed timerfd, so their schedule isn't reset.
+    pub fn reload(&mut self, mut units: Vec<(String, TimerUnit)>) {
+        // Saved state only describes the units the daemon started with
+        self.saved_units.clear();
+        // Armed units are clamped already, so unchanged ones must compare equal
+        for (name, unit) in &mut units {
+            if unit.enabled {
+                clamp_intervals(name, unit, self.min_interval);
+            }
+        }
+
+        let current: HashMap<&str, &TimerUnit> = self
+            .active_timers
+            .values()
+            .map(|timer| (timer.name.as_str(), &timer.unit))
+            .collect();
+        let diff = diff_units(&current, units);
+
+        for name in diff
+            .removed
+            .iter()
+            .chain(diff.changed.iter().map(|(name, _)| name))
+        {
+            self.remove_timer(name);
+        }
+
+        info!(
+            "Reload: +{} -{} ~{}",
+            diff.added.len(),
+            diff.removed.len(),
+            diff.changed.len()
+        );
+
+        for (name, unit) in diff.added.into_iter().chain(diff.changed) {
+            if let Err(e) = self.add_timer(name.clone(), unit) {
+                error!("Failed to schedule [{}]: {:#}", name, e);
+            }
+        }
+    }
+
+    /// Waits up to `timeout` (`None` = forever) for events and handles them
+    ///
+    /// Fired timers are re-armed and their commands dispatched to worker threads.
+    /// With a watchdog, the wait is cut short to keep its heartbeat going, and so it
+    /// is while commands run with exit-when-empty, to notice the last one finishing.
+    pub fn tick(&mut self, timeout: Option<Duration>) -> Result<Tick> {
+        if self.exit_when_empty && self.finished() {
+            info!("No timer will fire again, shutting down");
+            return Ok(Tick::Shutdown);
+        }
+        self.group_wakeups();
+
+        let timeout = match &self.watchdog {
+            Some(watchdog) => {
+                watchdog.beat();
+                Some(timeout.map_or(watchdog.max_wait(), |t| t.min(watchdog.max_wait())))
+            }
+            None => timeout,
+        };
+        let timeout = match self.exit_when_empty && self.running_workers.load(Ordering::SeqCst) > 0
+        {
+            true => Some(timeout.map_or(EXIT_POLL, |t| t.min(EXIT_POLL))),
+            false => timeout,
+        };
+        let timeout_ms = timeout.map_or(-1, |t| t.as_millis().min(isize::MAX as u128) as isize);
+
+        // Taken out for the wait, since handling the events needs the whole scheduler
+        let registered = FIRST_TIMER_TOKEN as usize + self.active_timers.len() + self.wakeups.len();
+        let mut events = mem::take(&mut self.events);
+        if events.len() < registered {
+            events.resize(registered, EpollEvent::empty());
+        }
+        let result = self.wait_and_handle(&mut events, timeout_ms);
+        self.events = events;
+        result
+    }
+
+    /// Waits up to `timeout_ms` for ready fds and handles them, collecting what didn't
+    /// fit into `events` without blocking again
+    fn wait_and_handle(&mut self, events: &mut [EpollEvent], timeout_ms: isize) -> Result<Tick> {
+        let mut timeout_ms = timeout_ms;
+        loop {
+            let num_events = match self.epoll.wait(events, timeout_ms) {
+                Ok(n) => n,
+                Err(e) => match classify_epoll_error(e) {
+                    EpollAction::Retry => return Ok(Tick::Continue),
+                    EpollAction::Backoff => {
+                        error!("epoll_wait failed, retrying: {}", e);
+                        thread::sleep(EPOLL_RETRY_DELAY);
+                        return Ok(Tick::Continue);
+                    }
+                    EpollAction::Fatal => return Err(e.into()),
+                },
+            };
+
+            // Control and signal events first, then timers in name order, so units
+            // expiring together fire in a predictable order
+            let ready = &mut events[..num_events];
+            ready.sort_by_cached_key(|event| {
+                let token = event.data();
+                let name = self
+                    .active_timers
+                    .get(&token)
+                    .map(|timer| timer.name.clone());
+                (token >= FIRST_TIMER_TOKEN, name, token)
+            });
+            for event in ready.iter() {
+                if self.handle_event(event.data()) == Tick::Shutdown {
+                    return Ok(Tick::Shutdown);
+                }
+            }
+
+            // A full buffer may have left events behind; collect them without blocking
+            if num_events < events.len() {
+                return Ok(Tick::Continue);
+            }
+            timeout_ms = 0;
+        }
+    }
+
+    /// Handles one ready epoll token: the signalfd, control socket, config watch, a
+    /// worker's re-arm, a shared wakeup or a fired timer
+    fn handle_event(&mut self, token: u64) -> Tick {
+        match token {
+            SIGNAL_TOKEN => return self.handle_pending_signals(),
+            CONTROL_TOKEN => {
+                self.handle_control();
+                return Tick::Continue;
+            }
+            WATCH_TOKEN => {
+                if let Some(watch) = &self.watch
+                    && let Err(e) = watch.handle_events()
+                {
+                    error!("Failed to read configuration changes: {}", e);
+                }
+                return Tick::Continue;
+            }
+            STATE_TOKEN => {
+                if let Some(timer) = &self.state_timer
+                    && read_expirations(timer).is_ok()
+                {
+                    self.write_state();
+                }
+                return Tick::Continue;
+            }
+            REARM_TOKEN => {
+                // Only wakes the loop; the next tick groups the new armings
+                let mut buf = [0u8; 8];
+                let _ = nix::unistd::read(self.rearmed.as_raw_fd(), &mut buf);
+                return Tick::Continue;
+            }
+            RELOAD_TOKEN => {
+                if self.watch.as_ref().is_some_and(ConfigWatch::reload_due) {
+                    info!("Configuration changed, reloading");
+                    self.reload_config_dir();
+                }
+                return Tick::Continue;
+            }
+            _ => {}
+        }
+
+        if self.wakeups.contains_key(&token) {
+            self.handle_wakeup(token);
+            return Tick::Continue;
+        }
+
+        let Some(timer) = self.active_timers.get_mut(&token) else {
+            return Tick::Continue;
+        };
+        // Read from timerfd to clear the trigger
+        let expirations = match read_expirations(&timer.tfd) {
+            Ok(n) => n,
+            Err(nix::Error::EAGAIN) => return Tick::Continue,
+            Err(nix::Error::ECANCELED) => {
+                info!("[{}] clock changed, recomputing schedule", timer.tag());
+                if let Err(e) = rearm_after_clock_change(timer, self.run_config.stamp_dir()) {
+                    error!("Failed to re-arm [{}]: {}", timer.tag(), e);
+                }
+                return Tick::Continue;
+            }
+            Err(e) => {
+                error!("Failed to read timer [{}]: {}", timer.tag(), e);
+                return Tick::Continue;
+            }
+        };
+        self.fire(token, expirations);
+        Tick::Continue
+    }
+
+    /// Handles a fired shared wakeup: every member fires as if its own timerfd had
+    ///
+    /// Members go in name order. One that a worker re-armed on its own timerfd meanwhile
+    /// follows that arming instead.
+    fn handle_wakeup(&mut self, token: u64) {
+        let Some(wakeup) = self.wakeups.get(&token) else {
+            return;
+        };
+        let read = read_expirations(&wakeup.tfd);
+        if read == Err(nix::Error::EAGAIN) {
+            return;
+        }
+        let Some(wakeup) = self.wakeups.remove(&token) else {
+            return;
+        };
+        if let Err(e) = self.epoll.delete(&wakeup.tfd) {
+            error!("Failed to unregister a shared wakeup: {}", e);
+        }
+
+        let mut members: Vec<(String, u64)> = wakeup
+            .members
+            .iter()
+            .filter_map(|member| {
+                let timer = self.active_timers.get(member)?;
+                Some((timer.name.clone(), *member))
+            })
+            .collect();
+        members.sort();
+        debug!("Shared wakeup fired for {} timer(s)", members.len());
+
+        for (_, member) in members {
+            let Some(timer) = self.active_timers.get_mut(&member) else {
+                continue;
+            };
+            timer.wakeup = None;
+            if time_left(&timer.tfd).is_some() || expiration_pending(&timer.tfd) {
+                continue;
+            }
+            match read {
+                Ok(_) => self.fire(member, 1),
+                Err(e) => {
+                    // Hand the arming back to the unit's own timerfd
+                    let result = arm_tfd_at(&timer.tfd, timer.clock, wakeup.at, Duration::ZERO)
+                        .and_then(|()| match e {
+                            nix::Error::ECANCELED => {
+                                info!("[{}] clock changed, recomputing schedule", timer.tag());
+                                rearm_after_clock_change(timer, self.run_config.stamp_dir())
+                            }
+                            e => {
+                                error!("Failed to read the wakeup of [{}]: {}", timer.tag(), e);
+                                Ok(())
+                            }
+                        });
+                    if let Err(e) = result {
+                        error!("Failed to re-arm [{}]: {}", timer.tag(), e);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Handles `expirations` of a timer: re-arms it and dispatches its runs
+    fn fire(&mut self, token: u64, expirations: u64) {
+        let deps = self
+            .active_timers
+            .get(&token)
+            .map(|timer| self.dependencies(&timer.unit))
+            .unwrap_or_default();
+        let Some(timer) = self.active_timers.get_mut(&token) else {
+            return;
+        };
+
+        // Armed by a run that finished during the pause, or pending when it began
+        if let Some(paused) = &mut self.paused {
+            info!("[{}] paused, skipping firing", timer.tag());
+            rearm(timer);
+            rearm_inactive(&timer.name, &timer.unit, &timer.tfd, timer.clock, None);
+            match pause_timer(timer) {
+                Ok(Some(armed)) => {
+                    paused.insert(token, armed);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to pause [{}]: {}", timer.tag(), e),
+            }
+            return;
+        }
+
+        // Re-arm before running so the schedule doesn't depend on the command
+        let expirations = expirations + rearm(timer);
+
+        dispatch(
+            &self.run_config,
+            timer,
+            runs_for_expirations(timer, expirations),
+            deps,
+            &self.firing_slots,
+            &self.rearmed,
+            &self.running_workers,
+        );
+    }
+
+    /// Moves every timer armed on its own timerfd onto the shared wakeup for its
+    /// instant, creating the wakeup if no other timer is due then
+    ///
+    /// Runs before each wait, so armings made since the last one, by the loop or by a
+    /// worker, are grouped before they fire. Units with an `AccuracySec` under
+    /// `MIN_COALESCE_ACCURACY` keep their own timerfd, as do kernel-driven intervals
+    /// and, while paused, everything.
+    fn group_wakeups(&mut self) {
+        if self.paused.is_some() {
+            return;
+        }
+        for (token, timer) in &mut self.active_timers {
+            if timer.unit.accuracy_sec < MIN_COALESCE_ACCURACY
+                || !matches!(timer.tfd.get(), Ok(Some(Expiration::OneShot(_))))
+            {
+                continue;
+            }
+            // Taking the time first keeps the instant from drifting past the aligned
+            // one it was armed for; an arming that expired in between is due right away
+            let target = match clock_now(timer.clock)
+                .and_then(|now| Ok(now + TimeSpec::from(take_arming(&timer.tfd)?)))
+            {
+                Ok(target) => target,
+                Err(e) => {
+                    error!("Failed to group [{}]: {}", timer.tag(), e);
+                    continue;
+                }
+            };
+            if let Some((wakeup, _)) = timer.wakeup.take() {
+                leave_wakeup(&mut self.wakeups, &self.epoll, wakeup, *token);
+            }
+
+            // Like the alignment, joining an earlier wakeup within the window also
+            // gathers units with different windows or jitter
+            let aligned = align(target, timer.unit.accuracy_sec);
+            let joined = self
+                .wakeups
+                .iter_mut()
+                .filter(|(_, wakeup)| {
+                    wakeup.clock == timer.clock && wakeup.at >= target && wakeup.at <= aligned
+                })
+                .min_by_key(|(_, wakeup)| wakeup.at);
+            if let Some((wakeup, shared)) = joined {
+                shared.members.insert(*token);
+                timer.wakeup = Some((*wakeup, shared.at));
+                continue;
+            }
+
+            let wakeup = self.next_token;
+            let mut flags = EpollFlags::EPOLLIN;
+            if is_alarm(timer.clock) {
+                flags |= EpollFlags::EPOLLWAKEUP;
+            }
+            let created = arm_wakeup(timer.clock, aligned).and_then(|tfd| {
+                self.epoll.add(&tfd, EpollEvent::new(flags, wakeup))?;
+                Ok(tfd)
+            });
+            let tfd = match created {
+                Ok(tfd) => tfd,
+                Err(e) => {
+                    error!("Failed to share a wakeup for [{}]: {}", timer.tag(), e);
+                    if let Err(e) = arm_tfd_at(&timer.tfd, timer.clock, aligned, Duration::ZERO) {
+                        error!("Failed to re-arm [{}]: {}", timer.tag(), e);
+                    }
+                    continue;
+                }
+            };
+            self.next_token += 1;
+            self.wakeups.insert(
+                wakeup,
+                Wakeup {
+                    tfd,
+                    clock: timer.clock,
+                    at: aligned,
+                    members: HashSet::from([*token]),
+                },
+            );
+            timer.wakeup = Some((wakeup, aligned));
+        }
+    }
+
+    /// Hands every shared wakeup's instant back to its members' own timerfds
+    fn ungroup_wakeups(&mut self) {
+        for (_, wakeup) in self.wakeups.drain() {
+            if let Err(e) = self.epoll.delete(&wakeup.tfd) {
+                error!("Failed to unregister a shared wakeup: {}", e);
+            }
+            for member in wakeup.members {
+                let Some(timer) = self.active_timers.get_mut(&member) else {
+                    continue;
+                };
+                timer.wakeup = None;
+                if time_left(&timer.tfd).is_some() || expiration_pending(&timer.tfd) {
+                    continue;
+                }
+                if let Err(e) = arm_tfd_at(&timer.tfd, timer.clock, wakeup.at, Duration::ZERO) {
+                    error!("Failed to re-arm [{}]: {}", timer.tag(), e);
+                }
+            }
+        }
+    }
+
+    /// Disarms every timer, remembering the time each had left; returns `false` if
+    /// already paused
+    ///
+    /// Runs in progress finish, but nothing fires until [`Scheduler::resume`]. Units
+    /// added meanwhile (e.g. by a reload) are paused as soon as they're armed.
+    pub fn pause(&mut self) -> bool {
+        if self.paused.is_some() {
+            return false;
+        }
+        self.ungroup_wakeups();
+        let mut paused = HashMap::new();
+        for (token, timer) in &self.active_timers {
+            match pause_timer(timer) {
+                Ok(Some(armed)) => {
+                    paused.insert(*token, armed);
+                }
+                Ok(None) => {}
+                Err(e) => error!("Failed to pause [{}]: {}", timer.tag(), e),
+            }
+        }
+        info!("Paused {} timer(s)", paused.len());
+        self.paused = Some(paused);
+        true
+    }
+
+    /// Re-arms every timer [`Scheduler::pause`] disarmed for the time it had left;
+    /// returns `false` if not paused
+    ///
+    /// Elapses that fell into the pause are skipped rather than caught up.
+    pub fn resume(&mut self) -> bool {
+        let Some(paused) = self.paused.take() else {
+            return false;
+        };
+        let mut resumed = 0;
+        for (token, armed) in paused {
+            let Some(timer) = self.active_timers.get_mut(&token) else {
+                continue;
+            };
+            match resume_timer(timer, armed) {
+                Ok(()) => resumed += 1,
+                Err(e) => error!("Failed to resume [{}]: {}", timer.tag(), e),
+            }
+        }
+        info!("Resumed {} timer(s)", resumed);
+        true
+    }
+
+    /// Looks up the run states of the units `unit` runs `After` and `Requires`
+    ///
+    /// `After` units that aren't scheduled never run, so there's nothing to wait for.
+    fn dependencies(&self, unit: &TimerUnit) -> Dependencies {
+        let status = |name: &String| {
+            self.active_timers
+                .values()
+                .find(|timer| &timer.name == name)
+                .map(|timer| Arc::clone(&timer.status))
+        };
+        Dependencies {
+            after: unit
+                .after
+                .iter()
+                .filter_map(|name| Some((name.clone(), status(name)?)))
+                .collect(),
+            requires: unit
+                .requires
+                .iter()
+                .map(|name| (name.clone(), status(name)))
+                .collect(),
+        }
+    }
+
+    /// Fires a unit now, outside its schedule; its `Concurrency` policy still applies
+    ///
+    /// Returns `None` if no armed unit has that name.
+    pub fn trigger(&self, name: &str) -> Option<FireDecision> {
+        let timer = self
+            .active_timers
+            .values()
+            .find(|timer| timer.name == name)?;
+        info!("[{}] triggered manually", timer.tag());
+        Some(dispatch(
+            &self.run_config,
+            timer,
+            1,
+            self.dependencies(&timer.unit),
+            &self.firing_slots,
+            &self.rearmed,
+            &self.running_workers,
+        ))
+    }
+
+    /// Changes an armed unit's `OnUnitActiveSec` or `OnUnitInactiveSec` and re-arms it
+    /// for the new interval from now
+    ///
+    /// Only the daemon's copy changes, so the next reload restores the file's value.
+    pub(crate) fn set_interval(
+        &mut self,
+        name: &str,
+        key: IntervalKey,
+        interval: Duration,
+    ) -> Result<UnitStatus> {
+        let (token, timer) = self
+            .active_timers
+            .iter_mut()
+            .find(|(_, timer)| timer.name == name)
+            .ok_or_else(|| anyhow!("No armed unit named {:?}", name))?;
+        if !timer.calendar.is_empty() {
+            bail!("[{}] follows OnCalendar, it has no interval", timer.tag());
+        }
+
+        let mut unit = timer.unit.clone();
+        match key {
+            IntervalKey::Active => unit.on_unit_active_sec = Some(interval),
+            IntervalKey::Inactive => unit.on_unit_inactive_sec = Some(interval),
+        }
+        unit.validate()?;
+        clamp_intervals(&timer.name, &mut unit, self.min_interval);
+        timer.unit = unit;
+        rearm_interval(timer)?;
+
+        if let Some(paused) = &mut self.paused {
+            match pause_timer(timer)? {
+                Some(armed) => paused.insert(*token, armed),
+                None => paused.remove(token),
+            };
+        }
+        info!(
+            "[{}] {} set to {} until the next reload",
+            timer.tag(),
+            key.as_str(),
+            humantime::format_duration(interval)
+        );
+        Ok(unit_status(timer))
+    }
+
+    /// Serves every pending control connection
+    fn handle_control(&mut self) {
+        loop {
+            let accepted = match &self.control {
+                Some(control) => control.accept(),
+                None => return,
+            };
+            match accepted {
+                Ok(Some(stream)) => {
+                    if let Err(e) = self.serve_client(&stream) {
+                        debug!("Control client failed: {}", e);
+                    }
+                }
+                Ok(None) => return,
+                Err(e) => {
+                    error!("Failed to accept control connection: {}", e);
+                    return;
+                }
+            }
+        }
+    }
+
+    fn serve_client(&mut self, mut stream: &UnixStream) -> io::Result<()> {
+        let line = control::read_request(stream)?;
+        let (json, request) = control::parse_request(&line);
+        let response = match request {
+            Ok(request) => self.respond(request),
+            Err(e) => Response::Error(format!("{:#}", e)),
+        };
+        stream.write_all(response.render(json).as_bytes())
+    }
+
+    fn respond(&mut self, request: Request) -> Response {
+        let unknown = |name: &str| Response::Error(format!("No armed unit named {:?}", name));
+
+        match request {
+            Request::List => Response::List(self.unit_statuses()),
+            Request::Status(name) => match self.active_timers.values().find(|t| t.name == name) {
+                Some(timer) => Response::Status(unit_status(timer)),
+                None => unknown(&name),
+            },
+            Request::Trigger(name) => match self.trigger(&name) {
+                Some(decision) => Response::Triggered(decision),
+                None => unknown(&name),
+            },
+            Request::Pause => Response::Done(match self.pause() {
+                true => "paused",
+                false => "already paused",
+            }),
+            Request::Resume => Response::Done(match self.resume() {
+                true => "resumed",
+                false => "not paused",
+            }),
+            Request::Set {
+                name,
+                key,
+                interval,
+            } => match self.set_interval(&name, key, interval) {
+                Ok(status) => Response::Status(status),
+                Err(e) => Response::Error(format!("{:#}", e)),
+            },
+        }
+    }
+
+    /// Whether nothing is left to do: no timer will fire again and no worker runs
+    ///
+    /// Workers count until they have re-armed their `OnUnitInactiveSec` unit.
+    fn finished(&self) -> bool {
+        self.running_workers.load(Ordering::SeqCst) == 0
+            && self.active_timers.iter().all(|(token, timer)| {
+                !self
+                    .paused
+                    .as_ref()
+                    .is_some_and(|paused| paused.contains_key(token))
+                    && timer.time_left().is_none()
+                    && !expiration_pending(&timer.tfd)
+            })
+    }
+
+    /// Status of every armed unit, sorted by name
+    fn unit_statuses(&self) -> Vec<UnitStatus> {
+        let mut units: Vec<UnitStatus> = self.active_timers.values().map(unit_status).collect();
+        units.sort_by(|a, b| a.name.cmp(&b.name));
+        units
+    }
+
+    /// Writes every armed unit's next elapse and last run to the state file
+    fn write_state(&self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+
+        let now = SystemTime::now();
+        let units = self
+            .active_timers
+            .values()
+            .map(|timer| {
+                let last_run = timer.status.lock().unwrap().metrics.last_run;
+                let saved = SavedUnit {
+                    fingerprint: fingerprint(&timer.unit),
+                    next_elapse_ms: timer.time_left().map(|left| to_millis(now + left)),
+                    last_run: last_run.map(SavedRun::new),
+                };
+                (timer.name.clone(), saved)
+            })
+            .collect();
+        let state = SavedState {
+            version: STATE_VERSION,
+            boot_id: boot_nonce().to_string(),
+            units,
+        };
+
+        match state::save_state(path, &state) {
+            Ok(()) => debug!("Saved scheduler state to {:?}", path),
+            Err(e) => error!("Failed to save scheduler state to {:?}: {:#}", path, e),
+        }
+    }
+
+    /// Logs every armed unit's next elapse, last run and whether it is running
+    fn log_schedule(&self) {
+        let units = self.unit_statuses();
+        info!("SIGUSR1 received, {} unit(s) scheduled", units.len());
+        for unit in units {
+            info!(unit = unit.name.as_str(); "{}", unit.summary());
+        }
+    }
+
+    /// Writes the status of every unit to the metrics file, replacing it atomically
+    fn write_metrics(&self) {
+        let Some(path) = &self.metrics_file else {
+            info!("SIGUSR1 received, but no metrics file is set");
+            return;
+        };
+
+        let snapshot = serde_json::to_vec_pretty(&self.unit_statuses()).map_err(io::Error::from);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(".tmp");
+        let result = snapshot.and_then(|snapshot| {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(&tmp, snapshot)?;
+            fs::rename(&tmp, path)
+        });
+        match result {
+            Ok(()) => info!("Wrote metrics to {:?}", path),
+            Err(e) => error!("Failed to write metrics to {:?}: {}", path, e),
+        }
+    }
+
+    fn handle_pending_signals(&mut self) -> Tick {
+        let Some(signal_fd) = self.signal_fd.as_mut() else {
+            return Tick::Continue;
+        };
+        let mut signals = Vec::new();
+        while let Ok(Some(info)) = signal_fd.read_signal() {
+            signals.push(info.ssi_signo as i32);
+        }
+
+        for signo in signals {
+            match Signal::try_from(signo) {
+                Ok(Signal::SIGHUP) => {
+                    info!("SIGHUP received, reloading configuration");
+                    self.reload_config_dir();
+                }
+                Ok(Signal::SIGUSR1) => {
+                    self.log_schedule();
+                    self.write_metrics();
+                }
+                Ok(signal @ (Signal::SIGTERM | Signal::SIGINT)) => {
+                    info!("{} received, shutting down", signal);
+                    return Tick::Shutdown;
+                }
+                _ => {}
+            }
+        }
+        Tick::Continue
+    }
+
+    /// Re-reads the configuration directory and applies the differences
+    fn reload_config_dir(&mut self) {
+        let Some(dir) = self.config_dir.clone() else {
+            info!("No configuration directory is set, nothing to reload");
+            return;
+        };
+
+        match load_timers(&dir, self.defaults_file.as_deref()) {
+            Ok(units) => self.reload(units),
+            Err(e) => error!("Reload failed, keeping current timers: {:#}", e),
+        }
+    }
+
+    /// Stops the scheduler: waits briefly for running commands, kills what is left in
+    /// their units' `CGroup`s, then drops held wakelocks
+    pub fn shutdown(self) {
+        if let Some(watchdog) = &self.watchdog {
+            watchdog.disarm();
+        }
+        let deadline = Instant::now() + SHUTDOWN_GRACE;
+        while self.running_workers.load(Ordering::SeqCst) > 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(100));
+        }
+
+        let still_running = self.running_workers.load(Ordering::SeqCst);
+        if still_running > 0 {
+            info!("{} command(s) still running at shutdown", still_running);
+        }
+        // Other commands outlive the daemon; a cgroup tells which processes to clean up
+        for timer in self.active_timers.values() {
+            if let Some(cgroup) = &timer.unit.cgroup
+                && timer.status.lock().unwrap().running > 0
+            {
+                info!("[{}] killing what is left in {:?}", timer.tag(), cgroup);
+                kill_cgroup(cgroup, Signal::SIGKILL);
+            }
+        }
+
+        self.write_state();
+        self.run_config.wakelocks().release_all();
+    }
+}
+
+/// Snapshot of a timer's state for the control socket
+fn unit_status(timer: &RuntimeTimer) -> UnitStatus {
+    let next_elapse = timer.time_left();
+    let status = timer.status.lock().unwrap();
+    UnitStatus::new(&timer.name, next_elapse, status.running, &status.metrics)
+}
+
+/// Ticks `scheduler` until a shutdown signal arrives, then shuts it down
+pub fn run(mut scheduler: Scheduler) -> Result<()> {
+    info!("Event loop started. Waiting for triggers...");
+
+    while scheduler.tick(None)? == Tick::Continue {}
+
+    scheduler.shutdown();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stamp::{DEFAULT_STAMP_DIR, write_stamp};
+    use std::env;
+
+    fn unit(source: &str) -> TimerUnit {
+        source.parse().unwrap()
+    }
+
+    /// A timer for `source` as `add_timer` builds it, not armed yet
+    fn runtime(name: &str, source: &str) -> RuntimeTimer {
+        let unit = unit(source);
+        let (clock, tfd) = create_timerfd(name, &unit).unwrap();
+        let calendar = unit
+            .on_calendar
+            .iter()
+            .map(|expr| CalendarSpec::parse(expr).unwrap())
+            .collect();
+        RuntimeTimer {
+            name: name.to_string(),
+            unit,
+            tfd: Arc::new(tfd),
+            clock,
+            calendar,
+            next_base: None,
+            armings: 0,
+            status: Arc::default(),
+            wakeup: None,
+        }
+    }
+
+    /// Completed runs of the timer behind `token`
+    fn timer_runs(scheduler: &Scheduler, token: u64) -> u64 {
+        scheduler.active_timers[&token]
+            .status
+            .lock()
+            .unwrap()
+            .metrics
+            .runs
+    }
+
+    #[test]
+    fn exit_when_empty_after_one_shot_fired() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnStartupSec = "1ms"
+            AccuracySec = "0s"
+            WakeLock = false
+            "#,
+        );
+        let mut scheduler = Scheduler::from_units(vec![("once".to_string(), unit)]).unwrap();
+        scheduler.set_exit_when_empty(true);
+        assert!(!scheduler.finished());
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while scheduler.tick(Some(Duration::from_millis(50))).unwrap() == Tick::Continue {
+            assert!(Instant::now() < deadline, "never shut down");
+        }
+        assert_eq!(scheduler.len(), 1);
+    }
+
+    #[test]
+    fn timers_within_one_window_share_a_wakeup() {
+        let units = ["a", "b", "c"].iter().enumerate().map(|(i, name)| {
+            let unit = unit(&format!(
+                r#"
+                Exec = "true"
+                OnStartupSec = "{}ms"
+                AccuracySec = "1h"
+                WakeLock = false
+                "#,
+                i + 1
+            ));
+            (name.to_string(), unit)
+        });
+        let mut scheduler = Scheduler::from_units(units).unwrap();
+        scheduler.group_wakeups();
+
+        assert_eq!(scheduler.wakeups.len(), 1);
+        let (token, wakeup) = scheduler.wakeups.iter().next().unwrap();
+        assert_eq!(wakeup.members.len(), 3);
+        for timer in scheduler.active_timers.values() {
+            assert_eq!(timer.wakeup.map(|(wakeup, _)| wakeup), Some(*token));
+            assert_eq!(time_left(&timer.tfd), None);
+            assert!(timer.time_left().is_some());
+        }
+
+        // Bring the hour-aligned wakeup forward instead of waiting for it
+        wakeup
+            .tfd
+            .set(
+                Expiration::OneShot(TimeSpec::from(ASAP)),
+                TimerSetTimeFlags::empty(),
+            )
+            .unwrap();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while !scheduler
+            .active_timers
+            .values()
+            .all(|timer| timer.status.lock().unwrap().metrics.runs == 1)
+        {
+            assert!(Instant::now() < deadline, "not every member ran");
+            scheduler.tick(Some(Duration::from_millis(50))).unwrap();
+        }
+        assert!(scheduler.wakeups.is_empty());
+    }
+
+    #[test]
+    fn after_dependency_wakes_waiter_when_run_finishes() {
+        let status = Arc::new(SharedRunState::default());
+        status.lock().unwrap().running = 1;
+        let deps = Dependencies {
+            after: vec![("first".to_string(), Arc::clone(&status))],
+            requires: Vec::new(),
+        };
+
+        let finisher = Arc::clone(&status);
+        let started = Instant::now();
+        let handle = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            finisher.lock().unwrap().running = 0;
+            finisher.notify_finished();
+        });
+        assert!(deps.wait("second"));
+        assert!(started.elapsed() < Duration::from_millis(400));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn stable_hash_is_pinned() {
+        // Changing these shifts every seeded RandomizedDelaySec on every device
+        assert_eq!(stable_hash(&[]), 17665956581633026203);
+        assert_eq!(stable_hash(&[b"fcm-hosts"]), 4099972158106479688);
+        assert_ne!(stable_hash(&[b"ab", b"c"]), stable_hash(&[b"a", b"bc"]));
+    }
+
+    #[test]
+    fn unit_seeded_jitter_is_deterministic_and_bounded() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            RandomizedDelaySec = "10min"
+            RandomizedDelaySeed = "Unit"
+            "#,
+        );
+        let first = jitter("fcm-hosts", &unit, 0);
+        assert_eq!(jitter("fcm-hosts", &unit, 1), first);
+        assert!(first <= Duration::from_secs(600));
+        assert_ne!(jitter("other", &unit, 0), first);
+    }
+
+    #[test]
+    fn unseeded_jitter_rerolls_each_arming() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            RandomizedDelaySec = "10min"
+            "#,
+        );
+        let delays: HashSet<Duration> = (0..8).map(|seed| jitter("a", &unit, seed)).collect();
+        assert!(delays.len() > 1);
+        assert!(
+            delays
+                .iter()
+                .all(|delay| *delay <= Duration::from_secs(600))
+        );
+    }
+
+    #[test]
+    fn kernel_interval_keeps_its_grid_when_the_handler_blocks() {
+        let timer = &mut runtime(
+            "grid",
+            r#"
+            Exec = "true"
+            OnStartupSec = "100ms"
+            OnUnitActiveSec = "100ms"
+            AccuracySec = "0s"
+            WakeLock = false
+            "#,
+        );
+        arm_initial(timer, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        assert!(matches!(
+            timer.tfd.get().unwrap(),
+            Some(Expiration::IntervalDelayed(..))
+        ));
+
+        // The first expiration at 100ms is handled 50ms late
+        thread::sleep(Duration::from_millis(150));
+        assert_eq!(read_expirations(&timer.tfd).unwrap(), 1);
+        let left = time_left(&timer.tfd).unwrap();
+        assert!(left <= Duration::from_millis(50), "next in {:?}", left);
+    }
+
+    #[test]
+    fn missed_expirations_coalesce_or_run_each() {
+        let source = r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            OnMissed = "{}"
+        "#;
+        let coalesce = runtime("a", &source.replace("{}", "Coalesce"));
+        assert_eq!(runs_for_expirations(&coalesce, 1), 1);
+        assert_eq!(runs_for_expirations(&coalesce, 3), 1);
+        let run_each = runtime("a", &source.replace("{}", "RunEach"));
+        assert_eq!(runs_for_expirations(&run_each, 3), 3);
+    }
+
+    #[test]
+    fn grid_points_passed_unnoticed_count_as_missed() {
+        let timer = &mut runtime(
+            "grid",
+            r#"
+            Exec = "true"
+            OnStartupSec = "1h"
+            OnUnitActiveSec = "100ms"
+            AccuracySec = "1ms"
+            "#,
+        );
+        let now = clock_now(timer.clock).unwrap();
+        let base = now - TimeSpec::from(Duration::from_millis(350));
+        let interval = Duration::from_millis(100);
+
+        // Points at -250ms, -150ms and -50ms passed; the next one is +50ms
+        assert_eq!(
+            rearm_grid(timer, base, interval, Duration::ZERO).unwrap(),
+            3
+        );
+        let next = timer.next_base.unwrap();
+        assert_eq!(next, base + TimeSpec::from(interval * 4));
+        // Plus up to 1ms of AccuracySec
+        assert!(time_left(&timer.tfd).unwrap() <= Duration::from_millis(51));
+    }
+
+    #[test]
+    fn persistent_catch_up_needs_a_stale_stamp() {
+        let now = SystemTime::now();
+        let hour = Duration::from_secs(3600);
+        let interval = runtime(
+            "every-hour",
+            r#"
+            Exec = "true"
+            OnBootSec = "1min"
+            OnUnitActiveSec = "1h"
+            Persistent = true
+            "#,
+        );
+        assert!(catch_up_due(&interval, now - hour * 2, now));
+        assert!(!catch_up_due(&interval, now - hour / 2, now));
+
+        let daily = runtime(
+            "daily",
+            r#"
+            Exec = "true"
+            OnCalendar = "daily"
+            Persistent = true
+            "#,
+        );
+        assert!(catch_up_due(&daily, now - hour * 25, now));
+        assert!(!catch_up_due(&daily, now - Duration::from_secs(1), now));
+    }
+
+    #[test]
+    fn slow_command_does_not_delay_other_timers() {
+        let slow = unit(
+            r#"
+            Exec = "sleep 1"
+            OnStartupSec = "1ms"
+            AccuracySec = "1ms"
+            WakeLock = false
+            "#,
+        );
+        let fast = unit(
+            r#"
+            Exec = "true"
+            OnStartupSec = "100ms"
+            AccuracySec = "1ms"
+            WakeLock = false
+            "#,
+        );
+        let units = vec![("slow".to_string(), slow), ("fast".to_string(), fast)];
+        let mut scheduler = Scheduler::from_units(units).unwrap();
+        let status = |scheduler: &Scheduler, name: &str| {
+            let timer = scheduler.active_timers.values().find(|t| t.name == name);
+            let state = timer.unwrap().status.lock().unwrap();
+            (state.running, state.metrics.runs)
+        };
+
+        let deadline = Instant::now() + Duration::from_millis(800);
+        while status(&scheduler, "fast").1 == 0 {
+            assert!(Instant::now() < deadline, "fast never ran");
+            scheduler.tick(Some(Duration::from_millis(20))).unwrap();
+        }
+        assert_eq!(status(&scheduler, "slow"), (1, 0));
+    }
+
+    #[test]
+    fn reload_diff_sorts_units_into_added_removed_and_changed() {
+        let hourly = unit("Exec = \"true\"\nOnBootSec = \"1h\"");
+        let daily = unit("Exec = \"true\"\nOnBootSec = \"1d\"");
+        let current = HashMap::from([("kept", &hourly), ("edited", &hourly), ("gone", &hourly)]);
+        let loaded = vec![
+            ("kept".to_string(), hourly.clone()),
+            ("edited".to_string(), daily.clone()),
+            ("new".to_string(), daily.clone()),
+        ];
+
+        let diff = diff_units(&current, loaded);
+        let names = |units: &[(String, TimerUnit)]| -> Vec<String> {
+            units.iter().map(|(name, _)| name.clone()).collect()
+        };
+        assert_eq!(names(&diff.added), ["new"]);
+        assert_eq!(diff.removed, ["gone"]);
+        assert_eq!(names(&diff.changed), ["edited"]);
+        assert_eq!(diff.changed[0].1, daily);
+    }
+
+    #[test]
+    fn busy_unit_skips_queues_once_or_runs_alongside() {
+        let source = r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            Concurrency = "{}"
+        "#;
+        let now = Instant::now();
+        let decide = |concurrency: &str| {
+            let unit = unit(&source.replace("{}", concurrency));
+            let mut state = RunState::default();
+            let decisions = [(); 3].map(|()| decide_firing(&mut state, &unit, now));
+            (decisions, state.running, state.queued)
+        };
+
+        use FireDecision::*;
+        assert_eq!(decide("Skip"), ([Start, Skip, Skip], 1, false));
+        // However many firings come in during the run, one deferred run is left
+        assert_eq!(decide("Queue"), ([Start, Queue, Queue], 1, true));
+        assert_eq!(decide("Parallel"), ([Start, Start, Start], 3, false));
+    }
+
+    #[test]
+    fn disabled_unit_is_parsed_but_not_armed() {
+        let on = unit("Exec = \"true\"\nOnBootSec = \"1h\"\nWakeLock = false");
+        let off = unit("Exec = \"true\"\nOnBootSec = \"1h\"\nWakeLock = false\nEnabled = false");
+        assert!(!off.enabled);
+
+        let mut scheduler = Scheduler::from_units(vec![
+            ("on".to_string(), on.clone()),
+            ("off".to_string(), off.clone()),
+        ])
+        .unwrap();
+        let armed = |scheduler: &Scheduler| -> Vec<String> {
+            scheduler
+                .active_timers
+                .values()
+                .map(|timer| timer.name.clone())
+                .collect()
+        };
+        assert_eq!(armed(&scheduler), ["on"]);
+
+        // Disabling an armed unit on reload stops its timer
+        scheduler.reload(vec![("on".to_string(), off), ("off".to_string(), on)]);
+        assert_eq!(armed(&scheduler), ["off"]);
+    }
+
+    #[test]
+    fn close_targets_align_to_the_same_armed_instant() {
+        let window = Duration::from_secs(60);
+        let boundary = TimeSpec::new(60 * 1000, 0);
+        assert_eq!(align(boundary, window), boundary);
+        assert_eq!(
+            align(boundary + TimeSpec::new(0, 1), window),
+            boundary + TimeSpec::from(window)
+        );
+        assert_eq!(
+            align(boundary + TimeSpec::new(1, 0), Duration::ZERO),
+            boundary + TimeSpec::new(1, 0)
+        );
+
+        // Two timers due 10s and 40s after the next boundary both fire at the one after it
+        let now = clock_now(ClockId::CLOCK_MONOTONIC).unwrap();
+        let start = align(now, window);
+        let tfds = [10, 40].map(|offset| {
+            let tfd = TimerFd::new(ClockId::CLOCK_MONOTONIC, TimerFlags::empty()).unwrap();
+            let target = start + TimeSpec::new(offset, 0);
+            arm_tfd_at(&tfd, ClockId::CLOCK_MONOTONIC, target, window).unwrap();
+            tfd
+        });
+        let left = tfds.map(|tfd| match tfd.get().unwrap() {
+            Some(Expiration::OneShot(left)) => Duration::from(left),
+            other => panic!("unexpected arming {:?}", other),
+        });
+        let expected = Duration::from(start + TimeSpec::from(window) - now);
+        for left in left {
+            assert!(left <= expected && expected - left < Duration::from_millis(100));
+        }
+    }
+
+    #[test]
+    fn stale_token_of_a_removed_timer_reaches_no_other_unit() {
+        let hourly =
+            unit("Exec = \"true\"\nOnBootSec = \"1h\"\nAccuracySec = \"0s\"\nWakeLock = false");
+        let mut scheduler =
+            Scheduler::from_units(vec![("old".to_string(), hourly.clone())]).unwrap();
+        let token = |scheduler: &Scheduler, name: &str| {
+            scheduler
+                .active_timers
+                .iter()
+                .find_map(|(token, timer)| (timer.name == name).then_some(*token))
+                .unwrap()
+        };
+        let old = token(&scheduler, "old");
+        assert!(scheduler.remove_timer("old"));
+        scheduler.add_timer("new", hourly).unwrap();
+        let new = token(&scheduler, "new");
+        assert_ne!(old, new);
+
+        // The new timerfd may well reuse the old fd number; the token still tells them apart
+        let timer = &scheduler.active_timers[&new];
+        timer
+            .tfd
+            .set(
+                Expiration::OneShot(TimeSpec::from(ASAP)),
+                TimerSetTimeFlags::empty(),
+            )
+            .unwrap();
+        thread::sleep(Duration::from_millis(10));
+        scheduler.handle_event(old);
+        assert_eq!(timer_runs(&scheduler, new), 0);
+
+        scheduler.handle_event(new);
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while timer_runs(&scheduler, new) == 0 {
+            assert!(Instant::now() < deadline, "the new unit never ran");
+            scheduler.tick(Some(Duration::from_millis(50))).unwrap();
+        }
+    }
+
+    #[test]
+    fn epoll_errors_retry_back_off_or_stop_the_loop() {
+        assert_eq!(classify_epoll_error(Errno::EINTR), EpollAction::Retry);
+        for err in [Errno::ENOMEM, Errno::EAGAIN, Errno::EIO] {
+            assert_eq!(classify_epoll_error(err), EpollAction::Backoff, "{}", err);
+        }
+        for err in [Errno::EBADF, Errno::EINVAL, Errno::EFAULT] {
+            assert_eq!(classify_epoll_error(err), EpollAction::Fatal, "{}", err);
+        }
+    }
+
+    #[test]
+    fn wake_system_selects_the_alarm_clocks() {
+        let clock = |source: &str| timer_clock(&unit(&format!("Exec = \"true\"\n{}", source)));
+        assert_eq!(clock("OnBootSec = \"1h\""), ClockId::CLOCK_BOOTTIME);
+        assert_eq!(
+            clock("OnBootSec = \"1h\"\nWakeSystem = true"),
+            ClockId::CLOCK_BOOTTIME_ALARM
+        );
+        assert_eq!(clock("OnCalendar = \"daily\""), ClockId::CLOCK_REALTIME);
+        assert_eq!(
+            clock("OnCalendar = \"daily\"\nWakeSystem = true"),
+            ClockId::CLOCK_REALTIME_ALARM
+        );
+        assert_eq!(
+            without_alarm(ClockId::CLOCK_BOOTTIME_ALARM),
+            ClockId::CLOCK_BOOTTIME
+        );
+        assert_eq!(
+            without_alarm(ClockId::CLOCK_REALTIME_ALARM),
+            ClockId::CLOCK_REALTIME
+        );
+    }
+
+    #[test]
+    fn clock_field_picks_the_timerfd_clock() {
+        let clock = |source: &str| timer_clock(&unit(&format!("Exec = \"true\"\n{}", source)));
+        assert_eq!(
+            clock("OnBootSec = \"1h\"\nClock = \"BootTime\""),
+            ClockId::CLOCK_BOOTTIME
+        );
+        assert_eq!(
+            clock("OnBootSec = \"1h\"\nClock = \"Monotonic\""),
+            ClockId::CLOCK_MONOTONIC
+        );
+        assert_eq!(
+            clock("OnBootSec = \"1h\"\nClock = \"Realtime\""),
+            ClockId::CLOCK_REALTIME
+        );
+        // Calendar units count wall time unless told otherwise
+        assert_eq!(
+            clock("OnCalendar = \"daily\"\nClock = \"BootTime\""),
+            ClockId::CLOCK_BOOTTIME
+        );
+        assert!(
+            "Exec = \"true\"\nOnBootSec = \"1h\"\nClock = \"Monotonic\"\nWakeSystem = true"
+                .parse::<TimerUnit>()
+                .is_err()
+        );
+    }
+
+    #[test]
+    fn elapsed_boot_offset_fires_right_away() {
+        let unit = |source: &str| unit(&format!("Exec = \"true\"\n{}", source));
+        let now = Local::now();
+        assert_eq!(
+            next_elapse(&unit("OnBootSec = \"1ms\""), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            next_elapse(&unit("OnBootSec = \"1ms\"\nOnStartupSec = \"5min\""), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(
+            next_elapse(&unit("OnStartupSec = \"5min\""), now),
+            Some(Duration::from_secs(300))
+        );
+        let ahead = uptime() + Duration::from_secs(3600);
+        let delay = next_elapse(&unit(&format!("OnBootSec = \"{}s\"", ahead.as_secs())), now);
+        assert!(delay.is_some_and(|delay| delay > Duration::from_secs(3590)));
+
+        let mut timer = runtime(
+            "late",
+            "Exec = \"true\"\nOnBootSec = \"1ms\"\nAccuracySec = \"0s\"\nWakeLock = false",
+        );
+        assert_eq!(
+            arm_initial(&mut timer, Path::new(DEFAULT_STAMP_DIR)).unwrap(),
+            Some(Duration::ZERO)
+        );
+        thread::sleep(Duration::from_millis(5));
+        assert_eq!(read_expirations(&timer.tfd).unwrap(), 1);
+    }
+
+    #[test]
+    fn inactive_interval_counts_from_the_end_of_the_run() {
+        let log = env::temp_dir().join(format!("micetimer-inactive-{}", std::process::id()));
+        let _ = fs::remove_file(&log);
+        let unit = unit(&format!(
+            r#"
+            Exec = "date +%s%N >> {}; sleep 0.05"
+            OnStartupSec = "10ms"
+            OnUnitInactiveSec = "100ms"
+            AccuracySec = "0s"
+            WakeLock = false
+            "#,
+            log.display()
+        ));
+        let mut scheduler = Scheduler::from_units(vec![("inactive".to_string(), unit)]).unwrap();
+        let starts = || -> Vec<u64> {
+            fs::read_to_string(&log)
+                .unwrap_or_default()
+                .lines()
+                .map(|line| line.parse().unwrap())
+                .collect()
+        };
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while starts().len() < 2 {
+            assert!(Instant::now() < deadline, "the unit wasn't re-armed");
+            scheduler.tick(Some(Duration::from_millis(10))).unwrap();
+        }
+        let starts = starts();
+        fs::remove_file(&log).unwrap();
+
+        // 50ms of running, then 100ms of inactivity
+        let gap = Duration::from_nanos(starts[1] - starts[0]);
+        assert!(
+            gap >= Duration::from_millis(145),
+            "re-armed after {:?}",
+            gap
+        );
+        assert!(gap < Duration::from_millis(400), "re-armed after {:?}", gap);
+
+        let both = "Exec = \"true\"\nOnUnitActiveSec = \"1h\"\nOnUnitInactiveSec = \"1h\"";
+        assert!(both.parse::<TimerUnit>().is_err());
+    }
+
+    #[test]
+    fn start_limit_refuses_firings_until_the_window_clears() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            StartLimitBurst = 3
+            StartLimitIntervalSec = "10s"
+            "#,
+        );
+        let mut state = RunState::default();
+        let start = Instant::now();
+        let hits: Vec<bool> = (0..5)
+            .map(|i| start_limit_hit(&mut state, &unit, start + Duration::from_millis(i)))
+            .collect();
+        assert_eq!(hits, [false, false, false, true, true]);
+
+        // The first start leaves the window 10s after it
+        assert!(start_limit_hit(
+            &mut state,
+            &unit,
+            start + Duration::from_millis(9999)
+        ));
+        assert!(!start_limit_hit(
+            &mut state,
+            &unit,
+            start + Duration::from_secs(10)
+        ));
+        assert!(start_limit_hit(
+            &mut state,
+            &unit,
+            start + Duration::from_secs(10)
+        ));
+
+        let unlimited = TimerUnit {
+            start_limit_burst: 0,
+            ..unit
+        };
+        let mut state = RunState::default();
+        assert!((0..100).all(|_| !start_limit_hit(&mut state, &unlimited, start)));
+    }
+
+    #[test]
+    fn metrics_count_a_success_and_a_failure() {
+        let marker = env::temp_dir().join(format!("micetimer-metrics-{}", std::process::id()));
+        let _ = fs::remove_file(&marker);
+        // Succeeds the first time, then fails with 4
+        let unit = unit(&format!(
+            r#"
+            Exec = "[ -e {0} ] && exit 4; touch {0}"
+            OnBootSec = "1h"
+            WakeLock = false
+            "#,
+            marker.display()
+        ));
+        let mut scheduler = Scheduler::from_units(vec![("counted".to_string(), unit)]).unwrap();
+        let token = *scheduler.active_timers.keys().next().unwrap();
+
+        for runs in 1..=2 {
+            let triggered = scheduler.respond(Request::Trigger("counted".to_string()));
+            assert!(matches!(
+                triggered,
+                Response::Triggered(FireDecision::Start)
+            ));
+            let deadline = Instant::now() + Duration::from_secs(5);
+            let running = |scheduler: &Scheduler| {
+                scheduler.active_timers[&token]
+                    .status
+                    .lock()
+                    .unwrap()
+                    .running
+            };
+            while timer_runs(&scheduler, token) < runs || running(&scheduler) > 0 {
+                assert!(Instant::now() < deadline, "run {} didn't finish", runs);
+                scheduler.tick(Some(Duration::from_millis(20))).unwrap();
+            }
+        }
+        fs::remove_file(&marker).unwrap();
+
+        let Response::Status(status) = scheduler.respond(Request::Status("counted".to_string()))
+        else {
+            panic!("no status for the unit");
+        };
+        assert_eq!((status.runs, status.successes, status.failures), (2, 1, 1));
+        assert_eq!(status.last_exit_code, Some(4));
+        assert_eq!(status.last_result, Some("failure"));
+        assert!(status.last_duration_sec.is_some() && status.last_run.is_some());
+        assert_eq!(status.running, 0);
+    }
+
+    #[test]
+    fn clock_change_recomputes_calendar_units_only() {
+        // As left behind by a jump: armed for an occurrence that is now far off
+        let stale = clock_now(ClockId::CLOCK_REALTIME).unwrap() + TimeSpec::new(10 * 86400, 0);
+
+        let mut calendar = runtime(
+            "hourly",
+            "Exec = \"true\"\nOnCalendar = \"hourly\"\nAccuracySec = \"0s\"\nWakeLock = false",
+        );
+        assert_eq!(calendar.clock, ClockId::CLOCK_REALTIME);
+        arm_at(&calendar, stale).unwrap();
+        rearm_after_clock_change(&mut calendar, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        let next = next_elapse(&calendar.unit, Local::now()).unwrap();
+        let left = time_left(&calendar.tfd).unwrap();
+        assert!(left <= next + Duration::from_secs(1) && next <= left + Duration::from_secs(1));
+
+        // A wall-clock interval keeps the time it had left
+        let mut interval = runtime(
+            "interval",
+            "Exec = \"true\"\nOnBootSec = \"1h\"\nClock = \"Realtime\"\nAccuracySec = \"0s\"\nWakeLock = false",
+        );
+        arm_at(
+            &interval,
+            clock_now(ClockId::CLOCK_REALTIME).unwrap() + TimeSpec::new(3600, 0),
+        )
+        .unwrap();
+        rearm_after_clock_change(&mut interval, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        let left = time_left(&interval.tfd).unwrap();
+        assert!(left <= Duration::from_secs(3600) && left > Duration::from_secs(3590));
+    }
+
+    #[test]
+    fn failed_or_unscheduled_requirement_skips_the_run() {
+        let requiring = |last_run: Option<bool>| {
+            let status = Arc::new(SharedRunState::default());
+            status.lock().unwrap().metrics.last_run =
+                last_run.map(|success| (SystemTime::now(), success));
+            Dependencies {
+                after: Vec::new(),
+                requires: vec![("first".to_string(), Some(status))],
+            }
+        };
+        assert!(requiring(None).wait("second"));
+        assert!(requiring(Some(true)).wait("second"));
+        assert!(!requiring(Some(false)).wait("second"));
+
+        let unscheduled = Dependencies {
+            after: Vec::new(),
+            requires: vec![("missing".to_string(), None)],
+        };
+        assert!(!unscheduled.wait("second"));
+    }
+
+    #[test]
+    fn saved_schedule_resumes_unless_the_unit_changed() {
+        let path = env::temp_dir().join(format!("micetimer-state-{}.json", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let hourly = unit(
+            "Exec = \"true\"\nOnStartupSec = \"1h\"\nOnUnitActiveSec = \"1h\"\nWakeLock = false",
+        );
+        let armed_left = |scheduler: &Scheduler, name: &str| {
+            scheduler
+                .active_timers
+                .values()
+                .find(|timer| timer.name == name)
+                .and_then(RuntimeTimer::time_left)
+                .unwrap()
+        };
+
+        let mut first = Scheduler::new().unwrap();
+        first.set_state_file(&path).unwrap();
+        first.add_timer("kept", hourly.clone()).unwrap();
+        first.add_timer("edited", hourly.clone()).unwrap();
+        first.write_state();
+        drop(first);
+
+        // Round trip, with both units due in ten minutes instead of an hour
+        let mut saved = state::load_state(&path).unwrap();
+        assert_eq!(saved.version, state::STATE_VERSION);
+        assert_eq!(saved.units.len(), 2);
+        let soon = state::to_millis(SystemTime::now() + Duration::from_secs(600));
+        for unit in saved.units.values_mut() {
+            assert!(unit.next_elapse_ms.is_some());
+            unit.next_elapse_ms = Some(soon);
+        }
+        state::save_state(&path, &saved).unwrap();
+
+        let mut second = Scheduler::new().unwrap();
+        second.set_state_file(&path).unwrap();
+        second.add_timer("kept", hourly.clone()).unwrap();
+        let edited = TimerUnit {
+            on_unit_active_sec: Some(Duration::from_secs(7200)),
+            ..hourly
+        };
+        second.add_timer("edited", edited).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let kept = armed_left(&second, "kept");
+        assert!(kept <= Duration::from_secs(600) && kept > Duration::from_secs(590));
+        // A changed configuration plans its first elapse afresh
+        assert!(armed_left(&second, "edited") > Duration::from_secs(600));
+    }
+
+    #[test]
+    fn failure_backoff_grows_to_the_cap_and_resets_on_success() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnUnitActiveSec = "10min"
+            FailureBackoff = { MaxInterval = "1h", Multiplier = 2.0 }
+            "#,
+        );
+        let minutes = |minutes: u64| Some(Duration::from_secs(minutes * 60));
+        let mut state = RunState::default();
+        let mut run = |success: bool| failure_backoff("flaky", &unit, &mut state, success);
+
+        assert_eq!(run(false), minutes(20));
+        assert_eq!(run(false), minutes(40));
+        assert_eq!(run(false), minutes(60));
+        assert_eq!(run(false), minutes(60));
+        // Success returns to the configured interval, and the next failure starts over
+        assert_eq!(run(true), None);
+        assert_eq!(run(false), minutes(20));
+        assert_eq!(unit.on_unit_active_sec, minutes(10));
+    }
+
+    #[test]
+    fn burst_of_expirations_drains_in_one_tick() {
+        let units = (0..20).map(|i| {
+            let unit = unit(
+                r#"
+                Exec = "sleep 0.2"
+                OnStartupSec = "1ms"
+                AccuracySec = "0s"
+                WakeLock = false
+                "#,
+            );
+            (format!("burst-{:02}", i), unit)
+        });
+        let mut scheduler = Scheduler::from_units(units).unwrap();
+        thread::sleep(Duration::from_millis(20));
+
+        scheduler.tick(Some(Duration::ZERO)).unwrap();
+        let running = scheduler
+            .active_timers
+            .values()
+            .filter(|timer| timer.status.lock().unwrap().running == 1)
+            .count();
+        assert_eq!(running, 20);
+    }
+
+    #[test]
+    fn device_seeds_spread_units_reproducibly() {
+        let max = Duration::from_secs(3600);
+        let offset = |device: &str| seeded_offset(max, device, "fcm-hosts", [0; 8]);
+        let first = offset("3d1219c7c4c5404a");
+        let second = offset("R58M1234ABC");
+        assert_eq!(offset("3d1219c7c4c5404a"), first);
+        assert_eq!(offset("R58M1234ABC"), second);
+        assert_ne!(first, second);
+        assert!(first <= max && second <= max);
+
+        // Device-seeded units keep their offset across armings
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            RandomizedDelaySec = "1h"
+            RandomizedDelaySeed = "Device"
+            "#,
+        );
+        assert_eq!(
+            jitter("fcm-hosts", &unit, 0),
+            seeded_offset(max, device_id(), "fcm-hosts", [0; 8])
+        );
+        assert_eq!(jitter("fcm-hosts", &unit, 5), jitter("fcm-hosts", &unit, 0));
+    }
+
+    #[test]
+    fn calendar_units_arm_absolute_wall_clock_instants() {
+        let mut timer = runtime(
+            "daily",
+            "Exec = \"true\"\nOnCalendar = \"03:00\"\nAccuracySec = \"0s\"\nWakeLock = false",
+        );
+        assert_eq!(timer.clock, ClockId::CLOCK_REALTIME);
+        assert_eq!(
+            absolute_flags(timer.clock),
+            TimerSetTimeFlags::TFD_TIMER_ABSTIME | TimerSetTimeFlags::TFD_TIMER_CANCEL_ON_SET
+        );
+        // Boot and interval units arm absolute instants too, but no clock jump affects them
+        assert_eq!(
+            absolute_flags(ClockId::CLOCK_BOOTTIME),
+            TimerSetTimeFlags::TFD_TIMER_ABSTIME
+        );
+
+        // The armed instant is the next occurrence itself, not a delay computed earlier
+        let now = Local::now();
+        let next = calendar::next_occurrence_of(&timer.calendar, now).unwrap();
+        arm_initial(&mut timer, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        let left = time_left(&timer.tfd).unwrap();
+        let expected = (next - Local::now()).to_std().unwrap();
+        assert!(left <= expected + Duration::from_millis(50));
+        assert!(expected <= left + Duration::from_millis(50));
+    }
+
+    #[test]
+    fn condition_skip_records_its_reason_and_time() {
+        let unit = unit(
+            r#"
+            Exec = "true"
+            OnBootSec = "1h"
+            ConditionPathExists = "/nonexistent/micetimer"
+            WakeLock = false
+            "#,
+        );
+        let mut scheduler = Scheduler::from_units(vec![("gated".to_string(), unit)]).unwrap();
+        let token = *scheduler.active_timers.keys().next().unwrap();
+        let before = SystemTime::now();
+
+        let triggered = scheduler.respond(Request::Trigger("gated".to_string()));
+        assert!(matches!(
+            triggered,
+            Response::Triggered(FireDecision::Start)
+        ));
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let last_skip = |scheduler: &Scheduler| {
+            scheduler.active_timers[&token]
+                .status
+                .lock()
+                .unwrap()
+                .metrics
+                .last_skip
+        };
+        while last_skip(&scheduler).is_none() {
+            assert!(Instant::now() < deadline, "the firing was never skipped");
+            scheduler.tick(Some(Duration::from_millis(20))).unwrap();
+        }
+
+        let (at, reason) = last_skip(&scheduler).unwrap();
+        assert_eq!(reason, SkipReason::Condition);
+        assert!(at >= before && at <= SystemTime::now());
+        assert_eq!(timer_runs(&scheduler, token), 0);
+
+        let Response::Status(status) = scheduler.respond(Request::Status("gated".to_string()))
+        else {
+            panic!("no status for the unit");
+        };
+        assert_eq!(status.last_skip_reason, Some("condition"));
+        assert!(status.last_skip.is_some());
+        assert_eq!(status.runs, 0);
+    }
+
+    #[test]
+    fn max_concurrent_runs_simultaneous_firings_serially() {
+        let log = env::temp_dir().join(format!("micetimer-serial-{}.log", std::process::id()));
+        let _ = fs::remove_file(&log);
+        let units = ["one", "two", "three"].map(|name| {
+            let unit = unit(&format!(
+                r#"
+                Exec = "echo start >> {0}; sleep 0.1; echo end >> {0}"
+                OnBootSec = "1h"
+                WakeLock = false
+                "#,
+                log.display()
+            ));
+            (name.to_string(), unit)
+        });
+        let mut scheduler = Scheduler::from_units(units.to_vec()).unwrap();
+        scheduler.set_max_concurrent(NonZeroUsize::new(1));
+        let tokens: Vec<u64> = scheduler.active_timers.keys().copied().collect();
+
+        for (name, _) in &units {
+            let triggered = scheduler.respond(Request::Trigger(name.clone()));
+            assert!(matches!(
+                triggered,
+                Response::Triggered(FireDecision::Start)
+            ));
+        }
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while tokens
+            .iter()
+            .any(|&token| timer_runs(&scheduler, token) < 1)
+        {
+            assert!(Instant::now() < deadline, "the firings didn't all finish");
+            scheduler.tick(Some(Duration::from_millis(20))).unwrap();
+        }
+
+        // Each run ended before the next one started
+        let lines = fs::read_to_string(&log).unwrap();
+        fs::remove_file(&log).unwrap();
+        assert_eq!(lines, "start\nend\n".repeat(3));
+    }
+
+    #[test]
+    fn run_on_start_fires_once_right_after_arming() {
+        let source = "Exec = \"true\"\nOnStartupSec = \"1d\"\nWakeLock = false";
+        let mut scheduler = Scheduler::from_units(vec![
+            (
+                "eager".to_string(),
+                unit(&format!("{}\nRunOnStart = true", source)),
+            ),
+            ("patient".to_string(), unit(source)),
+        ])
+        .unwrap();
+        let token = |name: &str| {
+            scheduler
+                .active_timers
+                .iter()
+                .find_map(|(token, timer)| (timer.name == name).then_some(*token))
+                .unwrap()
+        };
+        let (eager, patient) = (token("eager"), token("patient"));
+
+        let deadline = Instant::now() + Duration::from_secs(5);
+        while timer_runs(&scheduler, eager) < 1 {
+            assert!(Instant::now() < deadline, "no run at start");
+            scheduler.tick(Some(Duration::from_millis(20))).unwrap();
+        }
+        assert_eq!(timer_runs(&scheduler, patient), 0);
+        // The regular schedule is still ahead
+        let left = scheduler.active_timers[&eager].time_left().unwrap();
+        assert!(left > Duration::from_secs(86_000), "{:?}", left);
+    }
+
+    #[test]
+    fn calendar_list_arms_to_its_earliest_entry() {
+        let now = Local::now();
+        let clock = |hours| {
+            (now + chrono::Duration::hours(hours))
+                .format("%H:%M")
+                .to_string()
+        };
+        let (later, earlier) = (clock(5), clock(2));
+        let mut timer = runtime(
+            "twice",
+            &format!(
+                "Exec = \"true\"\nOnCalendar = [{:?}, {:?}]\nAccuracySec = \"0s\"\nWakeLock = false",
+                later, earlier
+            ),
+        );
+        assert_eq!(timer.calendar.len(), 2);
+
+        let first = CalendarSpec::parse(&earlier).unwrap();
+        let next = calendar::next_occurrence_of(&[first], now).unwrap();
+        arm_initial(&mut timer, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        let left = time_left(&timer.tfd).unwrap();
+        let expected = (next - Local::now()).to_std().unwrap();
+        assert!(left < Duration::from_secs(3 * 3600), "{:?}", left);
+        assert!(left <= expected + Duration::from_millis(50));
+        assert!(expected <= left + Duration::from_millis(50));
+    }
+
+    #[test]
+    fn removing_the_last_timer_exits_only_when_asked() {
+        let daily = "Exec = \"true\"\nOnBootSec = \"1d\"\nWakeLock = false";
+        let mut scheduler = Scheduler::from_units(vec![("last".to_string(), unit(daily))]).unwrap();
+        scheduler.set_exit_when_empty(true);
+        let tick = |scheduler: &mut Scheduler| scheduler.tick(Some(Duration::from_millis(10)));
+        assert_eq!(tick(&mut scheduler).unwrap(), Tick::Continue);
+
+        assert!(scheduler.remove_timer("last"));
+        assert_eq!(tick(&mut scheduler).unwrap(), Tick::Shutdown);
+
+        let mut waiting = Scheduler::from_units(vec![("last".to_string(), unit(daily))]).unwrap();
+        assert!(waiting.remove_timer("last"));
+        assert_eq!(tick(&mut waiting).unwrap(), Tick::Continue);
+    }
+
+    #[test]
+    fn boot_grace_pushes_out_only_earlier_first_elapses() {
+        // A grace ending 30s from now, as on a device that booted just now
+        let mut scheduler = Scheduler::new().unwrap();
+        scheduler.set_boot_grace(uptime() + Duration::from_secs(30));
+        let late_boot = uptime().as_secs() + 3600;
+        scheduler
+            .add_timer(
+                "early",
+                unit("Exec = \"true\"\nOnBootSec = \"1s\"\nAccuracySec = \"0s\"\nWakeLock = false"),
+            )
+            .unwrap();
+        scheduler
+            .add_timer(
+                "late",
+                unit(&format!(
+                    "Exec = \"true\"\nOnBootSec = \"{}s\"\nWakeLock = false",
+                    late_boot
+                )),
+            )
+            .unwrap();
+        let left = |name: &str| {
+            scheduler
+                .active_timers
+                .values()
+                .find(|timer| timer.name == name)
+                .and_then(RuntimeTimer::time_left)
+                .unwrap()
+        };
+
+        let early = left("early");
+        assert!(early <= Duration::from_secs(30), "{:?}", early);
+        assert!(early > Duration::from_secs(29), "{:?}", early);
+        let late = left("late");
+        assert!(late > Duration::from_secs(3590), "{:?}", late);
+    }
+
+    #[test]
+    fn resume_rearms_with_the_time_left_at_pause() {
+        let mut scheduler = Scheduler::from_units(vec![(
+            "paused".to_string(),
+            unit("Exec = \"true\"\nOnStartupSec = \"10s\"\nAccuracySec = \"0s\"\nWakeLock = false"),
+        )])
+        .unwrap();
+        let token = *scheduler.active_timers.keys().next().unwrap();
+        let left = |scheduler: &Scheduler| scheduler.active_timers[&token].time_left();
+
+        let before = left(&scheduler).unwrap();
+        assert!(scheduler.pause());
+        assert!(!scheduler.pause());
+        assert_eq!(left(&scheduler), None);
+
+        // Time spent paused doesn't count against the delay
+        thread::sleep(Duration::from_millis(300));
+        assert!(scheduler.resume());
+        assert!(!scheduler.resume());
+        let after = left(&scheduler).unwrap();
+        assert!(after <= before, "{:?} > {:?}", after, before);
+        assert!(after + Duration::from_millis(100) > before, "{:?}", after);
+    }
+
+    #[test]
+    fn calendar_jitter_is_bounded_and_stays_before_the_next_slot() {
+        use chrono::TimeZone;
+
+        let now = Local.with_ymd_and_hms(2026, 3, 4, 1, 0, 0).unwrap();
+        let delay = Duration::from_secs(2 * 3600); // to 03:00
+        let specs = [CalendarSpec::parse("03:00").unwrap()];
+        let day = Duration::from_secs(86_400);
+        let nightly = |window: &str| {
+            unit(&format!(
+                "Exec = \"true\"\nOnCalendar = \"03:00\"\nRandomizedDelaySec = \"{}\"",
+                window
+            ))
+        };
+
+        // Each arming rolls its own offset within the window
+        let spread = nightly("30min");
+        let offsets: HashSet<Duration> = (0..20)
+            .map(|seed| {
+                let offset = jitter("nightly", &spread, seed);
+                assert!(offset <= Duration::from_secs(1800), "{:?}", offset);
+                assert_eq!(bound_calendar_jitter(&specs, now, delay, offset), offset);
+                offset
+            })
+            .collect();
+        assert!(offsets.len() > 1);
+
+        // A window wider than the day wraps within it instead of reaching the next 03:00
+        let wide = nightly("36h");
+        for seed in 0..20 {
+            let offset = jitter("nightly", &wide, seed);
+            let bounded = bound_calendar_jitter(&specs, now, delay, offset);
+            assert!(bounded < day, "{:?} from {:?}", bounded, offset);
+        }
+        let wrapped = bound_calendar_jitter(&specs, now, delay, Duration::from_secs(25 * 3600));
+        assert_eq!(wrapped, Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn tiny_intervals_are_clamped_to_the_floor_with_a_warning() {
+        let logs = crate::exec::tests::captured_logs();
+        let mut runaway = unit("Exec = \"true\"\nOnBootSec = \"1h\"\nOnUnitActiveSec = \"1ms\"");
+        clamp_intervals("runaway", &mut runaway, Duration::from_secs(1));
+        assert_eq!(runaway.on_unit_active_sec, Some(Duration::from_secs(1)));
+        let mut slow = unit("Exec = \"true\"\nOnBootSec = \"1h\"\nOnUnitInactiveSec = \"2s\"");
+        clamp_intervals("slow", &mut slow, Duration::from_secs(1));
+        assert_eq!(slow.on_unit_inactive_sec, Some(Duration::from_secs(2)));
+
+        let logs = logs.lock().unwrap();
+        let warnings: Vec<&String> = logs
+            .iter()
+            .filter(|line| line.starts_with("WARN [runaway]") || line.starts_with("WARN [slow]"))
+            .collect();
+        assert_eq!(
+            warnings,
+            ["WARN [runaway] OnUnitActiveSec 1ms is below the minimum interval, using 1s"]
+        );
+    }
+
+    #[test]
+    fn default_seeded_units_arm_apart() {
+        let source = r#"
+            Exec = "true"
+            OnBootSec = "1d"
+            RandomizedDelaySec = "1d"
+            AccuracySec = "0s"
+            WakeLock = false
+            "#;
+        let scheduler = Scheduler::from_units([
+            ("first".to_string(), unit(source)),
+            ("second".to_string(), unit(source)),
+        ])
+        .unwrap();
+
+        let armed = |name: &str| {
+            let timer = scheduler
+                .active_timers
+                .values()
+                .find(|timer| timer.name == name)
+                .unwrap();
+            match timer.tfd.get().unwrap() {
+                Some(Expiration::OneShot(at)) => Duration::from(at),
+                other => panic!("[{}] armed as {:?}", name, other),
+            }
+        };
+        let (first, second) = (armed("first"), armed("second"));
+
+        // Same base delay, so the gap is exactly the difference of the two seeds
+        let unit = unit(source);
+        let expected = jitter("first", &unit, 0).abs_diff(jitter("second", &unit, 0));
+        assert!(expected > Duration::from_secs(1), "{:?}", expected);
+        let gap = first.abs_diff(second);
+        assert!(
+            gap.abs_diff(expected) < Duration::from_millis(500),
+            "{:?} vs {:?}",
+            gap,
+            expected
+        );
+    }
+
+    #[test]
+    fn default_accuracy_interval_rearms_on_an_aligned_grid() {
+        let timer = &mut runtime(
+            "aligned",
+            r#"
+            Exec = "true"
+            OnStartupSec = "1h"
+            OnUnitActiveSec = "10min"
+            WakeLock = false
+            "#,
+        );
+        arm_initial(timer, Path::new(DEFAULT_STAMP_DIR)).unwrap();
+        assert!(matches!(
+            timer.tfd.get().unwrap(),
+            Some(Expiration::OneShot(_))
+        ));
+        assert!(timer.next_base.is_some());
+
+        // Handled 25min after the grid's origin: the points at 10min and 20min passed
+        let minute = Duration::from_secs(60);
+        let interval = minute * 10;
+        let base = clock_now(timer.clock).unwrap() - TimeSpec::from(minute * 25);
+        assert_eq!(
+            rearm_grid(timer, base, interval, Duration::ZERO).unwrap(),
+            2
+        );
+        let next = timer.next_base.unwrap();
+        assert_eq!(next, base + TimeSpec::from(interval * 3));
+        // The point 5min out, rounded up to the default minute
+        let left = time_left(&timer.tfd).unwrap();
+        assert!(
+            left > minute * 5 - Duration::from_secs(1) && left <= minute * 6,
+            "next in {:?}",
+            left
+        );
+
+        // On time, the grid moves by exactly one interval
+        assert_eq!(
+            rearm_grid(timer, next, interval, Duration::ZERO).unwrap(),
+            0
+        );
+        assert_eq!(timer.next_base.unwrap(), next + TimeSpec::from(interval));
+    }
+
+    #[test]
+    fn stale_stamp_in_the_configured_dir_catches_up() {
+        let stamps = env::temp_dir().join(format!("micetimer-stamps-{}", std::process::id()));
+        let day = Duration::from_secs(86400);
+        write_stamp(&stamps, "stamped", SystemTime::now() - day * 2).unwrap();
+        let source = r#"
+            Exec = "true"
+            OnStartupSec = "1d"
+            OnUnitActiveSec = "1d"
+            AccuracySec = "0s"
+            Persistent = true
+            WakeLock = false
+            "#;
+
+        let mut config = RunConfig::default();
+        config.set_stamp_dir(&stamps);
+        let mut configured = Scheduler::new().unwrap();
+        configured.set_run_config(config);
+        configured
+            .add_timer("stamped".to_string(), unit(source))
+            .unwrap();
+        thread::sleep(Duration::from_millis(5));
+        let timer = configured.active_timers.values().next().unwrap();
+        assert_eq!(read_expirations(&timer.tfd).unwrap(), 1);
+
+        // Elsewhere, the unit has no stamp and waits for its schedule
+        let mut config = RunConfig::default();
+        config.set_stamp_dir(stamps.join("elsewhere"));
+        let mut unstamped = Scheduler::new().unwrap();
+        unstamped.set_run_config(config);
+        unstamped
+            .add_timer("stamped".to_string(), unit(source))
+            .unwrap();
+        let left = unstamped.active_timers.values().next().unwrap().time_left();
+        assert!(left.unwrap() > Duration::from_secs(3600), "{:?}", left);
+
+        fs::remove_dir_all(&stamps).unwrap();
+    }
+
+    #[test]
+    fn event_buffer_is_reused_across_ticks() {
+        let idle = unit("Exec = \"true\"\nOnBootSec = \"1d\"\nWakeLock = false");
+        let mut scheduler =
+            Scheduler::from_units((0..20).map(|i| (format!("idle-{}", i), idle.clone()))).unwrap();
+        scheduler
+            .add_timer(
+                "busy".to_string(),
+                unit(
+                    r#"
+                    Exec = "true"
+                    OnStartupSec = "1ms"
+                    OnUnitActiveSec = "20ms"
+                    AccuracySec = "0s"
+                    WakeLock = false
+                    "#,
+                ),
+            )
+            .unwrap();
+        assert_eq!(scheduler.events.len(), MIN_EVENT_BATCH);
+
+        scheduler.tick(Some(Duration::from_secs(5))).unwrap();
+        let grown = (scheduler.events.len(), scheduler.events.as_ptr());
+        assert!(grown.0 >= 21, "{} slots", grown.0);
+        scheduler.tick(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!((scheduler.events.len(), scheduler.events.as_ptr()), grown);
+
+        for i in 0..20 {
+            assert!(scheduler.remove_timer(&format!("idle-{}", i)));
+        }
+        scheduler.tick(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!((scheduler.events.len(), scheduler.events.as_ptr()), grown);
+    }
+}
@@ -0,0 +1,28 @@
+//! Last-run stamps for `Persistent=true` units
+
+use anyhow::Result;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Where `Persistent=true` units record their last successful run, unless configured
+pub(crate) const DEFAULT_STAMP_DIR: &str = "/data/adb/micetimer/state";
+
+fn stamp_path(dir: &Path, name: &str) -> PathBuf {
+    dir.join(format!("{}.stamp", name))
+}
+
+/// Reads the wall-clock time of the unit's last successful run from `dir`, if recorded
+pub(crate) fn read_stamp(dir: &Path, name: &str) -> Option<SystemTime> {
+    let content = fs::read_to_string(stamp_path(dir, name)).ok()?;
+    let secs: u64 = content.trim().parse().ok()?;
+    Some(UNIX_EPOCH + Duration::from_secs(secs))
+}
+
+/// Records `instant` in `dir` as the unit's last successful run
+pub(crate) fn write_stamp(dir: &Path, name: &str, instant: SystemTime) -> Result<()> {
+    let secs = instant.duration_since(UNIX_EPOCH)?.as_secs();
+    fs::create_dir_all(dir)?;
+    fs::write(stamp_path(dir, name), secs.to_string())?;
+    Ok(())
+}
@@ -0,0 +1,118 @@
+//! Scheduler state saved across daemon restarts, so repeating units keep their phase
+
+use crate::unit::TimerUnit;
+use anyhow::{Result, bail};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Schema version written to the state file; files from a newer version are ignored
+pub(crate) const STATE_VERSION: u32 = 1;
+
+/// Contents of the state file
+///
+/// Unknown fields are ignored and missing ones default, so older and newer daemons
+/// of the same version can read each other's files.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SavedState {
+    pub version: u32,
+    /// Boot the state was saved in; boot-relative schedules don't carry over a reboot
+    #[serde(default)]
+    pub boot_id: String,
+    #[serde(default)]
+    pub units: BTreeMap<String, SavedUnit>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct SavedUnit {
+    /// [`fingerprint`] of the unit's configuration when it was saved
+    pub fingerprint: String,
+    /// Wall-clock time of the next elapse, in milliseconds since the Unix epoch
+    #[serde(default)]
+    pub next_elapse_ms: Option<u64>,
+    #[serde(default)]
+    pub last_run: Option<SavedRun>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub(crate) struct SavedRun {
+    /// When the run finished, in seconds since the Unix epoch
+    pub finished: u64,
+    pub success: bool,
+}
+
+impl SavedRun {
+    pub(crate) fn new((finished, success): (SystemTime, bool)) -> Self {
+        Self {
+            finished: finished
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            success,
+        }
+    }
+
+    pub(crate) fn last_run(self) -> (SystemTime, bool) {
+        (
+            UNIX_EPOCH + Duration::from_secs(self.finished),
+            self.success,
+        )
+    }
+}
+
+/// Stable hash of a unit's whole configuration; any edit changes it
+///
+/// FNV-1a over the `Debug` form, which unlike `DefaultHasher` doesn't change
+/// between Rust releases.
+pub(crate) fn fingerprint(unit: &TimerUnit) -> String {
+    let hash = format!("{:?}", unit)
+        .bytes()
+        .fold(0xcbf29ce484222325u64, |hash, byte| {
+            (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+        });
+    format!("{:016x}", hash)
+}
+
+/// Converts a wall-clock time to the state file's representation
+pub(crate) fn to_millis(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+pub(crate) fn from_millis(millis: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(millis)
+}
+
+/// Reads the state file; a missing file is an empty state
+pub(crate) fn load_state(path: &Path) -> Result<SavedState> {
+    let content = match fs::read_to_string(path) {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(SavedState::default()),
+        Err(e) => return Err(e.into()),
+    };
+    let state: SavedState = serde_json::from_str(&content)?;
+    if state.version > STATE_VERSION {
+        bail!(
+            "State file version {} is newer than supported ({})",
+            state.version,
+            STATE_VERSION
+        );
+    }
+    Ok(state)
+}
+
+/// Writes the state file, replacing it atomically
+pub(crate) fn save_state(path: &Path, state: &SavedState) -> Result<()> {
+    let content = serde_json::to_vec_pretty(state)?;
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(".tmp");
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&tmp, content)?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
@@ -0,0 +1,2201 @@
+//! Timer unit configuration and loading
+
+use crate::calendar::CalendarSpec;
+use crate::exec::resolve_credentials;
+use crate::expand::expand_instance;
+use anyhow::{Context, Result, anyhow, bail};
+use log::{error, warn};
+use nix::sys::signal::Signal;
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Longest accepted duration; timer arithmetic on clock readings stays far from
+/// overflowing `TimeSpec` and the kernel's 292-year limit
+const MAX_DURATION: Duration = Duration::from_secs(3_155_760_000); // 100 years
+
+/// Represents a single timer unit configuration (one file = one unit, or one `[[timer]]` entry)
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")] // Match Systemd naming convention (e.g., Exec, OnBootSec)
+#[non_exhaustive]
+pub struct TimerUnit {
+    pub description: Option<String>,
+
+    /// Name the unit's log lines carry instead of its own; wakelocks, stamps and the
+    /// control socket keep the real name
+    pub log_tag: Option<String>,
+
+    /// Disabled units are still validated, but never armed
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+
+    /// Command to execute: a shell string, or an argv array run without a shell
+    pub exec: Exec,
+
+    /// Script run instead of a literal `Exec`, resolved against the config directory
+    ///
+    /// It's run directly if it's executable when it runs, through `Shell` otherwise;
+    /// `Exec` holds it as [`Exec::File`].
+    pub exec_file: Option<PathBuf>,
+
+    /// Program and leading arguments that run a string `Exec` and the hooks, e.g.
+    /// `["/data/adb/magisk/busybox", "sh", "-c"]`; `["sh", "-c"]` if unset
+    pub shell: Option<Vec<String>>,
+
+    /// Whether a firing waits for the command to finish
+    #[serde(rename = "Type", default)]
+    pub service_type: ServiceType,
+
+    /// Delay after system boot; fires right away if the daemon started later than that
+    #[serde(default, with = "humantime_serde")]
+    pub on_boot_sec: Option<Duration>,
+
+    /// Delay after the daemon (or a reload adding this unit) started
+    #[serde(default, with = "humantime_serde")]
+    pub on_startup_sec: Option<Duration>,
+
+    /// Also fire once as soon as the unit is scheduled (at daemon start, or when a
+    /// reload adds or changes it), unless its first elapse is due right away anyway
+    #[serde(default)]
+    pub run_on_start: bool,
+
+    /// Repeat interval relative to the last activation; "0s" means no repeat
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub on_unit_active_sec: Option<Duration>,
+
+    /// Repeat interval relative to when the last run finished; "0s" means no repeat
+    #[serde(default, deserialize_with = "deserialize_interval")]
+    pub on_unit_inactive_sec: Option<Duration>,
+
+    /// Stretch the repeat interval while runs keep failing, back to normal on success
+    pub failure_backoff: Option<BackoffConfig>,
+
+    /// Random extra delay in [0, RandomizedDelaySec] added to every expiration
+    #[serde(default, with = "humantime_serde")]
+    pub randomized_delay_sec: Option<Duration>,
+
+    /// Fix the `RandomizedDelaySec` offset to one derived from this source instead of
+    /// re-rolling it on every firing
+    pub randomized_delay_seed: Option<SeedSource>,
+
+    /// Expirations are rounded up to a multiple of this, so nearby timers fire together
+    #[serde(default = "default_accuracy", with = "humantime_serde")]
+    pub accuracy_sec: Duration,
+
+    /// Wall-clock schedule, e.g. "03:00" or "Mon,Fri 09:00"; a list fires at each
+    /// entry's occurrences
+    #[serde(default, deserialize_with = "deserialize_calendar")]
+    pub on_calendar: Vec<String>,
+
+    /// Clock the timer counts on; defaults to `Realtime` for `OnCalendar` units and
+    /// `BootTime` otherwise
+    pub clock: Option<ClockSource>,
+
+    /// Arm on an alarm clock so the expiration wakes the device from suspend
+    #[serde(default)]
+    pub wake_system: bool,
+
+    /// Whether to hold a partial wakelock during execution
+    #[serde(default = "default_wakelock")]
+    pub wake_lock: bool,
+
+    /// Force-release the wakelock after this long, even if the command is still running
+    #[serde(default, with = "humantime_serde")]
+    pub wake_lock_timeout_sec: Option<Duration>,
+
+    /// Only take the wakelock once a run is still going after this long, so quick
+    /// commands never touch it; the device may suspend during the delay
+    #[serde(default, with = "humantime_serde")]
+    pub wake_lock_delay: Option<Duration>,
+
+    /// Shell commands run first that decide whether this firing runs at all
+    ///
+    /// A nonzero exit from any of them skips the firing like an unmet condition,
+    /// rather than failing it.
+    #[serde(default)]
+    pub exec_condition: Vec<String>,
+
+    /// Shell commands run before `Exec`; a failure skips `Exec` unless prefixed with '-'
+    #[serde(default)]
+    pub exec_start_pre: Vec<String>,
+
+    /// Shell commands run after `Exec`, whether it succeeded or not
+    #[serde(default)]
+    pub exec_start_post: Vec<String>,
+
+    /// Shell command run once when `Exec` fails after all retries, e.g. to send a notification
+    ///
+    /// It sees `MICETIMER_UNIT`, `MICETIMER_EXIT_CODE` and `MICETIMER_EXEC`, and is
+    /// neither retried nor followed by another `OnFailure` if it fails itself.
+    pub on_failure: Option<String>,
+
+    /// Shell command run once after `Exec` succeeds, e.g. to start the next stage of a pipeline
+    ///
+    /// It sees `MICETIMER_UNIT` and `MICETIMER_EXEC`; if it fails, that's logged but
+    /// the run still counts as a success.
+    pub on_success: Option<String>,
+
+    /// How many times to re-run `Exec` after a failure
+    #[serde(default)]
+    pub retries: u32,
+
+    /// Delay before the first retry; doubles on every further attempt
+    #[serde(default = "default_retry_backoff", with = "humantime_serde")]
+    pub retry_backoff_sec: Duration,
+
+    /// Exit codes worth a retry; others, and signals, fail right away. Empty retries
+    /// any failure
+    #[serde(default)]
+    pub retry_on_exit: Vec<i32>,
+
+    /// Extra environment variables for the command
+    #[serde(default)]
+    pub environment: HashMap<String, String>,
+
+    /// File of KEY=VALUE lines read at execution time; overrides `Environment`
+    pub environment_file: Option<PathBuf>,
+
+    /// Start the command from an empty environment instead of the daemon's, so it
+    /// only sees a minimal `PATH` plus `Environment` and `EnvironmentFile`
+    #[serde(default)]
+    pub clean_environment: bool,
+
+    /// Directory the command runs in
+    pub working_directory: Option<PathBuf>,
+
+    /// Run the command as this user (name or numeric UID)
+    pub user: Option<String>,
+
+    /// Run the command with this group (name or numeric GID); defaults to the user's group
+    pub group: Option<String>,
+
+    /// CPU scheduling niceness of the command, from -20 (favored) to 19 (background)
+    pub nice: Option<i32>,
+
+    /// IO scheduling class of the command: "idle", "best-effort" or "realtime"
+    #[serde(rename = "IOSchedulingClass")]
+    pub io_scheduling_class: Option<IoClass>,
+
+    /// IO priority within the class, from 0 (highest) to 7; implies "best-effort" if
+    /// no class is set
+    #[serde(rename = "IOSchedulingPriority")]
+    pub io_scheduling_priority: Option<u8>,
+
+    /// The command's `oom_score_adj`, from -1000 (never killed) to 1000 (killed first)
+    #[serde(rename = "OOMScoreAdjust")]
+    pub oom_score_adjust: Option<i32>,
+
+    /// Address space limit of the command (`RLIMIT_AS`), e.g. `"128M"`; allocations
+    /// beyond it fail
+    #[serde(default, deserialize_with = "deserialize_byte_size")]
+    pub memory_max: Option<u64>,
+
+    /// CPU time the command may use (`RLIMIT_CPU`), in whole seconds; it then gets SIGXCPU
+    #[serde(rename = "CPUQuota", default, with = "humantime_serde")]
+    pub cpu_quota: Option<Duration>,
+
+    /// File mode creation mask of the command, written in octal, e.g. `"0027"`
+    #[serde(rename = "UMask", default, deserialize_with = "deserialize_umask")]
+    pub umask: Option<u32>,
+
+    /// Cgroup directory (e.g. under `/sys/fs/cgroup`) each command is moved into,
+    /// created if needed; shared by every run of the unit
+    #[serde(rename = "CGroup")]
+    pub cgroup: Option<PathBuf>,
+
+    /// SELinux context the command is executed in, e.g. `"u:r:magisk:s0"`; if the
+    /// transition can't be requested, the command runs in the daemon's context
+    #[serde(rename = "SELinuxContext")]
+    pub selinux_context: Option<String>,
+
+    /// Where the command's stdout/stderr go
+    #[serde(default)]
+    pub standard_output: OutputTarget,
+
+    /// Directory each firing writes `<unit>.json` with its outcome to, created if needed
+    pub result_dir: Option<PathBuf>,
+
+    /// Only run if this path exists ("!/path" to require that it doesn't)
+    pub condition_path_exists: Option<PathBuf>,
+
+    /// Only run if this is a non-empty regular file ("!/path" to negate)
+    pub condition_file_not_empty: Option<PathBuf>,
+
+    /// Only run when external power is (true) or isn't (false) connected
+    #[serde(rename = "ConditionACPower")]
+    pub condition_ac_power: Option<bool>,
+
+    /// Only run when the battery is charged to at least this percentage
+    pub condition_battery_above: Option<u8>,
+
+    /// Only run when the network is (true) or isn't (false) reachable, judged by `NetworkProbe`
+    #[serde(rename = "ConditionNetworkOnline")]
+    pub condition_network: Option<bool>,
+
+    /// How `ConditionNetworkOnline` checks connectivity
+    #[serde(default)]
+    pub network_probe: NetworkProbe,
+
+    /// Kill the command if it runs longer than this
+    #[serde(default, with = "humantime_serde")]
+    pub timeout_sec: Option<Duration>,
+
+    /// Signal that asks a timed-out command to stop, before SIGKILL after a grace period
+    #[serde(
+        default = "default_kill_signal",
+        deserialize_with = "deserialize_signal"
+    )]
+    pub kill_signal: Signal,
+
+    /// Which processes a timed-out command's signals go to
+    #[serde(default)]
+    pub kill_mode: KillMode,
+
+    /// Exit codes or signals that count as success, e.g. `[0, 75, "SIGTERM"]`;
+    /// a clean exit always does
+    #[serde(default = "default_success_exit_status")]
+    pub success_exit_status: Vec<SuccessStatus>,
+
+    /// Budget for a whole firing: hooks, every retry and the waits between them
+    #[serde(default, with = "humantime_serde")]
+    pub runtime_max_sec: Option<Duration>,
+
+    /// What to do when the timer fires while the previous run is still active
+    #[serde(default)]
+    pub concurrency: Concurrency,
+
+    /// Firings allowed within `StartLimitIntervalSec` before further ones are refused;
+    /// 0 disables the limit
+    #[serde(default = "default_start_limit_burst")]
+    pub start_limit_burst: u32,
+
+    /// Window over which `StartLimitBurst` is counted
+    #[serde(default = "default_start_limit_interval", with = "humantime_serde")]
+    pub start_limit_interval_sec: Duration,
+
+    /// Units that must not be running when this one starts; a firing waits for them
+    #[serde(default)]
+    pub after: Vec<String>,
+
+    /// Units whose last run must not have failed; otherwise a firing is skipped
+    ///
+    /// Combine with `After` to wait for a concurrent run of the unit before checking.
+    #[serde(default)]
+    pub requires: Vec<String>,
+
+    /// Record the last successful run and catch up on missed runs at startup
+    #[serde(default)]
+    pub persistent: bool,
+
+    /// What to do when several expirations accumulated before we read the timer
+    #[serde(default)]
+    pub on_missed: OnMissed,
+}
+
+/// One line of documentation per `TimerUnit` key for `micetimer generate`: the key,
+/// whether the value shown is its default (otherwise it's an example), the value, and
+/// what the key does
+///
+/// Keep it next to the struct: [`example_unit`] lists keys in the struct's own order
+/// and flags any that are missing here.
+#[rustfmt::skip]
+const KEY_DOCS: &[(&str, bool, &str, &str)] = &[
+    ("Description", false, "\"Refresh FCM hosts\"", "Text logged with each firing"),
+    ("LogTag", false, "\"fcm\"", "Name log lines carry instead of the unit's own"),
+    ("Enabled", true, "true", "Disabled units are still validated, but never armed"),
+    ("Exec", false, "\"/system/bin/fcm-update\"", "Command to run: a shell string, or an argv array run without a shell"),
+    ("ExecFile", false, "\"scripts/fcm-update.sh\"", "Script run instead of Exec, relative to the config directory"),
+    ("Shell", false, "[\"/data/adb/magisk/busybox\", \"sh\", \"-c\"]", "Program and arguments that run a string Exec and the hooks; sh -c if unset"),
+    ("Type", true, "\"OneShot\"", "OneShot waits for the command, Forking only for its parent, Simple not at all"),
+    ("OnBootSec", false, "\"5m\"", "Delay after boot"),
+    ("OnStartupSec", false, "\"1m\"", "Delay after the daemon started"),
+    ("RunOnStart", true, "false", "Also fire once as soon as the unit is scheduled"),
+    ("OnUnitActiveSec", false, "\"6h\"", "Repeat interval counted from the last activation"),
+    ("OnUnitInactiveSec", false, "\"6h\"", "Repeat interval counted from the end of the last run"),
+    ("FailureBackoff", false, "{ MaxInterval = \"1d\", Multiplier = 2.0 }", "Stretch OnUnitActiveSec or OnUnitInactiveSec while runs keep failing"),
+    ("RandomizedDelaySec", false, "\"5m\"", "Random extra delay added to every elapse"),
+    ("RandomizedDelaySeed", false, "\"Device\"", "Fix the RandomizedDelaySec offset per Device, Boot or Unit instead of re-rolling it"),
+    ("AccuracySec", true, "\"1m\"", "Elapses are rounded up to a multiple of this, so nearby timers fire together"),
+    ("OnCalendar", false, "\"03:00\"", "Wall-clock schedule, or a list of them"),
+    ("Clock", false, "\"BootTime\"", "BootTime, Monotonic or Realtime; Realtime by default for OnCalendar, BootTime otherwise"),
+    ("WakeSystem", true, "false", "Wake the device from suspend for the elapse"),
+    ("WakeLock", true, "true", "Hold a wakelock while the command runs"),
+    ("WakeLockTimeoutSec", false, "\"10m\"", "Release the wakelock after this long, even if the command still runs"),
+    ("WakeLockDelay", false, "\"10s\"", "Only take the wakelock once a run lasts this long"),
+    ("ExecCondition", true, "[]", "Commands that skip the firing if any exits nonzero"),
+    ("ExecStartPre", true, "[]", "Commands run before Exec; a failure skips Exec unless prefixed with \"-\""),
+    ("ExecStartPost", true, "[]", "Commands run after Exec, whether it succeeded or not"),
+    ("OnFailure", false, "\"echo failed >> /data/adb/micetimer/failures\"", "Command run once Exec failed after all retries"),
+    ("OnSuccess", false, "\"echo ok >> /data/adb/micetimer/successes\"", "Command run once Exec succeeded"),
+    ("Retries", true, "0", "How many times to re-run Exec after a failure"),
+    ("RetryBackoffSec", true, "\"5s\"", "Delay before the first retry, doubling on every further one"),
+    ("RetryOnExit", true, "[]", "Only retry these exit codes; empty retries any failure"),
+    ("Environment", false, "{ LANG = \"C\" }", "Extra environment variables for the command"),
+    ("EnvironmentFile", false, "\"/data/adb/micetimer/env\"", "File of KEY=VALUE lines read when the command starts"),
+    ("CleanEnvironment", true, "false", "Start from an empty environment instead of the daemon's"),
+    ("WorkingDirectory", false, "\"/data/adb/micetimer\"", "Directory the command runs in; the daemon's own if unset, which is / once daemonized"),
+    ("User", false, "\"shell\"", "Run the command as this user (name or UID)"),
+    ("Group", false, "\"shell\"", "Run the command with this group (name or GID); the user's if unset"),
+    ("Nice", false, "10", "CPU niceness of the command, from -20 to 19"),
+    ("IOSchedulingClass", false, "\"idle\"", "IO scheduling class: idle, best-effort or realtime"),
+    ("IOSchedulingPriority", false, "7", "IO priority within the class, from 0 to 7"),
+    ("OOMScoreAdjust", false, "500", "oom_score_adj of the command, from -1000 to 1000"),
+    ("MemoryMax", false, "\"256M\"", "Address space limit of the command"),
+    ("CPUQuota", false, "\"30s\"", "CPU time the command may use before it's killed"),
+    ("UMask", false, "\"0027\"", "File mode creation mask of the command, in octal"),
+    ("CGroup", false, "\"/sys/fs/cgroup/micetimer/fcm\"", "Cgroup directory the command is moved into"),
+    ("SELinuxContext", false, "\"u:r:magisk:s0\"", "SELinux context the command is executed in"),
+    ("StandardOutput", true, "\"Null\"", "Where output goes: Null, Log, or { File = \"/path\" }"),
+    ("ResultDir", false, "\"/data/adb/micetimer/results\"", "Directory each firing writes <unit>.json with its outcome to"),
+    ("ConditionPathExists", false, "\"/data/adb/modules/fcm\"", "Only run if this path exists (\"!/path\": if it doesn't)"),
+    ("ConditionFileNotEmpty", false, "\"/data/adb/fcm/hosts\"", "Only run if this is a non-empty file (\"!/path\" to negate)"),
+    ("ConditionACPower", false, "true", "Only run while charging (true) or on battery (false)"),
+    ("ConditionBatteryAbove", false, "20", "Only run with at least this much battery, in percent"),
+    ("ConditionNetworkOnline", false, "true", "Only run while the network is (true) or isn't (false) up"),
+    ("NetworkProbe", true, "\"Operstate\"", "How the network is checked: Operstate, or { Connect = \"host:port\" }"),
+    ("TimeoutSec", false, "\"5m\"", "Kill the command if it runs longer than this"),
+    ("KillSignal", true, "\"SIGTERM\"", "Signal sent to a timed-out command before SIGKILL"),
+    ("KillMode", true, "\"ControlGroup\"", "Kill the whole process group (ControlGroup) or only the command (Process)"),
+    ("SuccessExitStatus", true, "[0]", "Exit codes or signal names that count as success"),
+    ("RuntimeMaxSec", false, "\"10m\"", "Budget for a whole firing, hooks and retries included"),
+    ("Concurrency", true, "\"Skip\"", "Firing while the last run is active: Skip, Queue or Parallel"),
+    ("StartLimitBurst", true, "5", "Firings allowed per StartLimitIntervalSec; 0 disables the limit"),
+    ("StartLimitIntervalSec", true, "\"10s\"", "Window StartLimitBurst is counted over"),
+    ("After", true, "[]", "Units that must not be running when this one starts"),
+    ("Requires", true, "[]", "Units whose last run must not have failed"),
+    ("Persistent", true, "false", "Catch up on a run missed while the daemon was down"),
+    ("OnMissed", true, "\"Coalesce\"", "Missed elapses run once (Coalesce) or once each (RunEach)"),
+];
+
+/// Keys `TimerUnit` accepts, in declaration order, as its derived `Deserialize` lists them
+fn unit_keys() -> &'static [&'static str] {
+    use serde::de::{self, Deserializer, Visitor};
+
+    /// Deserializer that only records the field list it's asked for
+    struct Fields(&'static [&'static str]);
+
+    impl<'de> Deserializer<'de> for &mut Fields {
+        type Error = de::value::Error;
+
+        fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, Self::Error> {
+            Err(de::Error::custom("not a struct"))
+        }
+
+        fn deserialize_struct<V: Visitor<'de>>(
+            self,
+            _name: &'static str,
+            fields: &'static [&'static str],
+            _visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            self.0 = fields;
+            Err(de::Error::custom("fields recorded"))
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string bytes
+            byte_buf option unit unit_struct newtype_struct seq tuple tuple_struct map
+            enum identifier ignored_any
+        }
+    }
+
+    let mut fields = Fields(&[]);
+    let _ = TimerUnit::deserialize(&mut fields);
+    fields.0
+}
+
+/// A commented example unit file documenting every key, for `micetimer generate`
+///
+/// `Exec` and `OnBootSec` are set, so the file is a valid unit as written; every other
+/// key is commented out with its default, or an example where it has none.
+pub fn example_unit() -> String {
+    let mut out = String::from(
+        "# Example micetimer unit; uncomment and edit the keys you need.\n\
+         # A unit needs Exec (or ExecFile) and at least one of OnBootSec, OnStartupSec,\n\
+         # OnUnitActiveSec, OnUnitInactiveSec or OnCalendar.\n",
+    );
+    for key in unit_keys() {
+        out.push('\n');
+        let Some(&(_, default, value, about)) = KEY_DOCS.iter().find(|doc| doc.0 == *key) else {
+            out.push_str(&format!("# {} (undocumented)\n", key));
+            continue;
+        };
+        out.push_str(&format!("# {}\n", about));
+        match *key {
+            "Exec" | "OnBootSec" => out.push_str(&format!("{} = {}\n", key, value)),
+            _ if default => out.push_str(&format!("# {} = {}  # default\n", key, value)),
+            _ => out.push_str(&format!("# {} = {}\n", key, value)),
+        }
+    }
+    out
+}
+
+/// A unit's command line
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum Exec {
+    /// Run through `sh -c`
+    Shell(String),
+    /// Run directly, each element passed as one argument
+    Argv(Vec<String>),
+    /// An `ExecFile` script: run directly if it's executable, through the shell otherwise
+    #[serde(skip)]
+    File(String),
+}
+
+impl Exec {
+    /// The same command with `f` applied to the shell line or to each argument
+    pub(crate) fn map(&self, f: impl Fn(&str) -> String) -> Exec {
+        match self {
+            Exec::Shell(line) => Exec::Shell(f(line)),
+            Exec::Argv(argv) => Exec::Argv(argv.iter().map(|arg| f(arg)).collect()),
+            Exec::File(path) => Exec::File(f(path)),
+        }
+    }
+
+    /// The command to spawn; a shell line runs through `shell`, or `sh -c` if `None`
+    ///
+    /// A script that isn't executable runs through `shell` minus a trailing `-c`, so
+    /// the shell reads it as a file; whether it is executable is checked each time.
+    pub(crate) fn command(&self, shell: Option<&[String]>) -> Command {
+        match self {
+            Exec::Shell(line) => {
+                let mut command = match shell {
+                    Some([program, args @ ..]) => {
+                        let mut command = Command::new(program);
+                        command.args(args);
+                        command
+                    }
+                    _ => {
+                        let mut command = Command::new("sh");
+                        command.arg("-c");
+                        command
+                    }
+                };
+                command.arg(line);
+                command
+            }
+            Exec::Argv(argv) => {
+                let mut command = Command::new(&argv[0]);
+                command.args(&argv[1..]);
+                command
+            }
+            Exec::File(path) => {
+                let executable =
+                    fs::metadata(path).is_ok_and(|meta| meta.permissions().mode() & 0o111 != 0);
+                if executable {
+                    return Command::new(path);
+                }
+                let mut command = match shell {
+                    Some([program, args @ ..]) => {
+                        let mut command = Command::new(program);
+                        command.args(args.strip_suffix(&["-c".to_string()]).unwrap_or(args));
+                        command
+                    }
+                    _ => Command::new("sh"),
+                };
+                command.arg(path);
+                command
+            }
+        }
+    }
+}
+
+impl fmt::Display for Exec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Exec::Shell(line) => f.write_str(line),
+            Exec::Argv(argv) => write!(f, "{:?}", argv),
+            Exec::File(path) => f.write_str(path),
+        }
+    }
+}
+
+/// An exit status that counts as a successful run
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(try_from = "RawSuccessStatus")]
+#[non_exhaustive]
+pub enum SuccessStatus {
+    /// Exited with this code
+    Code(i32),
+    /// Terminated by this signal
+    Signal(Signal),
+}
+
+/// `SuccessExitStatus` entries as written: a code, or a signal name like "SIGTERM" or "TERM"
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawSuccessStatus {
+    Code(i32),
+    Signal(String),
+}
+
+impl TryFrom<RawSuccessStatus> for SuccessStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawSuccessStatus) -> Result<Self> {
+        match raw {
+            RawSuccessStatus::Code(code) => Ok(SuccessStatus::Code(code)),
+            RawSuccessStatus::Signal(name) => parse_signal(&name)
+                .map(SuccessStatus::Signal)
+                .ok_or_else(|| anyhow!("Unknown signal {:?} in SuccessExitStatus", name)),
+        }
+    }
+}
+
+/// Parses a signal name with or without the `SIG` prefix, in any case: "SIGINT", "int"
+fn parse_signal(name: &str) -> Option<Signal> {
+    let upper = name.to_ascii_uppercase();
+    let full = if upper.starts_with("SIG") {
+        upper
+    } else {
+        format!("SIG{}", upper)
+    };
+    full.parse().ok()
+}
+
+fn deserialize_signal<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Signal, D::Error> {
+    let name = String::deserialize(deserializer)?;
+    parse_signal(&name)
+        .ok_or_else(|| serde::de::Error::custom(format!("Unknown signal {:?}", name)))
+}
+
+impl SuccessStatus {
+    pub(crate) fn matches(self, status: ExitStatus) -> bool {
+        match self {
+            SuccessStatus::Code(code) => status.code() == Some(code),
+            SuccessStatus::Signal(signal) => status.signal() == Some(signal as i32),
+        }
+    }
+}
+
+/// How a firing treats the command it starts, named as in systemd's `Type`
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ServiceType {
+    /// Runs to completion; its exit status is the firing's result
+    #[default]
+    OneShot,
+    /// Starts something long-lived in the background and exits; only that parent is
+    /// waited for, and what it leaves behind keeps running
+    Forking,
+    /// Is started and left alone: no wakelock, no exit tracking, no retries
+    Simple,
+}
+
+/// Which processes a timed-out command's `KillSignal` and SIGKILL are sent to
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum KillMode {
+    /// The command's whole process group, including anything it started
+    #[default]
+    ControlGroup,
+    /// The main process only; what it started is left running
+    Process,
+}
+
+/// Destination for a command's stdout and stderr
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub enum OutputTarget {
+    /// Discard output
+    #[default]
+    Null,
+    /// Relay each line into the daemon log (stdout at info, stderr at warn)
+    Log,
+    /// Append both streams to a file, e.g. `StandardOutput = { File = "/data/adb/x.log" }`
+    File(PathBuf),
+}
+
+/// IO scheduling class (see ioprio_set(2)), named as in systemd's `IOSchedulingClass`
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+#[non_exhaustive]
+pub enum IoClass {
+    /// Served before everything else
+    Realtime,
+    /// The default class for processes
+    BestEffort,
+    /// Only served when no other process needs the disk
+    Idle,
+}
+
+/// How `ConditionNetworkOnline` decides whether the network is up
+#[derive(Debug, Deserialize, Clone, Default, PartialEq)]
+#[non_exhaustive]
+pub enum NetworkProbe {
+    /// Some non-loopback interface in `/sys/class/net` is up
+    #[default]
+    Operstate,
+    /// A TCP connection to this `host:port` succeeds, e.g. `NetworkProbe = { Connect = "1.1.1.1:53" }`
+    Connect(String),
+}
+
+/// Clock a unit's timer counts on
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ClockSource {
+    /// Time since boot including deep sleep (CLOCK_BOOTTIME)
+    ///
+    /// The default: Android devices spend most of their time suspended, and a clock
+    /// that stops in suspend would push "5m after boot" or "every 6h" far out.
+    BootTime,
+    /// Time since boot excluding deep sleep (CLOCK_MONOTONIC): counts awake time only
+    Monotonic,
+    /// Wall-clock time (CLOCK_REALTIME), following clock changes and NTP corrections
+    Realtime,
+}
+
+/// What a fixed `RandomizedDelaySec` offset is derived from, together with the unit name
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum SeedSource {
+    /// The device's serial number: the same offset on every boot, a different one on
+    /// every device, so a fleet running the same unit spreads out
+    Device,
+    /// The kernel's boot id: a new offset on every boot
+    Boot,
+    /// The unit name alone: the same offset on every device
+    Unit,
+}
+
+/// Policy for a firing that arrives while the previous run is still executing
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Concurrency {
+    /// Drop the firing
+    #[default]
+    Skip,
+    /// Run once more after the current run finishes
+    Queue,
+    /// Start another run alongside the current one
+    Parallel,
+}
+
+/// How the repeat interval grows after failed runs, e.g.
+/// `FailureBackoff = { MaxInterval = "6h", Multiplier = 2.0 }`
+#[derive(Debug, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "PascalCase")]
+#[non_exhaustive]
+pub struct BackoffConfig {
+    /// Longest interval the backoff grows to
+    #[serde(with = "humantime_serde")]
+    pub max_interval: Duration,
+    /// Factor applied to the interval on every consecutive failure
+    #[serde(default = "default_backoff_multiplier")]
+    pub multiplier: f64,
+}
+
+/// Policy for expirations that piled up while the loop was busy or the device slept
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum OnMissed {
+    /// Run once, no matter how many expirations were missed
+    #[default]
+    Coalesce,
+    /// Run once per expiration
+    RunEach,
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+fn default_accuracy() -> Duration {
+    Duration::from_secs(60)
+}
+
+fn default_wakelock() -> bool {
+    true
+}
+
+/// A repeat interval, where zero (e.g. from a drop-in turning repetition off) is unset
+/// rather than an interval that would re-fire immediately, forever
+fn deserialize_interval<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<Duration>, D::Error> {
+    let interval: Option<Duration> = humantime_serde::deserialize(deserializer)?;
+    Ok(interval.filter(|interval| !interval.is_zero()))
+}
+
+/// `OnCalendar`: one expression or a list of them
+fn deserialize_calendar<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Vec<String>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(String),
+        Many(Vec<String>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(expr) => vec![expr],
+        OneOrMany::Many(exprs) => exprs,
+    })
+}
+
+/// A size in bytes: a number, or one with a `K`, `M`, `G` or `T` suffix (powers of 1024)
+fn deserialize_byte_size<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u64>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Size {
+        Bytes(u64),
+        Text(String),
+    }
+
+    let text = match Option::<Size>::deserialize(deserializer)? {
+        None => return Ok(None),
+        Some(Size::Bytes(bytes)) => return Ok(Some(bytes)),
+        Some(Size::Text(text)) => text,
+    };
+    parse_byte_size(&text).map(Some).ok_or_else(|| {
+        serde::de::Error::custom(format!(
+            "Expected a size like \"512K\", \"128M\" or \"1G\", got {:?}",
+            text
+        ))
+    })
+}
+
+fn parse_byte_size(text: &str) -> Option<u64> {
+    let text = text.trim();
+    let digits = text
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(text.len());
+    let (number, suffix) = text.split_at(digits);
+    let shift = match suffix.trim().trim_end_matches(['B', 'b']) {
+        "" => 0,
+        "K" | "k" | "Ki" => 10,
+        "M" | "m" | "Mi" => 20,
+        "G" | "g" | "Gi" => 30,
+        "T" | "t" | "Ti" => 40,
+        _ => return None,
+    };
+    number.parse::<u64>().ok()?.checked_mul(1 << shift)
+}
+
+/// Whether `context` has the `user:role:type[:level]` shape of an SELinux context
+///
+/// The level may itself contain colons, e.g. `s0:c512,c768`.
+fn is_selinux_context(context: &str) -> bool {
+    let mut parts = context.splitn(4, ':');
+    let named = parts.by_ref().take(3).filter(|part| {
+        !part.is_empty()
+            && part
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-')
+    });
+    named.count() == 3
+        && parts.next().is_none_or(|level| {
+            !level.is_empty() && !level.contains(|c: char| c.is_whitespace() || c.is_control())
+        })
+}
+
+fn deserialize_umask<'de, D: serde::Deserializer<'de>>(
+    deserializer: D,
+) -> std::result::Result<Option<u32>, D::Error> {
+    let Some(mask) = Option::<String>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    u32::from_str_radix(&mask, 8)
+        .ok()
+        .filter(|mask| *mask <= 0o777)
+        .map(Some)
+        .ok_or_else(|| {
+            serde::de::Error::custom(format!(
+                "UMask must be an octal mode like \"0027\", got {:?}",
+                mask
+            ))
+        })
+}
+
+fn default_backoff_multiplier() -> f64 {
+    2.0
+}
+
+fn default_retry_backoff() -> Duration {
+    Duration::from_secs(5)
+}
+
+fn default_kill_signal() -> Signal {
+    Signal::SIGTERM
+}
+
+fn default_success_exit_status() -> Vec<SuccessStatus> {
+    vec![SuccessStatus::Code(0)]
+}
+
+fn default_start_limit_burst() -> u32 {
+    5
+}
+
+fn default_start_limit_interval() -> Duration {
+    Duration::from_secs(10)
+}
+
+impl TimerUnit {
+    /// Whether a firing holds the wakelock; `Type = "Simple"` ones don't wait for their
+    /// command, so there's nothing to hold it for
+    pub(crate) fn holds_wakelock(&self) -> bool {
+        self.wake_lock && self.service_type != ServiceType::Simple
+    }
+
+    /// What the unit named `name` is called in log lines: its `LogTag`, or `name`
+    pub fn tag<'a>(&'a self, name: &'a str) -> &'a str {
+        self.log_tag.as_deref().unwrap_or(name)
+    }
+
+    /// The `Clock` setting, or its default for this kind of unit
+    pub fn clock_source(&self) -> ClockSource {
+        match self.clock {
+            Some(clock) => clock,
+            None if !self.on_calendar.is_empty() => ClockSource::Realtime,
+            None => ClockSource::BootTime,
+        }
+    }
+
+    /// Checks what deserialization alone can't: calendar syntax, credentials, argv
+    pub(crate) fn validate(&self) -> Result<()> {
+        let scheduled = self.on_boot_sec.is_some()
+            || self.on_startup_sec.is_some()
+            || !self.on_calendar.is_empty()
+            || self.on_unit_active_sec.is_some()
+            || self.on_unit_inactive_sec.is_some();
+        if !scheduled {
+            bail!(
+                "No schedule: set at least one of OnBootSec, OnStartupSec, OnCalendar, \
+                 OnUnitActiveSec or OnUnitInactiveSec"
+            );
+        }
+
+        let durations = [
+            ("OnBootSec", self.on_boot_sec),
+            ("OnStartupSec", self.on_startup_sec),
+            ("OnUnitActiveSec", self.on_unit_active_sec),
+            ("OnUnitInactiveSec", self.on_unit_inactive_sec),
+            ("RandomizedDelaySec", self.randomized_delay_sec),
+            ("AccuracySec", Some(self.accuracy_sec)),
+            ("WakeLockTimeoutSec", self.wake_lock_timeout_sec),
+            ("WakeLockDelay", self.wake_lock_delay),
+            ("CPUQuota", self.cpu_quota),
+            ("RetryBackoffSec", Some(self.retry_backoff_sec)),
+            ("TimeoutSec", self.timeout_sec),
+            ("RuntimeMaxSec", self.runtime_max_sec),
+            ("StartLimitIntervalSec", Some(self.start_limit_interval_sec)),
+            (
+                "FailureBackoff MaxInterval",
+                self.failure_backoff
+                    .as_ref()
+                    .map(|backoff| backoff.max_interval),
+            ),
+        ];
+        for (key, duration) in durations {
+            if duration.is_some_and(|duration| duration > MAX_DURATION) {
+                bail!(
+                    "{} is longer than the supported {}",
+                    key,
+                    humantime::format_duration(MAX_DURATION)
+                );
+            }
+        }
+
+        for expr in &self.on_calendar {
+            CalendarSpec::parse(expr).with_context(|| format!("Invalid OnCalendar {:?}", expr))?;
+        }
+
+        if self.shell.as_ref().is_some_and(|shell| shell.is_empty()) {
+            bail!("Shell array must not be empty");
+        }
+
+        if self
+            .log_tag
+            .as_ref()
+            .is_some_and(|tag| tag.trim().is_empty())
+        {
+            bail!("LogTag must not be empty");
+        }
+
+        resolve_credentials(self).context("Invalid User/Group")?;
+
+        if self.cgroup.as_ref().is_some_and(|path| !path.is_absolute()) {
+            bail!("CGroup must be an absolute path");
+        }
+        if self
+            .result_dir
+            .as_ref()
+            .is_some_and(|path| !path.is_absolute())
+        {
+            bail!("ResultDir must be an absolute path");
+        }
+
+        if let Some(nice) = self.nice
+            && !(-20..=19).contains(&nice)
+        {
+            bail!("Nice must be between -20 and 19, got {}", nice);
+        }
+        if let Some(adjust) = self.oom_score_adjust
+            && !(-1000..=1000).contains(&adjust)
+        {
+            bail!(
+                "OOMScoreAdjust must be between -1000 and 1000, got {}",
+                adjust
+            );
+        }
+        if self.memory_max == Some(0) {
+            bail!("MemoryMax must be more than 0");
+        }
+        if let Some(context) = &self.selinux_context
+            && !is_selinux_context(context)
+        {
+            bail!(
+                "SELinuxContext must look like user:role:type[:level], got {:?}",
+                context
+            );
+        }
+        if self
+            .cpu_quota
+            .is_some_and(|quota| quota < Duration::from_secs(1))
+        {
+            bail!("CPUQuota must be at least 1s");
+        }
+        if let Some(priority) = self.io_scheduling_priority {
+            if priority > 7 {
+                bail!(
+                    "IOSchedulingPriority must be between 0 and 7, got {}",
+                    priority
+                );
+            }
+            if self.io_scheduling_class == Some(IoClass::Idle) {
+                bail!("IOSchedulingPriority has no effect with IOSchedulingClass \"idle\"");
+            }
+        }
+
+        if let Exec::Argv(argv) = &self.exec
+            && argv.is_empty()
+        {
+            bail!("Exec array must not be empty");
+        }
+
+        if self.on_unit_active_sec.is_some() && self.on_unit_inactive_sec.is_some() {
+            bail!("OnUnitActiveSec and OnUnitInactiveSec are mutually exclusive");
+        }
+
+        if let NetworkProbe::Connect(target) = &self.network_probe
+            && target
+                .rsplit_once(':')
+                .is_none_or(|(host, port)| host.is_empty() || port.parse::<u16>().is_err())
+        {
+            bail!("NetworkProbe Connect needs \"host:port\", got {:?}", target);
+        }
+
+        if let Some(backoff) = &self.failure_backoff {
+            let Some(interval) = self.on_unit_active_sec.or(self.on_unit_inactive_sec) else {
+                bail!("FailureBackoff needs OnUnitActiveSec or OnUnitInactiveSec");
+            };
+            if !self.on_calendar.is_empty() {
+                bail!("FailureBackoff can't be combined with OnCalendar");
+            }
+            if !backoff.multiplier.is_finite() || backoff.multiplier < 1.0 {
+                bail!(
+                    "FailureBackoff Multiplier must be at least 1, got {}",
+                    backoff.multiplier
+                );
+            }
+            if backoff.max_interval < interval {
+                bail!("FailureBackoff MaxInterval must not be shorter than the repeat interval");
+            }
+        }
+
+        if self.randomized_delay_seed.is_some() && self.randomized_delay_sec.is_none() {
+            bail!("RandomizedDelaySeed needs RandomizedDelaySec");
+        }
+
+        if self.wake_lock_delay.is_some() && !self.wake_lock {
+            bail!("WakeLockDelay needs WakeLock");
+        }
+        if !self.retry_on_exit.is_empty() && self.retries == 0 {
+            bail!("RetryOnExit needs Retries");
+        }
+        if self.service_type == ServiceType::Simple {
+            if self.retries > 0 {
+                bail!("Retries doesn't apply to Type = \"Simple\"");
+            }
+            if self.timeout_sec.is_some() {
+                bail!("TimeoutSec doesn't apply to Type = \"Simple\"");
+            }
+        }
+
+        if self.wake_system && self.clock_source() == ClockSource::Monotonic {
+            bail!("WakeSystem needs Clock = \"BootTime\" or \"Realtime\"");
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for TimerUnit {
+    type Err = anyhow::Error;
+
+    /// Parses and validates a unit from its TOML source
+    fn from_str(content: &str) -> Result<Self> {
+        // Parse with context for better error messages
+        let unit: TimerUnit = toml::from_str(content).context("Failed to parse configuration")?;
+        unit.validate()?;
+        Ok(unit)
+    }
+}
+
+/// Whether `program` names an existing file, directly or through `PATH`
+fn program_exists(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).exists();
+    }
+    env::var_os("PATH")
+        .is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).exists()))
+}
+
+/// Warns about settings that load but likely don't do what was meant, see [`questionable`]
+fn warn_questionable(name: &str, unit: &TimerUnit) {
+    for warning in questionable(unit) {
+        warn!("[{}] {}", name, warning);
+    }
+}
+
+/// Settings that load but likely don't do what was meant: a repeat interval with
+/// nothing to start it, which runs the unit one second after the daemon starts, or
+/// a `Shell` that isn't installed
+fn questionable(unit: &TimerUnit) -> Vec<String> {
+    let mut warnings = Vec::new();
+    if let Some(program) = unit.shell.as_ref().and_then(|shell| shell.first())
+        && !program_exists(program)
+    {
+        warnings.push(format!("Shell program {:?} was not found", program));
+    }
+
+    let repeats = unit.on_unit_active_sec.is_some() || unit.on_unit_inactive_sec.is_some();
+    let started =
+        unit.on_boot_sec.is_some() || unit.on_startup_sec.is_some() || !unit.on_calendar.is_empty();
+    if repeats && !started {
+        warnings.push(
+            "has a repeat interval but no OnBootSec, OnStartupSec or OnCalendar; \
+             it first runs 1s after the daemon starts"
+                .to_string(),
+        );
+    }
+    warnings
+}
+
+/// One `[[timer]]` entry of a multi-unit file
+#[derive(Debug, Deserialize)]
+struct NamedUnit {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(flatten)]
+    unit: TimerUnit,
+}
+
+/// A file grouping several units as a `[[timer]]` array of tables
+#[derive(Debug, Deserialize)]
+struct UnitList {
+    timer: Vec<NamedUnit>,
+}
+
+/// Parses a unit file in either shape: one unit, or a `[[timer]]` array
+///
+/// Units from an array are named `<stem>.<Name>`, so equal names in different
+/// files can't collide.
+fn parse_unit_file(stem: &str, table: toml::Table) -> Result<Vec<(String, TimerUnit)>> {
+    if !table.contains_key("timer") {
+        let mut unit: TimerUnit = table.try_into().context("Failed to parse configuration")?;
+        use_exec_file(&mut unit);
+        unit.validate()?;
+        warn_questionable(stem, &unit);
+        return Ok(vec![(stem.to_string(), unit)]);
+    }
+
+    let list: UnitList = table.try_into().context("Failed to parse configuration")?;
+
+    let mut seen = HashSet::new();
+    let mut units = Vec::with_capacity(list.timer.len());
+    for NamedUnit { name, mut unit } in list.timer {
+        use_exec_file(&mut unit);
+        if name.is_empty() || name.contains('/') {
+            bail!("Invalid timer name {:?}", name);
+        }
+        if !seen.insert(name.clone()) {
+            bail!("Duplicate timer name {:?}", name);
+        }
+        unit.validate()
+            .with_context(|| format!("Invalid timer {:?}", name))?;
+        let name = format!("{}.{}", stem, name);
+        warn_questionable(&name, &unit);
+        units.push((name, unit));
+    }
+    Ok(units)
+}
+
+/// Syntax of a configuration file, chosen by its extension
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Format {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl Format {
+    fn of(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Format::Toml),
+            "json" => Some(Format::Json),
+            "yaml" | "yml" => Some(Format::Yaml),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Format::Toml => "TOML",
+            Format::Json => "JSON",
+            Format::Yaml => "YAML",
+        })
+    }
+}
+
+/// Whether `path` has the extension of a configuration file (`.toml`, `.json`, `.yaml`/`.yml`)
+pub(crate) fn is_config_file(path: &Path) -> bool {
+    Format::of(path).is_some()
+}
+
+/// Reads a configuration file into a table, parsing it as its extension says
+///
+/// Every format goes through the same table, so templates, drop-ins and defaults
+/// work alike whichever format each file is written in.
+fn read_table(path: &Path) -> Result<toml::Table> {
+    let format = Format::of(path).unwrap_or(Format::Toml);
+    let content = fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let parsed = match format {
+        Format::Toml => toml::from_str(&content).map_err(anyhow::Error::from),
+        Format::Json => serde_json::from_str(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(json_table),
+        Format::Yaml => serde_yaml::from_str(&content)
+            .map_err(anyhow::Error::from)
+            .and_then(json_table),
+    };
+    parsed.with_context(|| format!("Failed to parse {:?} as {}", path, format))
+}
+
+/// Converts a parsed JSON or YAML document to a table, dropping `null` values
+///
+/// TOML has no null, and a YAML `Key:` without a value is common, so such keys count
+/// as absent. A null inside an array has no such meaning and is reported by its key.
+fn json_table(mut value: serde_json::Value) -> Result<toml::Table> {
+    strip_nulls(&mut value, "")?;
+    Ok(serde_json::from_value(value)?)
+}
+
+fn strip_nulls(value: &mut serde_json::Value, key: &str) -> Result<()> {
+    match value {
+        serde_json::Value::Object(map) => {
+            map.retain(|_, value| !value.is_null());
+            for (name, value) in map {
+                let key = match key {
+                    "" => name.clone(),
+                    _ => format!("{}.{}", key, name),
+                };
+                strip_nulls(value, &key)?;
+            }
+        }
+        serde_json::Value::Array(items) => {
+            for (index, item) in items.iter_mut().enumerate() {
+                let key = format!("{}[{}]", key, index);
+                if item.is_null() {
+                    bail!("{} is null", key);
+                }
+                strip_nulls(item, &key)?;
+            }
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+/// Reads, parses and validates a single unit file, with its drop-ins applied
+///
+/// An instance `<name>@<instance>.toml` (often a symlink) is merged over its template
+/// `<name>@.toml`, which has the same extension, and `%i` in its string values is
+/// replaced by the instance. The result is merged over `defaults`, for each unit of
+/// a `[[timer]]` file.
+fn load_unit(path: &Path, defaults: Option<&toml::Table>) -> Result<Vec<(String, TimerUnit)>> {
+    let mut table = read_table(path)?;
+    // Files the keys may come from, the one whose value wins last
+    let mut sources = vec![path.to_path_buf()];
+
+    let stem = path.file_stem().unwrap().to_string_lossy();
+    let instance = instance_of(&stem);
+    if let Some((template, _)) = instance {
+        let extension = path.extension().unwrap_or_default().to_string_lossy();
+        let template_path = path.with_file_name(format!("{}@.{}", template, extension));
+        if !template_path.exists() {
+            bail!("No template {:?} for instance {:?}", template_path, stem);
+        }
+        let mut template = read_table(&template_path).context("Invalid template")?;
+        merge_tables(&mut template, table);
+        table = template;
+        sources.insert(0, template_path);
+    }
+
+    for drop_in in drop_ins(path)? {
+        if table.contains_key("timer") {
+            bail!("Drop-ins are only supported for single-unit files");
+        }
+        let overlay = read_table(&drop_in).context("Invalid drop-in")?;
+        merge_tables(&mut table, overlay);
+        sources.push(drop_in);
+    }
+
+    if let Some(defaults) = defaults {
+        match table.get_mut("timer") {
+            Some(toml::Value::Array(entries)) => {
+                for entry in entries {
+                    if let toml::Value::Table(entry) = entry {
+                        *entry = with_defaults(defaults, std::mem::take(entry));
+                    }
+                }
+            }
+            _ => table = with_defaults(defaults, table),
+        }
+    }
+
+    if let Some((_, instance)) = instance {
+        for (_, value) in table.iter_mut() {
+            substitute_instance(value, instance);
+        }
+    }
+
+    let dir = path.parent().unwrap_or(Path::new("."));
+    match table.get_mut("timer") {
+        Some(toml::Value::Array(entries)) => {
+            for entry in entries {
+                if let toml::Value::Table(entry) = entry {
+                    let name = entry.get("Name").and_then(|name| name.as_str());
+                    let context = format!("Invalid timer {:?}", name.unwrap_or_default());
+                    resolve_exec_file(entry, dir).context(context)?;
+                }
+            }
+        }
+        _ => resolve_exec_file(&mut table, dir)?,
+    }
+    parse_unit_file(&stem, table).map_err(|e| match error_location(&e, path, &sources) {
+        Some(location) => e.context(location),
+        None => e,
+    })
+}
+
+/// Where the key a deserialization error names is set, e.g. `Line 3` or
+/// `Line 1 of "a.toml.d/override.toml"`
+///
+/// Errors about a `[[timer]]` entry as a whole, or about keys only set by the
+/// defaults, have no location.
+fn error_location(error: &anyhow::Error, path: &Path, sources: &[PathBuf]) -> Option<String> {
+    let message = error
+        .chain()
+        .find_map(|cause| cause.downcast_ref::<toml::de::Error>())?
+        .to_string();
+    let (_, key) = message.rsplit_once("in `")?;
+    let key = key.split('`').next()?.rsplit('.').next()?;
+    if key == "timer" {
+        return None;
+    }
+
+    sources.iter().rev().find_map(|source| {
+        let content = fs::read_to_string(source).ok()?;
+        let line = content.lines().position(|line| defines_key(line, key))? + 1;
+        Some(if source == path {
+            format!("Line {}", line)
+        } else {
+            format!("Line {} of {:?}", line, source)
+        })
+    })
+}
+
+/// Whether a TOML (`Key =`), JSON (`"Key":`) or YAML (`Key:`) line sets `key`
+fn defines_key(line: &str, key: &str) -> bool {
+    let line = line.trim_start().trim_start_matches(['{', ',', ' ']);
+    let quoted = line.strip_prefix(['"', '\'']);
+    let rest = match quoted {
+        Some(line) => line
+            .strip_prefix(key)
+            .and_then(|rest| rest.strip_prefix(['"', '\''])),
+        None => line.strip_prefix(key),
+    };
+    rest.is_some_and(|rest| rest.trim_start().starts_with(['=', ':']))
+}
+
+/// Points `Exec` at the unit's `ExecFile`, which [`resolve_exec_file`] made absolute
+fn use_exec_file(unit: &mut TimerUnit) {
+    if let Some(file) = &unit.exec_file {
+        unit.exec = Exec::File(file.to_string_lossy().into_owned());
+    }
+}
+
+/// Makes a unit's `ExecFile` absolute, even under a relative `dir`, and checks that it
+/// exists
+///
+/// Exactly one of `Exec` and `ExecFile` must be set. `Exec` gets a placeholder,
+/// which [`use_exec_file`] replaces once the unit is parsed.
+fn resolve_exec_file(table: &mut toml::Table, dir: &Path) -> Result<()> {
+    let file = match (table.get("Exec"), table.get("ExecFile")) {
+        (Some(_), Some(_)) => bail!("Exec and ExecFile are mutually exclusive"),
+        (None, None) => bail!("Either Exec or ExecFile must be set"),
+        (Some(_), None) => return Ok(()),
+        (None, Some(toml::Value::String(file))) => dir.join(file),
+        (None, Some(_)) => bail!("ExecFile must be a path"),
+    };
+    // Absolute against the current directory too, which the daemon leaves for "/"
+    let file = std::path::absolute(&file)
+        .with_context(|| format!("Failed to resolve ExecFile {:?}", file))?;
+
+    fs::metadata(&file).with_context(|| format!("Failed to read ExecFile {:?}", file))?;
+    let file = file.to_string_lossy().into_owned();
+    table.insert("ExecFile".to_string(), toml::Value::String(file.clone()));
+    table.insert("Exec".to_string(), toml::Value::String(file));
+    Ok(())
+}
+
+/// Splits an instance stem `<name>@<instance>` into its template name and instance
+///
+/// Templates themselves (`<name>@`) aren't instances.
+fn instance_of(stem: &str) -> Option<(&str, &str)> {
+    stem.split_once('@')
+        .filter(|(_, instance)| !instance.is_empty())
+}
+
+/// Whether `path` is a template (`<name>@.toml` or another extension), loaded only through its instances
+fn is_template(path: &Path) -> bool {
+    path.file_stem()
+        .and_then(|stem| stem.to_str())
+        .is_some_and(|stem| stem.ends_with('@'))
+}
+
+/// Replaces `%i` with `instance` in every string of a TOML value, recursively
+fn substitute_instance(value: &mut toml::Value, instance: &str) {
+    match value {
+        toml::Value::String(s) => *s = expand_instance(s, instance),
+        toml::Value::Array(values) => {
+            for value in values {
+                substitute_instance(value, instance);
+            }
+        }
+        toml::Value::Table(table) => {
+            for (_, value) in table.iter_mut() {
+                substitute_instance(value, instance);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// The configuration files in `<path>.d/`, in the order they are applied
+fn drop_ins(path: &Path) -> Result<Vec<PathBuf>> {
+    let mut dir = path.as_os_str().to_owned();
+    dir.push(".d");
+    let dir = PathBuf::from(dir);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut drop_ins = Vec::new();
+    for entry in fs::read_dir(&dir).with_context(|| format!("Failed to read {:?}", dir))? {
+        let path = entry?.path();
+        if is_config_file(&path) {
+            drop_ins.push(path);
+        }
+    }
+    drop_ins.sort();
+    Ok(drop_ins)
+}
+
+/// Deep-merges `overlay` into `base`: tables merge key by key, other values are replaced
+fn merge_tables(base: &mut toml::Table, overlay: toml::Table) {
+    for (key, value) in overlay {
+        match (base.get_mut(&key), value) {
+            (Some(toml::Value::Table(base)), toml::Value::Table(overlay)) => {
+                merge_tables(base, overlay)
+            }
+            (Some(slot), value) => *slot = value,
+            (None, value) => {
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// `unit` merged over a copy of `defaults`, so the unit's own keys win
+fn with_defaults(defaults: &toml::Table, unit: toml::Table) -> toml::Table {
+    let mut merged = defaults.clone();
+    merge_tables(&mut merged, unit);
+    merged
+}
+
+/// Where the defaults are read from unless given: `defaults.toml` beside the config directory
+fn default_defaults_path(dir: &Path) -> Option<PathBuf> {
+    dir.parent().map(|parent| parent.join("defaults.toml"))
+}
+
+/// Reads the keys merged beneath every unit
+///
+/// The file at `path` must exist; without one, the default location is optional.
+fn load_defaults(dir: &Path, path: Option<&Path>) -> Result<Option<toml::Table>> {
+    let path = match path {
+        Some(path) => path.to_path_buf(),
+        None => match default_defaults_path(dir) {
+            Some(path) if path.exists() => path,
+            _ => return Ok(None),
+        },
+    };
+
+    let defaults = read_table(&path).context("Invalid defaults")?;
+
+    for key in ["Exec", "ExecFile", "Name", "timer"] {
+        if defaults.contains_key(key) {
+            bail!("{} can't be set in defaults {:?}", key, path);
+        }
+    }
+    // Type-check the keys with a placeholder command, since Exec is set per unit
+    let mut check = defaults.clone();
+    check.insert("Exec".to_string(), toml::Value::String(String::new()));
+    check
+        .try_into::<TimerUnit>()
+        .with_context(|| format!("Invalid defaults {:?}", path))?;
+    Ok(Some(defaults))
+}
+
+/// One configuration file and the units parsed from it
+#[derive(Debug)]
+pub struct UnitFile {
+    pub path: PathBuf,
+    /// The file's `(name, unit)` entries, or why it couldn't be loaded
+    pub units: Result<Vec<(String, TimerUnit)>>,
+}
+
+/// Parses every configuration file in the directory, keeping per-file errors
+///
+/// Each entry carries the file's path, so callers can report which file is broken.
+/// A missing directory yields no entries; only an unreadable directory or an invalid
+/// defaults file is an error. Keys from `defaults` (or, if `None`, from an optional
+/// `defaults.toml` beside the directory) apply to every unit that doesn't set them.
+///
+/// `dir` may also be a single configuration file, which is loaded as if it were the
+/// only file in its directory.
+pub fn scan_units<P: AsRef<Path>>(dir: P, defaults: Option<&Path>) -> Result<Vec<UnitFile>> {
+    let mut units = Vec::new();
+    let path_ref = dir.as_ref();
+
+    if !path_ref.exists() {
+        // Just return empty if dir doesn't exist yet
+        return Ok(units);
+    }
+
+    if path_ref.is_file() {
+        if !is_config_file(path_ref) {
+            bail!(
+                "{:?} is neither a directory nor a .toml, .json or .yaml file",
+                path_ref
+            );
+        }
+        let dir = path_ref.parent().unwrap_or(Path::new("."));
+        let defaults = load_defaults(dir, defaults)?;
+        let loaded = match is_template(path_ref) {
+            true => Err(anyhow!(
+                "Templates only take effect through their instances"
+            )),
+            false => load_unit(path_ref, defaults.as_ref()),
+        };
+        units.push(UnitFile {
+            units: loaded,
+            path: path_ref.to_path_buf(),
+        });
+    } else {
+        let defaults = load_defaults(path_ref, defaults)?;
+
+        let entries = fs::read_dir(path_ref)
+            .with_context(|| format!("Failed to read configuration directory {:?}", path_ref))?;
+
+        for entry in entries {
+            let entry = entry?;
+            let path = entry.path();
+
+            // Other files are ignored; templates only take effect through their instances
+            if is_config_file(&path) && !is_template(&path) {
+                units.push(UnitFile {
+                    units: load_unit(&path, defaults.as_ref()),
+                    path,
+                });
+            }
+        }
+        units.sort_by(|a, b| a.path.cmp(&b.path));
+    }
+
+    // A name defined by an earlier file invalidates the later file as a whole
+    let mut owners: HashMap<String, PathBuf> = HashMap::new();
+    for file in &mut units {
+        let Ok(loaded) = &file.units else { continue };
+        let duplicate = loaded
+            .iter()
+            .find_map(|(name, _)| owners.get(name).map(|owner| (name, owner)));
+        if let Some((name, owner)) = duplicate {
+            file.units = Err(anyhow!(
+                "Duplicate unit name {:?}, already defined in {:?}",
+                name,
+                owner
+            ));
+            continue;
+        }
+        for (name, _) in loaded {
+            owners.insert(name.clone(), file.path.clone());
+        }
+    }
+
+    // Each cycle invalidates the files defining its units; repeat until none is left
+    loop {
+        let graph: BTreeMap<&str, Vec<&str>> = units
+            .iter()
+            .filter_map(|file| file.units.as_ref().ok())
+            .flatten()
+            .map(|(name, unit)| {
+                let deps = unit.after.iter().chain(&unit.requires);
+                (name.as_str(), deps.map(String::as_str).collect())
+            })
+            .collect();
+        let Some(cycle) = find_cycle(&graph) else {
+            break;
+        };
+
+        let members: Vec<String> = cycle.iter().map(|name| name.to_string()).collect();
+        let message = format!("Dependency cycle: {}", members.join(" -> "));
+        for file in &mut units {
+            let in_cycle = file
+                .units
+                .as_ref()
+                .is_ok_and(|loaded| loaded.iter().any(|(name, _)| members.contains(name)));
+            if in_cycle {
+                file.units = Err(anyhow!("{}", message));
+            }
+        }
+    }
+    Ok(units)
+}
+
+/// Some cycle among the `After`/`Requires` edges, as a path that ends where it starts
+///
+/// Edges to units that aren't in `graph` are ignored.
+fn find_cycle<'a>(graph: &BTreeMap<&'a str, Vec<&'a str>>) -> Option<Vec<&'a str>> {
+    fn visit<'a>(
+        name: &'a str,
+        graph: &BTreeMap<&'a str, Vec<&'a str>>,
+        path: &mut Vec<&'a str>,
+        done: &mut HashSet<&'a str>,
+    ) -> Option<Vec<&'a str>> {
+        if let Some(start) = path.iter().position(|&visited| visited == name) {
+            let mut cycle = path[start..].to_vec();
+            cycle.push(name);
+            return Some(cycle);
+        }
+        if done.contains(name) {
+            return None;
+        }
+
+        path.push(name);
+        for &dep in graph.get(name).into_iter().flatten() {
+            if graph.contains_key(dep)
+                && let Some(cycle) = visit(dep, graph, path, done)
+            {
+                return Some(cycle);
+            }
+        }
+        path.pop();
+        done.insert(name);
+        None
+    }
+
+    let mut done = HashSet::new();
+    graph
+        .keys()
+        .find_map(|&name| visit(name, graph, &mut Vec::new(), &mut done))
+}
+
+/// Scans the configuration directory for .toml files, returning the units sorted by name
+///
+/// Files that fail to read or parse (including a name another file already defines,
+/// e.g. `a.toml` and `a.yaml`) are logged and skipped, so one broken unit doesn't
+/// take down the others. Only an unreadable directory or an invalid defaults
+/// file is an error; see [`scan_units`] for `defaults`.
+pub fn load_timers<P: AsRef<Path>>(
+    dir: P,
+    defaults: Option<&Path>,
+) -> Result<Vec<(String, TimerUnit)>> {
+    let mut timers = Vec::new();
+    let files = scan_units(dir, defaults)?;
+    let total = files.len();
+    let mut invalid = 0;
+    for file in files {
+        match file.units {
+            Ok(units) => timers.extend(units),
+            Err(e) => {
+                invalid += 1;
+                error!("Skipping invalid unit {:?}: {:#}", file.path, e);
+            }
+        }
+    }
+    if invalid > 0 {
+        warn!(
+            "{} of {} configuration file(s) are invalid and were skipped",
+            invalid, total
+        );
+    }
+    timers.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(timers)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty `conf` directory of its own for each test, with room for a defaults
+    /// file beside it
+    fn config_dir(test: &str) -> PathBuf {
+        let root = env::temp_dir().join(format!("micetimer-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let dir = root.join("conf");
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// `source` plus a schedule, so each test only spells out the keys it is about
+    fn parse(source: &str) -> Result<TimerUnit> {
+        format!("OnBootSec = \"1h\"\n{}", source).parse()
+    }
+
+    fn names(units: &[(String, TimerUnit)]) -> Vec<&str> {
+        units.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    fn args(command: &Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|arg| arg.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn exec_file_follows_shell_and_mode_at_run_time() {
+        let script = env::temp_dir().join(format!("micetimer-exec-file-{}.sh", std::process::id()));
+        fs::write(&script, "echo hi\n").unwrap();
+        let path = script.to_string_lossy().into_owned();
+        let exec = Exec::File(path.clone());
+        let busybox = ["/bin/busybox", "sh", "-c"].map(String::from);
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_eq!(args(&exec.command(None)), ["sh", path.as_str()]);
+        assert_eq!(
+            args(&exec.command(Some(&busybox))),
+            ["/bin/busybox", "sh", path.as_str()]
+        );
+
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+        assert_eq!(args(&exec.command(Some(&busybox))), [path.as_str()]);
+        fs::remove_file(&script).unwrap();
+    }
+
+    #[test]
+    fn broken_file_is_skipped_without_the_others() {
+        let dir = config_dir("broken-file");
+        fs::write(
+            dir.join("good.toml"),
+            "Exec = \"true\"\nOnBootSec = \"1min\"\n",
+        )
+        .unwrap();
+        fs::write(dir.join("bad.toml"), "Exec = \"true\nOnBootSec = \n").unwrap();
+
+        let units = load_timers(&dir, None).unwrap();
+        assert_eq!(names(&units), ["good"]);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn exec_takes_a_shell_line_or_an_argv_array() {
+        let shell = parse(r#"Exec = "echo 'a b' > /dev/null""#).unwrap();
+        assert_eq!(
+            shell.exec,
+            Exec::Shell("echo 'a b' > /dev/null".to_string())
+        );
+        assert_eq!(args(&shell.exec.command(None))[..2], ["sh", "-c"]);
+
+        let argv = parse(r#"Exec = ["printf", "%s|", "a b", "c"]"#).unwrap();
+        assert_eq!(
+            argv.exec,
+            Exec::Argv(["printf", "%s|", "a b", "c"].map(String::from).to_vec())
+        );
+        let output = argv.exec.command(None).output().unwrap();
+        assert_eq!(output.stdout, b"a b|c|");
+
+        assert!(parse("Exec = []").is_err());
+    }
+
+    #[test]
+    fn multi_unit_files_load_beside_single_units() {
+        let dir = config_dir("multi-unit");
+        fs::write(
+            dir.join("net.toml"),
+            r#"
+            [[timer]]
+            Name = "ping"
+            Exec = "true"
+            OnBootSec = "15min"
+
+            [[timer]]
+            Name = "hosts"
+            Exec = "true"
+            OnCalendar = "03:00"
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("solo.toml"),
+            "Exec = \"true\"\nOnBootSec = \"1h\"\n",
+        )
+        .unwrap();
+
+        let mut units = load_timers(&dir, None).unwrap();
+        units.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names(&units), ["net.hosts", "net.ping", "solo"]);
+        assert_eq!(units[0].1.on_calendar, ["03:00"]);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn multi_unit_file_rejects_duplicate_names() {
+        let table: toml::Table = r#"
+            [[timer]]
+            Name = "ping"
+            Exec = "true"
+            OnBootSec = "15min"
+
+            [[timer]]
+            Name = "ping"
+            Exec = "false"
+            OnBootSec = "1h"
+        "#
+        .parse()
+        .unwrap();
+        let error = parse_unit_file("net", table).unwrap_err();
+        assert!(error.to_string().contains("Duplicate timer name \"ping\""));
+    }
+
+    #[test]
+    fn drop_in_overrides_only_the_keys_it_sets() {
+        let dir = config_dir("drop-in");
+        fs::write(
+            dir.join("sync.toml"),
+            "Exec = \"true\"\nOnBootSec = \"5min\"\nOnUnitActiveSec = \"1h\"\nWakeLock = false\n",
+        )
+        .unwrap();
+        let drop_ins = dir.join("sync.toml.d");
+        fs::create_dir(&drop_ins).unwrap();
+        fs::write(
+            drop_ins.join("10-faster.toml"),
+            "OnUnitActiveSec = \"15min\"\n",
+        )
+        .unwrap();
+        // Later fragments win
+        fs::write(
+            drop_ins.join("20-fastest.toml"),
+            "OnUnitActiveSec = \"5min\"\n",
+        )
+        .unwrap();
+
+        let units = load_timers(&dir, None).unwrap();
+        assert_eq!(names(&units), ["sync"]);
+        let unit = &units[0].1;
+        assert_eq!(unit.on_unit_active_sec, Some(Duration::from_secs(300)));
+        assert_eq!(unit.on_boot_sec, Some(Duration::from_secs(300)));
+        assert_eq!(unit.exec, Exec::Shell("true".to_string()));
+        assert!(!unit.wake_lock);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn template_instances_expand_their_own_name() {
+        let dir = config_dir("template");
+        fs::write(
+            dir.join("backup@.toml"),
+            r#"
+            Exec = "backup.sh %i %n"
+            OnCalendar = "03:00"
+            Environment = { TARGET = "/sdcard/%i" }
+            "#,
+        )
+        .unwrap();
+        std::os::unix::fs::symlink("backup@.toml", dir.join("backup@acct1.toml")).unwrap();
+        fs::write(dir.join("backup@acct2.toml"), "OnCalendar = \"04:00\"\n").unwrap();
+
+        let mut units = load_timers(&dir, None).unwrap();
+        units.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names(&units), ["backup@acct1", "backup@acct2"]);
+        for ((_, unit), (instance, calendar)) in
+            units.iter().zip([("acct1", "03:00"), ("acct2", "04:00")])
+        {
+            // %n is left for run time
+            assert_eq!(unit.exec, Exec::Shell(format!("backup.sh {} %n", instance)));
+            assert_eq!(unit.environment["TARGET"], format!("/sdcard/{}", instance));
+            assert_eq!(unit.on_calendar, [calendar]);
+        }
+
+        // An instance needs its template
+        fs::write(
+            dir.join("orphan@x.toml"),
+            "Exec = \"true\"\nOnBootSec = \"1h\"\n",
+        )
+        .unwrap();
+        let units = load_timers(&dir, None).unwrap();
+        assert!(!names(&units).contains(&"orphan@x"));
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn defaults_apply_beneath_units_that_omit_the_key() {
+        let dir = config_dir("defaults");
+        fs::write(
+            dir.parent().unwrap().join("defaults.toml"),
+            "WakeLock = false\nAccuracySec = \"5min\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("inherits.toml"),
+            "Exec = \"true\"\nOnBootSec = \"1h\"\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("overrides.toml"),
+            "Exec = \"true\"\nOnBootSec = \"1h\"\nWakeLock = true\n",
+        )
+        .unwrap();
+
+        let mut units = load_timers(&dir, None).unwrap();
+        units.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names(&units), ["inherits", "overrides"]);
+        assert!(!units[0].1.wake_lock);
+        assert!(units[1].1.wake_lock);
+        for (_, unit) in &units {
+            assert_eq!(unit.accuracy_sec, Duration::from_secs(300));
+        }
+
+        // An explicit defaults file replaces the one beside the directory
+        let other = dir.parent().unwrap().join("other.toml");
+        fs::write(&other, "AccuracySec = \"1s\"\n").unwrap();
+        let units = load_timers(&dir, Some(&other)).unwrap();
+        assert!(
+            units
+                .iter()
+                .all(|(_, unit)| unit.accuracy_sec == Duration::from_secs(1))
+        );
+        assert!(units.iter().all(|(_, unit)| unit.wake_lock));
+
+        // Exec still has to come from each unit
+        fs::write(
+            dir.parent().unwrap().join("defaults.toml"),
+            "Exec = \"true\"\n",
+        )
+        .unwrap();
+        let error = load_timers(&dir, None).unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .starts_with("Exec can't be set in defaults")
+        );
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn dependency_cycles_are_rejected_at_load_time() {
+        let graph = BTreeMap::from([
+            ("a", vec!["b"]),
+            ("b", vec!["c", "outside"]),
+            ("c", vec!["a"]),
+            ("d", vec!["a"]),
+        ]);
+        assert_eq!(find_cycle(&graph), Some(vec!["a", "b", "c", "a"]));
+        let acyclic = BTreeMap::from([("a", vec!["b"]), ("b", vec![]), ("c", vec!["a", "b"])]);
+        assert_eq!(find_cycle(&acyclic), None);
+
+        let dir = config_dir("cycle");
+        for (name, deps) in [
+            ("a", "After = [\"b\"]"),
+            ("b", "Requires = [\"a\"]"),
+            ("c", "After = [\"a\"]"),
+        ] {
+            fs::write(
+                dir.join(format!("{}.toml", name)),
+                format!("Exec = \"true\"\nOnBootSec = \"1h\"\n{}\n", deps),
+            )
+            .unwrap();
+        }
+        let units = load_timers(&dir, None).unwrap();
+        assert_eq!(names(&units), ["c"]);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn toml_yaml_and_json_load_the_same_unit() {
+        let dir = config_dir("formats");
+        fs::write(
+            dir.join("a.toml"),
+            r#"
+            Exec = ["sync.sh", "--all"]
+            OnCalendar = ["03:00", "15:00"]
+            WakeLock = false
+            Environment = { MODE = "full" }
+            "#,
+        )
+        .unwrap();
+        fs::write(
+            dir.join("b.yaml"),
+            "Exec: [sync.sh, --all]\nOnCalendar: ['03:00', '15:00']\nWakeLock: false\nEnvironment:\n  MODE: full\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("c.json"),
+            r#"{"Exec": ["sync.sh", "--all"], "OnCalendar": ["03:00", "15:00"],
+                "WakeLock": false, "Environment": {"MODE": "full"}}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("d.yml"), "Exec: [unclosed\n").unwrap();
+        fs::write(dir.join("notes.txt"), "not a unit").unwrap();
+
+        let mut units = load_timers(&dir, None).unwrap();
+        units.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(names(&units), ["a", "b", "c"]);
+        assert_eq!(units[0].1, units[1].1);
+        assert_eq!(units[0].1, units[2].1);
+
+        let broken = scan_units(&dir, None)
+            .unwrap()
+            .into_iter()
+            .find_map(|file| file.units.err())
+            .unwrap();
+        let message = format!("{:#}", broken);
+        assert!(
+            message.contains("d.yml") && message.contains("YAML"),
+            "{}",
+            message
+        );
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn signal_names_parse_with_or_without_prefix() {
+        assert_eq!(parse_signal("SIGINT"), Some(Signal::SIGINT));
+        assert_eq!(parse_signal("int"), Some(Signal::SIGINT));
+        assert_eq!(parse_signal("Hup"), Some(Signal::SIGHUP));
+        assert_eq!(parse_signal("sigusr1"), Some(Signal::SIGUSR1));
+        assert_eq!(parse_signal("SIGNOPE"), None);
+        assert_eq!(parse_signal("9"), None);
+
+        assert_eq!(
+            parse("Exec = \"true\"").unwrap().kill_signal,
+            Signal::SIGTERM
+        );
+        let unit = parse("Exec = \"true\"\nKillSignal = \"INT\"\nKillMode = \"Process\"").unwrap();
+        assert_eq!(unit.kill_signal, Signal::SIGINT);
+        assert_eq!(unit.kill_mode, KillMode::Process);
+        assert!(parse("Exec = \"true\"\nKillSignal = \"SIGBOGUS\"").is_err());
+    }
+
+    #[test]
+    fn unit_without_a_schedule_is_rejected() {
+        let error = "Exec = \"true\""
+            .parse::<TimerUnit>()
+            .unwrap_err()
+            .to_string();
+        assert!(
+            error.starts_with("No schedule: set at least one of OnBootSec"),
+            "{}",
+            error
+        );
+        assert!(error.contains("OnUnitInactiveSec"), "{}", error);
+        assert!(
+            "Exec = \"true\"\nOnUnitActiveSec = \"1h\""
+                .parse::<TimerUnit>()
+                .is_ok()
+        );
+    }
+
+    #[test]
+    fn interval_without_an_initial_trigger_is_questionable() {
+        let alone: TimerUnit = "Exec = \"true\"\nOnUnitActiveSec = \"1h\"".parse().unwrap();
+        let warnings = questionable(&alone);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].starts_with("has a repeat interval but no OnBootSec"));
+
+        let started = parse("Exec = \"true\"\nOnUnitActiveSec = \"1h\"").unwrap();
+        assert_eq!(questionable(&started), Vec::<String>::new());
+        let missing_shell = parse("Exec = \"true\"\nShell = [\"/no/such/shell\", \"-c\"]").unwrap();
+        assert_eq!(
+            questionable(&missing_shell),
+            ["Shell program \"/no/such/shell\" was not found"]
+        );
+    }
+
+    #[test]
+    fn exactly_one_of_exec_and_exec_file_is_required() {
+        let dir = Path::new("/");
+        let mut both: toml::Table = "Exec = \"true\"\nExecFile = \"bin/true\"".parse().unwrap();
+        let error = resolve_exec_file(&mut both, dir).unwrap_err();
+        assert!(error.to_string().contains("mutually exclusive"));
+        let mut neither: toml::Table = "OnBootSec = \"1h\"".parse().unwrap();
+        let error = resolve_exec_file(&mut neither, dir).unwrap_err();
+        assert!(error.to_string().contains("Either Exec or ExecFile"));
+    }
+
+    #[test]
+    fn exec_file_runs_the_script_relative_to_the_config_dir() {
+        let dir = config_dir("exec-file");
+        fs::create_dir(dir.join("scripts")).unwrap();
+        fs::write(dir.join("scripts/greet.sh"), "echo hello from $0\n").unwrap();
+        fs::write(
+            dir.join("greet.toml"),
+            "ExecFile = \"scripts/greet.sh\"\nOnBootSec = \"1h\"\n",
+        )
+        .unwrap();
+
+        let units = load_timers(&dir, None).unwrap();
+        let script = dir.join("scripts/greet.sh");
+        let unit = &units[0].1;
+        assert_eq!(unit.exec_file.as_deref(), Some(script.as_path()));
+        let output = unit.exec.command(None).output().unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            format!("hello from {}\n", script.display())
+        );
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn over_range_durations_are_rejected_and_zero_intervals_unset() {
+        let error = parse("Exec = \"true\"\nOnUnitActiveSec = \"200y\"").unwrap_err();
+        assert!(
+            error.to_string().contains("OnUnitActiveSec is longer than"),
+            "{}",
+            error
+        );
+        assert!(parse("Exec = \"true\"\nOnUnitActiveSec = \"3650d\"").is_ok());
+
+        // Zero would re-arm the moment it fires; it means "no repeat" instead
+        let zero =
+            parse("Exec = \"true\"\nOnUnitActiveSec = \"0s\"\nOnUnitInactiveSec = \"0s\"").unwrap();
+        assert_eq!(zero.on_unit_active_sec, None);
+        assert_eq!(zero.on_unit_inactive_sec, None);
+    }
+
+    #[test]
+    fn units_load_sorted_and_duplicate_names_are_rejected() {
+        let dir = config_dir("sorted");
+        for name in ["charlie", "alpha", "bravo"] {
+            fs::write(
+                dir.join(format!("{}.toml", name)),
+                "Exec = \"true\"\nOnBootSec = \"1h\"\n",
+            )
+            .unwrap();
+        }
+        fs::write(
+            dir.join("alpha.yaml"),
+            "Exec: \"false\"\nOnBootSec: \"2h\"\n",
+        )
+        .unwrap();
+
+        let units = load_timers(&dir, None).unwrap();
+        assert_eq!(names(&units), ["alpha", "bravo", "charlie"]);
+        assert_eq!(units[0].1.exec, Exec::Shell("true".to_string()));
+
+        let files = scan_units(&dir, None).unwrap();
+        let duplicate = files
+            .iter()
+            .find(|file| file.path.ends_with("alpha.yaml"))
+            .unwrap();
+        let error = duplicate.units.as_ref().unwrap_err().to_string();
+        assert!(error.contains("Duplicate unit name \"alpha\""), "{}", error);
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn single_file_path_loads_just_that_unit() {
+        let dir = config_dir("single-file");
+        let unit = "Exec = \"true\"\nOnBootSec = \"1h\"\n";
+        fs::write(dir.join("chosen.toml"), unit).unwrap();
+        fs::write(dir.join("sibling.toml"), unit).unwrap();
+
+        let units = load_timers(dir.join("chosen.toml"), None).unwrap();
+        assert_eq!(names(&units), ["chosen"]);
+        // Missing, it's empty like a missing directory; present, it isn't a unit
+        assert!(load_timers(dir.join("notes.txt"), None).unwrap().is_empty());
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        assert!(load_timers(dir.join("notes.txt"), None).is_err());
+        fs::remove_dir_all(dir.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn generated_example_round_trips_into_a_valid_unit() {
+        let example = example_unit();
+        assert!(!example.contains("(undocumented)"), "{}", example);
+        let unit: TimerUnit = toml::from_str(&example).unwrap();
+        assert!(unit.validate().is_ok());
+
+        // The values shown as defaults are the defaults
+        let defaults_set: String = example
+            .lines()
+            .map(|line| match line.strip_suffix("  # default") {
+                Some(line) => line.trim_start_matches("# "),
+                None => line,
+            })
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert_ne!(defaults_set, example);
+        assert_eq!(toml::from_str::<TimerUnit>(&defaults_set).unwrap(), unit);
+    }
+
+    #[test]
+    fn selinux_contexts_need_user_role_type_and_an_optional_level() {
+        for context in ["u:r:magisk:s0", "u:r:untrusted_app:s0:c512,c768", "u:r:su"] {
+            assert!(is_selinux_context(context), "{}", context);
+        }
+        for context in [
+            "magisk",
+            "u:r:",
+            "u::magisk:s0",
+            "u:r:magisk:",
+            "u:r:bad type:s0",
+        ] {
+            assert!(!is_selinux_context(context), "{}", context);
+        }
+
+        let error = parse("Exec = \"true\"\nSELinuxContext = \"magisk\"").unwrap_err();
+        assert!(
+            error.to_string().contains("user:role:type[:level]"),
+            "{}",
+            error
+        );
+    }
+}
@@ -0,0 +1,545 @@
+//! Android wakelocks held while commands run
+
+use log::{debug, error, info, warn};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::Duration;
+
+/// Where wakelocks are taken and dropped
+///
+/// Calls are serialized, and each name is released at most once per acquisition.
+pub trait WakeLock: Send + Sync {
+    fn acquire(&self, name: &str) -> io::Result<()>;
+    fn release(&self, name: &str) -> io::Result<()>;
+
+    /// Names of the wakelocks currently held by any process, for backends that can tell
+    fn held(&self) -> io::Result<Vec<String>> {
+        Ok(Vec::new())
+    }
+}
+
+/// The kernel's `wake_lock` and `wake_unlock` files, by default in `/sys/power`
+#[derive(Debug, Clone)]
+pub struct SysfsWakeLock {
+    dir: PathBuf,
+}
+
+impl SysfsWakeLock {
+    /// Uses the `wake_lock` and `wake_unlock` files in `dir`
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Whether the kernel offers the interface in this directory
+    pub fn available(&self) -> bool {
+        self.dir.join("wake_lock").exists()
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+}
+
+impl Default for SysfsWakeLock {
+    fn default() -> Self {
+        Self::new("/sys/power")
+    }
+}
+
+impl WakeLock for SysfsWakeLock {
+    fn acquire(&self, name: &str) -> io::Result<()> {
+        fs::write(self.dir.join("wake_lock"), name)
+    }
+
+    fn release(&self, name: &str) -> io::Result<()> {
+        fs::write(self.dir.join("wake_unlock"), name)
+    }
+
+    /// Reading `wake_lock` lists the active locks, separated by spaces
+    fn held(&self) -> io::Result<Vec<String>> {
+        let active = fs::read_to_string(self.dir.join("wake_lock"))?;
+        Ok(active.split_whitespace().map(String::from).collect())
+    }
+}
+
+/// Holds nothing, for kernels without wakelocks or devices that may suspend anyway
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopWakeLock;
+
+impl WakeLock for NoopWakeLock {
+    fn acquire(&self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn release(&self, _name: &str) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Prefix of every wakelock name when none is configured
+const DEFAULT_PREFIX: &str = "micetimer:";
+
+/// The wakelocks a daemon takes: the backend, the names, and which of them are held
+///
+/// One is shared by a scheduler and its workers, so shutdown can release what a
+/// worker leaked.
+pub struct WakeLocks {
+    /// Set by [`WakeLocks::new`], or detected on first use
+    backend: OnceLock<Box<dyn WakeLock>>,
+    prefix: String,
+    /// Wakelocks currently held by workers
+    ///
+    /// Backend calls happen under this lock, so the map and the kernel agree.
+    held: Mutex<BTreeMap<String, HeldLock>>,
+    /// Source of `HeldLock::id`
+    acquisitions: AtomicU64,
+    /// Source of the sequence number in lock names
+    sequence: AtomicU64,
+}
+
+impl Default for WakeLocks {
+    /// Uses the sysfs interface if the kernel has one, and no wakelocks otherwise
+    fn default() -> Self {
+        Self {
+            backend: OnceLock::new(),
+            prefix: DEFAULT_PREFIX.to_string(),
+            held: Mutex::default(),
+            acquisitions: AtomicU64::new(0),
+            sequence: AtomicU64::new(0),
+        }
+    }
+}
+
+/// The sysfs interface if the kernel has one, no wakelocks otherwise
+fn detect_backend() -> Box<dyn WakeLock> {
+    let sysfs = SysfsWakeLock::default();
+    if sysfs.available() {
+        return Box::new(sysfs);
+    }
+    warn!(
+        "No wakelock interface in {:?}, running without wakelocks",
+        sysfs.dir()
+    );
+    Box::new(NoopWakeLock)
+}
+
+struct HeldLock {
+    /// Tells a fallback apart from one armed by a later acquisition of the same name
+    id: u64,
+    /// Dropped on release, which wakes the fallback thread so it exits early
+    _cancel: Option<Sender<()>>,
+}
+
+impl WakeLocks {
+    /// Takes every wakelock through `backend`
+    pub fn new(backend: impl WakeLock + 'static) -> Self {
+        let locks = Self::default();
+        let _ = locks.backend.set(Box::new(backend));
+        locks
+    }
+
+    /// Names wakelocks `<prefix><unit>` instead of `micetimer:<unit>`
+    ///
+    /// [`WakeLocks::reclaim_stale`] releases every lock with this prefix, so it should
+    /// be unique to this daemon.
+    pub fn set_prefix(&mut self, prefix: impl Into<String>) {
+        self.prefix = prefix.into();
+    }
+
+    fn backend(&self) -> &dyn WakeLock {
+        self.backend.get_or_init(detect_backend).as_ref()
+    }
+
+    /// Releases wakelocks with our prefix that a previous instance, e.g. one that
+    /// crashed mid-firing, left held; returns how many
+    ///
+    /// Call this before any unit fires, since every such lock counts as stale.
+    pub fn reclaim_stale(&self) -> usize {
+        let active = match self.backend().held() {
+            Ok(active) => active,
+            Err(e) => {
+                warn!("Failed to list active wakelocks: {}", e);
+                return 0;
+            }
+        };
+
+        let held = self.held.lock().unwrap();
+        let mut reclaimed = 0;
+        for name in active {
+            if !name.starts_with(&self.prefix) || held.contains_key(&name) {
+                continue;
+            }
+            match self.backend().release(&name) {
+                Ok(()) => {
+                    warn!(
+                        "Released stale WakeLock {} left by a previous instance",
+                        name
+                    );
+                    reclaimed += 1;
+                }
+                Err(e) => error!("Failed to release stale WakeLock {}: {}", name, e),
+            }
+        }
+        reclaimed
+    }
+
+    /// A fresh name for a WakeLock held while `unit_name` runs: `<prefix><unit>.<n>`
+    ///
+    /// The kernel doesn't count acquisitions of one name, so overlapping runs of a unit
+    /// (`Concurrency = "Parallel"`, a queued run next to a detached `Type = "Simple"`
+    /// command) each take their own lock, and one finishing can't release the other's.
+    pub(crate) fn lock_name(&self, unit_name: &str) -> String {
+        let sequence = self.sequence.fetch_add(1, Ordering::Relaxed);
+        format!("{}{}.{}", self.prefix, unit_name, sequence)
+    }
+
+    /// Acquires an Android WakeLock and records it as held
+    ///
+    /// With `max_hold`, the lock is force-released once that much time passes, even if
+    /// the command holding it is still running.
+    pub(crate) fn acquire(self: &Arc<Self>, lock_name: &str, max_hold: Option<Duration>) -> bool {
+        let mut held = self.held.lock().unwrap();
+        if let Err(e) = self.backend().acquire(lock_name) {
+            error!("Failed to acquire WakeLock {}: {}", lock_name, e);
+            return false;
+        }
+        debug!("Acquired WakeLock: {}", lock_name);
+
+        let id = self.acquisitions.fetch_add(1, Ordering::Relaxed);
+        let cancel = max_hold.and_then(|max_hold| self.release_after(lock_name, id, max_hold));
+        held.insert(
+            lock_name.to_string(),
+            HeldLock {
+                id,
+                _cancel: cancel,
+            },
+        );
+        true
+    }
+
+    /// Spawns the fallback that releases acquisition `id` of `lock_name` after `max_hold`
+    fn release_after(
+        self: &Arc<Self>,
+        lock_name: &str,
+        id: u64,
+        max_hold: Duration,
+    ) -> Option<Sender<()>> {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let name = lock_name.to_string();
+        let locks = Arc::clone(self);
+
+        let spawned = thread::Builder::new()
+            .name("wakelock-timeout".to_string())
+            .spawn(move || {
+                if cancelled.recv_timeout(max_hold) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+
+                let mut held = locks.held.lock().unwrap();
+                if held.get(&name).is_none_or(|lock| lock.id != id) {
+                    return;
+                }
+                warn!(
+                    "WakeLock {} held for longer than {:?}, releasing it",
+                    name, max_hold
+                );
+                held.remove(&name);
+                locks.unlock(&name);
+            });
+
+        match spawned {
+            Ok(_) => Some(cancel),
+            Err(e) => {
+                error!("Failed to arm WakeLock timeout for {}: {}", lock_name, e);
+                None
+            }
+        }
+    }
+
+    /// Acquires `lock_name` as [`WakeLocks::acquire`] does once `delay` passes, unless
+    /// the returned guard is released first
+    ///
+    /// If no delay thread can be started, the lock is acquired right away instead.
+    pub(crate) fn acquire_after(
+        self: &Arc<Self>,
+        lock_name: &str,
+        delay: Duration,
+        max_hold: Option<Duration>,
+    ) -> DelayedWakelock {
+        let (cancel, cancelled) = mpsc::channel::<()>();
+        let released = Arc::new(Mutex::new(false));
+        let name = lock_name.to_string();
+        let pending = Arc::clone(&released);
+        let locks = Arc::clone(self);
+
+        let spawned = thread::Builder::new()
+            .name("wakelock-delay".to_string())
+            .spawn(move || {
+                if cancelled.recv_timeout(delay) != Err(RecvTimeoutError::Timeout) {
+                    return;
+                }
+                let released = pending.lock().unwrap();
+                if !*released {
+                    debug!("{} still running after {:?}", name, delay);
+                    locks.acquire(&name, max_hold);
+                }
+            });
+        if let Err(e) = spawned {
+            error!("Failed to delay WakeLock {}: {}", lock_name, e);
+            self.acquire(lock_name, max_hold);
+        }
+
+        DelayedWakelock {
+            locks: Arc::clone(self),
+            lock_name: lock_name.to_string(),
+            released,
+            _cancel: cancel,
+        }
+    }
+
+    /// Releases an Android WakeLock and forgets it; does nothing if it isn't held
+    pub(crate) fn release(&self, lock_name: &str) {
+        let mut held = self.held.lock().unwrap();
+        if held.remove(lock_name).is_some() {
+            self.unlock(lock_name);
+        }
+    }
+
+    fn unlock(&self, lock_name: &str) {
+        if let Err(e) = self.backend().release(lock_name) {
+            error!("Failed to release WakeLock {}: {}", lock_name, e);
+        } else {
+            debug!("Released WakeLock: {}", lock_name);
+        }
+    }
+
+    /// Releases every WakeLock still held by a worker, so none leak past our exit
+    pub(crate) fn release_all(&self) {
+        let held: Vec<String> = self.held.lock().unwrap().keys().cloned().collect();
+        for lock_name in held {
+            info!("Releasing held WakeLock on shutdown: {}", lock_name);
+            self.release(&lock_name);
+        }
+    }
+}
+
+/// A wakelock taken only if the run is still going once its delay passed
+pub(crate) struct DelayedWakelock {
+    locks: Arc<WakeLocks>,
+    lock_name: String,
+    /// Set on release under the lock, so the delay thread can't acquire after it
+    released: Arc<Mutex<bool>>,
+    /// Dropped on release, which wakes the delay thread so it exits early
+    _cancel: Sender<()>,
+}
+
+impl DelayedWakelock {
+    /// Releases the wakelock if the delay ran out, and cancels it otherwise
+    pub(crate) fn release(self) {
+        *self.released.lock().unwrap() = true;
+        self.locks.release(&self.lock_name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::exec::{RunConfig, run_unit};
+    use crate::unit::TimerUnit;
+
+    /// Stands in for the kernel: the names currently locked, and every name ever
+    /// acquired
+    #[derive(Clone, Default)]
+    struct Recorder {
+        active: Arc<Mutex<Vec<String>>>,
+        acquired: Arc<Mutex<Vec<String>>>,
+    }
+
+    impl WakeLock for Recorder {
+        fn acquire(&self, name: &str) -> io::Result<()> {
+            self.active.lock().unwrap().push(name.to_string());
+            self.acquired.lock().unwrap().push(name.to_string());
+            Ok(())
+        }
+
+        fn release(&self, name: &str) -> io::Result<()> {
+            self.active.lock().unwrap().retain(|active| active != name);
+            Ok(())
+        }
+
+        fn held(&self) -> io::Result<Vec<String>> {
+            Ok(self.active.lock().unwrap().clone())
+        }
+    }
+
+    impl Recorder {
+        fn is_active(&self, name: &str) -> bool {
+            self.active
+                .lock()
+                .unwrap()
+                .iter()
+                .any(|active| active == name)
+        }
+
+        fn was_acquired(&self, name: &str) -> bool {
+            self.acquired.lock().unwrap().iter().any(|n| n == name)
+        }
+    }
+
+    /// Wakelocks of a test's own, taken through a recorder it can inspect
+    fn setup() -> (Arc<WakeLocks>, Recorder) {
+        let recorder = Recorder::default();
+        (Arc::new(WakeLocks::new(recorder.clone())), recorder)
+    }
+
+    /// A config that runs firings with the wakelocks of a test's own
+    fn run_config() -> (RunConfig, Recorder) {
+        let recorder = Recorder::default();
+        let mut config = RunConfig::default();
+        config.set_wakelocks(WakeLocks::new(recorder.clone()));
+        (config, recorder)
+    }
+
+    #[test]
+    fn overlapping_runs_hold_separate_locks() {
+        let (locks, recorder) = setup();
+        let first = locks.lock_name("parallel");
+        let second = locks.lock_name("parallel");
+        assert_ne!(first, second);
+        assert!(first.starts_with("micetimer:parallel."));
+
+        assert!(locks.acquire(&first, None));
+        assert!(locks.acquire(&second, None));
+        locks.release(&first);
+        assert!(!recorder.is_active(&first));
+        assert!(recorder.is_active(&second));
+        locks.release(&second);
+        assert!(!recorder.is_active(&second));
+    }
+
+    #[test]
+    fn shutdown_releases_locks_still_held() {
+        let (locks, recorder) = setup();
+        let stuck = locks.lock_name("stuck");
+        assert!(locks.acquire(&stuck, None));
+        assert!(recorder.is_active(&stuck));
+
+        locks.release_all();
+        assert!(!recorder.is_active(&stuck));
+        assert!(locks.held.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn max_hold_releases_a_lock_the_command_still_holds() {
+        let (locks, recorder) = setup();
+        let long = locks.lock_name("long");
+        assert!(locks.acquire(&long, Some(Duration::from_millis(50))));
+        assert!(recorder.is_active(&long));
+        thread::sleep(Duration::from_millis(200));
+        assert!(!recorder.is_active(&long));
+        assert!(!locks.held.lock().unwrap().contains_key(&long));
+        // The run's own release afterwards finds nothing left to do
+        locks.release(&long);
+
+        // A fallback outlived by its acquisition leaves the next one of the name alone
+        let reused = "micetimer:reused";
+        assert!(locks.acquire(reused, Some(Duration::from_millis(50))));
+        locks.release(reused);
+        assert!(locks.acquire(reused, None));
+        thread::sleep(Duration::from_millis(200));
+        assert!(recorder.is_active(reused));
+        locks.release(reused);
+        assert!(!recorder.is_active(reused));
+    }
+
+    #[test]
+    fn prefix_names_every_lock() {
+        let mut locks = WakeLocks::new(NoopWakeLock);
+        locks.set_prefix("other:");
+        assert!(locks.lock_name("unit").starts_with("other:unit."));
+    }
+
+    #[test]
+    fn wakelock_is_named_after_the_unit_not_its_log_tag() {
+        let (config, recorder) = run_config();
+        let unit: TimerUnit = "Exec = \"true\"\nOnBootSec = \"1h\"\nLogTag = \"Friendly\""
+            .parse()
+            .unwrap();
+        assert!(run_unit(&config, "tagged-stem", &unit).is_ok());
+
+        let seen = recorder.acquired.lock().unwrap().clone();
+        assert!(
+            seen.iter()
+                .any(|name| name.starts_with("micetimer:tagged-stem.")),
+            "{:?}",
+            seen
+        );
+        assert!(
+            !seen.iter().any(|name| name.contains("Friendly")),
+            "{:?}",
+            seen
+        );
+    }
+
+    #[test]
+    fn delayed_lock_is_taken_only_by_a_slow_command() {
+        let (locks, recorder) = setup();
+        let delay = Duration::from_millis(50);
+
+        let fast = locks.lock_name("fast");
+        locks.acquire_after(&fast, delay, None).release();
+        thread::sleep(delay * 3);
+        assert!(!recorder.was_acquired(&fast));
+
+        let slow = locks.lock_name("slow");
+        let guard = locks.acquire_after(&slow, delay, None);
+        thread::sleep(delay * 3);
+        assert!(recorder.is_active(&slow));
+        guard.release();
+        assert!(!recorder.is_active(&slow));
+        assert!(recorder.was_acquired(&slow));
+    }
+
+    #[test]
+    fn firing_acquires_and_releases_its_lock_once() {
+        let (config, recorder) = run_config();
+        let unit: TimerUnit = "Exec = \"true\"\nOnBootSec = \"1h\"".parse().unwrap();
+
+        assert!(run_unit(&config, "balanced", &unit).unwrap().success);
+        let locks = recorder.acquired.lock().unwrap().clone();
+        assert_eq!(locks.len(), 1, "{:?}", locks);
+        assert!(locks[0].starts_with("micetimer:balanced."));
+        assert!(recorder.active.lock().unwrap().is_empty());
+        assert!(config.wakelocks().held.lock().unwrap().is_empty());
+
+        let unlocked: TimerUnit = "Exec = \"true\"\nOnBootSec = \"1h\"\nWakeLock = false"
+            .parse()
+            .unwrap();
+        assert!(run_unit(&config, "unlocked", &unlocked).unwrap().success);
+        assert_eq!(recorder.acquired.lock().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn startup_reclaims_stale_locks_with_our_prefix_only() {
+        let (locks, recorder) = setup();
+        let live = locks.lock_name("live");
+        assert!(locks.acquire(&live, None));
+        // Left behind by a crashed instance, and by someone else
+        recorder
+            .active
+            .lock()
+            .unwrap()
+            .extend(["micetimer:stale.3", "other"].map(String::from));
+
+        assert_eq!(locks.reclaim_stale(), 1);
+        assert!(!recorder.is_active("micetimer:stale.3"));
+        assert!(recorder.is_active("other"));
+        assert!(recorder.is_active(&live));
+    }
+}
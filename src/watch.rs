@@ -0,0 +1,180 @@
+//! Watching the configuration directory, so edits are reloaded without SIGHUP
+
+use crate::unit::is_config_file;
+use anyhow::{Context, Result};
+use log::{debug, warn};
+use nix::sys::inotify::{AddWatchFlags, InitFlags, Inotify};
+use nix::sys::time::TimeSpec;
+use nix::sys::timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags};
+use std::fs;
+use std::os::unix::io::{AsFd, AsRawFd};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Quiet period after the last change before reloading, so an editor's burst of
+/// writes (temp file, rename, chmod) reloads once
+const RELOAD_DEBOUNCE: Duration = Duration::from_millis(500);
+
+/// What changes are watched for, in the directory and in its drop-in directories
+const WATCH_FLAGS: AddWatchFlags = AddWatchFlags::IN_CREATE
+    .union(AddWatchFlags::IN_CLOSE_WRITE)
+    .union(AddWatchFlags::IN_DELETE)
+    .union(AddWatchFlags::IN_MOVED_FROM)
+    .union(AddWatchFlags::IN_MOVED_TO);
+
+/// Inotify watches on the config directory and its `<unit>.toml.d/` drop-in
+/// directories, plus the timer that debounces them
+pub(crate) struct ConfigWatch {
+    inotify: Inotify,
+    debounce: TimerFd,
+    dir: PathBuf,
+}
+
+impl ConfigWatch {
+    pub(crate) fn new(dir: &Path) -> Result<Self> {
+        let inotify = Inotify::init(InitFlags::IN_NONBLOCK | InitFlags::IN_CLOEXEC)?;
+        inotify
+            .add_watch(
+                dir,
+                WATCH_FLAGS | AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_MOVE_SELF,
+            )
+            .with_context(|| format!("Failed to watch {:?}", dir))?;
+
+        let debounce = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )?;
+        let watch = Self {
+            inotify,
+            debounce,
+            dir: dir.to_path_buf(),
+        };
+
+        for entry in fs::read_dir(dir)?.flatten() {
+            if is_drop_in_dir(entry.file_name().as_ref()) {
+                watch.watch_drop_ins(entry.file_name().as_ref());
+            }
+        }
+        Ok(watch)
+    }
+
+    /// Adds a watch on the drop-in directory `name` inside the config directory
+    fn watch_drop_ins(&self, name: &Path) {
+        let path = self.dir.join(name);
+        if !path.is_dir() {
+            return;
+        }
+        if let Err(e) = self.inotify.add_watch(&path, WATCH_FLAGS) {
+            warn!("Failed to watch drop-in directory {:?}: {}", path, e);
+        }
+    }
+
+    pub(crate) fn inotify(&self) -> &Inotify {
+        &self.inotify
+    }
+
+    pub(crate) fn debounce(&self) -> &TimerFd {
+        &self.debounce
+    }
+
+    /// Drains pending inotify events; (re)starts the debounce if any concerned a unit file
+    pub(crate) fn handle_events(&self) -> nix::Result<()> {
+        let mut changed = false;
+        loop {
+            let events = match self.inotify.read_events() {
+                Ok(events) => events,
+                Err(nix::Error::EAGAIN) => break,
+                Err(e) => return Err(e),
+            };
+
+            for event in events {
+                if event
+                    .mask
+                    .intersects(AddWatchFlags::IN_DELETE_SELF | AddWatchFlags::IN_MOVE_SELF)
+                {
+                    warn!("Configuration directory was removed or moved, no longer watching it");
+                }
+
+                let name = event.name.as_deref().map(Path::new);
+                // Drop-ins in a new directory only appear once it is watched
+                let new_drop_ins = name.filter(|name| {
+                    event
+                        .mask
+                        .intersects(AddWatchFlags::IN_CREATE | AddWatchFlags::IN_MOVED_TO)
+                        && event.mask.contains(AddWatchFlags::IN_ISDIR)
+                        && is_drop_in_dir(name)
+                });
+                if let Some(name) = new_drop_ins {
+                    self.watch_drop_ins(name);
+                }
+
+                // An overflow may have dropped unit file events
+                let relevant = event.mask.contains(AddWatchFlags::IN_Q_OVERFLOW)
+                    || name.is_some_and(|name| is_config_file(name) || is_drop_in_dir(name));
+                if relevant {
+                    debug!("Configuration change: {:?} {:?}", event.mask, event.name);
+                    changed = true;
+                }
+            }
+        }
+
+        if changed {
+            self.debounce.set(
+                Expiration::OneShot(TimeSpec::from(RELOAD_DEBOUNCE)),
+                TimerSetTimeFlags::empty(),
+            )?;
+        }
+        Ok(())
+    }
+
+    /// Whether the debounce elapsed since the last call, i.e. a reload is due
+    pub(crate) fn reload_due(&self) -> bool {
+        let mut buf = [0u8; 8];
+        nix::unistd::read(self.debounce.as_fd().as_raw_fd(), &mut buf).is_ok_and(|n| n == 8)
+    }
+}
+
+/// Whether `name` is a `<unit>.toml.d` (or `.json.d`, ...) drop-in directory name
+fn is_drop_in_dir(name: &Path) -> bool {
+    name.to_str()
+        .and_then(|name| name.strip_suffix(".d"))
+        .is_some_and(|file| is_config_file(Path::new(file)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::thread;
+
+    fn armed(watch: &ConfigWatch) -> bool {
+        watch.debounce().get().unwrap().is_some()
+    }
+
+    #[test]
+    fn burst_of_changes_schedules_one_reload() {
+        let dir = env::temp_dir().join(format!("micetimer-watch-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let watch = ConfigWatch::new(&dir).unwrap();
+
+        // Files that aren't units don't count
+        fs::write(dir.join("notes.txt"), "").unwrap();
+        watch.handle_events().unwrap();
+        assert!(!armed(&watch));
+
+        for i in 0..5 {
+            fs::write(dir.join("a.toml"), format!("# edit {}", i)).unwrap();
+            fs::write(dir.join(format!("b{}.toml", i)), "").unwrap();
+        }
+        fs::remove_file(dir.join("b0.toml")).unwrap();
+        watch.handle_events().unwrap();
+        assert!(armed(&watch));
+        assert!(!watch.reload_due());
+
+        thread::sleep(RELOAD_DEBOUNCE + Duration::from_millis(100));
+        assert!(watch.reload_due());
+        assert!(!watch.reload_due());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
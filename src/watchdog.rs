@@ -0,0 +1,127 @@
+//! Aborting the daemon when its event loop stops making progress, so the init
+//! system restarts it instead of it going silent
+
+use log::error;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// Heartbeat value that disarms the monitor
+const DISARMED: u64 = u64::MAX;
+
+/// The event loop's heartbeat and the thread checking it
+///
+/// Times are milliseconds on the monotonic clock, which stops during suspend, so
+/// sleeping through the night doesn't count as a stall.
+pub(crate) struct Watchdog {
+    timeout: Duration,
+    start: Instant,
+    heartbeat: Arc<AtomicU64>,
+}
+
+impl Watchdog {
+    /// Starts the monitor, which aborts the process once no [`Watchdog::beat`] came
+    /// for `timeout`
+    pub(crate) fn start(timeout: Duration) -> std::io::Result<Self> {
+        Self::start_with(timeout, move |stalled| {
+            error!(
+                "Event loop stalled for {:?} (watchdog {:?}), aborting",
+                stalled, timeout
+            );
+            log::logger().flush();
+            std::process::abort();
+        })
+    }
+
+    /// Starts the monitor, which calls `on_stall` with how long the loop has been
+    /// silent once no [`Watchdog::beat`] came for `timeout`, then stops
+    fn start_with(
+        timeout: Duration,
+        on_stall: impl FnOnce(Duration) + Send + 'static,
+    ) -> std::io::Result<Self> {
+        let start = Instant::now();
+        let heartbeat = Arc::new(AtomicU64::new(0));
+        let shared = heartbeat.clone();
+        thread::Builder::new()
+            .name("watchdog".to_string())
+            .spawn(move || {
+                loop {
+                    thread::sleep(timeout / 4);
+                    let last = shared.load(Ordering::Relaxed);
+                    if last == DISARMED {
+                        return;
+                    }
+                    let stalled = millis_since(start).saturating_sub(last);
+                    if stalled > timeout.as_millis() as u64 {
+                        on_stall(Duration::from_millis(stalled));
+                        return;
+                    }
+                }
+            })?;
+        Ok(Self {
+            timeout,
+            start,
+            heartbeat,
+        })
+    }
+
+    /// Longest the event loop may block in one wait, leaving half the timeout for
+    /// handling what woke it
+    pub(crate) fn max_wait(&self) -> Duration {
+        self.timeout / 2
+    }
+
+    /// Records that the event loop is alive
+    pub(crate) fn beat(&self) {
+        self.heartbeat
+            .store(millis_since(self.start), Ordering::Relaxed);
+    }
+
+    /// Stops the monitor, e.g. before a shutdown that may wait on workers
+    pub(crate) fn disarm(&self) {
+        self.heartbeat.store(DISARMED, Ordering::Relaxed);
+    }
+}
+
+fn millis_since(start: Instant) -> u64 {
+    start.elapsed().as_millis() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn stalled_heartbeat_triggers_the_watchdog() {
+        let timeout = Duration::from_millis(100);
+        let (stalls, stalled) = mpsc::channel();
+        let watchdog = Watchdog::start_with(timeout, move |silent| {
+            stalls.send(silent).unwrap();
+        })
+        .unwrap();
+
+        // Beating well within the timeout keeps it quiet
+        for _ in 0..6 {
+            watchdog.beat();
+            thread::sleep(timeout / 4);
+        }
+        assert!(stalled.try_recv().is_err());
+
+        let silent = stalled.recv_timeout(Duration::from_secs(2)).unwrap();
+        assert!(silent > timeout, "{:?}", silent);
+    }
+
+    #[test]
+    fn disarmed_watchdog_never_fires() {
+        let timeout = Duration::from_millis(40);
+        let (stalls, stalled) = mpsc::channel();
+        let watchdog = Watchdog::start_with(timeout, move |silent| {
+            stalls.send(silent).unwrap();
+        })
+        .unwrap();
+        watchdog.disarm();
+        assert!(stalled.recv_timeout(timeout * 5).is_err());
+    }
+}
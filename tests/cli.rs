@@ -0,0 +1,452 @@
+//! End-to-end tests of the `micetimer` binary
+
+use std::env;
+use std::fs;
+use std::io::{Read, Write};
+use std::net::Shutdown;
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// A scratch directory per test, holding an empty `conf` directory and room for the
+/// daemon's PID file, socket and state
+struct Sandbox {
+    root: PathBuf,
+}
+
+impl Sandbox {
+    fn new(test: &str) -> Self {
+        let root = env::temp_dir().join(format!("micetimer-cli-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("conf")).unwrap();
+        Self { root }
+    }
+
+    fn conf(&self) -> PathBuf {
+        self.root.join("conf")
+    }
+
+    fn path(&self, name: &str) -> PathBuf {
+        self.root.join(name)
+    }
+
+    fn write(&self, file: &str, content: &str) -> PathBuf {
+        let path = self.conf().join(file);
+        fs::write(&path, content).unwrap();
+        path
+    }
+
+    /// The binary, with every path it writes to inside the sandbox
+    fn micetimer(&self) -> Command {
+        let mut command = Command::new(env!("CARGO_BIN_EXE_micetimer"));
+        command
+            .arg("--config-dir")
+            .arg(self.conf())
+            .arg("--pid-file")
+            .arg(self.path("micetimer.pid"))
+            .arg("--control-socket")
+            .arg(self.path("control.sock"))
+            .args(["--wakelock-backend", "none"]);
+        command
+    }
+
+    /// The daemon's own flags, on top of [`Sandbox::micetimer`]
+    fn daemon(&self) -> Command {
+        let mut command = self.micetimer();
+        command
+            .arg("--state-file")
+            .arg(self.path("state.json"))
+            .arg("--metrics-file")
+            .arg(self.path("metrics.json"));
+        command
+    }
+
+    /// The daemon in the foreground with `args`, once its control socket is up
+    fn spawn_daemon(&self, args: &[&str]) -> Child {
+        let daemon = self
+            .daemon()
+            .arg("--foreground")
+            .args(args)
+            .stdin(Stdio::null())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        wait_for("the control socket", || self.path("control.sock").exists());
+        daemon
+    }
+
+    /// Sends one control request and returns the whole response
+    fn request(&self, line: &str) -> String {
+        let mut stream = UnixStream::connect(self.path("control.sock")).unwrap();
+        writeln!(stream, "{}", line).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        response
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.root);
+    }
+}
+
+fn wait_for(what: &str, mut done: impl FnMut() -> bool) {
+    let deadline = Instant::now() + Duration::from_secs(5);
+    while !done() {
+        assert!(Instant::now() < deadline, "timed out waiting for {}", what);
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+fn read_pid(path: &Path) -> Option<i32> {
+    fs::read_to_string(path).ok()?.trim().parse().ok()
+}
+
+/// Fields of `/proc/<pid>/stat` after the command name, starting with the state
+fn proc_stat(pid: i32) -> Option<Vec<String>> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid)).ok()?;
+    let (_, fields) = stat.rsplit_once(')')?;
+    Some(fields.split_whitespace().map(String::from).collect())
+}
+
+/// Running, as opposed to gone or a zombie nobody reaped yet
+fn is_alive(pid: i32) -> bool {
+    proc_stat(pid).is_some_and(|fields| fields[0] != "Z")
+}
+
+#[test]
+fn daemon_detaches_into_its_own_session() {
+    let sandbox = Sandbox::new("detach");
+    sandbox.write("idle.toml", "Exec = \"true\"\nOnBootSec = \"1d\"\n");
+    let mut launcher = sandbox
+        .daemon()
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let launched = launcher.wait().unwrap();
+    assert!(launched.success());
+
+    let pid = read_pid(&sandbox.path("micetimer.pid")).expect("no PID file");
+    assert_ne!(pid, launcher.id() as i32);
+    assert!(is_alive(pid));
+    // The daemon runs in a new session, and isn't its leader
+    let session: i32 = proc_stat(pid).unwrap()[3].parse().unwrap();
+    assert_ne!(session, pid);
+    assert_ne!(session, std::process::id() as i32);
+
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    wait_for("the daemon to exit", || !is_alive(pid));
+}
+
+#[test]
+fn check_fails_naming_the_invalid_file() {
+    let sandbox = Sandbox::new("check");
+    sandbox.write("good.toml", "Exec = \"true\"\nOnBootSec = \"1h\"\n");
+    let bad = sandbox.write("bad.toml", "Exec = \n");
+    let output = sandbox.micetimer().arg("check").output().unwrap();
+    assert!(!output.status.success());
+
+    let printed = format!(
+        "{}{}",
+        String::from_utf8_lossy(&output.stdout),
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert!(printed.contains(&bad.display().to_string()), "{}", printed);
+    assert!(printed.contains("good: next elapse in"), "{}", printed);
+}
+
+#[test]
+fn control_socket_lists_and_triggers_units() {
+    let sandbox = Sandbox::new("control");
+    let marker = sandbox.path("triggered");
+    sandbox.write(
+        "touch.toml",
+        &format!(
+            "Exec = \"touch {}\"\nOnBootSec = \"1d\"\nWakeLock = false\n",
+            marker.display()
+        ),
+    );
+    let mut daemon = sandbox.spawn_daemon(&[]);
+
+    let mode = fs::metadata(sandbox.path("control.sock"))
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    let list = sandbox.request("LIST");
+    assert!(list.starts_with("touch\tin "), "{}", list);
+    let json = sandbox.request("JSON LIST");
+    assert!(
+        json.starts_with(r#"[{"#) && json.contains(r#""name":"touch""#),
+        "{}",
+        json
+    );
+
+    assert_eq!(sandbox.request("TRIGGER touch"), "started\n");
+    wait_for("the triggered command", || marker.exists());
+    assert!(sandbox.request("TRIGGER nope").starts_with("ERR "));
+
+    unsafe { libc::kill(daemon.id() as i32, libc::SIGTERM) };
+    assert!(daemon.wait().unwrap().success());
+}
+
+#[test]
+fn trigger_without_a_daemon_runs_the_unit_inline() {
+    let sandbox = Sandbox::new("trigger-inline");
+    let marker = sandbox.path("ran");
+    sandbox.write(
+        "ok.toml",
+        &format!(
+            "Exec = \"touch {}\"\nOnBootSec = \"1d\"\nWakeLock = false\n",
+            marker.display()
+        ),
+    );
+    sandbox.write(
+        "broken.toml",
+        "Exec = \"exit 3\"\nOnBootSec = \"1d\"\nWakeLock = false\n",
+    );
+    let trigger = |name: &str| {
+        sandbox
+            .micetimer()
+            .args(["trigger", name])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .status()
+            .unwrap()
+    };
+
+    assert!(trigger("ok").success());
+    assert!(marker.exists());
+    assert!(!trigger("broken").success());
+    assert!(!trigger("missing").success());
+}
+
+#[test]
+fn once_runs_every_unit_and_reports_a_failure() {
+    let sandbox = Sandbox::new("once");
+    let ok = sandbox.path("ok-ran");
+    let failed = sandbox.path("failed-ran");
+    for (name, marker, result) in [("a-ok", &ok, "true"), ("b-failing", &failed, "false")] {
+        sandbox.write(
+            &format!("{}.toml", name),
+            &format!(
+                "Exec = \"touch {}; {}\"\nOnBootSec = \"1d\"\nWakeLock = false\n",
+                marker.display(),
+                result
+            ),
+        );
+    }
+    let status = sandbox
+        .daemon()
+        .arg("--once")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert!(!status.success());
+    assert!(ok.exists());
+    assert!(failed.exists());
+}
+
+#[test]
+fn list_json_includes_disabled_and_invalid_units() {
+    let sandbox = Sandbox::new("list");
+    sandbox.write("on.toml", "Exec = \"true\"\nOnBootSec = \"1h\"\n");
+    sandbox.write(
+        "off.toml",
+        "Exec = \"true\"\nOnBootSec = \"1h\"\nEnabled = false\n",
+    );
+    sandbox.write("broken.toml", "Exec = \n");
+    let output = sandbox
+        .micetimer()
+        .args(["list", "--output", "json"])
+        .output()
+        .unwrap();
+    // The invalid file fails the command, but is listed all the same
+    assert!(!output.status.success());
+
+    let units: Vec<serde_json::Value> = serde_json::from_slice(&output.stdout).unwrap();
+    let rows: Vec<(&str, &serde_json::Value)> = units
+        .iter()
+        .map(|unit| (unit["name"].as_str().unwrap(), &unit["enabled"]))
+        .collect();
+    assert_eq!(
+        rows,
+        [
+            ("broken", &serde_json::Value::Null),
+            ("off", &false.into()),
+            ("on", &true.into())
+        ]
+    );
+    assert!(units[0]["error"].as_str().unwrap().contains("broken.toml"));
+    assert_eq!(units[1]["next_elapse_sec"], serde_json::Value::Null);
+    assert_eq!(units[2]["schedule"], "OnBootSec=1h");
+}
+
+#[test]
+fn dry_run_rearms_without_running_the_command() {
+    let sandbox = Sandbox::new("dry-run");
+    let marker = sandbox.path("ran");
+    sandbox.write(
+        "often.toml",
+        &format!(
+            "Exec = \"touch {}\"\nOnStartupSec = \"10ms\"\nOnUnitActiveSec = \"100ms\"\n\
+             AccuracySec = \"0s\"\nWakeLock = false\n",
+            marker.display()
+        ),
+    );
+    let mut daemon = sandbox.spawn_daemon(&["--dry-run"]);
+
+    let status = || -> serde_json::Value {
+        serde_json::from_str(&sandbox.request("JSON STATUS often")).unwrap()
+    };
+    wait_for("a dry firing", || status()["last_skip_reason"] == "dry-run");
+    let first = status()["last_skip"].as_u64().unwrap();
+    // Skipped firings keep coming on schedule
+    wait_for("a dry firing a second later", || {
+        status()["last_skip"].as_u64().unwrap() > first
+    });
+    let status = status();
+    assert_eq!(status["runs"], 0);
+    assert!(status["next_elapse_sec"].is_u64());
+    assert!(!marker.exists());
+
+    unsafe { libc::kill(daemon.id() as i32, libc::SIGTERM) };
+    assert!(daemon.wait().unwrap().success());
+}
+
+#[test]
+fn check_reports_every_broken_file() {
+    let sandbox = Sandbox::new("check-all");
+    sandbox.write("good.toml", "Exec = \"true\"\nOnBootSec = \"1h\"\n");
+    let typed = sandbox.write("typed.toml", "Exec = \"true\"\nOnBootSec = 5\n");
+    let syntax = sandbox.write("syntax.toml", "Exec = \"true\nOnBootSec = \n");
+    let output = sandbox.micetimer().arg("check").output().unwrap();
+    assert!(!output.status.success());
+
+    let errors = String::from_utf8_lossy(&output.stderr);
+    let typed_line = errors
+        .lines()
+        .find(|line| line.starts_with(&typed.display().to_string()))
+        .expect(&errors);
+    assert!(typed_line.contains("Line 2"), "{}", errors);
+    assert!(errors.contains(&syntax.display().to_string()), "{}", errors);
+    assert!(
+        errors.contains("2 of 3 configuration file(s) are invalid"),
+        "{}",
+        errors
+    );
+}
+
+#[test]
+fn set_rearms_a_unit_with_the_new_interval() {
+    let sandbox = Sandbox::new("set");
+    sandbox.write(
+        "tuned.toml",
+        "Exec = \"true\"\nOnStartupSec = \"10ms\"\nOnUnitActiveSec = \"1h\"\n\
+         AccuracySec = \"0s\"\nWakeLock = false\n",
+    );
+    let mut daemon = sandbox.spawn_daemon(&[]);
+    let status = |request: &str| -> serde_json::Value {
+        serde_json::from_str(&sandbox.request(request)).unwrap()
+    };
+    wait_for("the first run", || status("JSON STATUS tuned")["runs"] == 1);
+    assert!(
+        status("JSON STATUS tuned")["next_elapse_sec"]
+            .as_u64()
+            .unwrap()
+            > 3500
+    );
+
+    let set = status("JSON SET tuned OnUnitActiveSec=30s");
+    assert_eq!(set["name"], "tuned");
+    assert!(set["next_elapse_sec"].as_u64().unwrap() <= 30, "{}", set);
+    assert!(
+        status("JSON STATUS tuned")["next_elapse_sec"]
+            .as_u64()
+            .unwrap()
+            <= 30
+    );
+
+    assert!(
+        sandbox
+            .request("SET nope OnUnitActiveSec=30s")
+            .starts_with("ERR ")
+    );
+    assert!(
+        sandbox
+            .request("SET tuned OnBootSec=30s")
+            .starts_with("ERR ")
+    );
+
+    unsafe { libc::kill(daemon.id() as i32, libc::SIGTERM) };
+    assert!(daemon.wait().unwrap().success());
+}
+
+#[test]
+fn relative_exec_file_still_runs_once_daemonized() {
+    let sandbox = Sandbox::new("relative-exec-file");
+    let marker = sandbox.path("ran");
+    sandbox.write("s.sh", &format!("touch {}\n", marker.display()));
+    sandbox.write(
+        "x.toml",
+        "ExecFile = \"s.sh\"\nOnStartupSec = \"10ms\"\nAccuracySec = \"0s\"\nWakeLock = false\n",
+    );
+    // Loaded relative to the launching directory, run after the daemon left it for "/"
+    let launched = Command::new(env!("CARGO_BIN_EXE_micetimer"))
+        .current_dir(&sandbox.root)
+        .args(["--config-dir", "conf", "--pid-file", "micetimer.pid"])
+        .args([
+            "--control-socket",
+            "control.sock",
+            "--state-file",
+            "state.json",
+        ])
+        .args([
+            "--metrics-file",
+            "metrics.json",
+            "--wakelock-backend",
+            "none",
+        ])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+    assert!(launched.success());
+
+    let pid = read_pid(&sandbox.path("micetimer.pid")).expect("no PID file");
+    wait_for("the script to run", || marker.exists());
+    unsafe { libc::kill(pid, libc::SIGTERM) };
+    wait_for("the daemon to exit", || !is_alive(pid));
+}
+
+#[test]
+fn persistent_stamps_land_beside_the_state_file() {
+    let sandbox = Sandbox::new("stamps");
+    sandbox.write(
+        "stamped.toml",
+        "Exec = \"true\"\nOnBootSec = \"1d\"\nPersistent = true\nWakeLock = false\n",
+    );
+    let status = sandbox
+        .daemon()
+        .arg("--once")
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()
+        .unwrap();
+
+    assert!(status.success());
+    let stamp = fs::read_to_string(sandbox.path("state/stamped.stamp")).unwrap();
+    assert!(stamp.trim().parse::<u64>().is_ok(), "{:?}", stamp);
+}